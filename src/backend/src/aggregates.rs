@@ -0,0 +1,659 @@
+use crate::aliases;
+use crate::{
+    organizations, stations, units, AirQualityData, Error, Pollutant, AIR_QUALITY_STORAGE,
+};
+use ic_cdk::api::time;
+use std::collections::BTreeMap;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const NANOS_PER_HOUR: u64 = 3600 * NANOS_PER_SECOND;
+const NANOS_PER_DAY: u64 = 24 * NANOS_PER_HOUR;
+const MAX_HOURLY_PERIOD_NANOS: u64 = 366 * NANOS_PER_DAY;
+const MAX_PERCENTILES: usize = 20;
+const MIN_TREND_WINDOW_SECONDS: u64 = 60 * 60;
+const MAX_TREND_WINDOW_SECONDS: u64 = 366 * 24 * 60 * 60;
+// A trend moving the level by less than this share of its mean over the window is stable
+const STABLE_CHANGE_FRACTION: f64 = 0.05;
+const RATE_WINDOWS: u64 = 6;
+const MIN_WINDOW_SECONDS: u64 = 5 * 60;
+const MAX_WINDOW_SECONDS: u64 = 7 * 24 * 60 * 60;
+// µg/m³ per hour above which a window is flagged when the caller does not set a threshold
+const DEFAULT_RAPID_DETERIORATION_PER_HOUR: f64 = 10.0;
+
+// Summary of the readings at a location. Includes readings of aggregate-only stations, which
+// public queries never return individually.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct LocationAggregate {
+    location: String,
+    start_timestamp: u64,
+    end_timestamp: u64,
+    readings: u64,
+    average_air_quality_index: Option<f64>,
+    max_air_quality_index: Option<u32>,
+    // Mean level per pollutant in µg/m³, over the readings that report it
+    pollutant_averages: Vec<(Pollutant, f64)>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct MetricSummary {
+    count: u64,
+    min: f64,
+    max: f64,
+    mean: f64,
+}
+
+impl MetricSummary {
+    fn of(values: impl Iterator<Item = f64>) -> Option<MetricSummary> {
+        let mut summary: Option<MetricSummary> = None;
+        let mut sum = 0.0;
+        for value in values {
+            sum += value;
+            let entry = summary.get_or_insert(MetricSummary {
+                count: 0,
+                min: value,
+                max: value,
+                mean: 0.0,
+            });
+            entry.count += 1;
+            entry.min = entry.min.min(value);
+            entry.max = entry.max.max(value);
+        }
+        summary.map(|summary| MetricSummary {
+            mean: sum / summary.count as f64,
+            ..summary
+        })
+    }
+}
+
+// Statistics of the AQI and of each pollutant, in µg/m³, over the readings that report it
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct LocationSummary {
+    location: String,
+    start_timestamp: u64,
+    end_timestamp: u64,
+    readings: u64,
+    // None without readings
+    air_quality_index: Option<MetricSummary>,
+    pollutants: Vec<(Pollutant, MetricSummary)>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct HistogramBin {
+    lower: u64,
+    // Exclusive
+    upper: u64,
+    count: u64,
+}
+
+// Distribution of the AQI over a location's readings. Empty bins are left out.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct AqiHistogram {
+    location: String,
+    start_timestamp: u64,
+    end_timestamp: u64,
+    bin_width: u32,
+    readings: u64,
+    bins: Vec<HistogramBin>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub(crate) enum AggregationBucket {
+    Hour,
+    Day,
+    // UTC calendar month
+    Month,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub(crate) enum AggregationStat {
+    Mean,
+    Max,
+    Min,
+}
+
+// One bucket of a location's time series. Pollutant values are in µg/m³ and only cover the
+// readings that report the pollutant.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ReadingBucket {
+    bucket_start: u64,
+    // Exclusive
+    bucket_end: u64,
+    readings: u64,
+    air_quality_index: f64,
+    pollutants: Vec<(Pollutant, f64)>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct PercentileValue {
+    percentile: f64,
+    // µg/m³; None without readings
+    value: Option<f64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct PollutantPercentiles {
+    location: String,
+    pollutant: Pollutant,
+    start_timestamp: u64,
+    end_timestamp: u64,
+    readings: u64,
+    percentiles: Vec<PercentileValue>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum TrendDirection {
+    Improving,
+    Stable,
+    Worsening,
+}
+
+// Linear trend of a pollutant over the most recent window. Direction and slope are None with
+// fewer than two readings at distinct times.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct Trend {
+    location: String,
+    pollutant: Pollutant,
+    window_start: u64,
+    window_end: u64,
+    readings: u64,
+    // µg/m³
+    mean: Option<f64>,
+    // µg/m³ per hour
+    slope_per_hour: Option<f64>,
+    // Change of the fitted line over the whole window, µg/m³
+    change: Option<f64>,
+    direction: Option<TrendDirection>,
+}
+
+// Trend of one pollutant over a window. The rate is the least-squares slope of the window's
+// levels, so a single outlier does not dominate it.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct RateOfChange {
+    window_start: u64,
+    window_end: u64,
+    readings: u64,
+    // µg/m³ per hour; None with fewer than two readings at distinct times
+    rate_per_hour: Option<f64>,
+    rapid_deterioration: bool,
+}
+
+// Live readings of a location in the period the caller may count: aggregate-only stations
+// included, embargoed readings and those of organizations hidden from the caller left out
+pub(crate) fn readings_at(
+    location: &str,
+    start_timestamp: u64,
+    end_timestamp: u64,
+) -> Vec<AirQualityData> {
+    AIR_QUALITY_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, data)| {
+                !data.is_deleted()
+                    && data.is_published()
+                    && data.location == location
+                    && data.timestamp >= start_timestamp
+                    && data.timestamp <= end_timestamp
+                    && organizations::is_visible_to_caller(data.organization_id)
+                    && data.station_id.as_deref().is_none_or(|station_id| {
+                        !stations::is_embargoed(station_id, data.timestamp)
+                    })
+            })
+            .map(|(_, data)| data)
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn get_location_aggregate(
+    location: String,
+    start_timestamp: u64,
+    end_timestamp: u64,
+) -> LocationAggregate {
+    let location = aliases::location(location);
+    let readings = readings_at(&location, start_timestamp, end_timestamp);
+    let count = readings.len() as u64;
+
+    let mut pollutant_sums: BTreeMap<Pollutant, (f64, u64)> = BTreeMap::new();
+    for data in &readings {
+        for pollutant in data.pollutant_levels.keys() {
+            if let Some(level) = level_in_micrograms(data, pollutant) {
+                let entry = pollutant_sums.entry(pollutant.clone()).or_default();
+                entry.0 += level;
+                entry.1 += 1;
+            }
+        }
+    }
+
+    LocationAggregate {
+        average_air_quality_index: (count > 0).then(|| {
+            readings
+                .iter()
+                .map(|data| data.air_quality_index as f64)
+                .sum::<f64>()
+                / count as f64
+        }),
+        max_air_quality_index: readings.iter().map(|data| data.air_quality_index).max(),
+        pollutant_averages: pollutant_sums
+            .into_iter()
+            .map(|(pollutant, (sum, n))| (pollutant, sum / n as f64))
+            .collect(),
+        location,
+        start_timestamp,
+        end_timestamp,
+        readings: count,
+    }
+}
+
+// The p-th percentile of sorted levels by the nearest-rank method; None without levels
+fn nearest_rank(sorted: &[f64], p: f64) -> Option<f64> {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil().max(1.0) as usize;
+    sorted.get(rank - 1).copied()
+}
+
+// Percentiles of a pollutant's levels at a location, in µg/m³, by the nearest-rank method:
+// the p-th percentile is the smallest level that at least p% of the readings do not exceed
+#[ic_cdk::query]
+pub(crate) fn get_pollutant_percentiles(
+    location: String,
+    pollutant: Pollutant,
+    start_timestamp: u64,
+    end_timestamp: u64,
+    percentiles: Vec<f64>,
+) -> Result<PollutantPercentiles, Error> {
+    let location = aliases::location(location);
+    if percentiles.is_empty()
+        || percentiles.len() > MAX_PERCENTILES
+        || percentiles.iter().any(|p| !(*p > 0.0 && *p <= 100.0))
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "pass 1 to {} percentiles, each above 0 and at most 100",
+                MAX_PERCENTILES
+            ),
+            violations: None,
+        });
+    }
+    let pollutant = pollutant.normalized();
+    let mut levels: Vec<f64> = readings_at(&location, start_timestamp, end_timestamp)
+        .iter()
+        .filter_map(|data| level_in_micrograms(data, &pollutant))
+        .collect();
+    levels.sort_by(f64::total_cmp);
+
+    Ok(PollutantPercentiles {
+        percentiles: percentiles
+            .into_iter()
+            .map(|percentile| PercentileValue {
+                percentile,
+                value: nearest_rank(&levels, percentile),
+            })
+            .collect(),
+        readings: levels.len() as u64,
+        location,
+        pollutant,
+        start_timestamp,
+        end_timestamp,
+    })
+}
+
+// Days since 1970-01-01 of the first day of a month (Hinnant's days_from_civil)
+pub(crate) fn days_from_civil(year: i64, month: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let shifted_month = (month + 9) % 12;
+    let day_of_year = (153 * shifted_month as i64 + 2) / 5;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// (year, month) of a day since 1970-01-01 (Hinnant's civil_from_days)
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month)
+}
+
+// Start and exclusive end of the bucket a timestamp falls in
+pub(crate) fn bucket_bounds(timestamp: u64, bucket: AggregationBucket) -> (u64, u64) {
+    let fixed = |width: u64| {
+        let start = timestamp - timestamp % width;
+        (start, start.saturating_add(width))
+    };
+    match bucket {
+        AggregationBucket::Hour => fixed(NANOS_PER_HOUR),
+        AggregationBucket::Day => fixed(NANOS_PER_DAY),
+        AggregationBucket::Month => {
+            let (year, month) = civil_from_days((timestamp / NANOS_PER_DAY) as i64);
+            let (next_year, next_month) = if month == 12 {
+                (year + 1, 1)
+            } else {
+                (year, month + 1)
+            };
+            let start = days_from_civil(year, month) as u64 * NANOS_PER_DAY;
+            let end = days_from_civil(next_year, next_month) as u64 * NANOS_PER_DAY;
+            (start, end)
+        }
+    }
+}
+
+fn combine(values: &[f64], stat: AggregationStat) -> f64 {
+    match stat {
+        AggregationStat::Mean => values.iter().sum::<f64>() / values.len() as f64,
+        AggregationStat::Max => values.iter().copied().fold(f64::MIN, f64::max),
+        AggregationStat::Min => values.iter().copied().fold(f64::MAX, f64::min),
+    }
+}
+
+// The time series of a location bucketed into hours, days or months, oldest first. Buckets
+// without readings are left out. Hourly buckets are limited to periods of up to 366 days.
+#[ic_cdk::query]
+pub(crate) fn aggregate_readings(
+    location: String,
+    start_timestamp: u64,
+    end_timestamp: u64,
+    bucket: AggregationBucket,
+    stat: AggregationStat,
+) -> Result<Vec<ReadingBucket>, Error> {
+    let location = aliases::location(location);
+    if start_timestamp > end_timestamp {
+        return Err(Error::InvalidInput {
+            msg: "start_timestamp must not be after end_timestamp".to_string(),
+            violations: None,
+        });
+    }
+    if matches!(bucket, AggregationBucket::Hour)
+        && end_timestamp - start_timestamp > MAX_HOURLY_PERIOD_NANOS
+    {
+        return Err(Error::InvalidInput {
+            msg: "hourly buckets cover at most 366 days".to_string(),
+            violations: None,
+        });
+    }
+
+    let mut buckets: BTreeMap<(u64, u64), Vec<AirQualityData>> = BTreeMap::new();
+    for data in readings_at(&location, start_timestamp, end_timestamp) {
+        buckets
+            .entry(bucket_bounds(data.timestamp, bucket))
+            .or_default()
+            .push(data);
+    }
+
+    Ok(buckets
+        .into_iter()
+        .map(|((bucket_start, bucket_end), readings)| {
+            let indices: Vec<f64> = readings
+                .iter()
+                .map(|data| data.air_quality_index as f64)
+                .collect();
+            let mut levels: BTreeMap<Pollutant, Vec<f64>> = BTreeMap::new();
+            for data in &readings {
+                for pollutant in data.pollutant_levels.keys() {
+                    if let Some(level) = level_in_micrograms(data, pollutant) {
+                        levels.entry(pollutant.clone()).or_default().push(level);
+                    }
+                }
+            }
+            ReadingBucket {
+                bucket_start,
+                bucket_end,
+                readings: readings.len() as u64,
+                air_quality_index: combine(&indices, stat),
+                pollutants: levels
+                    .into_iter()
+                    .map(|(pollutant, values)| (pollutant, combine(&values, stat)))
+                    .collect(),
+            }
+        })
+        .collect())
+}
+
+#[ic_cdk::query]
+pub(crate) fn get_location_summary(
+    location: String,
+    start_timestamp: u64,
+    end_timestamp: u64,
+) -> LocationSummary {
+    let location = aliases::location(location);
+    let readings = readings_at(&location, start_timestamp, end_timestamp);
+    let mut levels: BTreeMap<Pollutant, Vec<f64>> = BTreeMap::new();
+    for data in &readings {
+        for pollutant in data.pollutant_levels.keys() {
+            if let Some(level) = level_in_micrograms(data, pollutant) {
+                levels.entry(pollutant.clone()).or_default().push(level);
+            }
+        }
+    }
+    LocationSummary {
+        readings: readings.len() as u64,
+        air_quality_index: MetricSummary::of(
+            readings.iter().map(|data| data.air_quality_index as f64),
+        ),
+        pollutants: levels
+            .into_iter()
+            .filter_map(|(pollutant, values)| {
+                Some((pollutant, MetricSummary::of(values.into_iter())?))
+            })
+            .collect(),
+        location,
+        start_timestamp,
+        end_timestamp,
+    }
+}
+
+#[ic_cdk::query]
+fn get_aqi_histogram(
+    location: String,
+    start_timestamp: u64,
+    end_timestamp: u64,
+    bin_width: u32,
+) -> Result<AqiHistogram, Error> {
+    let location = aliases::location(location);
+    if bin_width == 0 {
+        return Err(Error::InvalidInput {
+            msg: "bin_width must be positive".to_string(),
+            violations: None,
+        });
+    }
+    let readings = readings_at(&location, start_timestamp, end_timestamp);
+    let mut counts: BTreeMap<u32, u64> = BTreeMap::new();
+    for data in &readings {
+        *counts
+            .entry(data.air_quality_index / bin_width)
+            .or_default() += 1;
+    }
+    Ok(AqiHistogram {
+        bins: counts
+            .into_iter()
+            .map(|(bin, count)| {
+                let lower = bin as u64 * bin_width as u64;
+                HistogramBin {
+                    lower,
+                    upper: lower + bin_width as u64,
+                    count,
+                }
+            })
+            .collect(),
+        readings: readings.len() as u64,
+        location,
+        start_timestamp,
+        end_timestamp,
+        bin_width,
+    })
+}
+
+// The level of a pollutant in µg/m³. Levels without a recorded unit are taken as µg/m³.
+pub(crate) fn level_in_micrograms(data: &AirQualityData, pollutant: &Pollutant) -> Option<f64> {
+    let level = *data.pollutant_levels.get(pollutant)?;
+    match data
+        .pollutant_units
+        .as_ref()
+        .and_then(|units| units.get(pollutant))
+    {
+        Some(unit) => units::to_micrograms(pollutant, level, *unit, &data.weather_conditions),
+        None => Some(level),
+    }
+}
+
+// Least-squares slope of (nanoseconds, level) points, per hour
+fn slope_per_hour(points: &[(u64, f64)]) -> Option<f64> {
+    let n = points.len() as f64;
+    let origin = points.first()?.0;
+    let hours: Vec<f64> = points
+        .iter()
+        .map(|(t, _)| (t - origin) as f64 / (3600 * NANOS_PER_SECOND) as f64)
+        .collect();
+    let mean_t = hours.iter().sum::<f64>() / n;
+    let mean_v = points.iter().map(|(_, v)| v).sum::<f64>() / n;
+    let (covariance, variance) = hours.iter().zip(points).fold((0.0, 0.0), |(c, v), (t, p)| {
+        (
+            c + (t - mean_t) * (p.1 - mean_v),
+            v + (t - mean_t) * (t - mean_t),
+        )
+    });
+    (variance > 0.0).then(|| covariance / variance)
+}
+
+// Least-squares trend of a pollutant at a location over the last `window_seconds` (1 hour to
+// 366 days). Falling levels are improving; a change under 5% of the mean is stable.
+#[ic_cdk::query]
+pub(crate) fn get_trend(
+    location: String,
+    pollutant: Pollutant,
+    window_seconds: u64,
+) -> Result<Trend, Error> {
+    let location = aliases::location(location);
+    if !(MIN_TREND_WINDOW_SECONDS..=MAX_TREND_WINDOW_SECONDS).contains(&window_seconds) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "window_seconds must be between {} and {}",
+                MIN_TREND_WINDOW_SECONDS, MAX_TREND_WINDOW_SECONDS
+            ),
+            violations: None,
+        });
+    }
+    let pollutant = pollutant.normalized();
+    let window_end = time();
+    let window_start = window_end.saturating_sub(window_seconds * NANOS_PER_SECOND);
+    let mut points: Vec<(u64, f64)> = readings_at(&location, window_start, window_end)
+        .iter()
+        .filter_map(|data| Some((data.timestamp, level_in_micrograms(data, &pollutant)?)))
+        .collect();
+    points.sort_by_key(|(timestamp, _)| *timestamp);
+
+    let mean = (!points.is_empty())
+        .then(|| points.iter().map(|(_, level)| level).sum::<f64>() / points.len() as f64);
+    let slope = slope_per_hour(&points);
+    let change = slope.map(|slope| slope * window_seconds as f64 / 3600.0);
+    let direction = change.zip(mean).map(|(change, mean)| {
+        if change.abs() < STABLE_CHANGE_FRACTION * mean.abs() {
+            TrendDirection::Stable
+        } else if change < 0.0 {
+            TrendDirection::Improving
+        } else {
+            TrendDirection::Worsening
+        }
+    });
+    Ok(Trend {
+        location,
+        pollutant,
+        window_start,
+        window_end,
+        readings: points.len() as u64,
+        mean,
+        slope_per_hour: slope,
+        change,
+        direction,
+    })
+}
+
+// Rate of change of a pollutant at a location over the most recent windows, oldest first
+#[ic_cdk::query]
+fn get_rate_of_change(
+    location: String,
+    pollutant: Pollutant,
+    window_seconds: u64,
+    threshold_per_hour: Option<f64>,
+) -> Result<Vec<RateOfChange>, Error> {
+    let location = aliases::location(location);
+    if !(MIN_WINDOW_SECONDS..=MAX_WINDOW_SECONDS).contains(&window_seconds) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "window_seconds must be between {} and {}",
+                MIN_WINDOW_SECONDS, MAX_WINDOW_SECONDS
+            ),
+            violations: None,
+        });
+    }
+    let pollutant = pollutant.normalized();
+    let threshold = threshold_per_hour.unwrap_or(DEFAULT_RAPID_DETERIORATION_PER_HOUR);
+    let window = window_seconds * NANOS_PER_SECOND;
+    let end = time();
+    let start = end.saturating_sub(window * RATE_WINDOWS);
+
+    let mut points: Vec<(u64, f64)> = readings_at(&location, start, end)
+        .iter()
+        .filter_map(|data| Some((data.timestamp, level_in_micrograms(data, &pollutant)?)))
+        .collect();
+    points.sort_by_key(|(timestamp, _)| *timestamp);
+
+    Ok((0..RATE_WINDOWS)
+        .map(|i| {
+            let window_start = start + i * window;
+            let window_end = window_start + window;
+            let in_window: Vec<(u64, f64)> = points
+                .iter()
+                .filter(|(t, _)| *t >= window_start && *t < window_end)
+                .copied()
+                .collect();
+            let rate_per_hour = slope_per_hour(&in_window);
+            RateOfChange {
+                window_start,
+                window_end,
+                readings: in_window.len() as u64,
+                rate_per_hour,
+                rapid_deterioration: rate_per_hour.is_some_and(|rate| rate >= threshold),
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_rank_without_levels_is_none() {
+        assert_eq!(nearest_rank(&[], 50.0), None);
+    }
+
+    #[test]
+    fn nearest_rank_picks_a_stored_level_instead_of_interpolating() {
+        let levels = [10.0, 20.0, 30.0, 40.0];
+        // 50% of 4 readings is rank 2; 60% rounds up to rank 3 rather than lying between
+        assert_eq!(nearest_rank(&levels, 50.0), Some(20.0));
+        assert_eq!(nearest_rank(&levels, 60.0), Some(30.0));
+        assert_eq!(nearest_rank(&levels, 1.0), Some(10.0));
+        assert_eq!(nearest_rank(&levels, 100.0), Some(40.0));
+    }
+
+    #[test]
+    fn nearest_rank_with_ties() {
+        let levels = [5.0, 5.0, 5.0, 9.0];
+        assert_eq!(nearest_rank(&levels, 25.0), Some(5.0));
+        assert_eq!(nearest_rank(&levels, 75.0), Some(5.0));
+        assert_eq!(nearest_rank(&levels, 76.0), Some(9.0));
+    }
+}
@@ -0,0 +1,363 @@
+use crate::aggregates::{civil_from_days, days_from_civil};
+use crate::grafana::parse_time;
+use crate::heatmap::BoundingBox;
+use crate::sources::{self, ReadingSource};
+use crate::units::{ConcentrationUnit, PollutantUnits};
+use crate::{
+    _add_air_quality_data, accepts_writes, ensure_admin, AirQualityUpdatePayload, Error, Pollutant,
+};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpMethod, TransformContext,
+};
+use ic_cdk::api::time;
+use std::collections::{BTreeMap, HashMap};
+
+const API_BASE: &str = "https://www.airnowapi.org/aq";
+const MAX_API_KEY_LEN: usize = 64;
+const DEFAULT_DISTANCE_MILES: u32 = 25;
+const MAX_DISTANCE_MILES: u32 = 100;
+// Hours fetched for a bounding box, so hours the monitors report late are picked up
+const WINDOW_HOURS: u64 = 2;
+const MAX_RESPONSE_BYTES: u64 = 2_000_000;
+// Generous upper bound for a 2MB outcall on a 13-node subnet; unused cycles are refunded
+const OUTCALL_CYCLES: u128 = 50_000_000_000;
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+const NANOS_PER_DAY: u64 = 24 * NANOS_PER_HOUR;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum AirNowArea {
+    // Current AQI of the reporting areas near a US ZIP code. AirNow reports no concentrations
+    // for these.
+    ZipCode {
+        zip_code: String,
+        distance_miles: Option<u32>,
+    },
+    // Hourly concentrations and AQI of every monitor in the box, over the last two hours
+    BoundingBox(BoundingBox),
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct AirNowIngestReport {
+    // Rows in AirNow's response, one per monitor or area, hour and pollutant
+    observations: u32,
+    stored_ids: Vec<u64>,
+    // Readings refused, mostly duplicates of ones ingested before
+    skipped: u32,
+}
+
+// A row of /observation/zipCode/current
+#[derive(Deserialize)]
+struct ZipObservation {
+    #[serde(rename = "DateObserved")]
+    date_observed: String,
+    #[serde(rename = "HourObserved")]
+    hour_observed: u64,
+    #[serde(rename = "LocalTimeZone")]
+    local_time_zone: String,
+    #[serde(rename = "ReportingArea")]
+    reporting_area: String,
+    #[serde(rename = "StateCode")]
+    state_code: String,
+    #[serde(rename = "AQI")]
+    aqi: i64,
+    #[serde(rename = "Category")]
+    category: ZipCategory,
+}
+
+#[derive(Deserialize)]
+struct ZipCategory {
+    #[serde(rename = "Name")]
+    name: String,
+}
+
+// A row of /data with dataType=B and verbose=1
+#[derive(Deserialize)]
+struct MonitorObservation {
+    #[serde(rename = "UTC")]
+    utc: String,
+    #[serde(rename = "Parameter")]
+    parameter: String,
+    #[serde(rename = "Unit")]
+    unit: String,
+    #[serde(rename = "Value")]
+    value: f64,
+    #[serde(rename = "AQI")]
+    aqi: i64,
+    #[serde(rename = "Category")]
+    category: u32,
+    #[serde(rename = "SiteName")]
+    site_name: String,
+}
+
+// What the rows of one area or monitor and hour add up to
+#[derive(Default)]
+struct Reading {
+    // AirNow's overall AQI: the highest of the pollutants
+    air_quality_index: u32,
+    category: String,
+    levels: HashMap<Pollutant, f64>,
+    units: PollutantUnits,
+}
+
+impl Reading {
+    fn add_index(&mut self, aqi: i64, category: String) {
+        // AirNow reports -1 for pollutants without an index
+        let Ok(aqi) = u32::try_from(aqi) else {
+            return;
+        };
+        if self.category.is_empty() || aqi > self.air_quality_index {
+            self.air_quality_index = aqi;
+            self.category = category;
+        }
+    }
+
+    fn into_payload(self, location: String, observed_at: u64) -> AirQualityUpdatePayload {
+        AirQualityUpdatePayload {
+            location,
+            air_quality_index: self.air_quality_index,
+            health_recommendations: format!("AirNow category: {}", self.category),
+            pollutant_levels: (!self.levels.is_empty()).then_some(self.levels),
+            pollutant_units: (!self.units.is_empty()).then_some(self.units),
+            observed_at: Some(observed_at),
+            ..Default::default()
+        }
+    }
+}
+
+fn pollutant(parameter: &str) -> Pollutant {
+    match parameter {
+        "OZONE" => Pollutant::O3,
+        parameter => Pollutant::parse(parameter),
+    }
+}
+
+fn unit(unit: &str) -> Option<ConcentrationUnit> {
+    match unit {
+        "UG/M3" => Some(ConcentrationUnit::MicrogramsPerCubicMeter),
+        "PPB" => Some(ConcentrationUnit::Ppb),
+        "PPM" => Some(ConcentrationUnit::Ppm),
+        _ => None,
+    }
+}
+
+fn category_name(category: u32) -> &'static str {
+    match category {
+        1 => "Good",
+        2 => "Moderate",
+        3 => "Unhealthy for Sensitive Groups",
+        4 => "Unhealthy",
+        5 => "Very Unhealthy",
+        6 => "Hazardous",
+        _ => "Unavailable",
+    }
+}
+
+// Hours the US time zones AirNow reports in are behind UTC
+fn utc_offset_hours(zone: &str) -> Option<u64> {
+    match zone {
+        "EDT" => Some(4),
+        "EST" | "CDT" => Some(5),
+        "CST" | "MDT" => Some(6),
+        "MST" | "PDT" => Some(7),
+        "PST" | "AKDT" => Some(8),
+        "AKST" | "HDT" => Some(9),
+        "HST" => Some(10),
+        _ => None,
+    }
+}
+
+// AirNow's "YYYY-MM-DDTHH" form of the UTC hour containing `nanos`
+fn hour_param(nanos: u64) -> String {
+    let days = (nanos / NANOS_PER_DAY) as i64;
+    let (year, month) = civil_from_days(days);
+    let day = days - days_from_civil(year, month) + 1;
+    let hour = nanos % NANOS_PER_DAY / NANOS_PER_HOUR;
+    format!("{:04}-{:02}-{:02}T{:02}", year, month, day, hour)
+}
+
+fn validate(area: &AirNowArea, api_key: &str) -> Result<(), Error> {
+    let key_valid = !api_key.is_empty()
+        && api_key.len() <= MAX_API_KEY_LEN
+        && api_key
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-');
+    if !key_valid {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "api_key must be 1 to {} letters, digits and hyphens",
+                MAX_API_KEY_LEN
+            ),
+            violations: None,
+        });
+    }
+    match area {
+        AirNowArea::ZipCode {
+            zip_code,
+            distance_miles,
+        } => {
+            if zip_code.len() != 5 || !zip_code.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(Error::InvalidInput {
+                    msg: "zip_code must be a five-digit US ZIP code".to_string(),
+                    violations: None,
+                });
+            }
+            if distance_miles.is_some_and(|miles| !(1..=MAX_DISTANCE_MILES).contains(&miles)) {
+                return Err(Error::InvalidInput {
+                    msg: format!("distance_miles must be 1 to {}", MAX_DISTANCE_MILES),
+                    violations: None,
+                });
+            }
+            Ok(())
+        }
+        AirNowArea::BoundingBox(bbox) => bbox.validate(),
+    }
+}
+
+fn url(area: &AirNowArea, api_key: &str) -> String {
+    match area {
+        AirNowArea::ZipCode {
+            zip_code,
+            distance_miles,
+        } => format!(
+            "{}/observation/zipCode/current/?format=application/json&zipCode={}&distance={}&API_KEY={}",
+            API_BASE,
+            zip_code,
+            distance_miles.unwrap_or(DEFAULT_DISTANCE_MILES),
+            api_key
+        ),
+        AirNowArea::BoundingBox(bbox) => {
+            let (west, south, east, north) = bbox.west_south_east_north();
+            let now = time();
+            format!(
+                "{}/data/?startDate={}&endDate={}&parameters=OZONE,PM25,PM10,CO,NO2,SO2&BBOX={},{},{},{}&dataType=B&format=application/json&verbose=1&monitorType=0&includerawconcentrations=0&API_KEY={}",
+                API_BASE,
+                hour_param(now.saturating_sub(WINDOW_HOURS * NANOS_PER_HOUR)),
+                hour_param(now),
+                west,
+                south,
+                east,
+                north,
+                api_key
+            )
+        }
+    }
+}
+
+// Rows are grouped into one reading per area or monitor and hour
+fn readings(area: &AirNowArea, body: &[u8]) -> Result<(u32, Vec<AirQualityUpdatePayload>), String> {
+    let mut readings: BTreeMap<(String, u64), Reading> = BTreeMap::new();
+    let observations = match area {
+        AirNowArea::ZipCode { .. } => {
+            let rows: Vec<ZipObservation> = serde_json::from_slice(body)
+                .map_err(|e| format!("cannot parse AirNow's response: {}", e))?;
+            for row in &rows {
+                let local = parse_time(&format!(
+                    "{}T{:02}:00:00Z",
+                    row.date_observed.trim(),
+                    row.hour_observed
+                ));
+                let (Some(local), Some(offset)) = (local, utc_offset_hours(&row.local_time_zone))
+                else {
+                    continue;
+                };
+                let location = format!("{}, {}", row.reporting_area, row.state_code);
+                readings
+                    .entry((location, local + offset * NANOS_PER_HOUR))
+                    .or_default()
+                    .add_index(row.aqi, row.category.name.clone());
+            }
+            rows.len()
+        }
+        AirNowArea::BoundingBox(_) => {
+            let rows: Vec<MonitorObservation> = serde_json::from_slice(body)
+                .map_err(|e| format!("cannot parse AirNow's response: {}", e))?;
+            for row in &rows {
+                let Some(observed_at) = parse_time(&format!("{}:00Z", row.utc)) else {
+                    continue;
+                };
+                let reading = readings
+                    .entry((row.site_name.clone(), observed_at))
+                    .or_default();
+                reading.add_index(row.aqi, category_name(row.category).to_string());
+                // AirNow reports -999 for missing concentrations
+                if let (Some(unit), true) = (unit(&row.unit), row.value >= 0.0) {
+                    let pollutant = pollutant(&row.parameter);
+                    reading.levels.insert(pollutant.clone(), row.value);
+                    reading.units.insert(pollutant, unit);
+                }
+            }
+            rows.len()
+        }
+    };
+    Ok((
+        observations as u32,
+        readings
+            .into_iter()
+            .filter(|(_, reading)| !reading.category.is_empty())
+            .map(|((location, observed_at), reading)| reading.into_payload(location, observed_at))
+            .collect(),
+    ))
+}
+
+// Fetches the current observations of AirNow, the US EPA's air quality service, for an area and
+// stores them as readings (controllers only). The API key is sent with the request and not
+// kept. Readings already ingested are skipped as duplicates, so the call can be repeated every
+// hour.
+#[ic_cdk::update(guard = "accepts_writes")]
+async fn ingest_from_airnow(
+    area: AirNowArea,
+    api_key: String,
+) -> Result<AirNowIngestReport, Error> {
+    ensure_admin()?;
+    validate(&area, &api_key)?;
+    let request = CanisterHttpRequestArgument {
+        url: url(&area, &api_key),
+        max_response_bytes: Some(MAX_RESPONSE_BYTES),
+        method: HttpMethod::GET,
+        headers: vec![],
+        body: None,
+        // Backfill's transform keeps only the status and body, which is all this needs too
+        transform: Some(TransformContext::from_name(
+            "transform_backfill_response".to_string(),
+            vec![],
+        )),
+    };
+    let (response,) = http_request(request, OUTCALL_CYCLES)
+        .await
+        .map_err(|(code, msg)| Error::InvalidInput {
+            msg: format!("the AirNow request failed: {:?} {}", code, msg),
+            violations: None,
+        })?;
+    if response.status != 200u16 {
+        return Err(Error::InvalidInput {
+            msg: format!("AirNow responded with status {}", response.status),
+            violations: None,
+        });
+    }
+    let (observations, payloads) =
+        readings(&area, &response.body).map_err(|msg| Error::InvalidInput {
+            msg,
+            violations: None,
+        })?;
+    let mut stored_ids = Vec::new();
+    let mut skipped = 0;
+    for payload in payloads {
+        let source = ReadingSource::import(sources::AIRNOW_CONNECTOR, &payload.location);
+        match _add_air_quality_data(payload, source) {
+            Ok(data) => stored_ids.push(data.id),
+            Err(_) => skipped += 1,
+        }
+    }
+    log!(
+        Info,
+        "AirNow observations ingested",
+        "observations" => observations,
+        "stored" => stored_ids.len(),
+        "skipped" => skipped,
+    );
+    Ok(AirNowIngestReport {
+        observations,
+        stored_ids,
+        skipped,
+    })
+}
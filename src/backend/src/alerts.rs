@@ -0,0 +1,671 @@
+use crate::aggregates::level_in_micrograms;
+use crate::paging::{self, ReplyBudget};
+use crate::standards::AqiCategory;
+use crate::{
+    accepts_writes, capacity, clear_stable_map, get_memory, next_id, AirQualityData, Error, IdCell,
+    Memory, Pollutant, StringKey, ALERT_DELIVERY_QUEUE_MEMORY_ID, ALERT_ID_COUNTER_MEMORY_ID,
+    ALERT_STORAGE_MEMORY_ID, SUBSCRIPTION_ID_COUNTER_MEMORY_ID, SUBSCRIPTION_INDEX_MEMORY_ID,
+    SUBSCRIPTION_STORAGE_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::ops::Bound;
+
+// Longer locations do not fit an index key
+const MAX_LOCATION_LEN: usize = 200;
+const MAX_POLLUTANT_NAME_LEN: usize = 64;
+const MAX_METHOD_LEN: usize = 64;
+const MAX_SUBSCRIPTIONS_PER_OWNER: usize = 100;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// Failed deliveries are retried after 30s, 1m, 2m, ... up to an hour apart
+const FIRST_RETRY_SECONDS: u64 = 30;
+const MAX_RETRY_SECONDS: u64 = 3_600;
+// Alerts not delivered after this many attempts are dead-lettered
+const MAX_DELIVERY_ATTEMPTS: u32 = 8;
+const MAX_CALLS_PER_TICK: usize = 20;
+const MAX_ERROR_LEN: usize = 256;
+// Half the width of the narrowest AQI category
+const MAX_HYSTERESIS: u32 = 25;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub(crate) enum AlertMetric {
+    AirQualityIndex,
+    // In µg/m³
+    Pollutant(Pollutant),
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum Comparison {
+    Above,
+    Below,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum DeliveryTarget {
+    // Alerts are only recorded, for the owner to fetch
+    Inbox,
+    // Alerts are passed to a method of a canister
+    Canister {
+        canister_id: Principal,
+        method: String,
+    },
+}
+
+// Alert when the AQI moves to another category instead of on every reading past a threshold.
+// The index must go `hysteresis` points past a category's edge before the location counts as
+// having left it, so readings hovering at the edge do not alert on every crossing.
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct CategoryChange {
+    hysteresis: u32,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct CategoryTransition {
+    from: AqiCategory,
+    to: AqiCategory,
+}
+
+// A threshold a principal wants to hear about, e.g. PM2.5 above 35 µg/m³ in "Delhi"
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Subscription {
+    id: u64,
+    owner: Principal,
+    location: String,
+    metric: AlertMetric,
+    comparison: Comparison,
+    threshold: f64,
+    delivery: DeliveryTarget,
+    created_at: u64,
+    updated_at: u64,
+    // Set for subscriptions to category transitions, which ignore comparison and threshold
+    category_change: Option<CategoryChange>,
+    // Category the location is in as of the last reading, once one was seen
+    current_category: Option<AqiCategory>,
+}
+
+impl_bounded_storable!(Subscription, 1024);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct SubscriptionPayload {
+    location: String,
+    metric: AlertMetric,
+    comparison: Comparison,
+    threshold: f64,
+    delivery: DeliveryTarget,
+    // Only with the AirQualityIndex metric
+    category_change: Option<CategoryChange>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum DeliveryState {
+    Pending,
+    Delivered,
+    // Gave up after MAX_DELIVERY_ATTEMPTS; redeliver_alert queues it again
+    DeadLettered,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct AlertDelivery {
+    canister_id: Principal,
+    method: String,
+    state: DeliveryState,
+    attempts: u32,
+    next_attempt_at: Option<u64>,
+    last_error: Option<String>,
+    delivered_at: Option<u64>,
+}
+
+// A reading that met a subscription's threshold
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Alert {
+    id: u64,
+    subscription_id: u64,
+    reading_id: u64,
+    location: String,
+    metric: AlertMetric,
+    value: f64,
+    threshold: f64,
+    triggered_at: u64,
+    // None for inbox subscriptions
+    delivery: Option<AlertDelivery>,
+    // For category changes; `threshold` is then the edge of the category left
+    transition: Option<CategoryTransition>,
+    // Set once someone has seen and handled the breach
+    acknowledged_at: Option<u64>,
+    acknowledged_by: Option<Principal>,
+}
+
+impl_bounded_storable!(Alert, 2048);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct AlertPage {
+    alerts: Vec<Alert>,
+    // More alerts exist; pass `next_cursor` to fetch them
+    truncated: bool,
+    next_cursor: Option<u64>,
+}
+
+// What the subscriber's method receives, as its only argument
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct AlertNotification {
+    alert_id: u64,
+    subscription_id: u64,
+    reading_id: u64,
+    location: String,
+    metric: AlertMetric,
+    value: f64,
+    threshold: f64,
+    triggered_at: u64,
+    transition: Option<CategoryTransition>,
+}
+
+type LocationKey = (StringKey, u64);
+
+thread_local! {
+    static SUBSCRIPTION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(get_memory(SUBSCRIPTION_ID_COUNTER_MEMORY_ID), 0)
+            .expect("Cannot create a counter for subscriptions")
+    );
+
+    static SUBSCRIPTION_STORAGE: RefCell<StableBTreeMap<u64, Subscription, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(SUBSCRIPTION_STORAGE_MEMORY_ID)));
+
+    // (location, subscription id) of every subscription, to match readings quickly
+    static SUBSCRIPTION_INDEX: RefCell<StableBTreeMap<LocationKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(SUBSCRIPTION_INDEX_MEMORY_ID)));
+
+    static ALERT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(get_memory(ALERT_ID_COUNTER_MEMORY_ID), 0)
+            .expect("Cannot create a counter for alerts")
+    );
+
+    static ALERT_STORAGE: RefCell<StableBTreeMap<u64, Alert, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ALERT_STORAGE_MEMORY_ID)));
+
+    // (next attempt time, alert id) of every pending delivery
+    static ALERT_DELIVERY_QUEUE: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ALERT_DELIVERY_QUEUE_MEMORY_ID)));
+
+    // Alerts with a call awaiting its response
+    static DELIVERIES_IN_FLIGHT: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+// Drops the alert history and its pending deliveries, for a storage reset. Subscriptions
+// are kept.
+pub(crate) fn reset() {
+    ALERT_STORAGE.with(|s| clear_stable_map(s, ALERT_STORAGE_MEMORY_ID));
+    ALERT_DELIVERY_QUEUE.with(|s| clear_stable_map(s, ALERT_DELIVERY_QUEUE_MEMORY_ID));
+}
+
+impl Subscription {
+    fn value_of(&self, data: &AirQualityData) -> Option<f64> {
+        match &self.metric {
+            AlertMetric::AirQualityIndex => Some(data.air_quality_index as f64),
+            AlertMetric::Pollutant(pollutant) => level_in_micrograms(data, pollutant),
+        }
+    }
+
+    fn is_met_by(&self, value: f64) -> bool {
+        match self.comparison {
+            Comparison::Above => value > self.threshold,
+            Comparison::Below => value < self.threshold,
+        }
+    }
+
+    // Moves the location to the category of a new index once it is far enough past the edge of
+    // the current one. The first reading only sets the current category.
+    fn observe_category(
+        &mut self,
+        change: CategoryChange,
+        air_quality_index: f64,
+    ) -> Option<(CategoryTransition, f64)> {
+        let category = AqiCategory::of(air_quality_index);
+        let Some(current) = self.current_category else {
+            self.current_category = Some(category);
+            return None;
+        };
+        let (min, max) = current.bounds();
+        let margin = change.hysteresis as f64;
+        let edge = if category > current {
+            max.filter(|max| air_quality_index > *max as f64 + margin)?
+        } else if category < current {
+            Some(min).filter(|min| air_quality_index < *min as f64 - margin)?
+        } else {
+            return None;
+        };
+        self.current_category = Some(category);
+        Some((
+            CategoryTransition {
+                from: current,
+                to: category,
+            },
+            edge as f64,
+        ))
+    }
+
+    // The value and threshold of an alert for the reading, if it triggers one
+    fn evaluate(
+        &mut self,
+        data: &AirQualityData,
+    ) -> Option<(f64, f64, Option<CategoryTransition>)> {
+        let value = self.value_of(data)?;
+        match self.category_change {
+            Some(change) => {
+                let (transition, edge) = self.observe_category(change, value)?;
+                Some((value, edge, Some(transition)))
+            }
+            None => self
+                .is_met_by(value)
+                .then_some((value, self.threshold, None)),
+        }
+    }
+}
+
+fn validate_payload(payload: &SubscriptionPayload) -> Result<(), Error> {
+    if payload.location.is_empty() || payload.location.len() > MAX_LOCATION_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!("location must be 1 to {} bytes", MAX_LOCATION_LEN),
+            violations: None,
+        });
+    }
+    if let AlertMetric::Pollutant(pollutant) = &payload.metric {
+        if pollutant.name().is_empty() || pollutant.name().len() > MAX_POLLUTANT_NAME_LEN {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "pollutant names must be 1 to {} bytes",
+                    MAX_POLLUTANT_NAME_LEN
+                ),
+                violations: None,
+            });
+        }
+    }
+    if !payload.threshold.is_finite() {
+        return Err(Error::InvalidInput {
+            msg: "threshold must be finite".to_string(),
+            violations: None,
+        });
+    }
+    if let Some(change) = &payload.category_change {
+        if payload.metric != AlertMetric::AirQualityIndex {
+            return Err(Error::InvalidInput {
+                msg: "category changes can only be subscribed to for the AirQualityIndex metric"
+                    .to_string(),
+                violations: None,
+            });
+        }
+        if change.hysteresis > MAX_HYSTERESIS {
+            return Err(Error::InvalidInput {
+                msg: format!("hysteresis must be at most {}", MAX_HYSTERESIS),
+                violations: None,
+            });
+        }
+    }
+    if let DeliveryTarget::Canister { method, .. } = &payload.delivery {
+        if method.is_empty() || method.len() > MAX_METHOD_LEN {
+            return Err(Error::InvalidInput {
+                msg: format!("method must be 1 to {} bytes", MAX_METHOD_LEN),
+                violations: None,
+            });
+        }
+    }
+    Ok(())
+}
+
+fn normalized_metric(metric: AlertMetric) -> AlertMetric {
+    match metric {
+        AlertMetric::Pollutant(pollutant) => AlertMetric::Pollutant(pollutant.normalized()),
+        metric => metric,
+    }
+}
+
+fn do_insert_subscription(subscription: &Subscription, previous: Option<&Subscription>) {
+    SUBSCRIPTION_INDEX.with(|s| {
+        let mut index = s.borrow_mut();
+        if let Some(previous) = previous {
+            index.remove(&(StringKey(previous.location.clone()), previous.id));
+        }
+        index.insert(
+            (StringKey(subscription.location.clone()), subscription.id),
+            (),
+        );
+    });
+    SUBSCRIPTION_STORAGE.with(|s| s.borrow_mut().insert(subscription.id, subscription.clone()));
+}
+
+// The subscription, if the caller owns it or is a controller
+fn owned_subscription(id: u64) -> Result<Subscription, Error> {
+    let subscription =
+        SUBSCRIPTION_STORAGE
+            .with(|s| s.borrow().get(&id))
+            .ok_or(Error::NotFound {
+                msg: format!("subscription with id={} not found", id),
+            })?;
+    let caller = ic_cdk::caller();
+    if subscription.owner != caller && !ic_cdk::api::is_controller(&caller) {
+        return Err(Error::Unauthorized {
+            msg: format!("caller does not own subscription with id={}", id),
+        });
+    }
+    Ok(subscription)
+}
+
+fn subscriptions_of(owner: &Principal) -> Vec<Subscription> {
+    SUBSCRIPTION_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, subscription)| subscription)
+            .filter(|subscription| subscription.owner == *owner)
+            .collect()
+    })
+}
+
+// Records an alert for every subscription of the reading's location it meets
+pub(crate) fn on_insert(data: &AirQualityData) {
+    if data.location.len() > MAX_LOCATION_LEN {
+        return;
+    }
+    let location = StringKey(data.location.clone());
+    let ids: Vec<u64> = SUBSCRIPTION_INDEX.with(|s| {
+        s.borrow()
+            .range((
+                Bound::Included((location.clone(), 0)),
+                Bound::Included((location, u64::MAX)),
+            ))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    for id in ids {
+        let Some(mut subscription) = SUBSCRIPTION_STORAGE.with(|s| s.borrow().get(&id)) else {
+            continue;
+        };
+        let previous_category = subscription.current_category;
+        let evaluation = subscription.evaluate(data);
+        if subscription.current_category != previous_category {
+            SUBSCRIPTION_STORAGE.with(|s| s.borrow_mut().insert(id, subscription.clone()));
+        }
+        let Some((value, threshold, transition)) = evaluation else {
+            continue;
+        };
+        let now = time();
+        let delivery = match &subscription.delivery {
+            DeliveryTarget::Inbox => None,
+            DeliveryTarget::Canister {
+                canister_id,
+                method,
+            } => Some(AlertDelivery {
+                canister_id: *canister_id,
+                method: method.clone(),
+                state: DeliveryState::Pending,
+                attempts: 0,
+                next_attempt_at: Some(now),
+                last_error: None,
+                delivered_at: None,
+            }),
+        };
+        let alert = Alert {
+            id: next_id(&ALERT_ID_COUNTER),
+            subscription_id: subscription.id,
+            reading_id: data.id,
+            location: data.location.clone(),
+            metric: subscription.metric.clone(),
+            value,
+            threshold,
+            triggered_at: now,
+            delivery,
+            transition,
+            acknowledged_at: None,
+            acknowledged_by: None,
+        };
+        if alert.delivery.is_some() {
+            ALERT_DELIVERY_QUEUE.with(|q| q.borrow_mut().insert((now, alert.id), ()));
+        }
+        ALERT_STORAGE.with(|s| s.borrow_mut().insert(alert.id, alert));
+    }
+}
+
+pub(crate) fn retry_delay_nanos(attempts: u32) -> u64 {
+    let seconds = FIRST_RETRY_SECONDS.saturating_mul(1 << attempts.saturating_sub(1).min(16));
+    seconds.min(MAX_RETRY_SECONDS) * NANOS_PER_SECOND
+}
+
+// Moves an alert's queue entry to its next attempt, or drops it once delivered or dead
+fn update_delivery(alert_id: u64, update: impl FnOnce(&mut AlertDelivery)) {
+    let Some(mut alert) = ALERT_STORAGE.with(|s| s.borrow().get(&alert_id)) else {
+        return;
+    };
+    let Some(delivery) = alert.delivery.as_mut() else {
+        return;
+    };
+    let previous = delivery.next_attempt_at;
+    update(delivery);
+    ALERT_DELIVERY_QUEUE.with(|q| {
+        let mut queue = q.borrow_mut();
+        if let Some(at) = previous {
+            queue.remove(&(at, alert_id));
+        }
+        if let Some(at) = delivery.next_attempt_at {
+            queue.insert((at, alert_id), ());
+        }
+    });
+    ALERT_STORAGE.with(|s| s.borrow_mut().insert(alert_id, alert));
+}
+
+fn on_delivery_result(alert_id: u64, result: Result<(), String>) {
+    let now = time();
+    update_delivery(alert_id, |delivery| match result {
+        Ok(()) => {
+            delivery.state = DeliveryState::Delivered;
+            delivery.next_attempt_at = None;
+            delivery.delivered_at = Some(now);
+            delivery.last_error = None;
+        }
+        Err(error) => {
+            delivery.last_error = Some(error.chars().take(MAX_ERROR_LEN).collect());
+            if delivery.attempts >= MAX_DELIVERY_ATTEMPTS {
+                delivery.state = DeliveryState::DeadLettered;
+                delivery.next_attempt_at = None;
+            }
+        }
+    });
+    if let Some(alert) = ALERT_STORAGE.with(|s| s.borrow().get(&alert_id)) {
+        if alert
+            .delivery
+            .is_some_and(|delivery| delivery.state == DeliveryState::DeadLettered)
+        {
+            log!(Warn, "alert dead-lettered", "alert" => alert_id, "subscription" => alert.subscription_id);
+        }
+    }
+}
+
+// Calls the subscriber of every alert due for delivery. The next attempt is scheduled before
+// the call, so a call lost to an upgrade is simply retried.
+pub(crate) fn on_timer() {
+    let now = time();
+    let due: Vec<u64> = ALERT_DELIVERY_QUEUE.with(|q| {
+        q.borrow()
+            .range(..=(now, u64::MAX))
+            .map(|((_, id), _)| id)
+            .filter(|id| !DELIVERIES_IN_FLIGHT.with(|f| f.borrow().contains(id)))
+            .take(MAX_CALLS_PER_TICK)
+            .collect()
+    });
+    for alert_id in due {
+        let Some(alert) = ALERT_STORAGE.with(|s| s.borrow().get(&alert_id)) else {
+            continue;
+        };
+        let Some(delivery) = alert.delivery.clone() else {
+            continue;
+        };
+        update_delivery(alert_id, |delivery| {
+            delivery.attempts += 1;
+            delivery.next_attempt_at = Some(now + retry_delay_nanos(delivery.attempts));
+        });
+        let notification = AlertNotification {
+            alert_id,
+            subscription_id: alert.subscription_id,
+            reading_id: alert.reading_id,
+            location: alert.location,
+            metric: alert.metric,
+            value: alert.value,
+            threshold: alert.threshold,
+            triggered_at: alert.triggered_at,
+            transition: alert.transition,
+        };
+        DELIVERIES_IN_FLIGHT.with(|f| f.borrow_mut().insert(alert_id));
+        ic_cdk::spawn(async move {
+            let result: Result<(), _> =
+                ic_cdk::call(delivery.canister_id, &delivery.method, (notification,)).await;
+            on_delivery_result(
+                alert_id,
+                result.map_err(|(code, msg)| format!("call failed: {:?} {}", code, msg)),
+            );
+            DELIVERIES_IN_FLIGHT.with(|f| f.borrow_mut().remove(&alert_id));
+        });
+    }
+}
+
+// Subscribes the caller to readings of a location meeting a threshold
+#[ic_cdk::update(guard = "accepts_writes")]
+fn create_subscription(payload: SubscriptionPayload) -> Result<Subscription, Error> {
+    validate_payload(&payload)?;
+    capacity::ensure_room_for_optional_write()?;
+    let owner = ic_cdk::caller();
+    if subscriptions_of(&owner).len() >= MAX_SUBSCRIPTIONS_PER_OWNER {
+        return Err(Error::TooLarge {
+            msg: format!(
+                "a principal can have at most {} subscriptions",
+                MAX_SUBSCRIPTIONS_PER_OWNER
+            ),
+        });
+    }
+    let now = time();
+    let subscription = Subscription {
+        id: next_id(&SUBSCRIPTION_ID_COUNTER),
+        owner,
+        location: payload.location,
+        metric: normalized_metric(payload.metric),
+        comparison: payload.comparison,
+        threshold: payload.threshold,
+        delivery: payload.delivery,
+        created_at: now,
+        updated_at: now,
+        category_change: payload.category_change,
+        current_category: None,
+    };
+    do_insert_subscription(&subscription, None);
+    Ok(subscription)
+}
+
+#[ic_cdk::query]
+fn get_subscription(id: u64) -> Result<Subscription, Error> {
+    owned_subscription(id)
+}
+
+#[ic_cdk::query]
+fn list_my_subscriptions() -> Vec<Subscription> {
+    subscriptions_of(&ic_cdk::caller())
+}
+
+// Replaces the threshold and delivery target of a subscription (owner or controllers)
+#[ic_cdk::update(guard = "accepts_writes")]
+fn update_subscription(id: u64, payload: SubscriptionPayload) -> Result<Subscription, Error> {
+    validate_payload(&payload)?;
+    let existing = owned_subscription(id)?;
+    let subscription = Subscription {
+        location: payload.location,
+        metric: normalized_metric(payload.metric),
+        comparison: payload.comparison,
+        threshold: payload.threshold,
+        delivery: payload.delivery,
+        updated_at: time(),
+        category_change: payload.category_change,
+        // The location's category is tracked afresh from the next reading
+        current_category: None,
+        ..existing.clone()
+    };
+    do_insert_subscription(&subscription, Some(&existing));
+    Ok(subscription)
+}
+
+// Removes a subscription; its alerts are kept
+#[ic_cdk::update(guard = "accepts_writes")]
+fn delete_subscription(id: u64) -> Result<Subscription, Error> {
+    let subscription = owned_subscription(id)?;
+    SUBSCRIPTION_INDEX.with(|s| {
+        s.borrow_mut()
+            .remove(&(StringKey(subscription.location.clone()), id))
+    });
+    SUBSCRIPTION_STORAGE.with(|s| s.borrow_mut().remove(&id));
+    Ok(subscription)
+}
+
+fn get_alert(alert_id: u64) -> Result<Alert, Error> {
+    ALERT_STORAGE
+        .with(|s| s.borrow().get(&alert_id))
+        .ok_or(Error::NotFound {
+            msg: format!("alert with id={} not found", alert_id),
+        })
+}
+
+// Queues a dead-lettered alert for delivery again, with a fresh set of attempts (owner or
+// controllers of its subscription)
+#[ic_cdk::update(guard = "accepts_writes")]
+fn redeliver_alert(alert_id: u64) -> Result<Alert, Error> {
+    let alert = get_alert(alert_id)?;
+    owned_subscription(alert.subscription_id)?;
+    if !alert
+        .delivery
+        .as_ref()
+        .is_some_and(|delivery| delivery.state == DeliveryState::DeadLettered)
+    {
+        return Err(Error::Conflict {
+            msg: format!("alert with id={} is not dead-lettered", alert_id),
+        });
+    }
+    update_delivery(alert_id, |delivery| {
+        delivery.state = DeliveryState::Pending;
+        delivery.attempts = 0;
+        delivery.next_attempt_at = Some(time());
+    });
+    Ok(ALERT_STORAGE
+        .with(|s| s.borrow().get(&alert_id))
+        .expect("the alert was just updated"))
+}
+
+// Marks an alert as seen and handled (owner or controllers of its subscription). Acknowledging
+// it again keeps the first acknowledgement.
+#[ic_cdk::update(guard = "accepts_writes")]
+fn acknowledge_alert(alert_id: u64) -> Result<Alert, Error> {
+    let mut alert = get_alert(alert_id)?;
+    owned_subscription(alert.subscription_id)?;
+    if alert.acknowledged_at.is_none() {
+        alert.acknowledged_at = Some(time());
+        alert.acknowledged_by = Some(ic_cdk::caller());
+        ALERT_STORAGE.with(|s| s.borrow_mut().insert(alert_id, alert.clone()));
+    }
+    Ok(alert)
+}
+
+// Alerts triggered by a subscription after `cursor`, oldest first (owner or controllers)
+#[ic_cdk::query]
+fn get_alert_history(subscription_id: u64, cursor: Option<u64>) -> Result<AlertPage, Error> {
+    owned_subscription(subscription_id)?;
+    let start = cursor.map_or(Bound::Unbounded, Bound::Excluded);
+    let (alerts, truncated) = ALERT_STORAGE.with(|s| {
+        paging::take_within(
+            s.borrow()
+                .range((start, Bound::Unbounded))
+                .map(|(_, alert)| alert)
+                .filter(|alert| alert.subscription_id == subscription_id),
+            &mut ReplyBudget::new(),
+        )
+    });
+    Ok(AlertPage {
+        next_cursor: alerts.last().filter(|_| truncated).map(|alert| alert.id),
+        alerts,
+        truncated,
+    })
+}
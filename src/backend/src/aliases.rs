@@ -0,0 +1,233 @@
+use crate::{
+    accepts_writes, ensure_admin, get_memory, locations, stations, Error, Memory, StringKey,
+    ALIAS_STORAGE_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// A DNS label: lowercase letters, digits and inner hyphens
+const MAX_SLUG_LEN: usize = 63;
+const MAX_LOCATION_LEN: usize = 200;
+// Keeps an alias, with its history, within its storage slot
+const MAX_RENAMES: usize = 20;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub(crate) enum AliasTarget {
+    Station(String),
+    Location(String),
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SlugRename {
+    from: String,
+    renamed_at: u64,
+}
+
+// A human-friendly name such as "delhi-anand-vihar" for a station or location. Slugs are never
+// reused: a renamed slug stays behind as a redirect to the current one.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Alias {
+    slug: String,
+    target: AliasTarget,
+    created_by: Principal,
+    created_at: u64,
+    // Set on the slugs a rename left behind
+    renamed_to: Option<String>,
+    // Earlier slugs of the current one, oldest first
+    history: Vec<SlugRename>,
+}
+
+impl_bounded_storable!(Alias, 4096);
+
+thread_local! {
+    static ALIAS_STORAGE: RefCell<StableBTreeMap<StringKey, Alias, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ALIAS_STORAGE_MEMORY_ID)));
+}
+
+fn validate_slug(slug: &str) -> Result<(), Error> {
+    let valid = !slug.is_empty()
+        && slug.len() <= MAX_SLUG_LEN
+        && slug
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        && !slug.starts_with('-')
+        && !slug.ends_with('-');
+    if !valid {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "slugs are 1 to {} lowercase letters, digits and hyphens, not starting or ending with a hyphen",
+                MAX_SLUG_LEN
+            ),
+            violations: None,
+        });
+    }
+    Ok(())
+}
+
+fn get_alias(slug: &str) -> Option<Alias> {
+    ALIAS_STORAGE.with(|s| s.borrow().get(&StringKey(slug.to_string())))
+}
+
+fn do_insert_alias(alias: &Alias) {
+    ALIAS_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert(StringKey(alias.slug.clone()), alias.clone())
+    });
+}
+
+// The current alias a slug names, following a rename, and whether it was renamed
+pub(crate) fn lookup(slug: &str) -> Option<(Alias, bool)> {
+    let alias = get_alias(slug)?;
+    match &alias.renamed_to {
+        Some(current) => get_alias(current).map(|current| (current, true)),
+        None => Some((alias, false)),
+    }
+}
+
+// The location a location parameter names: the target of a location slug, or else the
+// canonical name of the parameter
+pub(crate) fn location(name: String) -> String {
+    match lookup(&name) {
+        Some((
+            Alias {
+                target: AliasTarget::Location(location),
+                ..
+            },
+            _,
+        )) => location,
+        _ => locations::canonical(name),
+    }
+}
+
+// The station id a station parameter names: the target of a station slug, or else the
+// parameter itself
+pub(crate) fn station_id(name: String) -> String {
+    match lookup(&name) {
+        Some((
+            Alias {
+                target: AliasTarget::Station(station_id),
+                ..
+            },
+            _,
+        )) => station_id,
+        _ => name,
+    }
+}
+
+pub(crate) fn slug(alias: &Alias) -> &str {
+    &alias.slug
+}
+
+// Station slugs are managed by the station's owner and controllers, location slugs by
+// controllers
+fn ensure_can_manage(target: &AliasTarget) -> Result<(), Error> {
+    match target {
+        AliasTarget::Location(_) => ensure_admin(),
+        AliasTarget::Station(station_id) => {
+            let station = stations::get_station(station_id).ok_or(Error::NotFound {
+                msg: format!("station {} not found", station_id),
+            })?;
+            if !station.is_managed_by(&ic_cdk::caller()) {
+                return Err(Error::Unauthorized {
+                    msg: format!("caller does not own station {}", station_id),
+                });
+            }
+            Ok(())
+        }
+    }
+}
+
+fn ensure_available(slug: &str) -> Result<(), Error> {
+    if get_alias(slug).is_some() {
+        return Err(Error::Conflict {
+            msg: format!("slug {} is taken", slug),
+        });
+    }
+    Ok(())
+}
+
+// Gives a station or location a slug. Each has at most one current slug.
+#[ic_cdk::update(guard = "accepts_writes")]
+fn create_slug(slug: String, target: AliasTarget) -> Result<Alias, Error> {
+    validate_slug(&slug)?;
+    if let AliasTarget::Location(location) = &target {
+        if location.is_empty() || location.len() > MAX_LOCATION_LEN {
+            return Err(Error::InvalidInput {
+                msg: format!("location must be 1 to {} bytes", MAX_LOCATION_LEN),
+                violations: None,
+            });
+        }
+    }
+    ensure_can_manage(&target)?;
+    ensure_available(&slug)?;
+    let existing = ALIAS_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, alias)| alias)
+            .find(|alias| alias.renamed_to.is_none() && alias.target == target)
+    });
+    if let Some(existing) = existing {
+        return Err(Error::Conflict {
+            msg: format!("the target already has the slug {}", existing.slug),
+        });
+    }
+    let alias = Alias {
+        slug,
+        target,
+        created_by: ic_cdk::caller(),
+        created_at: time(),
+        renamed_to: None,
+        history: Vec::new(),
+    };
+    do_insert_alias(&alias);
+    Ok(alias)
+}
+
+// Moves a current slug to a new one. The old slug and every earlier one redirect to it.
+#[ic_cdk::update(guard = "accepts_writes")]
+fn rename_slug(slug: String, new_slug: String) -> Result<Alias, Error> {
+    validate_slug(&new_slug)?;
+    let alias = get_alias(&slug)
+        .filter(|alias| alias.renamed_to.is_none())
+        .ok_or(Error::NotFound {
+            msg: format!("no current slug {}", slug),
+        })?;
+    ensure_can_manage(&alias.target)?;
+    ensure_available(&new_slug)?;
+    if alias.history.len() >= MAX_RENAMES {
+        return Err(Error::TooLarge {
+            msg: format!("a slug can be renamed at most {} times", MAX_RENAMES),
+        });
+    }
+    let now = time();
+    let mut history = alias.history.clone();
+    history.push(SlugRename {
+        from: slug,
+        renamed_at: now,
+    });
+    for rename in &history {
+        if let Some(mut old) = get_alias(&rename.from) {
+            old.renamed_to = Some(new_slug.clone());
+            do_insert_alias(&old);
+        }
+    }
+    let renamed = Alias {
+        slug: new_slug,
+        history,
+        ..alias
+    };
+    do_insert_alias(&renamed);
+    Ok(renamed)
+}
+
+// The current alias of a slug, following renames
+#[ic_cdk::query]
+fn resolve_slug(slug: String) -> Result<Alias, Error> {
+    lookup(&slug)
+        .map(|(alias, _)| alias)
+        .ok_or(Error::NotFound {
+            msg: format!("slug {} not found", slug),
+        })
+}
@@ -0,0 +1,124 @@
+use crate::aliases;
+use crate::{
+    get_memory, next_id, Error, IdCell, Memory, ANNOTATION_ID_COUNTER_MEMORY_ID,
+    ANNOTATION_STORAGE_MEMORY_ID,
+};
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// What produced an annotation, so analysts can follow it back to its origin
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum AnnotationSource {
+    Incident { incident_id: u64 },
+    FieldVisit { visit_id: u64 },
+}
+
+// A note attached to a location over a period of its data
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Annotation {
+    id: u64,
+    location: String,
+    start: u64,
+    end: Option<u64>,
+    source: AnnotationSource,
+    text: String,
+    created_at: u64,
+}
+
+impl_bounded_storable!(Annotation, 4096);
+
+thread_local! {
+    static ANNOTATION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(get_memory(ANNOTATION_ID_COUNTER_MEMORY_ID), 0)
+            .expect("Cannot create a counter for annotations")
+    );
+
+    static ANNOTATION_STORAGE: RefCell<StableBTreeMap<u64, Annotation, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ANNOTATION_STORAGE_MEMORY_ID)));
+}
+
+impl Annotation {
+    // An open-ended annotation covers everything from its start onwards
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end
+            && self
+                .end
+                .is_none_or(|annotation_end| annotation_end >= start)
+    }
+}
+
+// Stores an annotation and returns its id
+pub(crate) fn annotate(
+    location: &str,
+    start: u64,
+    end: Option<u64>,
+    source: AnnotationSource,
+    text: &str,
+) -> u64 {
+    let annotation = Annotation {
+        id: next_id(&ANNOTATION_ID_COUNTER),
+        location: location.to_string(),
+        start,
+        end,
+        source,
+        text: text.to_string(),
+        created_at: time(),
+    };
+    ANNOTATION_STORAGE.with(|s| s.borrow_mut().insert(annotation.id, annotation.clone()));
+    annotation.id
+}
+
+// Annotates every affected location of an incident and returns the new annotation ids
+pub(crate) fn annotate_incident(
+    incident_id: u64,
+    locations: &[String],
+    start: u64,
+    end: Option<u64>,
+    text: &str,
+) -> Vec<u64> {
+    locations
+        .iter()
+        .map(|location| {
+            annotate(
+                location,
+                start,
+                end,
+                AnnotationSource::Incident { incident_id },
+                text,
+            )
+        })
+        .collect()
+}
+
+pub(crate) fn remove_annotations(ids: &[u64]) {
+    ANNOTATION_STORAGE.with(|s| {
+        let mut storage = s.borrow_mut();
+        for id in ids {
+            storage.remove(id);
+        }
+    });
+}
+
+continued_page!(AnnotationPage, Annotation);
+
+#[ic_cdk::query]
+fn get_annotations(
+    location: String,
+    start: u64,
+    end: u64,
+    continuation: Option<String>,
+) -> Result<AnnotationPage, Error> {
+    let location = aliases::location(location);
+    ANNOTATION_STORAGE.with(|s| {
+        AnnotationPage::new(
+            s.borrow()
+                .iter()
+                .filter(|(_, annotation)| {
+                    annotation.location == location && annotation.overlaps(start, end)
+                })
+                .map(|(_, annotation)| annotation),
+            continuation,
+        )
+    })
+}
@@ -0,0 +1,114 @@
+use crate::{
+    accepts_writes, ensure_admin, get_memory, next_id, Error, IdCell, Memory,
+    ANNOUNCEMENT_ID_COUNTER_MEMORY_ID, ANNOUNCEMENT_STORAGE_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_TITLE_LEN: usize = 200;
+const MAX_BODY_LEN: usize = 2000;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum AnnouncementSeverity {
+    Info,
+    Warning,
+    Critical,
+}
+
+// A message from the operators to every user, e.g. about outages or sensor recalls
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Announcement {
+    id: u64,
+    title: String,
+    body: String,
+    severity: AnnouncementSeverity,
+    created_by: Principal,
+    created_at: u64,
+    // Never expires when None
+    expires_at: Option<u64>,
+}
+
+impl_bounded_storable!(Announcement, 4096);
+
+thread_local! {
+    static ANNOUNCEMENT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(get_memory(ANNOUNCEMENT_ID_COUNTER_MEMORY_ID), 0)
+            .expect("Cannot create a counter for announcements")
+    );
+
+    static ANNOUNCEMENT_STORAGE: RefCell<StableBTreeMap<u64, Announcement, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ANNOUNCEMENT_STORAGE_MEMORY_ID)));
+}
+
+impl Announcement {
+    fn is_active(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+pub(crate) fn active_announcements() -> Vec<Announcement> {
+    let now = time();
+    ANNOUNCEMENT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, announcement)| announcement.is_active(now))
+            .map(|(_, announcement)| announcement)
+            .collect()
+    })
+}
+
+#[ic_cdk::update(guard = "accepts_writes")]
+fn create_announcement(
+    title: String,
+    body: String,
+    severity: AnnouncementSeverity,
+    expiry: Option<u64>,
+) -> Result<Announcement, Error> {
+    ensure_admin()?;
+    if title.is_empty() || title.len() > MAX_TITLE_LEN || body.len() > MAX_BODY_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "title must be 1 to {} bytes and body at most {} bytes",
+                MAX_TITLE_LEN, MAX_BODY_LEN
+            ),
+            violations: None,
+        });
+    }
+    let now = time();
+    if expiry.is_some_and(|expiry| expiry <= now) {
+        return Err(Error::InvalidInput {
+            msg: "expiry is in the past".to_string(),
+            violations: None,
+        });
+    }
+
+    let announcement = Announcement {
+        id: next_id(&ANNOUNCEMENT_ID_COUNTER),
+        title,
+        body,
+        severity,
+        created_by: ic_cdk::caller(),
+        created_at: now,
+        expires_at: expiry,
+    };
+    ANNOUNCEMENT_STORAGE.with(|s| s.borrow_mut().insert(announcement.id, announcement.clone()));
+    Ok(announcement)
+}
+
+// Withdraws an announcement before it expires
+#[ic_cdk::update(guard = "accepts_writes")]
+fn delete_announcement(id: u64) -> Result<Announcement, Error> {
+    ensure_admin()?;
+    ANNOUNCEMENT_STORAGE
+        .with(|s| s.borrow_mut().remove(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("announcement {} not found", id),
+        })
+}
+
+#[ic_cdk::query]
+fn get_active_announcements() -> Vec<Announcement> {
+    active_announcements()
+}
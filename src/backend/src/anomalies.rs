@@ -0,0 +1,241 @@
+use crate::paging::{self, ReplyBudget};
+use crate::{
+    accepts_writes, clear_stable_map, ensure_admin, get_memory, is_visible_to_caller,
+    AirQualityData, Error, Memory, StringKey, AIR_QUALITY_STORAGE, ANOMALY_FLAG_MEMORY_ID,
+    ANOMALY_WINDOW_MEMORY_ID,
+};
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::ops::Bound;
+
+// Readings of a location the detector compares against, most recent last
+const WINDOW_SIZE: usize = 30;
+// Fewer readings than this are not enough to tell a spike from normal variation
+const MIN_HISTORY: usize = 10;
+// Modified z-score above which a value is suspect (Iglewicz and Hoaglin)
+const SUSPECT_SCORE: f64 = 3.5;
+const MAX_LOCATION_LEN: usize = 200;
+const MAX_METRIC_LEN: usize = 64;
+// Keeps a flag within its storage slot
+const MAX_ANOMALIES: usize = 16;
+const AIR_QUALITY_INDEX: &str = "air_quality_index";
+
+// Recent values of one metric (the AQI or a pollutant) at one location
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Window {
+    values: Vec<f64>,
+}
+
+impl_bounded_storable!(Window, 512);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Anomaly {
+    // "air_quality_index" or a pollutant name
+    metric: String,
+    value: f64,
+    median: f64,
+    score: f64,
+}
+
+// Why a reading was flagged as suspect on insert
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SuspectFlag {
+    reading_id: u64,
+    flagged_at: u64,
+    anomalies: Vec<Anomaly>,
+}
+
+impl_bounded_storable!(SuspectFlag, 4096);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct FlaggedReading {
+    reading: AirQualityData,
+    flag: SuspectFlag,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct FlaggedReadingPage {
+    readings: Vec<FlaggedReading>,
+    // More flagged readings exist; pass `next_cursor` to fetch them
+    truncated: bool,
+    next_cursor: Option<u64>,
+}
+
+type WindowKey = (StringKey, StringKey);
+
+thread_local! {
+    static ANOMALY_WINDOWS: RefCell<StableBTreeMap<WindowKey, Window, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ANOMALY_WINDOW_MEMORY_ID)));
+
+    static SUSPECT_FLAGS: RefCell<StableBTreeMap<u64, SuspectFlag, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ANOMALY_FLAG_MEMORY_ID)));
+}
+
+pub(crate) fn reset() {
+    ANOMALY_WINDOWS.with(|s| clear_stable_map(s, ANOMALY_WINDOW_MEMORY_ID));
+    SUSPECT_FLAGS.with(|s| clear_stable_map(s, ANOMALY_FLAG_MEMORY_ID));
+}
+
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(f64::total_cmp);
+    let middle = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[middle - 1] + values[middle]) / 2.0
+    } else {
+        values[middle]
+    }
+}
+
+// Modified z-score of `value` against `history`, from the median absolute deviation. A zero
+// MAD falls back to the mean absolute deviation; None if that is zero too.
+fn robust_score(value: f64, history: &[f64]) -> Option<(f64, f64)> {
+    let center = median(&mut history.to_vec());
+    let mut deviations: Vec<f64> = history.iter().map(|x| (x - center).abs()).collect();
+    let mad = median(&mut deviations);
+    let score = if mad > 0.0 {
+        0.6745 * (value - center) / mad
+    } else {
+        let mean_deviation = deviations.iter().sum::<f64>() / deviations.len() as f64;
+        if mean_deviation == 0.0 {
+            return None;
+        }
+        (value - center) / (1.253_314 * mean_deviation)
+    };
+    Some((center, score))
+}
+
+// Compares the metric with the location's recent values, then adds it to them
+fn observe(location: &str, metric: &str, value: f64) -> Option<Anomaly> {
+    if metric.len() > MAX_METRIC_LEN || !value.is_finite() {
+        return None;
+    }
+    let key = (
+        StringKey(location.to_string()),
+        StringKey(metric.to_string()),
+    );
+    ANOMALY_WINDOWS.with(|s| {
+        let mut storage = s.borrow_mut();
+        let mut window = storage.get(&key).unwrap_or_default();
+        let anomaly = (window.values.len() >= MIN_HISTORY)
+            .then(|| robust_score(value, &window.values))
+            .flatten()
+            .filter(|(_, score)| score.abs() > SUSPECT_SCORE)
+            .map(|(median, score)| Anomaly {
+                metric: metric.to_string(),
+                value,
+                median,
+                score,
+            });
+        window.values.push(value);
+        if window.values.len() > WINDOW_SIZE {
+            window.values.remove(0);
+        }
+        storage.insert(key, window);
+        anomaly
+    })
+}
+
+// Flags a newly inserted reading whose AQI or pollutant levels stand out from the location's
+// recent readings
+pub(crate) fn on_insert(data: &AirQualityData) {
+    if data.location.len() > MAX_LOCATION_LEN {
+        return;
+    }
+    let mut anomalies: Vec<Anomaly> = observe(
+        &data.location,
+        AIR_QUALITY_INDEX,
+        data.air_quality_index as f64,
+    )
+    .into_iter()
+    .collect();
+    let mut levels: Vec<_> = data.pollutant_levels.iter().collect();
+    levels.sort_by_key(|(pollutant, _)| *pollutant);
+    for (pollutant, level) in levels {
+        anomalies.extend(observe(&data.location, pollutant.name(), *level));
+    }
+    if anomalies.is_empty() {
+        return;
+    }
+    anomalies.truncate(MAX_ANOMALIES);
+    let flag = SuspectFlag {
+        reading_id: data.id,
+        flagged_at: time(),
+        anomalies,
+    };
+    log!(Warn, "suspect reading", "id" => data.id, "location" => data.location);
+    SUSPECT_FLAGS.with(|s| s.borrow_mut().insert(data.id, flag));
+}
+
+// Drops the flag of a reading removed from storage
+pub(crate) fn on_remove(id: u64) {
+    SUSPECT_FLAGS.with(|s| s.borrow_mut().remove(&id));
+}
+
+// Suspect readings after `cursor` in id order, skipping deleted and hidden ones
+#[ic_cdk::query]
+fn get_flagged_readings(cursor: Option<u64>) -> FlaggedReadingPage {
+    let start = cursor.map_or(Bound::Unbounded, Bound::Excluded);
+    let (readings, truncated) = SUSPECT_FLAGS.with(|s| {
+        paging::take_within(
+            s.borrow()
+                .range((start, Bound::Unbounded))
+                .filter_map(|(id, flag)| {
+                    let reading = AIR_QUALITY_STORAGE.with(|service| service.borrow().get(&id))?;
+                    (!reading.is_deleted() && is_visible_to_caller(&reading))
+                        .then_some(FlaggedReading { reading, flag })
+                }),
+            &mut ReplyBudget::new(),
+        )
+    });
+    FlaggedReadingPage {
+        next_cursor: readings
+            .last()
+            .filter(|_| truncated)
+            .map(|item| item.flag.reading_id),
+        readings,
+        truncated,
+    }
+}
+
+// Clears the flag of a reading reviewed and found valid (controllers only)
+#[ic_cdk::update(guard = "accepts_writes")]
+fn clear_suspect_flag(id: u64) -> Result<(), Error> {
+    ensure_admin()?;
+    SUSPECT_FLAGS
+        .with(|s| s.borrow_mut().remove(&id))
+        .map(|_| ())
+        .ok_or(Error::NotFound {
+            msg: format!("reading with id={} is not flagged", id),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn robust_score_of_a_constant_history_is_none() {
+        assert!(robust_score(5.0, &[5.0; 10]).is_none());
+        assert!(robust_score(500.0, &[5.0; 10]).is_none());
+    }
+
+    #[test]
+    fn robust_score_falls_back_to_the_mean_deviation() {
+        // More than half the history is at the median, so the MAD is zero
+        let history = [5.0, 5.0, 5.0, 5.0, 5.0, 5.0, 7.0, 9.0];
+        let (median, score) = robust_score(9.0, &history).expect("a score");
+        assert_eq!(median, 5.0);
+        assert!(score > 0.0);
+    }
+
+    #[test]
+    fn robust_score_of_an_outlier() {
+        let history = [10.0, 11.0, 9.0, 10.0, 12.0, 8.0, 10.0, 11.0, 9.0, 10.0];
+        let (median, score) = robust_score(40.0, &history).expect("a score");
+        assert_eq!(median, 10.0);
+        assert!(score > SUSPECT_SCORE);
+        let (_, score) = robust_score(10.0, &history).expect("a score");
+        assert_eq!(score, 0.0);
+    }
+}
@@ -0,0 +1,275 @@
+use crate::http::{error, HttpGatewayResponse};
+use crate::{
+    accepts_writes, ensure_admin, get_memory, next_id, Error, IdCell, Memory,
+    API_KEY_ID_COUNTER_MEMORY_ID, API_KEY_STORAGE_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+const SECRET_PREFIX: &str = "aqk";
+const MAX_LABEL_LEN: usize = 64;
+const MAX_API_KEYS: u64 = 1000;
+const DEFAULT_REQUESTS_PER_MINUTE: u32 = 60;
+const MAX_REQUESTS_PER_MINUTE: u32 = 600;
+const NANOS_PER_MINUTE: u64 = 60 * 1_000_000_000;
+
+// The routes of the HTTP interface a key may be used for
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) enum ApiScope {
+    // POST /search and /query
+    Grafana,
+    // GET /tools and POST /tools/{name}
+    Tools,
+    // GET /ha/{location}/sensor.json
+    HomeAssistant,
+    // GET /stations/{station_id}/branding.json
+    Branding,
+    // GET /v1.1/...
+    SensorThings,
+}
+
+// A key identifies a client of the HTTP interface; it does not act as a principal, so keyed
+// requests are served what anonymous ones are
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ApiKey {
+    id: u64,
+    label: String,
+    scopes: Vec<ApiScope>,
+    requests_per_minute: u32,
+    // SHA-256 of the secret; the secret itself is only returned by issue_api_key
+    secret_sha256: Vec<u8>,
+    created_by: Principal,
+    created_at: u64,
+    expires_at: Option<u64>,
+    revoked_at: Option<u64>,
+    last_used_at: Option<u64>,
+    requests: u64,
+}
+
+impl_bounded_storable!(ApiKey, 512);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ApiKeyPayload {
+    label: String,
+    scopes: Vec<ApiScope>,
+    // At most 600; None for 60
+    requests_per_minute: Option<u32>,
+    // None for a key that does not expire
+    expires_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct IssuedApiKey {
+    key: ApiKey,
+    // Send it as `Authorization: Bearer <secret>` or `X-API-Key: <secret>`. It cannot be
+    // retrieved again.
+    secret: String,
+}
+
+thread_local! {
+    static API_KEY_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(get_memory(API_KEY_ID_COUNTER_MEMORY_ID), 0)
+            .expect("Cannot create a counter for API keys")
+    );
+
+    static API_KEY_STORAGE: RefCell<StableBTreeMap<u64, ApiKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(API_KEY_STORAGE_MEMORY_ID)));
+
+    // Requests per key in the current minute. Kept on the heap: the windows start over after an
+    // upgrade.
+    static RATE_WINDOWS: RefCell<BTreeMap<u64, (u64, u32)>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+fn sha256(secret: &str) -> Vec<u8> {
+    Sha256::digest(secret.as_bytes()).to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Secrets are `aqk_{id}_{64 hex digits}`, so a key is found without an index of hashes
+fn key_id_of(secret: &str) -> Option<u64> {
+    let mut parts = secret.splitn(3, '_');
+    if parts.next() != Some(SECRET_PREFIX) {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+// The secret a request presents, from `Authorization: Bearer` or `X-API-Key`
+pub(crate) fn presented_secret(headers: &[(String, String)]) -> Option<&str> {
+    headers.iter().find_map(|(name, value)| {
+        if name.eq_ignore_ascii_case("x-api-key") {
+            Some(value.trim())
+        } else if name.eq_ignore_ascii_case("authorization") {
+            value
+                .trim()
+                .split_once(' ')
+                .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+                .map(|(_, secret)| secret.trim())
+        } else {
+            None
+        }
+    })
+}
+
+fn unauthorized(msg: &str) -> HttpGatewayResponse {
+    error(401, msg).with_header("WWW-Authenticate", "Bearer")
+}
+
+// Checks a presented secret, its scope and its rate limit, and counts the request. Must run in
+// an update call, or the count is lost.
+pub(crate) fn authenticate(
+    secret: &str,
+    scope: Option<ApiScope>,
+) -> Result<(), HttpGatewayResponse> {
+    let now = time();
+    let key = key_id_of(secret)
+        .and_then(|id| API_KEY_STORAGE.with(|s| s.borrow().get(&id)))
+        .filter(|key| {
+            // Compared in full so the time taken does not tell how much of a hash matched
+            let hash = sha256(secret);
+            hash.len() == key.secret_sha256.len()
+                && hash
+                    .iter()
+                    .zip(&key.secret_sha256)
+                    .fold(0, |diff, (a, b)| diff | (a ^ b))
+                    == 0
+        })
+        .ok_or_else(|| unauthorized("invalid API key"))?;
+    if key.revoked_at.is_some() {
+        return Err(unauthorized("the API key was revoked"));
+    }
+    if key.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(unauthorized("the API key has expired"));
+    }
+    let minute = now / NANOS_PER_MINUTE;
+    let admitted = RATE_WINDOWS.with(|w| {
+        let mut windows = w.borrow_mut();
+        let window = windows.entry(key.id).or_insert((minute, 0));
+        if window.0 != minute {
+            *window = (minute, 0);
+        }
+        if window.1 >= key.requests_per_minute {
+            return false;
+        }
+        window.1 += 1;
+        true
+    });
+    if !admitted {
+        let retry_after = ((minute + 1) * NANOS_PER_MINUTE - now).div_ceil(1_000_000_000);
+        return Err(error(429, "the API key's rate limit is exhausted")
+            .with_header("Retry-After", &retry_after.to_string()));
+    }
+    API_KEY_STORAGE.with(|s| {
+        let mut storage = s.borrow_mut();
+        let mut key = key.clone();
+        key.last_used_at = Some(now);
+        key.requests += 1;
+        storage.insert(key.id, key);
+    });
+    if scope.is_some_and(|scope| !key.scopes.contains(&scope)) {
+        return Err(error(403, "the API key does not cover this route"));
+    }
+    Ok(())
+}
+
+fn validate_payload(payload: &ApiKeyPayload) -> Result<(), Error> {
+    let invalid = |msg: &str| Error::InvalidInput {
+        msg: msg.to_string(),
+        violations: None,
+    };
+    if payload.label.trim().is_empty() || payload.label.len() > MAX_LABEL_LEN {
+        return Err(invalid("label must be 1 to 64 bytes"));
+    }
+    if payload.scopes.is_empty() {
+        return Err(invalid("a key needs at least one scope"));
+    }
+    if payload
+        .requests_per_minute
+        .is_some_and(|limit| limit == 0 || limit > MAX_REQUESTS_PER_MINUTE)
+    {
+        return Err(invalid("requests_per_minute must be 1 to 600"));
+    }
+    if payload
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= time())
+    {
+        return Err(invalid("expires_at must be in the future"));
+    }
+    Ok(())
+}
+
+// Issues a key for the HTTP interface (controllers only). Only its hash is stored.
+#[ic_cdk::update(guard = "accepts_writes")]
+async fn issue_api_key(payload: ApiKeyPayload) -> Result<IssuedApiKey, Error> {
+    ensure_admin()?;
+    validate_payload(&payload)?;
+    if API_KEY_STORAGE.with(|s| s.borrow().len()) >= MAX_API_KEYS {
+        return Err(Error::TooLarge {
+            msg: format!("at most {} API keys can be issued", MAX_API_KEYS),
+        });
+    }
+    let (random,) = raw_rand()
+        .await
+        .map_err(|(code, msg)| Error::InvalidInput {
+            msg: format!("cannot draw randomness for the key: {:?} {}", code, msg),
+            violations: None,
+        })?;
+    let id = next_id(&API_KEY_ID_COUNTER);
+    let secret = format!("{}_{}_{}", SECRET_PREFIX, id, hex(&random));
+    let mut scopes: Vec<ApiScope> = Vec::new();
+    for scope in payload.scopes {
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+    let key = ApiKey {
+        id,
+        label: payload.label.trim().to_string(),
+        scopes,
+        requests_per_minute: payload
+            .requests_per_minute
+            .unwrap_or(DEFAULT_REQUESTS_PER_MINUTE),
+        secret_sha256: sha256(&secret),
+        created_by: ic_cdk::caller(),
+        created_at: time(),
+        expires_at: payload.expires_at,
+        revoked_at: None,
+        last_used_at: None,
+        requests: 0,
+    };
+    API_KEY_STORAGE.with(|s| s.borrow_mut().insert(id, key.clone()));
+    log!(Info, "API key issued", "key" => id, "caller" => ic_cdk::caller());
+    Ok(IssuedApiKey { key, secret })
+}
+
+// Revokes a key; requests with it are refused from then on (controllers only)
+#[ic_cdk::update(guard = "accepts_writes")]
+fn revoke_api_key(id: u64) -> Result<ApiKey, Error> {
+    ensure_admin()?;
+    API_KEY_STORAGE.with(|s| {
+        let mut storage = s.borrow_mut();
+        let mut key = storage.get(&id).ok_or(Error::NotFound {
+            msg: format!("API key with id={} not found", id),
+        })?;
+        key.revoked_at.get_or_insert(time());
+        storage.insert(id, key.clone());
+        Ok(key)
+    })
+}
+
+continued_page!(ApiKeyPage, ApiKey);
+
+// Every key issued, revoked and expired ones included (controllers only)
+#[ic_cdk::query]
+fn list_api_keys(continuation: Option<String>) -> Result<ApiKeyPage, Error> {
+    ensure_admin()?;
+    API_KEY_STORAGE.with(|s| ApiKeyPage::new(s.borrow().iter().map(|(_, key)| key), continuation))
+}
@@ -0,0 +1,68 @@
+use crate::{
+    clear_stable_map, get_memory, AirQualityData, Memory, AIR_QUALITY_STORAGE, AQI_INDEX_MEMORY_ID,
+};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::ops::Bound;
+
+type AqiKey = (u32, u64);
+
+thread_local! {
+    // (AQI, id) of every live reading, for threshold queries. Entries of readings removed
+    // without a write are skipped by lookups.
+    static AQI_INDEX: RefCell<StableBTreeMap<AqiKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(AQI_INDEX_MEMORY_ID)));
+}
+
+pub(crate) fn reset() {
+    AQI_INDEX.with(|s| clear_stable_map(s, AQI_INDEX_MEMORY_ID));
+}
+
+fn index_key(data: &AirQualityData) -> Option<AqiKey> {
+    (!data.is_deleted()).then_some((data.air_quality_index, data.id))
+}
+
+// Keeps the index in step with a write; `previous` is the record it replaced
+pub(crate) fn on_write(previous: Option<&AirQualityData>, data: &AirQualityData) {
+    AQI_INDEX.with(|s| {
+        let mut index = s.borrow_mut();
+        if let Some(key) = previous.and_then(index_key) {
+            index.remove(&key);
+        }
+        if let Some(key) = index_key(data) {
+            index.insert(key, ());
+        }
+    });
+}
+
+// Adds a reading stored before the index existed
+pub(crate) fn backfill(data: &AirQualityData) {
+    on_write(None, data);
+}
+
+// Live readings with an AQI between the bounds, both included, in AQI order. A missing bound is
+// open.
+pub(crate) fn readings_within(min: Option<f64>, max: Option<f64>) -> Vec<AirQualityData> {
+    let min = min.map_or(0, |min| min.ceil().max(0.0) as u32);
+    let max = match max {
+        Some(max) if max < 0.0 => return Vec::new(),
+        Some(max) => max.floor().min(u32::MAX as f64) as u32,
+        None => u32::MAX,
+    };
+    if min > max {
+        return Vec::new();
+    }
+    let ids: Vec<u64> = AQI_INDEX.with(|s| {
+        s.borrow()
+            .range((Bound::Included((min, 0)), Bound::Included((max, u64::MAX))))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    AIR_QUALITY_STORAGE.with(|service| {
+        let storage = service.borrow();
+        ids.into_iter()
+            .filter_map(|id| storage.get(&id))
+            .filter(|data| !data.is_deleted())
+            .collect()
+    })
+}
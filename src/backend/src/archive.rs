@@ -0,0 +1,291 @@
+use crate::time_index::{self, TimeKey};
+use crate::{
+    accepts_writes, do_insert_air_quality, ensure_admin, get_memory, migrations,
+    remove_air_quality, AirQualityData, Error, Memory, Removal, AIR_QUALITY_STORAGE,
+    ARCHIVE_STATE_MEMORY_ID, ARCHIVE_WASM_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::management_canister::main::{
+    create_canister, install_code, CanisterInstallMode, CanisterSettings, CreateCanisterArgument,
+    InstallCodeArgument,
+};
+use ic_cdk::api::{id, time};
+use ic_stable_structures::{Cell, Storable};
+use std::cell::RefCell;
+
+const NANOS_PER_DAY: u64 = 86_400 * 1_000_000_000;
+// Keeps a batch of 1KiB records well below the 2MiB inter-canister message limit
+const MAX_BATCH_SIZE: u32 = 1000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ArchiveSettings {
+    enabled: bool,
+    archive_canister: Option<Principal>,
+    // Archiving starts once the primary store holds more records than this
+    threshold_records: u64,
+    // Only readings older than this are moved
+    min_age_days: u32,
+    batch_size: u32,
+}
+
+impl Default for ArchiveSettings {
+    fn default() -> Self {
+        ArchiveSettings {
+            enabled: false,
+            archive_canister: None,
+            threshold_records: 1_000_000,
+            min_age_days: 90,
+            batch_size: 500,
+        }
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ArchiveStatus {
+    settings: ArchiveSettings,
+    // Set on an archive canister: the primary allowed to push records into it
+    primary: Option<Principal>,
+    records_archived: u64,
+    last_archived_at: Option<u64>,
+    last_error: Option<String>,
+}
+
+impl_bounded_storable!(ArchiveStatus, 1024);
+
+thread_local! {
+    static ARCHIVE_STATE: RefCell<Cell<ArchiveStatus, Memory>> = RefCell::new(
+        Cell::init(get_memory(ARCHIVE_STATE_MEMORY_ID), ArchiveStatus::default())
+            .expect("Cannot create the archive state cell")
+    );
+
+    // Wasm module installed into archive canisters spawned by this canister
+    static ARCHIVE_WASM: RefCell<Cell<Vec<u8>, Memory>> = RefCell::new(
+        Cell::init(get_memory(ARCHIVE_WASM_MEMORY_ID), Vec::new())
+            .expect("Cannot create the archive wasm cell")
+    );
+
+    static ARCHIVE_IN_FLIGHT: RefCell<bool> = const { RefCell::new(false) };
+
+    // Time index key of the last reading sent to the archive. Readings that changed in flight
+    // are kept and picked up again once a pass over the old readings wraps around.
+    static ARCHIVE_CURSOR: RefCell<Option<TimeKey>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn reset() {
+    ARCHIVE_CURSOR.with(|c| *c.borrow_mut() = None);
+}
+
+fn state() -> ArchiveStatus {
+    ARCHIVE_STATE.with(|s| s.borrow().get().clone())
+}
+
+fn update_state<F: FnOnce(&mut ArchiveStatus)>(f: F) -> ArchiveStatus {
+    let mut state = state();
+    f(&mut state);
+    ARCHIVE_STATE
+        .with(|s| s.borrow_mut().set(state.clone()))
+        .expect("cannot store the archive state");
+    state
+}
+
+pub(crate) fn archive_canister() -> Option<Principal> {
+    state().settings.archive_canister
+}
+
+#[ic_cdk::update(guard = "accepts_writes")]
+fn configure_archive(settings: ArchiveSettings) -> Result<ArchiveStatus, Error> {
+    ensure_admin()?;
+    if settings.batch_size == 0 || settings.batch_size > MAX_BATCH_SIZE {
+        return Err(Error::InvalidInput {
+            msg: format!("batch_size must be between 1 and {}", MAX_BATCH_SIZE),
+            violations: None,
+        });
+    }
+    Ok(update_state(|state| state.settings = settings))
+}
+
+// Called on an archive canister to accept records from the given primary
+#[ic_cdk::update(guard = "accepts_writes")]
+fn set_archive_primary(primary: Option<Principal>) -> Result<ArchiveStatus, Error> {
+    ensure_admin()?;
+    Ok(update_state(|state| state.primary = primary))
+}
+
+#[ic_cdk::query]
+fn get_archive_status() -> ArchiveStatus {
+    state()
+}
+
+// Uploads the archive wasm in chunks; `reset` starts a new module
+#[ic_cdk::update(guard = "accepts_writes")]
+fn upload_archive_wasm(chunk: Vec<u8>, reset: bool) -> Result<u64, Error> {
+    ensure_admin()?;
+    ARCHIVE_WASM.with(|w| {
+        let mut wasm = if reset {
+            Vec::new()
+        } else {
+            w.borrow().get().clone()
+        };
+        wasm.extend_from_slice(&chunk);
+        let len = wasm.len() as u64;
+        w.borrow_mut()
+            .set(wasm)
+            .expect("cannot store the archive wasm");
+        Ok(len)
+    })
+}
+
+// Creates an archive canister from the uploaded wasm and starts using it
+#[ic_cdk::update(guard = "accepts_writes")]
+async fn spawn_archive_canister(cycles: u128) -> Result<Principal, Error> {
+    ensure_admin()?;
+    let wasm_module = ARCHIVE_WASM.with(|w| w.borrow().get().clone());
+    if wasm_module.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "upload the archive wasm with upload_archive_wasm first".to_string(),
+            violations: None,
+        });
+    }
+
+    let settings = CanisterSettings {
+        controllers: Some(vec![id(), ic_cdk::caller()]),
+        compute_allocation: None,
+        memory_allocation: None,
+        freezing_threshold: None,
+    };
+    let (record,) = create_canister(
+        CreateCanisterArgument {
+            settings: Some(settings),
+        },
+        cycles,
+    )
+    .await
+    .map_err(|(code, msg)| Error::InvalidInput {
+        msg: format!("cannot create archive canister: {:?} {}", code, msg),
+        violations: None,
+    })?;
+    let canister_id = record.canister_id;
+
+    install_code(InstallCodeArgument {
+        mode: CanisterInstallMode::Install,
+        canister_id,
+        wasm_module,
+        arg: candid::encode_args(()).expect("cannot encode empty init args"),
+    })
+    .await
+    .map_err(|(code, msg)| Error::InvalidInput {
+        msg: format!("cannot install archive canister: {:?} {}", code, msg),
+        violations: None,
+    })?;
+
+    let (result,): (Result<ArchiveStatus, Error>,) =
+        ic_cdk::call(canister_id, "set_archive_primary", (Some(id()),))
+            .await
+            .map_err(|(code, msg)| Error::InvalidInput {
+                msg: format!("cannot configure archive canister: {:?} {}", code, msg),
+                violations: None,
+            })?;
+    result?;
+
+    update_state(|state| state.settings.archive_canister = Some(canister_id));
+    Ok(canister_id)
+}
+
+// Receives records moved out of the primary store
+#[ic_cdk::update(guard = "accepts_writes")]
+fn archive_store_records(records: Vec<AirQualityData>) -> Result<u64, Error> {
+    if state().primary != Some(ic_cdk::caller()) {
+        return Err(Error::Unauthorized {
+            msg: "caller is not the primary of this archive".to_string(),
+        });
+    }
+    for record in &records {
+        do_insert_air_quality(record);
+    }
+    Ok(records.len() as u64)
+}
+
+// Looks a record up in the archive, for records no longer held locally
+pub(crate) async fn get_archived(id: u64) -> Option<AirQualityData> {
+    let archive = archive_canister()?;
+    let result: Result<(Result<AirQualityData, Error>,), _> =
+        ic_cdk::call(archive, "get_air_quality_data", (id,)).await;
+    match result {
+        Ok((Ok(data),)) => Some(data),
+        _ => None,
+    }
+}
+
+// Moves one batch of old readings to the archive when the store is over its threshold
+pub(crate) fn on_timer() {
+    let state = state();
+    let settings = state.settings;
+    let Some(archive) = settings.archive_canister else {
+        return;
+    };
+    if !settings.enabled || ARCHIVE_IN_FLIGHT.with(|f| *f.borrow()) {
+        return;
+    }
+
+    // Readings are found through the time index, which is incomplete until it is built
+    if migrations::is_pending() {
+        return;
+    }
+    let stored = AIR_QUALITY_STORAGE.with(|service| service.borrow().len());
+    if stored <= settings.threshold_records {
+        return;
+    }
+    let cutoff = time().saturating_sub(settings.min_age_days as u64 * NANOS_PER_DAY);
+    let cursor = ARCHIVE_CURSOR.with(|c| *c.borrow());
+    let batch = time_index::readings_before(cutoff, cursor, settings.batch_size as usize);
+    let Some(last) = batch.last() else {
+        ARCHIVE_CURSOR.with(|c| *c.borrow_mut() = None);
+        return;
+    };
+    ARCHIVE_CURSOR.with(|c| *c.borrow_mut() = Some(time_index::key_of(last)));
+
+    ARCHIVE_IN_FLIGHT.with(|f| *f.borrow_mut() = true);
+    ic_cdk::spawn(async move {
+        let result: Result<(Result<u64, Error>,), _> =
+            ic_cdk::call(archive, "archive_store_records", (batch.clone(),)).await;
+        match result {
+            Ok((Ok(_),)) => {
+                let moved = remove_archived(&batch);
+                update_state(|state| {
+                    state.records_archived += moved;
+                    state.last_archived_at = Some(time());
+                    state.last_error = None;
+                });
+            }
+            Ok((Err(_),)) => {
+                log!(Error, "archive rejected a batch", "records" => batch.len());
+                update_state(|state| {
+                    state.last_error = Some("archive rejected the batch".to_string())
+                });
+            }
+            Err((code, msg)) => {
+                log!(Warn, "archive call failed", "code" => format!("{:?}", code), "error" => msg);
+                update_state(|state| state.last_error = Some(format!("{:?} {}", code, msg)));
+            }
+        }
+        ARCHIVE_IN_FLIGHT.with(|f| *f.borrow_mut() = false);
+    });
+}
+
+// Removes archived records, keeping any that changed while the batch was in flight
+fn remove_archived(batch: &[AirQualityData]) -> u64 {
+    let mut moved = 0;
+    for archived in batch {
+        let unchanged = AIR_QUALITY_STORAGE.with(|service| {
+            service
+                .borrow()
+                .get(&archived.id)
+                .is_some_and(|current| current.to_bytes() == archived.to_bytes())
+        });
+        if unchanged {
+            remove_air_quality(archived, Removal::Archived);
+            moved += 1;
+        }
+    }
+    moved
+}
@@ -0,0 +1,276 @@
+use crate::aggregates::{bucket_bounds, level_in_micrograms, readings_at, AggregationBucket};
+use crate::{AirQualityData, Error, Pollutant};
+use ic_cdk::api::time;
+use std::collections::BTreeMap;
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MAX_QUESTION_LEN: usize = 500;
+const MAX_LAST_DAYS: u64 = 366;
+const SUPPORTED: &str = "supported questions look like \"worst day in <location> last month\" or \
+\"average pm2.5 in <location> this week\": worst day, best day, average, highest or lowest, then \
+optionally aqi or a pollutant, then in <location>, then today, yesterday, this week, last week, \
+this month, last month or last <n> days";
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum QuestionIntent {
+    WorstDay,
+    BestDay,
+    Average,
+    Highest,
+    Lowest,
+}
+
+// How a question was understood, so the frontend can show it back
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct InterpretedQuestion {
+    intent: QuestionIntent,
+    // "aqi" or a pollutant name
+    metric: String,
+    location: String,
+    start_timestamp: u64,
+    end_timestamp: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct Answer {
+    interpreted: InterpretedQuestion,
+    readings: u64,
+    // µg/m³ for pollutants; None without readings
+    value: Option<f64>,
+    // Start of the UTC day, for worst and best day questions
+    day: Option<u64>,
+    text: String,
+}
+
+enum Metric {
+    AirQualityIndex,
+    Pollutant(Pollutant),
+}
+
+impl Metric {
+    fn name(&self) -> &str {
+        match self {
+            Metric::AirQualityIndex => "aqi",
+            Metric::Pollutant(pollutant) => pollutant.name(),
+        }
+    }
+
+    fn value_of(&self, data: &AirQualityData) -> Option<f64> {
+        match self {
+            Metric::AirQualityIndex => Some(data.air_quality_index as f64),
+            Metric::Pollutant(pollutant) => level_in_micrograms(data, pollutant),
+        }
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::InvalidInput {
+        msg: format!("{}; {}", msg, SUPPORTED),
+        violations: None,
+    }
+}
+
+fn parse_intent(question: &str) -> Option<(QuestionIntent, &str)> {
+    const INTENTS: &[(&str, QuestionIntent)] = &[
+        ("worst day", QuestionIntent::WorstDay),
+        ("best day", QuestionIntent::BestDay),
+        ("average", QuestionIntent::Average),
+        ("mean", QuestionIntent::Average),
+        ("highest", QuestionIntent::Highest),
+        ("maximum", QuestionIntent::Highest),
+        ("max", QuestionIntent::Highest),
+        ("lowest", QuestionIntent::Lowest),
+        ("minimum", QuestionIntent::Lowest),
+        ("min", QuestionIntent::Lowest),
+    ];
+    let question = question.strip_prefix("what was the ").unwrap_or(question);
+    let question = question.strip_prefix("what is the ").unwrap_or(question);
+    INTENTS.iter().find_map(|(prefix, intent)| {
+        let rest = question.strip_prefix(prefix)?;
+        rest.starts_with(' ').then(|| (*intent, rest.trim_start()))
+    })
+}
+
+// (start, end) of a period relative to `now`, both included
+fn parse_period(period: &str, now: u64) -> Option<(u64, u64)> {
+    let today = now - now % NANOS_PER_DAY;
+    // 1970-01-01 was a Thursday
+    let monday = today - ((today / NANOS_PER_DAY + 3) % 7) * NANOS_PER_DAY;
+    let (month_start, _) = bucket_bounds(now, AggregationBucket::Month);
+    let (start, end) = match period {
+        "today" => (today, now),
+        "yesterday" => (today.checked_sub(NANOS_PER_DAY)?, today - 1),
+        "this week" => (monday, now),
+        "last week" => (monday.checked_sub(7 * NANOS_PER_DAY)?, monday - 1),
+        "this month" => (month_start, now),
+        "last month" => {
+            let (start, _) = bucket_bounds(month_start.checked_sub(1)?, AggregationBucket::Month);
+            (start, month_start - 1)
+        }
+        _ => {
+            let days: u64 = period
+                .strip_prefix("last ")?
+                .strip_suffix(" days")?
+                .parse()
+                .ok()?;
+            if days == 0 || days > MAX_LAST_DAYS {
+                return None;
+            }
+            (now.saturating_sub(days * NANOS_PER_DAY), now)
+        }
+    };
+    Some((start, end))
+}
+
+const PERIODS: &[&str] = &[
+    "today",
+    "yesterday",
+    "this week",
+    "last week",
+    "this month",
+    "last month",
+];
+
+// Splits "<location> <period>" at the period the text ends with
+fn split_period(rest: &str) -> Option<(&str, &str)> {
+    if let Some(period) = PERIODS.iter().find(|period| rest.ends_with(*period)) {
+        let location = rest[..rest.len() - period.len()].trim_end();
+        return Some((location, period));
+    }
+    let without_days = rest.strip_suffix(" days")?;
+    let (before, count) = without_days.rsplit_once(' ')?;
+    if count.is_empty() || !count.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let location = before.strip_suffix(" last")?;
+    Some((location, &rest[location.len() + 1..]))
+}
+
+// Answers a question from a constrained set of templates, for chat-style frontends. Periods are
+// UTC; weeks start on Monday.
+#[ic_cdk::query]
+fn ask(question: String) -> Result<Answer, Error> {
+    if question.len() > MAX_QUESTION_LEN {
+        return Err(invalid(&format!(
+            "questions must be at most {} bytes",
+            MAX_QUESTION_LEN
+        )));
+    }
+    // Lowercasing ASCII keeps byte offsets, so the location keeps its original spelling
+    let original = question.trim().trim_end_matches('?').trim_end();
+    let lowered = original.to_ascii_lowercase();
+    let (intent, rest) = parse_intent(&lowered).ok_or_else(|| invalid("unknown question"))?;
+    let (metric, rest) = match rest.split_once(" in ") {
+        Some((metric, rest)) if !metric.is_empty() && metric != "aqi" => {
+            (Metric::Pollutant(Pollutant::parse(metric)), rest)
+        }
+        Some((_, rest)) => (Metric::AirQualityIndex, rest),
+        None => (
+            Metric::AirQualityIndex,
+            rest.strip_prefix("in ")
+                .ok_or_else(|| invalid("missing the location"))?,
+        ),
+    };
+    let (location, period) = split_period(rest).ok_or_else(|| invalid("unknown period"))?;
+    if location.is_empty() {
+        return Err(invalid("missing the location"));
+    }
+    let offset = lowered.len() - rest.len();
+    let location = original[offset..offset + location.len()].to_string();
+    let (start_timestamp, end_timestamp) =
+        parse_period(period, time()).ok_or_else(|| invalid("unknown period"))?;
+
+    let values: Vec<(u64, f64)> = readings_at(&location, start_timestamp, end_timestamp)
+        .iter()
+        .filter_map(|data| Some((data.timestamp, metric.value_of(data)?)))
+        .collect();
+    let readings = values.len() as u64;
+    let (value, day) = match intent {
+        QuestionIntent::WorstDay | QuestionIntent::BestDay => {
+            let mut days: BTreeMap<u64, (f64, u64)> = BTreeMap::new();
+            for (timestamp, value) in &values {
+                let entry = days
+                    .entry(timestamp - timestamp % NANOS_PER_DAY)
+                    .or_default();
+                entry.0 += value;
+                entry.1 += 1;
+            }
+            let means = days
+                .into_iter()
+                .map(|(day, (sum, count))| (day, sum / count as f64));
+            let pick = if intent == QuestionIntent::WorstDay {
+                means.max_by(|a, b| a.1.total_cmp(&b.1))
+            } else {
+                means.min_by(|a, b| a.1.total_cmp(&b.1))
+            };
+            (pick.map(|(_, mean)| mean), pick.map(|(day, _)| day))
+        }
+        QuestionIntent::Average => (
+            (readings > 0)
+                .then(|| values.iter().map(|(_, value)| value).sum::<f64>() / readings as f64),
+            None,
+        ),
+        QuestionIntent::Highest => (
+            values
+                .iter()
+                .map(|(_, value)| *value)
+                .max_by(f64::total_cmp),
+            None,
+        ),
+        QuestionIntent::Lowest => (
+            values
+                .iter()
+                .map(|(_, value)| *value)
+                .min_by(f64::total_cmp),
+            None,
+        ),
+    };
+
+    let text = match (value, day) {
+        (None, _) => format!(
+            "There are no {} readings in {} {}.",
+            metric.name(),
+            location,
+            period
+        ),
+        (Some(value), Some(day)) => format!(
+            "The {} day in {} {} started at {} with a mean {} of {:.1}.",
+            if intent == QuestionIntent::WorstDay {
+                "worst"
+            } else {
+                "best"
+            },
+            location,
+            period,
+            day,
+            metric.name(),
+            value
+        ),
+        (Some(value), None) => format!(
+            "The {} {} in {} {} was {:.1}, over {} readings.",
+            match intent {
+                QuestionIntent::Highest => "highest",
+                QuestionIntent::Lowest => "lowest",
+                _ => "average",
+            },
+            metric.name(),
+            location,
+            period,
+            value,
+            readings
+        ),
+    };
+    Ok(Answer {
+        interpreted: InterpretedQuestion {
+            intent,
+            metric: metric.name().to_string(),
+            location,
+            start_timestamp,
+            end_timestamp,
+        },
+        readings,
+        value,
+        day,
+        text,
+    })
+}
@@ -0,0 +1,366 @@
+use crate::sources::{self, ReadingSource};
+use crate::{
+    _add_air_quality_data, accepts_writes, capacity, clear_stable_map, ensure_admin, get_memory,
+    next_id, AirQualityUpdatePayload, Error, IdCell, Memory, BACKFILL_CHUNK_STORAGE_MEMORY_ID,
+    BACKFILL_ID_COUNTER_MEMORY_ID, BACKFILL_JOB_STORAGE_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+const MAX_URL_LEN: usize = 2048;
+const MAX_METHOD_LEN: usize = 64;
+const MAX_CHUNK_SIZE: u32 = 1000;
+const MAX_FAILED_ATTEMPTS: u32 = 5;
+const DEFAULT_MAX_RESPONSE_BYTES: u64 = 1_000_000;
+// Generous upper bound for a 1MB outcall on a 13-node subnet; unused cycles are refunded
+const OUTCALL_CYCLES: u128 = 25_000_000_000;
+
+// Where a backfill pulls its chunks from
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum BackfillSource {
+    // GET {url}?offset={offset}&limit={limit} returning a JSON array of AirQualityUpdatePayload
+    Http {
+        url: String,
+    },
+    // Calls {method}(offset : nat64, limit : nat32) -> (vec AirQualityUpdatePayload)
+    Canister {
+        canister_id: Principal,
+        method: String,
+    },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct BackfillSourceConfig {
+    source: BackfillSource,
+    chunk_size: u32,
+    // When unset the job completes at the first chunk shorter than chunk_size
+    total_chunks: Option<u64>,
+    max_response_bytes: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+enum BackfillState {
+    Running,
+    Paused,
+    Completed,
+    Failed { msg: String },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct BackfillJob {
+    id: u64,
+    config: BackfillSourceConfig,
+    state: BackfillState,
+    next_chunk: u64,
+    completed_chunks: u64,
+    records_imported: u64,
+    failed_attempts: u32,
+    last_error: Option<String>,
+    created_by: Principal,
+    created_at: u64,
+    updated_at: u64,
+}
+
+impl_bounded_storable!(BackfillJob, 4096);
+
+thread_local! {
+    static BACKFILL_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(get_memory(BACKFILL_ID_COUNTER_MEMORY_ID), 0)
+            .expect("Cannot create a counter for backfill jobs")
+    );
+
+    static BACKFILL_JOB_STORAGE: RefCell<StableBTreeMap<u64, BackfillJob, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(BACKFILL_JOB_STORAGE_MEMORY_ID)));
+
+    // (job id, chunk index) -> records imported from that chunk
+    static BACKFILL_CHUNK_STORAGE: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(BACKFILL_CHUNK_STORAGE_MEMORY_ID)));
+
+    // Jobs with a chunk request awaiting its response. Deliberately not stable:
+    // after an upgrade no request can still be in flight.
+    static IN_FLIGHT: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+fn get_job(id: u64) -> Result<BackfillJob, Error> {
+    BACKFILL_JOB_STORAGE
+        .with(|s| s.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("backfill job with id={} not found", id),
+        })
+}
+
+fn do_insert_job(job: &BackfillJob) {
+    BACKFILL_JOB_STORAGE.with(|s| s.borrow_mut().insert(job.id, job.clone()));
+}
+
+// The imported readings are gone once the readings are reset: chunk records are dropped, and
+// jobs not completed yet import again from their first chunk
+pub(crate) fn reset() {
+    BACKFILL_CHUNK_STORAGE.with(|s| clear_stable_map(s, BACKFILL_CHUNK_STORAGE_MEMORY_ID));
+    let jobs: Vec<BackfillJob> = BACKFILL_JOB_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, job)| job)
+            .filter(|job| job.state != BackfillState::Completed)
+            .collect()
+    });
+    for mut job in jobs {
+        job.next_chunk = 0;
+        job.completed_chunks = 0;
+        job.records_imported = 0;
+        do_insert_job(&job);
+    }
+}
+
+fn validate_config(config: &BackfillSourceConfig) -> Result<(), Error> {
+    if config.chunk_size == 0 || config.chunk_size > MAX_CHUNK_SIZE {
+        return Err(Error::InvalidInput {
+            msg: format!("chunk_size must be between 1 and {}", MAX_CHUNK_SIZE),
+            violations: None,
+        });
+    }
+    match &config.source {
+        BackfillSource::Http { url } if url.len() > MAX_URL_LEN || !url.starts_with("https://") => {
+            Err(Error::InvalidInput {
+                msg: format!(
+                    "url must be an https:// URL of at most {} bytes",
+                    MAX_URL_LEN
+                ),
+                violations: None,
+            })
+        }
+        BackfillSource::Canister { method, .. }
+            if method.is_empty() || method.len() > MAX_METHOD_LEN =>
+        {
+            Err(Error::InvalidInput {
+                msg: format!("method must be 1 to {} bytes", MAX_METHOD_LEN),
+                violations: None,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+#[ic_cdk::update(guard = "accepts_writes")]
+fn start_backfill(source_config: BackfillSourceConfig) -> Result<BackfillJob, Error> {
+    ensure_admin()?;
+    validate_config(&source_config)?;
+    capacity::ensure_room_for_optional_write()?;
+
+    let now = time();
+    let job = BackfillJob {
+        id: next_id(&BACKFILL_ID_COUNTER),
+        config: source_config,
+        state: BackfillState::Running,
+        next_chunk: 0,
+        completed_chunks: 0,
+        records_imported: 0,
+        failed_attempts: 0,
+        last_error: None,
+        created_by: ic_cdk::caller(),
+        created_at: now,
+        updated_at: now,
+    };
+    do_insert_job(&job);
+    Ok(job)
+}
+
+#[ic_cdk::update(guard = "accepts_writes")]
+fn pause_backfill(id: u64) -> Result<BackfillJob, Error> {
+    ensure_admin()?;
+    let mut job = get_job(id)?;
+    if job.state != BackfillState::Running {
+        return Err(Error::InvalidInput {
+            msg: format!("backfill job with id={} is not running", id),
+            violations: None,
+        });
+    }
+    job.state = BackfillState::Paused;
+    job.updated_at = time();
+    do_insert_job(&job);
+    Ok(job)
+}
+
+// Resumes a paused job, or retries a failed one from the chunk that failed
+#[ic_cdk::update(guard = "accepts_writes")]
+fn resume_backfill(id: u64) -> Result<BackfillJob, Error> {
+    ensure_admin()?;
+    let mut job = get_job(id)?;
+    match job.state {
+        BackfillState::Paused | BackfillState::Failed { .. } => {
+            job.state = BackfillState::Running;
+            job.failed_attempts = 0;
+            job.updated_at = time();
+            do_insert_job(&job);
+            Ok(job)
+        }
+        _ => Err(Error::InvalidInput {
+            msg: format!("backfill job with id={} is not paused or failed", id),
+            violations: None,
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn get_backfill_status(id: u64) -> Result<BackfillJob, Error> {
+    get_job(id)
+}
+
+continued_page!(BackfillJobPage, BackfillJob);
+
+#[ic_cdk::query]
+fn list_backfills(continuation: Option<String>) -> Result<BackfillJobPage, Error> {
+    BACKFILL_JOB_STORAGE
+        .with(|s| BackfillJobPage::new(s.borrow().iter().map(|(_, job)| job), continuation))
+}
+
+// Strips everything that differs between replicas so the responses reach consensus
+#[ic_cdk::query]
+fn transform_backfill_response(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: vec![],
+        body: args.response.body,
+    }
+}
+
+// Starts fetching the next chunk of every running job that has no request in flight
+pub(crate) fn on_timer() {
+    let runnable: Vec<BackfillJob> = BACKFILL_JOB_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(id, job)| {
+                job.state == BackfillState::Running && !IN_FLIGHT.with(|f| f.borrow().contains(id))
+            })
+            .map(|(_, job)| job)
+            .collect()
+    });
+
+    for job in runnable {
+        IN_FLIGHT.with(|f| f.borrow_mut().insert(job.id));
+        ic_cdk::spawn(async move {
+            let chunk = job.next_chunk;
+            let result = fetch_chunk(&job.config, chunk).await;
+            apply_chunk_result(job.id, chunk, result);
+            IN_FLIGHT.with(|f| f.borrow_mut().remove(&job.id));
+        });
+    }
+}
+
+async fn fetch_chunk(
+    config: &BackfillSourceConfig,
+    chunk: u64,
+) -> Result<Vec<AirQualityUpdatePayload>, String> {
+    let offset = chunk * config.chunk_size as u64;
+    match &config.source {
+        BackfillSource::Http { url } => {
+            let separator = if url.contains('?') { '&' } else { '?' };
+            let request = CanisterHttpRequestArgument {
+                url: format!(
+                    "{}{}offset={}&limit={}",
+                    url, separator, offset, config.chunk_size
+                ),
+                max_response_bytes: Some(
+                    config
+                        .max_response_bytes
+                        .unwrap_or(DEFAULT_MAX_RESPONSE_BYTES),
+                ),
+                method: HttpMethod::GET,
+                headers: vec![],
+                body: None,
+                transform: Some(TransformContext::from_name(
+                    "transform_backfill_response".to_string(),
+                    vec![],
+                )),
+            };
+            let (response,) = http_request(request, OUTCALL_CYCLES)
+                .await
+                .map_err(|(code, msg)| format!("outcall failed: {:?} {}", code, msg))?;
+            if response.status != 200u16 {
+                return Err(format!("source responded with status {}", response.status));
+            }
+            serde_json::from_slice(&response.body)
+                .map_err(|e| format!("cannot parse chunk {}: {}", chunk, e))
+        }
+        BackfillSource::Canister {
+            canister_id,
+            method,
+        } => {
+            let (records,): (Vec<AirQualityUpdatePayload>,) =
+                ic_cdk::call(*canister_id, method, (offset, config.chunk_size))
+                    .await
+                    .map_err(|(code, msg)| format!("call failed: {:?} {}", code, msg))?;
+            Ok(records)
+        }
+    }
+}
+
+fn apply_chunk_result(id: u64, chunk: u64, result: Result<Vec<AirQualityUpdatePayload>, String>) {
+    let Ok(mut job) = get_job(id) else {
+        return;
+    };
+    job.updated_at = time();
+
+    match result {
+        Ok(records) => {
+            let record_count = records.len() as u64;
+            // A chunk is imported at most once, even if its response is delivered twice
+            let already_imported =
+                BACKFILL_CHUNK_STORAGE.with(|s| s.borrow().contains_key(&(id, chunk)));
+            if !already_imported {
+                // Records that cannot be stored, such as oversized ones, are skipped
+                let imported = records
+                    .into_iter()
+                    .map(|record| {
+                        _add_air_quality_data(
+                            record,
+                            ReadingSource::import(sources::BACKFILL_CONNECTOR, id),
+                        )
+                    })
+                    .filter(Result::is_ok)
+                    .count() as u64;
+                BACKFILL_CHUNK_STORAGE.with(|s| s.borrow_mut().insert((id, chunk), imported));
+                job.records_imported += imported;
+                job.completed_chunks += 1;
+            }
+
+            job.next_chunk = job.next_chunk.max(chunk + 1);
+            job.failed_attempts = 0;
+            job.last_error = None;
+
+            let finished = match job.config.total_chunks {
+                Some(total_chunks) => job.next_chunk >= total_chunks,
+                None => record_count < job.config.chunk_size as u64,
+            };
+            if finished {
+                job.state = BackfillState::Completed;
+                log!(Info, "backfill completed", "job" => id, "records" => job.records_imported);
+            }
+        }
+        Err(msg) => {
+            job.failed_attempts += 1;
+            log!(
+                Warn,
+                "backfill chunk failed",
+                "job" => id,
+                "chunk" => chunk,
+                "attempt" => job.failed_attempts,
+                "error" => msg,
+            );
+            if job.failed_attempts >= MAX_FAILED_ATTEMPTS {
+                log!(Error, "backfill gave up", "job" => id);
+                job.state = BackfillState::Failed { msg: msg.clone() };
+            }
+            job.last_error = Some(msg);
+        }
+    }
+
+    do_insert_job(&job);
+}
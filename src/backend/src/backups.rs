@@ -0,0 +1,339 @@
+use crate::schema::CURRENT_SCHEMA_VERSION;
+use crate::sharding::fnv1a;
+use crate::{ensure_admin, get_memory, not_banned, Error};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::Memory as _;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+// Bumped whenever the chunk layout changes, so a restore never misreads an older backup
+const BACKUP_FORMAT_VERSION: u32 = 1;
+const WASM_PAGE_BYTES: u64 = 65_536;
+// Leaves room in a reply, and in an ingress message on restore, for the chunk's other fields
+const CHUNK_BYTES: u64 = 1_048_576;
+const BACKUP_TTL_NANOS: u64 = 60 * 60 * 1_000_000_000;
+// The memory manager hands out ids 0 to 254
+const MEMORY_IDS: u8 = 255;
+// Update methods accepted while a backup or a restore holds writes
+const FROZEN_METHODS: &[&str] = &["finish_backup", "restore_backup"];
+
+// One virtual memory of the stable memory, by the id the stable structures were given
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct BackupMemory {
+    memory_id: u8,
+    size_bytes: u64,
+    // Chunks of a memory are consecutive, in offset order
+    first_chunk: u64,
+}
+
+// A copy of every stable structure, taken page for page. While the backup is open, writes are
+// refused so its chunks all show the same state.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct BackupManifest {
+    // Creation time; backups of one canister never share it
+    backup_id: u64,
+    format_version: u32,
+    schema_version: u32,
+    canister_id: candid::Principal,
+    created_at: u64,
+    // Chunks can be fetched until then, or until finish_backup
+    expires_at: u64,
+    chunk_bytes: u64,
+    chunk_count: u64,
+    memories: Vec<BackupMemory>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct BackupChunk {
+    backup_id: u64,
+    format_version: u32,
+    schema_version: u32,
+    chunk_index: u64,
+    chunk_count: u64,
+    memory_id: u8,
+    offset: u64,
+    // Size of the whole memory, so a restore can grow it on the first chunk it sees
+    memory_size_bytes: u64,
+    bytes: Vec<u8>,
+    // FNV-1a of `bytes`
+    checksum: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RestoreStatus {
+    backup_id: u64,
+    chunks_written: u64,
+    chunk_count: u64,
+    bytes_written: u64,
+    // All chunks are written; upgrade the canister to load the restored state
+    complete: bool,
+}
+
+// Kept on the heap, which a restore does not write: an upgrade ends an open backup, and is
+// how a restore is finished
+struct RestoreProgress {
+    status: RestoreStatus,
+    written: BTreeSet<u64>,
+}
+
+thread_local! {
+    static OPEN_BACKUP: RefCell<Option<BackupManifest>> = const { RefCell::new(None) };
+
+    static RESTORE_PROGRESS: RefCell<Option<RestoreProgress>> = const { RefCell::new(None) };
+}
+
+fn open_backup() -> Option<BackupManifest> {
+    OPEN_BACKUP.with(|b| {
+        b.borrow()
+            .clone()
+            .filter(|backup| backup.expires_at > time())
+    })
+}
+
+pub(crate) fn is_restoring() -> bool {
+    RESTORE_PROGRESS.with(|r| r.borrow().is_some())
+}
+
+// Whether writes are held for an open backup or a restore under way
+pub(crate) fn is_frozen() -> bool {
+    open_backup().is_some() || is_restoring()
+}
+
+pub(crate) fn ensure_not_frozen() -> Result<(), Error> {
+    if is_frozen() {
+        return Err(Error::Conflict {
+            msg: "writes are held while a backup is open or a restore is under way".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Whether inspect_message lets an update call from a user through while writes are held.
+// Calls from other canisters do not pass through there; new readings are refused on their own.
+pub(crate) fn accepts_update(method: &str) -> bool {
+    !is_frozen() || FROZEN_METHODS.contains(&method)
+}
+
+// Guard of restore_backup. Once a restore is under way the ban map may be overwritten, and is
+// not read until the upgrade that loads the restored state.
+fn restore_guard() -> Result<(), String> {
+    if is_restoring() {
+        return Ok(());
+    }
+    not_banned()
+}
+
+fn chunks_of(size_bytes: u64) -> u64 {
+    size_bytes.div_ceil(CHUNK_BYTES)
+}
+
+// Opens a backup of all stable memory and holds writes until finish_backup, or for an hour
+// (controllers only). Fetch its chunks with get_backup_chunk.
+#[ic_cdk::update(guard = "not_banned")]
+fn create_backup() -> Result<BackupManifest, Error> {
+    ensure_admin()?;
+    if let Some(backup) = open_backup() {
+        return Err(Error::Conflict {
+            msg: format!("backup {} is still open", backup.backup_id),
+        });
+    }
+    if is_restoring() {
+        return Err(Error::Conflict {
+            msg: "a restore is under way".to_string(),
+        });
+    }
+    let mut memories = Vec::new();
+    let mut chunk_count = 0;
+    for id in 0..MEMORY_IDS {
+        let size_bytes = get_memory(MemoryId::new(id)).size() * WASM_PAGE_BYTES;
+        if size_bytes == 0 {
+            continue;
+        }
+        memories.push(BackupMemory {
+            memory_id: id,
+            size_bytes,
+            first_chunk: chunk_count,
+        });
+        chunk_count += chunks_of(size_bytes);
+    }
+    let now = time();
+    let backup = BackupManifest {
+        backup_id: now,
+        format_version: BACKUP_FORMAT_VERSION,
+        schema_version: CURRENT_SCHEMA_VERSION,
+        canister_id: ic_cdk::id(),
+        created_at: now,
+        expires_at: now + BACKUP_TTL_NANOS,
+        chunk_bytes: CHUNK_BYTES,
+        chunk_count,
+        memories,
+    };
+    OPEN_BACKUP.with(|b| *b.borrow_mut() = Some(backup.clone()));
+    log!(
+        Info,
+        "backup opened",
+        "backup" => backup.backup_id,
+        "chunks" => backup.chunk_count,
+        "caller" => ic_cdk::caller(),
+    );
+    Ok(backup)
+}
+
+// One chunk of the open backup (controllers only)
+#[ic_cdk::query]
+fn get_backup_chunk(backup_id: u64, chunk_index: u64) -> Result<BackupChunk, Error> {
+    ensure_admin()?;
+    let backup = open_backup()
+        .filter(|backup| backup.backup_id == backup_id)
+        .ok_or(Error::NotFound {
+            msg: format!("backup {} is not open", backup_id),
+        })?;
+    let memory = backup
+        .memories
+        .iter()
+        .rev()
+        .find(|memory| memory.first_chunk <= chunk_index)
+        .filter(|_| chunk_index < backup.chunk_count)
+        .ok_or(Error::InvalidInput {
+            msg: format!("the backup has {} chunks", backup.chunk_count),
+            violations: None,
+        })?;
+    let offset = (chunk_index - memory.first_chunk) * CHUNK_BYTES;
+    let mut bytes = vec![0; CHUNK_BYTES.min(memory.size_bytes - offset) as usize];
+    get_memory(MemoryId::new(memory.memory_id)).read(offset, &mut bytes);
+    Ok(BackupChunk {
+        backup_id,
+        format_version: backup.format_version,
+        schema_version: backup.schema_version,
+        chunk_index,
+        chunk_count: backup.chunk_count,
+        memory_id: memory.memory_id,
+        offset,
+        memory_size_bytes: memory.size_bytes,
+        checksum: fnv1a(&bytes),
+        bytes,
+    })
+}
+
+// Closes the open backup and lets writes through again (controllers only)
+#[ic_cdk::update(guard = "not_banned")]
+fn finish_backup(backup_id: u64) -> Result<BackupManifest, Error> {
+    ensure_admin()?;
+    let backup = open_backup()
+        .filter(|backup| backup.backup_id == backup_id)
+        .ok_or(Error::NotFound {
+            msg: format!("backup {} is not open", backup_id),
+        })?;
+    OPEN_BACKUP.with(|b| *b.borrow_mut() = None);
+    Ok(backup)
+}
+
+fn validate_chunk(chunk: &BackupChunk) -> Result<(), Error> {
+    let invalid = |msg: String| Error::InvalidInput {
+        msg,
+        violations: None,
+    };
+    if chunk.format_version != BACKUP_FORMAT_VERSION {
+        return Err(invalid(format!(
+            "backup format {} cannot be read; this build reads format {}",
+            chunk.format_version, BACKUP_FORMAT_VERSION
+        )));
+    }
+    if chunk.schema_version > CURRENT_SCHEMA_VERSION {
+        return Err(invalid(format!(
+            "the backup has schema version {}, newer than this build's {}",
+            chunk.schema_version, CURRENT_SCHEMA_VERSION
+        )));
+    }
+    if chunk.memory_id == MEMORY_IDS
+        || !chunk.offset.is_multiple_of(CHUNK_BYTES)
+        || chunk.bytes.len() as u64 > CHUNK_BYTES
+        || chunk.offset + chunk.bytes.len() as u64 > chunk.memory_size_bytes
+        || chunk.chunk_index >= chunk.chunk_count
+    {
+        return Err(invalid(format!(
+            "chunk {} does not fit the backup layout",
+            chunk.chunk_index
+        )));
+    }
+    if fnv1a(&chunk.bytes) != chunk.checksum {
+        return Err(invalid(format!(
+            "chunk {} does not match its checksum",
+            chunk.chunk_index
+        )));
+    }
+    Ok(())
+}
+
+// Writes chunks of a backup over this canister's stable memory (controllers only). Chunks may
+// come in any order over many calls, and again. Writes are held from the first chunk on, and
+// the canister must be upgraded once `complete` so every structure is loaded from the restored
+// memory; until then it serves stale or broken reads.
+#[ic_cdk::update(guard = "restore_guard")]
+fn restore_backup(chunks: Vec<BackupChunk>) -> Result<RestoreStatus, Error> {
+    ensure_admin()?;
+    if let Some(backup) = open_backup() {
+        return Err(Error::Conflict {
+            msg: format!("backup {} is open; finish it first", backup.backup_id),
+        });
+    }
+    for chunk in &chunks {
+        validate_chunk(chunk)?;
+        let expected = RESTORE_PROGRESS.with(|r| {
+            r.borrow()
+                .as_ref()
+                .map(|progress| (progress.status.backup_id, progress.status.chunk_count))
+        });
+        if expected.is_some_and(|expected| expected != (chunk.backup_id, chunk.chunk_count)) {
+            return Err(Error::Conflict {
+                msg: format!(
+                    "chunk {} is of another backup than the restore under way",
+                    chunk.chunk_index
+                ),
+            });
+        }
+    }
+    for chunk in chunks {
+        let memory = get_memory(MemoryId::new(chunk.memory_id));
+        let pages = chunk.memory_size_bytes.div_ceil(WASM_PAGE_BYTES);
+        if memory.size() < pages && memory.grow(pages - memory.size()) < 0 {
+            return Err(Error::TooLarge {
+                msg: format!(
+                    "memory {} cannot grow to {} bytes",
+                    chunk.memory_id, chunk.memory_size_bytes
+                ),
+            });
+        }
+        memory.write(chunk.offset, &chunk.bytes);
+        RESTORE_PROGRESS.with(|r| {
+            let mut progress = r.borrow_mut();
+            let progress = progress.get_or_insert_with(|| RestoreProgress {
+                status: RestoreStatus {
+                    backup_id: chunk.backup_id,
+                    chunks_written: 0,
+                    chunk_count: chunk.chunk_count,
+                    bytes_written: 0,
+                    complete: false,
+                },
+                written: BTreeSet::new(),
+            });
+            if progress.written.insert(chunk.chunk_index) {
+                progress.status.chunks_written += 1;
+                progress.status.bytes_written += chunk.bytes.len() as u64;
+            }
+            progress.status.complete =
+                progress.status.chunks_written == progress.status.chunk_count;
+        });
+    }
+    // Nothing is logged: the log lives in stable memory the chunks may have overwritten
+    get_restore_status().ok_or(Error::InvalidInput {
+        msg: "no chunks were given".to_string(),
+        violations: None,
+    })
+}
+
+#[ic_cdk::query]
+fn get_restore_status() -> Option<RestoreStatus> {
+    RESTORE_PROGRESS.with(|r| r.borrow().as_ref().map(|progress| progress.status.clone()))
+}
@@ -0,0 +1,118 @@
+use crate::{
+    accepts_writes, ensure_admin, get_memory, principal_key, Error, Memory, PrincipalKey,
+    BAN_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_REASON_LEN: usize = 200;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Ban {
+    principal: Principal,
+    reason: String,
+    banned_by: Principal,
+    banned_at: u64,
+    // The ban lifts on its own then; None for a ban that lasts until unban_principal
+    expires_at: Option<u64>,
+}
+
+impl_bounded_storable!(Ban, 512);
+
+impl Ban {
+    fn is_active(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+thread_local! {
+    static BANS: RefCell<StableBTreeMap<PrincipalKey, Ban, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(BAN_MEMORY_ID)));
+}
+
+pub(crate) fn is_banned(principal: &Principal) -> bool {
+    BANS.with(|b| b.borrow().get(&principal_key(principal)))
+        .is_some_and(|ban| ban.is_active(time()))
+}
+
+// Refuses banned callers in update methods, for calls inspect_message does not see, such as
+// those from other canisters
+pub(crate) fn not_banned() -> Result<(), String> {
+    if is_banned(&ic_cdk::caller()) {
+        return Err("caller is banned".to_string());
+    }
+    Ok(())
+}
+
+// Refuses every update call of a principal, until `expires_at` if given (controllers only).
+// Banning a principal again replaces its ban.
+#[ic_cdk::update(guard = "accepts_writes")]
+fn ban_principal(
+    principal: Principal,
+    reason: String,
+    expires_at: Option<u64>,
+) -> Result<Ban, Error> {
+    ensure_admin()?;
+    if ic_cdk::api::is_controller(&principal) {
+        return Err(Error::InvalidInput {
+            msg: "controllers cannot be banned".to_string(),
+            violations: None,
+        });
+    }
+    if reason.len() > MAX_REASON_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!("reason must be at most {} bytes", MAX_REASON_LEN),
+            violations: None,
+        });
+    }
+    let now = time();
+    if expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(Error::InvalidInput {
+            msg: "expires_at must be in the future".to_string(),
+            violations: None,
+        });
+    }
+    let ban = Ban {
+        principal,
+        reason,
+        banned_by: ic_cdk::caller(),
+        banned_at: now,
+        expires_at,
+    };
+    BANS.with(|b| {
+        b.borrow_mut()
+            .insert(principal_key(&principal), ban.clone())
+    });
+    log!(
+        Warn,
+        "principal banned",
+        "principal" => principal,
+        "caller" => ic_cdk::caller(),
+    );
+    Ok(ban)
+}
+
+#[ic_cdk::update(guard = "accepts_writes")]
+fn unban_principal(principal: Principal) -> Result<Ban, Error> {
+    ensure_admin()?;
+    BANS.with(|b| b.borrow_mut().remove(&principal_key(&principal)))
+        .ok_or(Error::NotFound {
+            msg: format!("{} is not banned", principal),
+        })
+}
+
+// Bans in force; expired ones are left out (controllers only)
+#[ic_cdk::query]
+fn list_banned_principals() -> Result<Vec<Ban>, Error> {
+    ensure_admin()?;
+    let now = time();
+    Ok(BANS.with(|b| {
+        b.borrow()
+            .iter()
+            .map(|(_, ban)| ban)
+            .filter(|ban| ban.is_active(now))
+            .collect()
+    }))
+}
@@ -0,0 +1,168 @@
+use crate::paging::ReadingPage;
+use crate::units::ConcentrationUnit;
+use crate::{
+    accepts_writes, do_insert_air_quality, ensure_admin, get_memory, Error, Memory,
+    AIR_QUALITY_ID_COUNTER, AIR_QUALITY_STORAGE, BOOTSTRAP_STATE_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::{id, time};
+use ic_stable_structures::Cell;
+use std::cell::RefCell;
+
+// Seeding a fresh canister, such as a regional mirror or a test environment, from the public
+// readings of another deployment
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct BootstrapStatus {
+    source: Option<Principal>,
+    // Id of the last reading imported
+    cursor: Option<u64>,
+    records_imported: u64,
+    chunks_imported: u64,
+    started_at: Option<u64>,
+    completed_at: Option<u64>,
+    // Failed chunks are retried on the next tick
+    last_error: Option<String>,
+}
+
+impl_bounded_storable!(BootstrapStatus, 1024);
+
+thread_local! {
+    static BOOTSTRAP_STATE: RefCell<Cell<BootstrapStatus, Memory>> = RefCell::new(
+        Cell::init(get_memory(BOOTSTRAP_STATE_MEMORY_ID), BootstrapStatus::default())
+            .expect("Cannot create the bootstrap state cell")
+    );
+
+    static BOOTSTRAP_IN_FLIGHT: RefCell<bool> = const { RefCell::new(false) };
+}
+
+fn state() -> BootstrapStatus {
+    BOOTSTRAP_STATE.with(|s| s.borrow().get().clone())
+}
+
+fn update_state<F: FnOnce(&mut BootstrapStatus)>(f: F) -> BootstrapStatus {
+    let mut state = state();
+    f(&mut state);
+    BOOTSTRAP_STATE
+        .with(|s| s.borrow_mut().set(state.clone()))
+        .expect("cannot store the bootstrap state");
+    state
+}
+
+fn is_seeding(state: &BootstrapStatus) -> bool {
+    state.source.is_some() && state.completed_at.is_none()
+}
+
+// New readings would take ids the snapshot may still bring, so they wait for seeding to finish
+pub(crate) fn ensure_not_seeding() -> Result<(), Error> {
+    if is_seeding(&state()) {
+        return Err(Error::Conflict {
+            msg: "the canister is being seeded from a snapshot; try again when it is done"
+                .to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Stores a chunk under the source's ids and keeps new ids above them
+fn import(page: ReadingPage) {
+    for data in &page.readings {
+        do_insert_air_quality(data);
+    }
+    if let Some(last) = page.readings.last() {
+        AIR_QUALITY_ID_COUNTER.with(|counter| {
+            let next = (*counter.borrow().get()).max(last.id + 1);
+            counter
+                .borrow_mut()
+                .set(next)
+                .expect("cannot store the id counter");
+        });
+    }
+    let state = update_state(|state| {
+        state.records_imported += page.readings.len() as u64;
+        state.chunks_imported += 1;
+        state.cursor = page.readings.last().map(|data| data.id).or(state.cursor);
+        state.last_error = None;
+        if !page.truncated {
+            state.completed_at = Some(time());
+        }
+    });
+    if state.completed_at.is_some() {
+        log!(
+            Info,
+            "bootstrap completed",
+            "source" => state.source.map(|source| source.to_text()).unwrap_or_default(),
+            "records" => state.records_imported,
+        );
+    }
+}
+
+// Pulls the next chunk of the source's export, one chunk in flight at a time
+pub(crate) fn on_timer() {
+    let state = state();
+    let Some(source) = state.source.filter(|_| is_seeding(&state)) else {
+        return;
+    };
+    if BOOTSTRAP_IN_FLIGHT.with(|f| *f.borrow()) {
+        return;
+    }
+    BOOTSTRAP_IN_FLIGHT.with(|f| *f.borrow_mut() = true);
+    ic_cdk::spawn(async move {
+        let result: Result<(Result<ReadingPage, Error>,), _> = ic_cdk::call(
+            source,
+            "get_all_air_quality_data",
+            (None::<ConcentrationUnit>, state.cursor),
+        )
+        .await;
+        match result {
+            Ok((Ok(page),)) => import(page),
+            Ok((Err(_),)) => {
+                log!(Error, "snapshot source rejected a chunk request", "cursor" => format!("{:?}", state.cursor));
+                update_state(|state| {
+                    state.last_error = Some("the source rejected the chunk request".to_string())
+                });
+            }
+            Err((code, msg)) => {
+                log!(Warn, "snapshot source call failed", "code" => format!("{:?}", code), "error" => msg);
+                update_state(|state| state.last_error = Some(format!("{:?} {}", code, msg)));
+            }
+        }
+        BOOTSTRAP_IN_FLIGHT.with(|f| *f.borrow_mut() = false);
+    });
+}
+
+// Seeds this canister with the public readings of `source`, another deployment of this
+// canister, a chunk per tick (controllers only). Only an empty canister can be seeded, and it
+// takes no new readings until seeding is done; follow progress with get_bootstrap_status.
+#[ic_cdk::update(guard = "accepts_writes")]
+fn bootstrap_from_snapshot(source: Principal) -> Result<BootstrapStatus, Error> {
+    ensure_admin()?;
+    if source == id() {
+        return Err(Error::InvalidInput {
+            msg: "a canister cannot be seeded from itself".to_string(),
+            violations: None,
+        });
+    }
+    let state = state();
+    if is_seeding(&state) {
+        return Err(Error::Conflict {
+            msg: "the canister is already being seeded".to_string(),
+        });
+    }
+    if !AIR_QUALITY_STORAGE.with(|service| service.borrow().is_empty()) {
+        return Err(Error::Conflict {
+            msg: "only a canister without readings can be seeded".to_string(),
+        });
+    }
+    Ok(update_state(|state| {
+        *state = BootstrapStatus {
+            source: Some(source),
+            started_at: Some(time()),
+            ..BootstrapStatus::default()
+        }
+    }))
+}
+
+#[ic_cdk::query]
+fn get_bootstrap_status() -> BootstrapStatus {
+    state()
+}
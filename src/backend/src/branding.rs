@@ -0,0 +1,121 @@
+use crate::aliases;
+use crate::http::{error, json, HttpGatewayResponse};
+use crate::{
+    accepts_writes, get_memory, principal_key, stations, Error, Memory, PrincipalKey,
+    BRANDING_STORAGE_MEMORY_ID,
+};
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_NAME_LEN: usize = 100;
+const MAX_LOGO_URL_LEN: usize = 512;
+const MAX_ATTRIBUTION_LEN: usize = 500;
+
+// How an organization's stations are presented in HTTP and JSON outputs, for white-labeled
+// deployments of the shared canister
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Branding {
+    name: String,
+    logo_url: Option<String>,
+    // e.g. "Data: City of Pune Environment Department"
+    attribution: Option<String>,
+    updated_at: u64,
+}
+
+impl_bounded_storable!(Branding, 2048);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct BrandingPayload {
+    name: String,
+    logo_url: Option<String>,
+    attribution: Option<String>,
+}
+
+thread_local! {
+    // Keyed by the principal owning the stations
+    static BRANDING_STORAGE: RefCell<StableBTreeMap<PrincipalKey, Branding, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(BRANDING_STORAGE_MEMORY_ID)));
+}
+
+fn validate_payload(payload: &BrandingPayload) -> Result<(), Error> {
+    if payload.name.trim().is_empty() || payload.name.len() > MAX_NAME_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!("name must be 1 to {} bytes", MAX_NAME_LEN),
+            violations: None,
+        });
+    }
+    if let Some(logo_url) = &payload.logo_url {
+        if !logo_url.starts_with("https://") || logo_url.len() > MAX_LOGO_URL_LEN {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "logo_url must be an https:// URL of at most {} bytes",
+                    MAX_LOGO_URL_LEN
+                ),
+                violations: None,
+            });
+        }
+    }
+    if payload
+        .attribution
+        .as_ref()
+        .is_some_and(|attribution| attribution.len() > MAX_ATTRIBUTION_LEN)
+    {
+        return Err(Error::InvalidInput {
+            msg: format!("attribution must be at most {} bytes", MAX_ATTRIBUTION_LEN),
+            violations: None,
+        });
+    }
+    Ok(())
+}
+
+// Branding of the owner of a station the caller may see
+pub(crate) fn of_station(station_id: &str) -> Option<Branding> {
+    if !stations::is_station_visible(station_id) {
+        return None;
+    }
+    let owner = stations::get_station(station_id)?.owner();
+    BRANDING_STORAGE.with(|s| s.borrow().get(&principal_key(&owner)))
+}
+
+// GET /stations/{station_id}/branding.json
+pub(crate) fn handle_station(station_id: &str) -> HttpGatewayResponse {
+    match of_station(station_id) {
+        Some(branding) => json(&branding),
+        None => error(404, "no branding for this station"),
+    }
+}
+
+// Sets the branding shown with the caller's stations
+#[ic_cdk::update(guard = "accepts_writes")]
+fn set_branding(payload: BrandingPayload) -> Result<Branding, Error> {
+    validate_payload(&payload)?;
+    let branding = Branding {
+        name: payload.name,
+        logo_url: payload.logo_url,
+        attribution: payload.attribution,
+        updated_at: time(),
+    };
+    BRANDING_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert(principal_key(&ic_cdk::caller()), branding.clone())
+    });
+    Ok(branding)
+}
+
+#[ic_cdk::update(guard = "accepts_writes")]
+fn clear_branding() -> Result<Branding, Error> {
+    BRANDING_STORAGE
+        .with(|s| s.borrow_mut().remove(&principal_key(&ic_cdk::caller())))
+        .ok_or(Error::NotFound {
+            msg: "the caller has no branding".to_string(),
+        })
+}
+
+#[ic_cdk::query]
+fn get_station_branding(station_id: String) -> Result<Branding, Error> {
+    let station_id = aliases::station_id(station_id);
+    of_station(&station_id).ok_or(Error::NotFound {
+        msg: format!("no branding for station {}", station_id),
+    })
+}
@@ -0,0 +1,155 @@
+use crate::{
+    accepts_writes, get_memory, next_id, stations, Error, IdCell, Memory, Pollutant, StringKey,
+    CALIBRATION_ID_COUNTER_MEMORY_ID, CALIBRATION_STORAGE_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Bound;
+
+const MAX_POLLUTANT_NAME_LEN: usize = 64;
+
+// Linear correction of one pollutant of a sensor, in the pollutant's unit of record:
+// corrected = slope × raw + offset
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Calibration {
+    id: u64,
+    // Station the sensor reports as
+    sensor_id: String,
+    pollutant: Pollutant,
+    slope: f64,
+    offset: f64,
+    // Applies to readings observed from then on, until a later calibration takes over
+    valid_from: u64,
+    created_by: Principal,
+    created_at: u64,
+}
+
+impl_bounded_storable!(Calibration, 512);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CalibrationPayload {
+    sensor_id: String,
+    pollutant: Pollutant,
+    slope: f64,
+    offset: f64,
+    valid_from: u64,
+}
+
+type CalibrationKey = (StringKey, u64);
+
+thread_local! {
+    static CALIBRATION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(get_memory(CALIBRATION_ID_COUNTER_MEMORY_ID), 0)
+            .expect("Cannot create a counter for calibrations")
+    );
+
+    static CALIBRATION_STORAGE: RefCell<StableBTreeMap<CalibrationKey, Calibration, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CALIBRATION_STORAGE_MEMORY_ID)));
+}
+
+fn calibrations_of(sensor_id: &str) -> Vec<Calibration> {
+    let start = (StringKey(sensor_id.to_string()), 0);
+    let end = (StringKey(sensor_id.to_string()), u64::MAX);
+    CALIBRATION_STORAGE.with(|s| {
+        s.borrow()
+            .range((Bound::Included(start), Bound::Included(end)))
+            .map(|(_, calibration)| calibration)
+            .collect()
+    })
+}
+
+// Corrects levels with the calibrations of the sensor active at `observed_at`. Pollutants
+// without one keep their level; corrections never go below zero.
+pub(crate) fn calibrate(
+    sensor_id: Option<&str>,
+    levels: &HashMap<Pollutant, f64>,
+    observed_at: u64,
+) -> Result<HashMap<Pollutant, f64>, Error> {
+    let Some(sensor_id) = sensor_id else {
+        return Err(Error::InvalidInput {
+            msg: "calibrated readings need a station_id".to_string(),
+            violations: None,
+        });
+    };
+    let calibrations = calibrations_of(sensor_id);
+    Ok(levels
+        .iter()
+        .map(|(pollutant, raw)| {
+            let active = calibrations
+                .iter()
+                .filter(|calibration| {
+                    calibration.pollutant == *pollutant && calibration.valid_from <= observed_at
+                })
+                .max_by_key(|calibration| (calibration.valid_from, calibration.id));
+            let level = active.map_or(*raw, |calibration| {
+                (calibration.slope * raw + calibration.offset).max(0.0)
+            });
+            (pollutant.clone(), level)
+        })
+        .collect())
+}
+
+// Adds a calibration for a sensor (its owner and controllers only). Earlier calibrations are
+// kept, so readings before `valid_from` keep using them.
+#[ic_cdk::update(guard = "accepts_writes")]
+fn add_calibration(payload: CalibrationPayload) -> Result<Calibration, Error> {
+    let station = stations::get_station(&payload.sensor_id).ok_or(Error::NotFound {
+        msg: format!("station {} not found", payload.sensor_id),
+    })?;
+    let caller = ic_cdk::caller();
+    if !station.is_managed_by(&caller) {
+        return Err(Error::Unauthorized {
+            msg: "only the station owner or a controller can calibrate it".to_string(),
+        });
+    }
+    let pollutant = payload.pollutant.normalized();
+    if pollutant.name().is_empty() || pollutant.name().len() > MAX_POLLUTANT_NAME_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "pollutant names must be 1 to {} bytes",
+                MAX_POLLUTANT_NAME_LEN
+            ),
+            violations: None,
+        });
+    }
+    if !payload.slope.is_finite() || payload.slope <= 0.0 || !payload.offset.is_finite() {
+        return Err(Error::InvalidInput {
+            msg: "slope must be positive and offset finite".to_string(),
+            violations: None,
+        });
+    }
+    let calibration = Calibration {
+        id: next_id(&CALIBRATION_ID_COUNTER),
+        sensor_id: payload.sensor_id,
+        pollutant,
+        slope: payload.slope,
+        offset: payload.offset,
+        valid_from: payload.valid_from,
+        created_by: caller,
+        created_at: time(),
+    };
+    CALIBRATION_STORAGE.with(|s| {
+        s.borrow_mut().insert(
+            (StringKey(calibration.sensor_id.clone()), calibration.id),
+            calibration.clone(),
+        )
+    });
+    Ok(calibration)
+}
+
+continued_page!(CalibrationPage, Calibration);
+
+// Every calibration of a sensor, oldest first
+#[ic_cdk::query]
+fn list_calibrations(
+    sensor_id: String,
+    continuation: Option<String>,
+) -> Result<CalibrationPage, Error> {
+    if !stations::is_station_visible(&sensor_id) {
+        return CalibrationPage::new(Vec::new(), continuation);
+    }
+    CalibrationPage::new(calibrations_of(&sensor_id), continuation)
+}
@@ -0,0 +1,204 @@
+use crate::events::{self, EventKind};
+use crate::{accepts_writes, ensure_admin, get_memory, Error, Memory, CAPACITY_SETTINGS_MEMORY_ID};
+use ic_cdk::api::stable::stable64_size;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Cell, Memory as _};
+use std::cell::RefCell;
+
+const WASM_PAGE_BYTES: u64 = 65_536;
+// The memory manager hands out ids 0 to 254
+const MEMORY_IDS: u8 = 255;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, PartialOrd, Debug)]
+pub(crate) enum CapacityLevel {
+    Normal,
+    // At or over `warning_percent` of the ceiling
+    Warning,
+    // At or over `refuse_percent`: non-essential writes are refused
+    Critical,
+    // At the ceiling: new readings are refused too
+    Full,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct CapacitySettings {
+    // Stable memory the canister may use, in 64 KiB pages
+    ceiling_pages: u64,
+    warning_percent: u8,
+    refuse_percent: u8,
+}
+
+impl Default for CapacitySettings {
+    fn default() -> Self {
+        // 400 GiB, short of the 500 GiB the replica allows a canister
+        CapacitySettings {
+            ceiling_pages: 400 * 16_384,
+            warning_percent: 80,
+            refuse_percent: 90,
+        }
+    }
+}
+
+impl_bounded_storable!(CapacitySettings, 128);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct MemoryUsage {
+    memory_id: u8,
+    pages: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct StableMemoryUsage {
+    // All stable memory, the memory manager's own bookkeeping included
+    used_pages: u64,
+    used_bytes: u64,
+    used_percent: f64,
+    level: CapacityLevel,
+    settings: CapacitySettings,
+    // Pages of each stable structure's memory, largest first; empty in get_status
+    memories: Vec<MemoryUsage>,
+}
+
+thread_local! {
+    static CAPACITY_SETTINGS: RefCell<Cell<CapacitySettings, Memory>> = RefCell::new(
+        Cell::init(get_memory(CAPACITY_SETTINGS_MEMORY_ID), CapacitySettings::default())
+            .expect("Cannot create the capacity settings cell")
+    );
+
+    // Level last raised as an event. Kept on the heap: after an upgrade the current level is
+    // raised again if it is not normal.
+    static LAST_LEVEL: RefCell<CapacityLevel> = const { RefCell::new(CapacityLevel::Normal) };
+}
+
+fn settings() -> CapacitySettings {
+    CAPACITY_SETTINGS.with(|s| s.borrow().get().clone())
+}
+
+fn used_percent(used_pages: u64, settings: &CapacitySettings) -> f64 {
+    used_pages as f64 * 100.0 / settings.ceiling_pages as f64
+}
+
+fn level_of(used_percent: f64, settings: &CapacitySettings) -> CapacityLevel {
+    if used_percent >= 100.0 {
+        CapacityLevel::Full
+    } else if used_percent >= settings.refuse_percent as f64 {
+        CapacityLevel::Critical
+    } else if used_percent >= settings.warning_percent as f64 {
+        CapacityLevel::Warning
+    } else {
+        CapacityLevel::Normal
+    }
+}
+
+fn level() -> CapacityLevel {
+    let settings = settings();
+    level_of(used_percent(stable64_size(), &settings), &settings)
+}
+
+// For writes the service can do without while stable memory runs short: new exports,
+// subscriptions, consumers, backfills and sensor imports
+pub(crate) fn ensure_room_for_optional_write() -> Result<(), Error> {
+    if level() >= CapacityLevel::Critical {
+        return Err(Error::TooLarge {
+            msg: format!(
+                "stable memory is at or over {}% of its ceiling; only essential writes are accepted",
+                settings().refuse_percent
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Readings are refused only once the ceiling is reached, before an insert can trap
+pub(crate) fn ensure_room_for_reading() -> Result<(), Error> {
+    if level() == CapacityLevel::Full {
+        return Err(Error::TooLarge {
+            msg: "stable memory has reached its ceiling; no readings can be stored".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Raises an event and a log entry whenever the level changes
+pub(crate) fn on_timer() {
+    let settings = settings();
+    let used_pages = stable64_size();
+    let used_percent = used_percent(used_pages, &settings);
+    let level = level_of(used_percent, &settings);
+    if LAST_LEVEL.with(|l| *l.borrow()) == level {
+        return;
+    }
+    LAST_LEVEL.with(|l| *l.borrow_mut() = level);
+    if level == CapacityLevel::Normal {
+        log!(Info, "stable memory usage is back to normal", "used_percent" => used_percent);
+    } else {
+        log!(
+            Error,
+            "stable memory usage crossed a capacity threshold",
+            "level" => format!("{:?}", level),
+            "used_pages" => used_pages,
+            "ceiling_pages" => settings.ceiling_pages,
+        );
+    }
+    events::emit(EventKind::StableMemoryCapacity {
+        level,
+        used_pages,
+        ceiling_pages: settings.ceiling_pages,
+    });
+}
+
+pub(crate) fn usage(with_memories: bool) -> StableMemoryUsage {
+    let settings = settings();
+    let used_pages = stable64_size();
+    let mut memories: Vec<MemoryUsage> = if with_memories {
+        (0..MEMORY_IDS)
+            .map(|memory_id| MemoryUsage {
+                memory_id,
+                pages: get_memory(MemoryId::new(memory_id)).size(),
+            })
+            .filter(|memory| memory.pages > 0)
+            .collect()
+    } else {
+        Vec::new()
+    };
+    memories.sort_by_key(|memory| std::cmp::Reverse(memory.pages));
+    let used_percent = used_percent(used_pages, &settings);
+    StableMemoryUsage {
+        used_pages,
+        used_bytes: used_pages * WASM_PAGE_BYTES,
+        used_percent,
+        level: level_of(used_percent, &settings),
+        settings,
+        memories,
+    }
+}
+
+#[ic_cdk::query]
+fn get_stable_memory_usage() -> StableMemoryUsage {
+    usage(true)
+}
+
+// Sets the ceiling and thresholds stable memory usage is measured against (controllers only)
+#[ic_cdk::update(guard = "accepts_writes")]
+fn set_capacity_settings(settings: CapacitySettings) -> Result<CapacitySettings, Error> {
+    ensure_admin()?;
+    if settings.ceiling_pages == 0 {
+        return Err(Error::InvalidInput {
+            msg: "ceiling_pages must be at least 1".to_string(),
+            violations: None,
+        });
+    }
+    if settings.warning_percent == 0
+        || settings.warning_percent > settings.refuse_percent
+        || settings.refuse_percent > 100
+    {
+        return Err(Error::InvalidInput {
+            msg: "thresholds must satisfy 0 < warning_percent <= refuse_percent <= 100".to_string(),
+            violations: None,
+        });
+    }
+    CAPACITY_SETTINGS
+        .with(|s| s.borrow_mut().set(settings.clone()))
+        .expect("cannot store the capacity settings");
+    Ok(settings)
+}
@@ -0,0 +1,82 @@
+use crate::paging::{self, ReadingPage};
+use crate::standards::AqiCategory;
+use crate::units::{self, ConcentrationUnit};
+use crate::{
+    clear_stable_map, get_memory, is_visible_to_caller, AirQualityData, Memory,
+    AIR_QUALITY_STORAGE, CATEGORY_INDEX_MEMORY_ID,
+};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::ops::Bound;
+
+type CategoryKey = (u8, u64);
+
+thread_local! {
+    // (US EPA category of the AQI, id) of every live reading. Entries of readings removed
+    // without a write are skipped by lookups.
+    static CATEGORY_INDEX: RefCell<StableBTreeMap<CategoryKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CATEGORY_INDEX_MEMORY_ID)));
+}
+
+pub(crate) fn reset() {
+    CATEGORY_INDEX.with(|s| clear_stable_map(s, CATEGORY_INDEX_MEMORY_ID));
+}
+
+fn ordinal(category: AqiCategory) -> u8 {
+    AqiCategory::ALL
+        .iter()
+        .position(|c| *c == category)
+        .expect("every category is listed") as u8
+}
+
+fn index_key(data: &AirQualityData) -> Option<CategoryKey> {
+    (!data.is_deleted()).then(|| {
+        (
+            ordinal(AqiCategory::of(data.air_quality_index as f64)),
+            data.id,
+        )
+    })
+}
+
+// Keeps the index in step with a write; `previous` is the record it replaced
+pub(crate) fn on_write(previous: Option<&AirQualityData>, data: &AirQualityData) {
+    CATEGORY_INDEX.with(|s| {
+        let mut index = s.borrow_mut();
+        if let Some(key) = previous.and_then(index_key) {
+            index.remove(&key);
+        }
+        if let Some(key) = index_key(data) {
+            index.insert(key, ());
+        }
+    });
+}
+
+// Adds a reading stored before the index existed
+pub(crate) fn backfill(data: &AirQualityData) {
+    on_write(None, data);
+}
+
+// Live readings the caller may see whose AQI is in the category, for example every Hazardous
+// reading, in id order and in pages
+#[ic_cdk::query]
+fn get_readings_by_category(
+    category: AqiCategory,
+    unit: Option<ConcentrationUnit>,
+    cursor: Option<u64>,
+) -> ReadingPage {
+    let category = ordinal(category);
+    let start = cursor.map_or(Bound::Included((category, 0)), |id| {
+        Bound::Excluded((category, id))
+    });
+    CATEGORY_INDEX.with(|s| {
+        paging::reading_page(
+            s.borrow()
+                .range((start, Bound::Included((category, u64::MAX))))
+                .filter_map(|((_, id), _)| {
+                    AIR_QUALITY_STORAGE.with(|service| service.borrow().get(&id))
+                })
+                .filter(|data| !data.is_deleted() && is_visible_to_caller(data))
+                .map(|data| units::in_unit(data, unit)),
+        )
+    })
+}
@@ -0,0 +1,103 @@
+use crate::schema::CURRENT_SCHEMA_VERSION;
+use crate::{
+    accepts_writes, ensure_admin, get_memory, next_id, Error, IdCell, Memory,
+    CHANGELOG_ID_COUNTER_MEMORY_ID, CHANGELOG_STORAGE_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::ops::Bound;
+
+const MAX_VERSION_LEN: usize = 64;
+const MAX_SUMMARY_LEN: usize = 2000;
+
+// A functional change shipped by an upgrade, recorded by the maintainers
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ChangelogEntry {
+    id: u64,
+    // Release the upgrade installed, e.g. "1.4.0"
+    version: String,
+    summary: String,
+    // Existing calls may fail or decode differently
+    breaking_interface: bool,
+    // Existing calls keep their types but return different results
+    breaking_behavior: bool,
+    // Layout version of stored readings when the entry was recorded
+    schema_version: u32,
+    created_by: Principal,
+    created_at: u64,
+}
+
+impl_bounded_storable!(ChangelogEntry, 4096);
+
+thread_local! {
+    static CHANGELOG_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(get_memory(CHANGELOG_ID_COUNTER_MEMORY_ID), 0)
+            .expect("Cannot create a counter for changelog entries")
+    );
+
+    static CHANGELOG_STORAGE: RefCell<StableBTreeMap<u64, ChangelogEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CHANGELOG_STORAGE_MEMORY_ID)));
+}
+
+// Records the changes of a release, usually right after upgrading to it (controllers only)
+#[ic_cdk::update(guard = "accepts_writes")]
+fn add_changelog_entry(
+    version: String,
+    summary: String,
+    breaking_interface: bool,
+    breaking_behavior: bool,
+) -> Result<ChangelogEntry, Error> {
+    ensure_admin()?;
+    if version.is_empty()
+        || version.len() > MAX_VERSION_LEN
+        || summary.is_empty()
+        || summary.len() > MAX_SUMMARY_LEN
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "version must be 1 to {} bytes and summary 1 to {} bytes",
+                MAX_VERSION_LEN, MAX_SUMMARY_LEN
+            ),
+            violations: None,
+        });
+    }
+    let exists =
+        CHANGELOG_STORAGE.with(|s| s.borrow().iter().any(|(_, entry)| entry.version == version));
+    if exists {
+        return Err(Error::Conflict {
+            msg: format!("changelog already has an entry for version {}", version),
+        });
+    }
+
+    let entry = ChangelogEntry {
+        id: next_id(&CHANGELOG_ID_COUNTER),
+        version,
+        summary,
+        breaking_interface,
+        breaking_behavior,
+        schema_version: CURRENT_SCHEMA_VERSION,
+        created_by: ic_cdk::caller(),
+        created_at: time(),
+    };
+    CHANGELOG_STORAGE.with(|s| s.borrow_mut().insert(entry.id, entry.clone()));
+    Ok(entry)
+}
+
+continued_page!(ChangelogPage, ChangelogEntry);
+
+// Entries in the order they were recorded, after entry `after` if given. Integrators can keep
+// the id of the last entry they saw and poll for newer ones.
+#[ic_cdk::query]
+fn get_changelog(after: Option<u64>, continuation: Option<String>) -> Result<ChangelogPage, Error> {
+    let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+    CHANGELOG_STORAGE.with(|s| {
+        ChangelogPage::new(
+            s.borrow()
+                .range((start, Bound::Unbounded))
+                .map(|(_, entry)| entry),
+            continuation,
+        )
+    })
+}
@@ -0,0 +1,108 @@
+use crate::{
+    clear_stable_map, get_memory, next_id, AirQualityData, IdCell, Memory, CHANGE_LOG_MEMORY_ID,
+    CHANGE_SEQ_COUNTER_MEMORY_ID,
+};
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::ops::Bound;
+
+// Oldest changes are dropped beyond this many; a replica further behind must resync from a dump
+const MAX_RETAINED_CHANGES: u64 = 1_000_000;
+const MAX_CHANGES_PER_PAGE: u32 = 1_000;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub(crate) enum ChangeKind {
+    Created,
+    Updated,
+    // Tombstoned; a restore is an update
+    Deleted,
+    // Taken out of storage by retention or a location deletion
+    Removed,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Change {
+    seq: u64,
+    kind: ChangeKind,
+    record_id: u64,
+    // Version of the record after the change; None for removals
+    version: Option<u64>,
+    timestamp: u64,
+}
+
+impl_bounded_storable!(Change, 128);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ChangePage {
+    changes: Vec<Change>,
+    // Pass as `since_seq` to continue after this page
+    next_seq: u64,
+    // Earliest change still retained; a replica that needs older ones must resync
+    oldest_seq: Option<u64>,
+}
+
+thread_local! {
+    static CHANGE_SEQ_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(get_memory(CHANGE_SEQ_COUNTER_MEMORY_ID), 0)
+            .expect("Cannot create a counter for changes")
+    );
+
+    static CHANGE_LOG: RefCell<StableBTreeMap<u64, Change, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CHANGE_LOG_MEMORY_ID)));
+}
+
+pub(crate) fn reset() {
+    CHANGE_LOG.with(|s| clear_stable_map(s, CHANGE_LOG_MEMORY_ID));
+}
+
+fn append(kind: ChangeKind, record_id: u64, version: Option<u64>) {
+    let seq = next_id(&CHANGE_SEQ_COUNTER);
+    let change = Change {
+        seq,
+        kind,
+        record_id,
+        version,
+        timestamp: time(),
+    };
+    CHANGE_LOG.with(|s| {
+        let mut storage = s.borrow_mut();
+        storage.insert(seq, change);
+        if let Some(expired) = seq.checked_sub(MAX_RETAINED_CHANGES) {
+            storage.remove(&expired);
+        }
+    });
+}
+
+// Writes that keep the version, such as index backfills, are not changes
+pub(crate) fn on_write(previous: Option<&AirQualityData>, current: &AirQualityData) {
+    let kind = match previous {
+        None => ChangeKind::Created,
+        Some(previous) if previous.version == current.version => return,
+        Some(previous) if !previous.is_deleted() && current.is_deleted() => ChangeKind::Deleted,
+        Some(_) => ChangeKind::Updated,
+    };
+    append(kind, current.id, Some(current.version));
+}
+
+pub(crate) fn on_remove(id: u64) {
+    append(ChangeKind::Removed, id, None);
+}
+
+// Changes with sequence numbers of at least `since_seq`, oldest first, up to 1,000 per call
+#[ic_cdk::query]
+fn get_changes(since_seq: u64, limit: u32) -> ChangePage {
+    CHANGE_LOG.with(|s| {
+        let storage = s.borrow();
+        let changes: Vec<Change> = storage
+            .range((Bound::Included(since_seq), Bound::Unbounded))
+            .take(limit.min(MAX_CHANGES_PER_PAGE) as usize)
+            .map(|(_, change)| change)
+            .collect();
+        ChangePage {
+            next_seq: changes.last().map_or(since_seq, |change| change.seq + 1),
+            oldest_seq: storage.first_key_value().map(|(seq, _)| seq),
+            changes,
+        }
+    })
+}
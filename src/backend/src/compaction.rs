@@ -0,0 +1,266 @@
+use crate::aggregates::level_in_micrograms;
+use crate::aliases;
+use crate::paging::{self, ReplyBudget};
+use crate::{
+    clear_stable_map, get_memory, organizations, AirQualityData, Error, Memory, Pollutant,
+    StringKey, DAILY_AGGREGATE_MEMORY_ID, ORGANIZATION_DAILY_AGGREGATE_MEMORY_ID,
+};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Longer locations do not fit an aggregate key; their readings are removed without one
+const MAX_LOCATION_LEN: usize = 200;
+// An aggregate keeps at most this many pollutants, with names of up to 64 bytes, so it stays
+// within its bound; others are left out
+const MAX_POLLUTANTS: usize = 32;
+const MAX_POLLUTANT_NAME_LEN: usize = 64;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct PollutantStats {
+    // µg/m³
+    sum: f64,
+    min: f64,
+    max: f64,
+    count: u64,
+}
+
+// What is left of the compacted readings of one location and UTC day
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct DailyAggregate {
+    readings: u64,
+    air_quality_index_sum: f64,
+    min_air_quality_index: u32,
+    max_air_quality_index: u32,
+    // Keyed by `Pollutant::name`
+    pollutants: BTreeMap<String, PollutantStats>,
+}
+
+impl_bounded_storable!(DailyAggregate, 4096);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct PollutantDailyStats {
+    pollutant: Pollutant,
+    mean: f64,
+    min: f64,
+    max: f64,
+    readings: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct DailyAggregateView {
+    day_start: u64,
+    readings: u64,
+    average_air_quality_index: f64,
+    min_air_quality_index: u32,
+    max_air_quality_index: u32,
+    pollutants: Vec<PollutantDailyStats>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct DailyAggregatePage {
+    days: Vec<DailyAggregateView>,
+    // More days are in the period; pass `next_cursor` to fetch them
+    truncated: bool,
+    next_cursor: Option<u64>,
+}
+
+// (location, day start)
+type DayKey = (StringKey, u64);
+
+thread_local! {
+    // Readings without an organization
+    static DAILY_AGGREGATE_STORAGE: RefCell<StableBTreeMap<DayKey, DailyAggregate, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DAILY_AGGREGATE_MEMORY_ID)));
+
+    // Readings of organizations, keyed by (day key, organization id), so queries only count
+    // the organizations the caller may see
+    static ORGANIZATION_DAILY_AGGREGATE_STORAGE: RefCell<StableBTreeMap<(DayKey, u64), DailyAggregate, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ORGANIZATION_DAILY_AGGREGATE_MEMORY_ID)));
+}
+
+pub(crate) fn reset() {
+    DAILY_AGGREGATE_STORAGE.with(|s| clear_stable_map(s, DAILY_AGGREGATE_MEMORY_ID));
+    ORGANIZATION_DAILY_AGGREGATE_STORAGE
+        .with(|s| clear_stable_map(s, ORGANIZATION_DAILY_AGGREGATE_MEMORY_ID));
+}
+
+impl DailyAggregate {
+    fn add(&mut self, data: &AirQualityData) {
+        let index = data.air_quality_index;
+        if self.readings == 0 {
+            self.min_air_quality_index = index;
+            self.max_air_quality_index = index;
+        }
+        self.readings += 1;
+        self.air_quality_index_sum += index as f64;
+        self.min_air_quality_index = self.min_air_quality_index.min(index);
+        self.max_air_quality_index = self.max_air_quality_index.max(index);
+        for pollutant in data.pollutant_levels.keys() {
+            let Some(level) = level_in_micrograms(data, pollutant) else {
+                continue;
+            };
+            let name = pollutant.name();
+            if name.len() > MAX_POLLUTANT_NAME_LEN
+                || (self.pollutants.len() >= MAX_POLLUTANTS && !self.pollutants.contains_key(name))
+            {
+                continue;
+            }
+            let stats = self
+                .pollutants
+                .entry(name.to_string())
+                .or_insert(PollutantStats {
+                    sum: 0.0,
+                    min: level,
+                    max: level,
+                    count: 0,
+                });
+            stats.sum += level;
+            stats.min = stats.min.min(level);
+            stats.max = stats.max.max(level);
+            stats.count += 1;
+        }
+    }
+
+    // Adds the statistics of another aggregate of the same day
+    fn merge(&mut self, other: &DailyAggregate) {
+        if self.readings == 0 {
+            *self = other.clone();
+            return;
+        }
+        self.readings += other.readings;
+        self.air_quality_index_sum += other.air_quality_index_sum;
+        self.min_air_quality_index = self.min_air_quality_index.min(other.min_air_quality_index);
+        self.max_air_quality_index = self.max_air_quality_index.max(other.max_air_quality_index);
+        for (name, other) in &other.pollutants {
+            let stats = self
+                .pollutants
+                .entry(name.clone())
+                .or_insert(PollutantStats {
+                    sum: 0.0,
+                    min: other.min,
+                    max: other.max,
+                    count: 0,
+                });
+            stats.sum += other.sum;
+            stats.min = stats.min.min(other.min);
+            stats.max = stats.max.max(other.max);
+            stats.count += other.count;
+        }
+    }
+
+    fn view(&self, day_start: u64) -> DailyAggregateView {
+        DailyAggregateView {
+            day_start,
+            readings: self.readings,
+            average_air_quality_index: self.air_quality_index_sum / self.readings as f64,
+            min_air_quality_index: self.min_air_quality_index,
+            max_air_quality_index: self.max_air_quality_index,
+            pollutants: self
+                .pollutants
+                .iter()
+                .map(|(name, stats)| PollutantDailyStats {
+                    pollutant: Pollutant::parse(name),
+                    mean: stats.sum / stats.count as f64,
+                    min: stats.min,
+                    max: stats.max,
+                    readings: stats.count,
+                })
+                .collect(),
+        }
+    }
+}
+
+// Folds a reading into the aggregate of its day, and of its organization, before retention
+// removes it. Tombstoned and unpublished readings leave nothing behind.
+pub(crate) fn compact(data: &AirQualityData) {
+    if data.is_deleted() || !data.is_published() || data.location.len() > MAX_LOCATION_LEN {
+        return;
+    }
+    let key = (
+        StringKey(data.location.clone()),
+        data.timestamp - data.timestamp % NANOS_PER_DAY,
+    );
+    match data.organization_id {
+        Some(organization_id) => ORGANIZATION_DAILY_AGGREGATE_STORAGE.with(|s| {
+            let mut storage = s.borrow_mut();
+            let key = (key, organization_id);
+            let mut aggregate = storage.get(&key).unwrap_or_default();
+            aggregate.add(data);
+            storage.insert(key, aggregate);
+        }),
+        None => DAILY_AGGREGATE_STORAGE.with(|s| {
+            let mut storage = s.borrow_mut();
+            let mut aggregate = storage.get(&key).unwrap_or_default();
+            aggregate.add(data);
+            storage.insert(key, aggregate);
+        }),
+    }
+}
+
+// The aggregates of the days of a location from `first_day`, or after the day `cursor`, up to
+// `end_timestamp`: the shared ones with those of the organizations the caller may see added in
+fn visible_aggregates(
+    location: &str,
+    first_day: u64,
+    cursor: Option<u64>,
+    end_timestamp: u64,
+) -> BTreeMap<u64, DailyAggregate> {
+    let location = StringKey(location.to_string());
+    let start = cursor.map_or(Bound::Included((location.clone(), first_day)), |cursor| {
+        Bound::Excluded((location.clone(), cursor))
+    });
+    let end = (location.clone(), end_timestamp);
+    let mut days: BTreeMap<u64, DailyAggregate> = DAILY_AGGREGATE_STORAGE.with(|s| {
+        s.borrow()
+            .range((start, Bound::Included(end.clone())))
+            .map(|((_, day_start), aggregate)| (day_start, aggregate))
+            .collect()
+    });
+    let start = cursor.map_or(
+        Bound::Included(((location.clone(), first_day), 0)),
+        |cursor| Bound::Excluded(((location.clone(), cursor), u64::MAX)),
+    );
+    ORGANIZATION_DAILY_AGGREGATE_STORAGE.with(|s| {
+        for (((_, day_start), organization_id), aggregate) in
+            s.borrow().range((start, Bound::Included((end, u64::MAX))))
+        {
+            if organizations::is_visible_to_caller(Some(organization_id)) {
+                days.entry(day_start).or_default().merge(&aggregate);
+            }
+        }
+    });
+    days
+}
+
+// Daily aggregates of the readings compaction removed, oldest first. Pages continue after the
+// day starting at `cursor`.
+#[ic_cdk::query]
+fn get_compacted_daily_aggregates(
+    location: String,
+    start_timestamp: u64,
+    end_timestamp: u64,
+    cursor: Option<u64>,
+) -> Result<DailyAggregatePage, Error> {
+    if end_timestamp < start_timestamp {
+        return Err(Error::InvalidInput {
+            msg: "end_timestamp must not be before start_timestamp".to_string(),
+            violations: None,
+        });
+    }
+    let location = aliases::location(location);
+    let first_day = start_timestamp - start_timestamp % NANOS_PER_DAY;
+    let (days, truncated) = paging::take_within(
+        visible_aggregates(&location, first_day, cursor, end_timestamp)
+            .into_iter()
+            .map(|(day_start, aggregate)| aggregate.view(day_start)),
+        &mut ReplyBudget::new(),
+    );
+    Ok(DailyAggregatePage {
+        next_cursor: days.last().filter(|_| truncated).map(|day| day.day_start),
+        days,
+        truncated,
+    })
+}
@@ -0,0 +1,430 @@
+use crate::alerts::{retry_delay_nanos, DeliveryState};
+use crate::filters::ReadingFilter;
+use crate::paging::{self, ReplyBudget};
+use crate::{
+    accepts_writes, capacity, clear_stable_map, get_memory, next_id, stations, units,
+    AirQualityData, Error, IdCell, Memory, AIR_QUALITY_STORAGE,
+    CONSUMER_DELIVERY_ID_COUNTER_MEMORY_ID, CONSUMER_DELIVERY_QUEUE_MEMORY_ID,
+    CONSUMER_DELIVERY_STORAGE_MEMORY_ID, CONSUMER_ID_COUNTER_MEMORY_ID, CONSUMER_STORAGE_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::ops::Bound;
+
+const MAX_METHOD_LEN: usize = 64;
+const MAX_CONSUMERS_PER_OWNER: usize = 10;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// Deliveries not made after this many attempts are dead-lettered
+const MAX_DELIVERY_ATTEMPTS: u32 = 8;
+const MAX_CALLS_PER_TICK: usize = 50;
+const MAX_ERROR_LEN: usize = 256;
+// Deliveries of embargoed readings are looked at again this often, without using up attempts
+const EMBARGO_RECHECK_SECONDS: u64 = 60;
+
+// A canister that wants new readings pushed to one of its methods
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Consumer {
+    id: u64,
+    owner: Principal,
+    canister_id: Principal,
+    method: String,
+    // Readings that pass it are pushed; None for every reading
+    filter: Option<ReadingFilter>,
+    created_at: u64,
+    delivered: u64,
+    dead_lettered: u64,
+}
+
+impl_bounded_storable!(Consumer, 2048);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ConsumerPayload {
+    canister_id: Principal,
+    method: String,
+    filter: Option<ReadingFilter>,
+}
+
+// Delivered deliveries are removed; dead-lettered ones are kept until redelivered or the
+// consumer is removed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ConsumerDelivery {
+    id: u64,
+    consumer_id: u64,
+    reading_id: u64,
+    state: DeliveryState,
+    attempts: u32,
+    next_attempt_at: Option<u64>,
+    last_error: Option<String>,
+}
+
+impl_bounded_storable!(ConsumerDelivery, 512);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ConsumerDeliveryPage {
+    deliveries: Vec<ConsumerDelivery>,
+    // More deliveries follow; pass `next_cursor` to fetch them
+    truncated: bool,
+    next_cursor: Option<u64>,
+}
+
+// What a consumer's method receives. A delivery may arrive more than once; `delivery_id`
+// tells repeats apart.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ReadingNotification {
+    delivery_id: u64,
+    consumer_id: u64,
+    reading: AirQualityData,
+}
+
+thread_local! {
+    static CONSUMER_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(get_memory(CONSUMER_ID_COUNTER_MEMORY_ID), 0)
+            .expect("Cannot create a counter for consumers")
+    );
+
+    static CONSUMER_STORAGE: RefCell<StableBTreeMap<u64, Consumer, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CONSUMER_STORAGE_MEMORY_ID)));
+
+    static CONSUMER_DELIVERY_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(get_memory(CONSUMER_DELIVERY_ID_COUNTER_MEMORY_ID), 0)
+            .expect("Cannot create a counter for consumer deliveries")
+    );
+
+    static CONSUMER_DELIVERY_STORAGE: RefCell<StableBTreeMap<u64, ConsumerDelivery, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CONSUMER_DELIVERY_STORAGE_MEMORY_ID)));
+
+    // Keyed by (next attempt, delivery id)
+    static CONSUMER_DELIVERY_QUEUE: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CONSUMER_DELIVERY_QUEUE_MEMORY_ID)));
+
+    static CONSUMER_DELIVERIES_IN_FLIGHT: RefCell<BTreeSet<u64>> =
+        const { RefCell::new(BTreeSet::new()) };
+}
+
+// Drops every delivery, dead letters included, for a storage reset. Consumers are kept.
+pub(crate) fn reset() {
+    CONSUMER_DELIVERY_STORAGE.with(|s| clear_stable_map(s, CONSUMER_DELIVERY_STORAGE_MEMORY_ID));
+    CONSUMER_DELIVERY_QUEUE.with(|s| clear_stable_map(s, CONSUMER_DELIVERY_QUEUE_MEMORY_ID));
+}
+
+fn get_consumer(id: u64) -> Option<Consumer> {
+    CONSUMER_STORAGE.with(|s| s.borrow().get(&id))
+}
+
+// The consumer, if the caller owns it or is a controller
+fn owned_consumer(id: u64) -> Result<Consumer, Error> {
+    let consumer = get_consumer(id).ok_or(Error::NotFound {
+        msg: format!("consumer with id={} not found", id),
+    })?;
+    let caller = ic_cdk::caller();
+    if consumer.owner != caller && !ic_cdk::api::is_controller(&caller) {
+        return Err(Error::Unauthorized {
+            msg: format!("caller does not own consumer with id={}", id),
+        });
+    }
+    Ok(consumer)
+}
+
+fn consumers_of(owner: &Principal) -> Vec<Consumer> {
+    CONSUMER_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, consumer)| consumer)
+            .filter(|consumer| consumer.owner == *owner)
+            .collect()
+    })
+}
+
+// Whether the consumer's owner may see the reading, embargo aside
+fn is_visible_to(data: &AirQualityData, owner: &Principal) -> bool {
+    !data.is_deleted()
+        && data.is_published()
+        && data
+            .station_id
+            .as_deref()
+            .is_none_or(|station_id| stations::is_station_visible_to(station_id, owner))
+}
+
+fn passes(consumer: &Consumer, data: &AirQualityData) -> bool {
+    let Some(filter) = consumer.filter.clone() else {
+        return true;
+    };
+    let unit = filter.unit();
+    filter.predicate()(&units::in_unit(data.clone(), unit))
+}
+
+// Queues a delivery of a newly public reading to every consumer it passes the filter of
+pub(crate) fn on_insert(data: &AirQualityData) {
+    let consumers: Vec<Consumer> = CONSUMER_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, consumer)| consumer)
+            .filter(|consumer| is_visible_to(data, &consumer.owner) && passes(consumer, data))
+            .collect()
+    });
+    let now = time();
+    for consumer in consumers {
+        let delivery = ConsumerDelivery {
+            id: next_id(&CONSUMER_DELIVERY_ID_COUNTER),
+            consumer_id: consumer.id,
+            reading_id: data.id,
+            state: DeliveryState::Pending,
+            attempts: 0,
+            next_attempt_at: Some(now),
+            last_error: None,
+        };
+        CONSUMER_DELIVERY_QUEUE.with(|q| q.borrow_mut().insert((now, delivery.id), ()));
+        CONSUMER_DELIVERY_STORAGE.with(|s| s.borrow_mut().insert(delivery.id, delivery));
+    }
+}
+
+fn remove_delivery(delivery: &ConsumerDelivery) {
+    if let Some(at) = delivery.next_attempt_at {
+        CONSUMER_DELIVERY_QUEUE.with(|q| q.borrow_mut().remove(&(at, delivery.id)));
+    }
+    CONSUMER_DELIVERY_STORAGE.with(|s| s.borrow_mut().remove(&delivery.id));
+}
+
+// Moves a delivery's queue entry to its next attempt, or drops it once dead
+fn update_delivery(delivery_id: u64, update: impl FnOnce(&mut ConsumerDelivery)) {
+    let Some(mut delivery) = CONSUMER_DELIVERY_STORAGE.with(|s| s.borrow().get(&delivery_id))
+    else {
+        return;
+    };
+    let previous = delivery.next_attempt_at;
+    update(&mut delivery);
+    CONSUMER_DELIVERY_QUEUE.with(|q| {
+        let mut queue = q.borrow_mut();
+        if let Some(at) = previous {
+            queue.remove(&(at, delivery_id));
+        }
+        if let Some(at) = delivery.next_attempt_at {
+            queue.insert((at, delivery_id), ());
+        }
+    });
+    CONSUMER_DELIVERY_STORAGE.with(|s| s.borrow_mut().insert(delivery_id, delivery));
+}
+
+fn update_consumer(id: u64, update: impl FnOnce(&mut Consumer)) {
+    if let Some(mut consumer) = get_consumer(id) {
+        update(&mut consumer);
+        CONSUMER_STORAGE.with(|s| s.borrow_mut().insert(id, consumer));
+    }
+}
+
+fn on_delivery_result(delivery_id: u64, result: Result<(), String>) {
+    let Some(delivery) = CONSUMER_DELIVERY_STORAGE.with(|s| s.borrow().get(&delivery_id)) else {
+        return;
+    };
+    match result {
+        Ok(()) => {
+            remove_delivery(&delivery);
+            update_consumer(delivery.consumer_id, |consumer| consumer.delivered += 1);
+        }
+        Err(error) => {
+            let dead = delivery.attempts >= MAX_DELIVERY_ATTEMPTS;
+            update_delivery(delivery_id, |delivery| {
+                delivery.last_error = Some(error.chars().take(MAX_ERROR_LEN).collect());
+                if dead {
+                    delivery.state = DeliveryState::DeadLettered;
+                    delivery.next_attempt_at = None;
+                }
+            });
+            if dead {
+                update_consumer(delivery.consumer_id, |consumer| consumer.dead_lettered += 1);
+                log!(
+                    Warn,
+                    "consumer delivery dead-lettered",
+                    "delivery" => delivery_id,
+                    "consumer" => delivery.consumer_id,
+                );
+            }
+        }
+    }
+}
+
+// Pushes every due delivery. As with alerts, the next attempt is scheduled before the call, so
+// a call lost to an upgrade is retried and every reading arrives at least once.
+pub(crate) fn on_timer() {
+    let now = time();
+    let due: Vec<u64> = CONSUMER_DELIVERY_QUEUE.with(|q| {
+        q.borrow()
+            .range(..=(now, u64::MAX))
+            .map(|((_, id), _)| id)
+            .filter(|id| !CONSUMER_DELIVERIES_IN_FLIGHT.with(|f| f.borrow().contains(id)))
+            .take(MAX_CALLS_PER_TICK)
+            .collect()
+    });
+    for delivery_id in due {
+        let Some(delivery) = CONSUMER_DELIVERY_STORAGE.with(|s| s.borrow().get(&delivery_id))
+        else {
+            continue;
+        };
+        let Some(consumer) = get_consumer(delivery.consumer_id) else {
+            remove_delivery(&delivery);
+            continue;
+        };
+        // Readings deleted, retracted or removed since they were queued are not pushed
+        let Some(reading) = AIR_QUALITY_STORAGE
+            .with(|service| service.borrow().get(&delivery.reading_id))
+            .filter(|data| is_visible_to(data, &consumer.owner))
+        else {
+            remove_delivery(&delivery);
+            continue;
+        };
+        if reading.station_id.as_deref().is_some_and(|station_id| {
+            stations::is_embargoed_for(station_id, reading.timestamp, &consumer.owner)
+        }) {
+            update_delivery(delivery_id, |delivery| {
+                delivery.next_attempt_at = Some(now + EMBARGO_RECHECK_SECONDS * NANOS_PER_SECOND);
+            });
+            continue;
+        }
+        update_delivery(delivery_id, |delivery| {
+            delivery.attempts += 1;
+            delivery.next_attempt_at = Some(now + retry_delay_nanos(delivery.attempts));
+        });
+        let notification = ReadingNotification {
+            delivery_id,
+            consumer_id: consumer.id,
+            reading,
+        };
+        CONSUMER_DELIVERIES_IN_FLIGHT.with(|f| f.borrow_mut().insert(delivery_id));
+        ic_cdk::spawn(async move {
+            let result: Result<(), _> =
+                ic_cdk::call(consumer.canister_id, &consumer.method, (notification,)).await;
+            on_delivery_result(
+                delivery_id,
+                result.map_err(|(code, msg)| format!("call failed: {:?} {}", code, msg)),
+            );
+            CONSUMER_DELIVERIES_IN_FLIGHT.with(|f| f.borrow_mut().remove(&delivery_id));
+        });
+    }
+}
+
+// Registers a canister to receive new readings at `method`, which must have the type
+// `(ReadingNotification) -> ()`. A canister registers itself; controllers may register any.
+#[ic_cdk::update(guard = "accepts_writes")]
+fn register_consumer(payload: ConsumerPayload) -> Result<Consumer, Error> {
+    capacity::ensure_room_for_optional_write()?;
+    let owner = ic_cdk::caller();
+    if payload.canister_id != owner && !ic_cdk::api::is_controller(&owner) {
+        return Err(Error::Unauthorized {
+            msg: "a canister can only register itself as a consumer".to_string(),
+        });
+    }
+    if payload.method.is_empty() || payload.method.len() > MAX_METHOD_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!("method must be 1 to {} bytes", MAX_METHOD_LEN),
+            violations: None,
+        });
+    }
+    if consumers_of(&owner).len() >= MAX_CONSUMERS_PER_OWNER {
+        return Err(Error::TooLarge {
+            msg: format!(
+                "a principal can register at most {} consumers",
+                MAX_CONSUMERS_PER_OWNER
+            ),
+        });
+    }
+    let consumer = Consumer {
+        id: next_id(&CONSUMER_ID_COUNTER),
+        owner,
+        canister_id: payload.canister_id,
+        method: payload.method,
+        filter: payload.filter,
+        created_at: time(),
+        delivered: 0,
+        dead_lettered: 0,
+    };
+    CONSUMER_STORAGE.with(|s| s.borrow_mut().insert(consumer.id, consumer.clone()));
+    Ok(consumer)
+}
+
+// Removes a consumer (owner or controllers). Its dead-lettered deliveries are dropped now, and
+// pending ones when they come due.
+#[ic_cdk::update(guard = "accepts_writes")]
+fn unregister_consumer(id: u64) -> Result<Consumer, Error> {
+    let consumer = owned_consumer(id)?;
+    CONSUMER_STORAGE.with(|s| s.borrow_mut().remove(&id));
+    let dead: Vec<ConsumerDelivery> = CONSUMER_DELIVERY_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, delivery)| delivery)
+            .filter(|delivery| {
+                delivery.consumer_id == id && delivery.state == DeliveryState::DeadLettered
+            })
+            .collect()
+    });
+    for delivery in &dead {
+        remove_delivery(delivery);
+    }
+    Ok(consumer)
+}
+
+#[ic_cdk::query]
+fn list_my_consumers() -> Vec<Consumer> {
+    consumers_of(&ic_cdk::caller())
+}
+
+// Dead-lettered deliveries of a consumer after `cursor`, oldest first (owner or controllers)
+#[ic_cdk::query]
+fn get_consumer_dead_letters(
+    consumer_id: u64,
+    cursor: Option<u64>,
+) -> Result<ConsumerDeliveryPage, Error> {
+    owned_consumer(consumer_id)?;
+    let start = cursor.map_or(Bound::Unbounded, Bound::Excluded);
+    let (deliveries, truncated) = CONSUMER_DELIVERY_STORAGE.with(|s| {
+        paging::take_within(
+            s.borrow()
+                .range((start, Bound::Unbounded))
+                .map(|(_, delivery)| delivery)
+                .filter(|delivery| {
+                    delivery.consumer_id == consumer_id
+                        && delivery.state == DeliveryState::DeadLettered
+                }),
+            &mut ReplyBudget::new(),
+        )
+    });
+    Ok(ConsumerDeliveryPage {
+        next_cursor: deliveries
+            .last()
+            .filter(|_| truncated)
+            .map(|delivery| delivery.id),
+        deliveries,
+        truncated,
+    })
+}
+
+// Queues every dead-lettered delivery of a consumer again, with a fresh set of attempts (owner
+// or controllers). Returns how many were queued.
+#[ic_cdk::update(guard = "accepts_writes")]
+fn redeliver_consumer_dead_letters(consumer_id: u64) -> Result<u64, Error> {
+    let consumer = owned_consumer(consumer_id)?;
+    let dead: Vec<u64> = CONSUMER_DELIVERY_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, delivery)| delivery)
+            .filter(|delivery| {
+                delivery.consumer_id == consumer_id && delivery.state == DeliveryState::DeadLettered
+            })
+            .map(|delivery| delivery.id)
+            .collect()
+    });
+    let now = time();
+    for delivery_id in &dead {
+        update_delivery(*delivery_id, |delivery| {
+            delivery.state = DeliveryState::Pending;
+            delivery.attempts = 0;
+            delivery.next_attempt_at = Some(now);
+        });
+    }
+    update_consumer(consumer.id, |consumer| {
+        consumer.dead_lettered = consumer.dead_lettered.saturating_sub(dead.len() as u64);
+    });
+    Ok(dead.len() as u64)
+}
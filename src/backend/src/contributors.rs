@@ -0,0 +1,111 @@
+use crate::events::{self, EventKind, Milestone};
+use crate::{
+    get_memory, principal_key, Error, Memory, PrincipalKey, CONTRIBUTOR_STORAGE_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+const STREAK_MILESTONES: &[u32] = &[7, 30, 100, 365];
+const READING_MILESTONES: &[u64] = &[100, 1_000, 10_000, 100_000];
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Badge {
+    milestone: Milestone,
+    earned_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ContributorStats {
+    contributor: Principal,
+    total_readings: u64,
+    // Consecutive UTC days with at least one accepted reading, ending on last_contribution_day
+    current_streak_days: u32,
+    longest_streak_days: u32,
+    // Days since the Unix epoch
+    last_contribution_day: u64,
+    badges: Vec<Badge>,
+}
+
+impl_bounded_storable!(ContributorStats, 1024);
+
+thread_local! {
+    static CONTRIBUTOR_STORAGE: RefCell<StableBTreeMap<PrincipalKey, ContributorStats, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CONTRIBUTOR_STORAGE_MEMORY_ID)));
+}
+
+impl ContributorStats {
+    fn new(contributor: Principal) -> Self {
+        ContributorStats {
+            contributor,
+            total_readings: 0,
+            current_streak_days: 0,
+            longest_streak_days: 0,
+            last_contribution_day: 0,
+            badges: Vec::new(),
+        }
+    }
+
+    fn has_badge(&self, milestone: Milestone) -> bool {
+        self.badges.iter().any(|badge| badge.milestone == milestone)
+    }
+
+    // Milestones reached by the latest reading that were not awarded before
+    fn new_milestones(&self) -> Vec<Milestone> {
+        let streaks = STREAK_MILESTONES
+            .iter()
+            .filter(|days| self.current_streak_days >= **days)
+            .map(|days| Milestone::StreakDays { days: *days });
+        let readings = READING_MILESTONES
+            .iter()
+            .filter(|count| self.total_readings >= **count)
+            .map(|count| Milestone::Readings { count: *count });
+        streaks
+            .chain(readings)
+            .filter(|milestone| !self.has_badge(*milestone))
+            .collect()
+    }
+}
+
+// Counts an accepted reading towards its contributor's streak and milestones
+pub(crate) fn record_contribution(contributor: Principal) {
+    let key = principal_key(&contributor);
+    let mut stats = CONTRIBUTOR_STORAGE
+        .with(|s| s.borrow().get(&key))
+        .unwrap_or_else(|| ContributorStats::new(contributor));
+
+    let today = time() / NANOS_PER_DAY;
+    stats.total_readings += 1;
+    if stats.current_streak_days == 0 || today > stats.last_contribution_day + 1 {
+        stats.current_streak_days = 1;
+    } else if today == stats.last_contribution_day + 1 {
+        stats.current_streak_days += 1;
+    }
+    stats.last_contribution_day = today;
+    stats.longest_streak_days = stats.longest_streak_days.max(stats.current_streak_days);
+
+    for milestone in stats.new_milestones() {
+        stats.badges.push(Badge {
+            milestone,
+            earned_at: time(),
+        });
+        events::emit(EventKind::MilestoneReached {
+            contributor,
+            milestone,
+        });
+    }
+
+    CONTRIBUTOR_STORAGE.with(|s| s.borrow_mut().insert(key, stats));
+}
+
+#[ic_cdk::query]
+fn get_contributor_stats(contributor: Principal) -> Result<ContributorStats, Error> {
+    CONTRIBUTOR_STORAGE
+        .with(|s| s.borrow().get(&principal_key(&contributor)))
+        .ok_or(Error::NotFound {
+            msg: format!("{} has not contributed any readings", contributor),
+        })
+}
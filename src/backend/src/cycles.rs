@@ -0,0 +1,111 @@
+use crate::events::{self, EventKind};
+use crate::{accepts_writes, ensure_admin, get_memory, Error, Memory, CYCLES_SETTINGS_MEMORY_ID};
+use ic_cdk::api::{canister_balance128, time};
+use ic_stable_structures::Cell;
+use std::cell::RefCell;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct CyclesSettings {
+    // Balance under which the canister raises a low-balance alarm
+    floor_cycles: u128,
+}
+
+impl Default for CyclesSettings {
+    fn default() -> Self {
+        // Well above the freezing threshold of a canister this size, so there is time to top up
+        CyclesSettings {
+            floor_cycles: 2_000_000_000_000,
+        }
+    }
+}
+
+impl_bounded_storable!(CyclesSettings, 64);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CyclesStatus {
+    balance: u128,
+    floor_cycles: u128,
+    low: bool,
+    // When the balance was first seen under the floor, while it stays there
+    low_since: Option<u64>,
+    last_checked_at: Option<u64>,
+}
+
+// Kept on the heap: after an upgrade a low balance is raised again on the first check
+#[derive(Default)]
+struct CyclesCheck {
+    low_since: Option<u64>,
+    last_checked_at: Option<u64>,
+}
+
+thread_local! {
+    static CYCLES_SETTINGS: RefCell<Cell<CyclesSettings, Memory>> = RefCell::new(
+        Cell::init(get_memory(CYCLES_SETTINGS_MEMORY_ID), CyclesSettings::default())
+            .expect("Cannot create the cycles settings cell")
+    );
+
+    static LAST_CHECK: RefCell<CyclesCheck> = RefCell::new(CyclesCheck::default());
+}
+
+fn settings() -> CyclesSettings {
+    CYCLES_SETTINGS.with(|s| s.borrow().get().clone())
+}
+
+// Raises an event and a log entry when the balance falls under the floor, and logs when it is
+// topped up again
+pub(crate) fn on_timer() {
+    let floor_cycles = settings().floor_cycles;
+    let balance = canister_balance128();
+    let now = time();
+    let was_low = LAST_CHECK.with(|c| {
+        let mut check = c.borrow_mut();
+        check.last_checked_at = Some(now);
+        let was_low = check.low_since.is_some();
+        check.low_since = if balance < floor_cycles {
+            check.low_since.or(Some(now))
+        } else {
+            None
+        };
+        was_low
+    });
+    if balance < floor_cycles && !was_low {
+        log!(
+            Error,
+            "cycles balance is under the floor; top up the canister",
+            "balance" => balance,
+            "floor_cycles" => floor_cycles,
+        );
+        events::emit(EventKind::CyclesLow {
+            balance,
+            floor_cycles,
+        });
+    } else if balance >= floor_cycles && was_low {
+        log!(Info, "cycles balance is back over the floor", "balance" => balance);
+    }
+}
+
+#[ic_cdk::query]
+fn get_cycles_status() -> CyclesStatus {
+    let floor_cycles = settings().floor_cycles;
+    let balance = canister_balance128();
+    let (low_since, last_checked_at) =
+        LAST_CHECK.with(|c| (c.borrow().low_since, c.borrow().last_checked_at));
+    CyclesStatus {
+        balance,
+        floor_cycles,
+        low: balance < floor_cycles,
+        low_since: low_since.filter(|_| balance < floor_cycles),
+        last_checked_at,
+    }
+}
+
+// Sets the balance under which the low-balance alarm is raised (controllers only)
+#[ic_cdk::update(guard = "accepts_writes")]
+fn set_cycles_floor(floor_cycles: u128) -> Result<CyclesSettings, Error> {
+    ensure_admin()?;
+    let settings = CyclesSettings { floor_cycles };
+    CYCLES_SETTINGS
+        .with(|s| s.borrow_mut().set(settings.clone()))
+        .expect("cannot store the cycles settings");
+    Ok(settings)
+}
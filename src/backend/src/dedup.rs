@@ -0,0 +1,205 @@
+use crate::{
+    clear_stable_map, get_memory, is_visible_to_caller, AirQualityData, Error, Memory, StringKey,
+    AIR_QUALITY_STORAGE, DEDUP_INDEX_MEMORY_ID,
+};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::ops::Bound;
+
+// Readings of a location observed this close together with the same levels are duplicates
+const DEDUP_WINDOW_NANOS: u64 = 60 * 1_000_000_000;
+// Longer locations do not fit an index key and are not checked
+const MAX_LOCATION_LEN: usize = 200;
+
+type ObservationKey = ((StringKey, u64), u64);
+
+thread_local! {
+    // (location, observation time) of every reading, with its id. Entries of removed readings
+    // are dropped when a lookup comes across them.
+    static DEDUP_INDEX: RefCell<StableBTreeMap<ObservationKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DEDUP_INDEX_MEMORY_ID)));
+}
+
+pub(crate) fn reset() {
+    DEDUP_INDEX.with(|s| clear_stable_map(s, DEDUP_INDEX_MEMORY_ID));
+}
+
+fn index_key(data: &AirQualityData) -> Option<ObservationKey> {
+    (data.location.len() <= MAX_LOCATION_LEN)
+        .then(|| ((StringKey(data.location.clone()), data.timestamp), data.id))
+}
+
+// Keeps the index in step with a write; `previous` is the record it replaced
+pub(crate) fn on_write(previous: Option<&AirQualityData>, data: &AirQualityData) {
+    DEDUP_INDEX.with(|s| {
+        let mut index = s.borrow_mut();
+        if let Some(key) = previous.and_then(index_key) {
+            index.remove(&key);
+        }
+        if let Some(key) = index_key(data) {
+            index.insert(key, ());
+        }
+    });
+}
+
+// Adds a reading stored before the index existed
+pub(crate) fn backfill(data: &AirQualityData) {
+    on_write(None, data);
+}
+
+// Live readings of a location observed within `window_nanos` of `timestamp`, in observation
+// order. Locations too long for the index have none.
+pub(crate) fn live_readings_near(
+    location: &str,
+    timestamp: u64,
+    window_nanos: u64,
+) -> Vec<AirQualityData> {
+    live_readings_between(
+        location,
+        timestamp.saturating_sub(window_nanos),
+        timestamp.saturating_add(window_nanos),
+    )
+}
+
+// Live readings of a location observed between the two timestamps, both included, in
+// observation order. Locations too long for the index have none.
+pub(crate) fn live_readings_between(
+    location: &str,
+    start_timestamp: u64,
+    end_timestamp: u64,
+) -> Vec<AirQualityData> {
+    if location.len() > MAX_LOCATION_LEN {
+        return Vec::new();
+    }
+    let location = StringKey(location.to_string());
+    let start = ((location.clone(), start_timestamp), 0);
+    let end = ((location, end_timestamp), u64::MAX);
+    let nearby: Vec<ObservationKey> = DEDUP_INDEX.with(|s| {
+        s.borrow()
+            .range((Bound::Included(start), Bound::Included(end)))
+            .map(|(key, _)| key)
+            .collect()
+    });
+    let mut readings = Vec::new();
+    for key in nearby {
+        let id = key.1;
+        match AIR_QUALITY_STORAGE.with(|service| service.borrow().get(&id)) {
+            Some(existing) if !existing.is_deleted() => readings.push(existing),
+            Some(_) => {}
+            None => {
+                DEDUP_INDEX.with(|s| s.borrow_mut().remove(&key));
+            }
+        }
+    }
+    readings
+}
+
+// Whether `existing` is the same reading as `candidate`: of the same organization, station and
+// pollutant levels. Readings without levels are never duplicates.
+fn is_duplicate(existing: &AirQualityData, candidate: &AirQualityData) -> bool {
+    !candidate.pollutant_levels.is_empty()
+        && existing.organization_id == candidate.organization_id
+        && existing.station_id == candidate.station_id
+        && existing.pollutant_levels == candidate.pollutant_levels
+}
+
+// Fails with `Duplicate` if a live reading of the same location, organization and station,
+// observed within the window, has exactly the same pollutant levels. The error only names the
+// stored reading if the caller may see it.
+pub(crate) fn check(candidate: &AirQualityData) -> Result<(), Error> {
+    if candidate.pollutant_levels.is_empty() {
+        return Ok(());
+    }
+    let nearby = live_readings_near(&candidate.location, candidate.timestamp, DEDUP_WINDOW_NANOS);
+    for existing in nearby {
+        if !is_duplicate(&existing, candidate) {
+            continue;
+        }
+        let existing_id = is_visible_to_caller(&existing).then_some(existing.id);
+        return Err(Error::Duplicate {
+            msg: match existing_id {
+                Some(id) => format!("the reading duplicates air quality data with id={}", id),
+                None => "the reading duplicates a stored reading".to_string(),
+            },
+            existing_id,
+        });
+    }
+    Ok(())
+}
+
+// Live readings of a location, last observed first. Records are read as the iterator is
+// advanced. Locations too long for the index have none.
+pub(crate) fn live_readings_newest_first(location: &str) -> impl Iterator<Item = AirQualityData> {
+    let keys: Vec<ObservationKey> = if location.len() > MAX_LOCATION_LEN {
+        Vec::new()
+    } else {
+        let location = StringKey(location.to_string());
+        DEDUP_INDEX.with(|s| {
+            s.borrow()
+                .range((
+                    Bound::Included(((location.clone(), 0), 0)),
+                    Bound::Included(((location, u64::MAX), u64::MAX)),
+                ))
+                .map(|(key, _)| key)
+                .collect()
+        })
+    };
+    keys.into_iter()
+        .rev()
+        .filter_map(|(_, id)| AIR_QUALITY_STORAGE.with(|service| service.borrow().get(&id)))
+        .filter(|data| !data.is_deleted())
+}
+
+// The live reading of a location observed last among those matching `predicate`
+pub(crate) fn latest_reading_where<F>(location: &str, predicate: F) -> Option<AirQualityData>
+where
+    F: Fn(&AirQualityData) -> bool,
+{
+    live_readings_newest_first(location).find(|data| predicate(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Pollutant;
+    use std::collections::HashMap;
+
+    fn reading(station_id: &str, organization_id: Option<u64>, pm25: f64) -> AirQualityData {
+        AirQualityData {
+            location: "Lyon".to_string(),
+            station_id: Some(station_id.to_string()),
+            organization_id,
+            pollutant_levels: HashMap::from([(Pollutant::PM25, pm25)]),
+            ..AirQualityData::default()
+        }
+    }
+
+    #[test]
+    fn equal_levels_of_the_same_station_are_duplicates() {
+        assert!(is_duplicate(
+            &reading("lyon-1", Some(1), 12.0),
+            &reading("lyon-1", Some(1), 12.0)
+        ));
+        assert!(!is_duplicate(
+            &reading("lyon-1", Some(1), 12.0),
+            &reading("lyon-1", Some(1), 12.5)
+        ));
+    }
+
+    #[test]
+    fn equal_levels_of_other_stations_or_organizations_are_not_duplicates() {
+        let existing = reading("lyon-1", Some(1), 12.0);
+        assert!(!is_duplicate(&existing, &reading("lyon-2", Some(1), 12.0)));
+        assert!(!is_duplicate(&existing, &reading("lyon-1", Some(2), 12.0)));
+        assert!(!is_duplicate(&existing, &reading("lyon-1", None, 12.0)));
+    }
+
+    #[test]
+    fn readings_without_levels_are_never_duplicates() {
+        let empty = AirQualityData {
+            pollutant_levels: HashMap::new(),
+            ..reading("lyon-1", None, 0.0)
+        };
+        assert!(!is_duplicate(&empty.clone(), &empty));
+    }
+}
@@ -0,0 +1,312 @@
+use crate::aliases;
+use crate::standards::{AqiCategory, GuidelineStandard};
+use crate::{
+    accepts_writes, capacity, clear_stable_map, get_memory, next_id, rollups, Error, IdCell,
+    Memory, Pollutant, StringKey, DIGEST_STORAGE_MEMORY_ID,
+    DIGEST_SUBSCRIPTION_ID_COUNTER_MEMORY_ID, DIGEST_SUBSCRIPTION_STORAGE_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::ops::Bound;
+
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+const NANOS_PER_DAY: u64 = 24 * NANOS_PER_HOUR;
+// Hours whose mean AQI is above this are unhealthy for sensitive groups
+const EXCEEDANCE_INDEX: f64 = 100.0;
+// Locations with rollups are at most this long
+const MAX_LOCATION_LEN: usize = 200;
+const MAX_METHOD_LEN: usize = 64;
+const MAX_SUBSCRIPTIONS_PER_OWNER: usize = 100;
+const LOCATIONS_PER_TICK: usize = 50;
+const MAX_PUSHES_PER_TICK: usize = 100;
+
+// One UTC day at a location, from the hourly rollups
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct DailyDigest {
+    location: String,
+    // Start of the day
+    date: u64,
+    hours_with_data: u32,
+    // Highest hourly mean AQI, and its category
+    max_air_quality_index: f64,
+    max_category: AqiCategory,
+    // Pollutant with the highest daily mean relative to its WHO 2021 guideline level; None if
+    // no guideline pollutant was reported
+    dominant_pollutant: Option<Pollutant>,
+    // Hours whose mean AQI was above 100
+    exceedance_hours: u32,
+    generated_at: u64,
+}
+
+impl_bounded_storable!(DailyDigest, 1024);
+
+// A canister that wants the digests of a location pushed to one of its methods
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct DigestSubscription {
+    id: u64,
+    owner: Principal,
+    location: String,
+    canister_id: Principal,
+    method: String,
+    created_at: u64,
+}
+
+impl_bounded_storable!(DigestSubscription, 1024);
+
+// Kept on the heap: after an upgrade the day is gone through again, skipping digests already
+// made
+struct DigestProgress {
+    day: u64,
+    // Last location gone through; None before the first
+    cursor: Option<String>,
+    done: bool,
+}
+
+type DigestKey = (StringKey, u64);
+
+thread_local! {
+    static DIGEST_STORAGE: RefCell<StableBTreeMap<DigestKey, DailyDigest, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DIGEST_STORAGE_MEMORY_ID)));
+
+    static DIGEST_SUBSCRIPTION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(get_memory(DIGEST_SUBSCRIPTION_ID_COUNTER_MEMORY_ID), 0)
+            .expect("Cannot create a counter for digest subscriptions")
+    );
+
+    // Keyed by (location, subscription id)
+    static DIGEST_SUBSCRIPTION_STORAGE: RefCell<StableBTreeMap<DigestKey, DigestSubscription, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(DIGEST_SUBSCRIPTION_STORAGE_MEMORY_ID)));
+
+    static DIGEST_PROGRESS: RefCell<DigestProgress> = const {
+        RefCell::new(DigestProgress {
+            day: 0,
+            cursor: None,
+            done: false,
+        })
+    };
+}
+
+// Digests summarize the rollups, so they go with them; the day is gone through again
+pub(crate) fn reset() {
+    DIGEST_STORAGE.with(|s| clear_stable_map(s, DIGEST_STORAGE_MEMORY_ID));
+    DIGEST_PROGRESS.with(|p| {
+        let mut progress = p.borrow_mut();
+        progress.cursor = None;
+        progress.done = false;
+    });
+}
+
+// None if the location has no rollups that day
+fn summarize(location: &str, day: u64) -> Option<DailyDigest> {
+    let start = day * NANOS_PER_DAY;
+    let end = start + NANOS_PER_DAY - 1;
+    let hourly = rollups::hourly_air_quality_index(location, start, end).ok()?;
+    let max_air_quality_index = hourly
+        .iter()
+        .map(|(_, index)| *index)
+        .max_by(f64::total_cmp)?;
+    let dominant_pollutant = GuidelineStandard::Who2021
+        .limits()
+        .iter()
+        .filter_map(|limit| {
+            let means = rollups::hourly_pollutant_averages(location, &limit.pollutant, start, end)
+                .ok()
+                .filter(|means| !means.is_empty())?;
+            let mean = means.values().sum::<f64>() / means.len() as f64;
+            Some((
+                limit.pollutant.clone(),
+                mean / limit.micrograms_per_cubic_meter,
+            ))
+        })
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(pollutant, _)| pollutant);
+    Some(DailyDigest {
+        location: location.to_string(),
+        date: start,
+        hours_with_data: hourly.len() as u32,
+        max_air_quality_index,
+        max_category: AqiCategory::of(max_air_quality_index),
+        dominant_pollutant,
+        exceedance_hours: hourly
+            .iter()
+            .filter(|(_, index)| *index > EXCEEDANCE_INDEX)
+            .count() as u32,
+        generated_at: time(),
+    })
+}
+
+fn subscriptions_at(location: &str) -> Vec<DigestSubscription> {
+    let location = StringKey(location.to_string());
+    DIGEST_SUBSCRIPTION_STORAGE.with(|s| {
+        s.borrow()
+            .range((
+                Bound::Included((location.clone(), 0)),
+                Bound::Included((location, u64::MAX)),
+            ))
+            .map(|(_, subscription)| subscription)
+            .collect()
+    })
+}
+
+// Pushes are made once; failed calls are logged and not retried
+fn push(digest: &DailyDigest, subscription: DigestSubscription) {
+    let digest = digest.clone();
+    ic_cdk::spawn(async move {
+        let result: Result<(), _> =
+            ic_cdk::call(subscription.canister_id, &subscription.method, (digest,)).await;
+        if let Err((code, msg)) = result {
+            log!(
+                Warn,
+                "digest push failed",
+                "subscription" => subscription.id,
+                "code" => format!("{:?}", code),
+                "error" => msg,
+            );
+        }
+    });
+}
+
+// Works through the locations for the previous UTC day, a batch per tick
+pub(crate) fn on_timer() {
+    let day = (time() / NANOS_PER_DAY).saturating_sub(1);
+    let cursor = DIGEST_PROGRESS.with(|p| {
+        let mut progress = p.borrow_mut();
+        if progress.day != day {
+            *progress = DigestProgress {
+                day,
+                cursor: None,
+                done: false,
+            };
+        }
+        (!progress.done).then(|| progress.cursor.clone())
+    });
+    let Some(mut cursor) = cursor else {
+        return;
+    };
+    let mut pushes = 0;
+    for _ in 0..LOCATIONS_PER_TICK {
+        if pushes >= MAX_PUSHES_PER_TICK {
+            break;
+        }
+        let Some(location) = rollups::next_location(cursor.as_deref()) else {
+            DIGEST_PROGRESS.with(|p| p.borrow_mut().done = true);
+            return;
+        };
+        let key = (StringKey(location.clone()), day);
+        let exists = DIGEST_STORAGE.with(|s| s.borrow().contains_key(&key));
+        if !exists {
+            if let Some(digest) = summarize(&location, day) {
+                for subscription in subscriptions_at(&location) {
+                    push(&digest, subscription);
+                    pushes += 1;
+                }
+                DIGEST_STORAGE.with(|s| s.borrow_mut().insert(key, digest));
+            }
+        }
+        cursor = Some(location);
+    }
+    DIGEST_PROGRESS.with(|p| p.borrow_mut().cursor = cursor);
+}
+
+// The digest of the UTC day containing `date`. Digests are made shortly after the day ends.
+#[ic_cdk::query]
+fn get_daily_digest(location: String, date: u64) -> Result<DailyDigest, Error> {
+    let location = aliases::location(location);
+    let day = date / NANOS_PER_DAY;
+    DIGEST_STORAGE
+        .with(|s| s.borrow().get(&(StringKey(location.clone()), day)))
+        .ok_or(Error::NotFound {
+            msg: format!(
+                "no digest of {} for the day starting at {}",
+                location,
+                day * NANOS_PER_DAY
+            ),
+        })
+}
+
+// Pushes every new digest of the location to `method` of `canister_id`, which must have the
+// type `(DailyDigest) -> ()`
+#[ic_cdk::update(guard = "accepts_writes")]
+fn subscribe_daily_digest(
+    location: String,
+    canister_id: Principal,
+    method: String,
+) -> Result<DigestSubscription, Error> {
+    capacity::ensure_room_for_optional_write()?;
+    if location.is_empty() || location.len() > MAX_LOCATION_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!("location must be 1 to {} bytes", MAX_LOCATION_LEN),
+            violations: None,
+        });
+    }
+    if method.is_empty() || method.len() > MAX_METHOD_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!("method must be 1 to {} bytes", MAX_METHOD_LEN),
+            violations: None,
+        });
+    }
+    let owner = ic_cdk::caller();
+    if list_my_digest_subscriptions().len() >= MAX_SUBSCRIPTIONS_PER_OWNER {
+        return Err(Error::TooLarge {
+            msg: format!(
+                "a principal can have at most {} digest subscriptions",
+                MAX_SUBSCRIPTIONS_PER_OWNER
+            ),
+        });
+    }
+    let subscription = DigestSubscription {
+        id: next_id(&DIGEST_SUBSCRIPTION_ID_COUNTER),
+        owner,
+        location,
+        canister_id,
+        method,
+        created_at: time(),
+    };
+    DIGEST_SUBSCRIPTION_STORAGE.with(|s| {
+        s.borrow_mut().insert(
+            (StringKey(subscription.location.clone()), subscription.id),
+            subscription.clone(),
+        )
+    });
+    Ok(subscription)
+}
+
+// Removes a digest subscription (owner or controllers)
+#[ic_cdk::update(guard = "accepts_writes")]
+fn unsubscribe_daily_digest(id: u64) -> Result<DigestSubscription, Error> {
+    let subscription = DIGEST_SUBSCRIPTION_STORAGE
+        .with(|s| {
+            s.borrow()
+                .iter()
+                .map(|(_, subscription)| subscription)
+                .find(|subscription| subscription.id == id)
+        })
+        .ok_or(Error::NotFound {
+            msg: format!("digest subscription with id={} not found", id),
+        })?;
+    let caller = ic_cdk::caller();
+    if subscription.owner != caller && !ic_cdk::api::is_controller(&caller) {
+        return Err(Error::Unauthorized {
+            msg: format!("caller does not own digest subscription with id={}", id),
+        });
+    }
+    DIGEST_SUBSCRIPTION_STORAGE.with(|s| {
+        s.borrow_mut()
+            .remove(&(StringKey(subscription.location.clone()), id))
+    });
+    Ok(subscription)
+}
+
+#[ic_cdk::query]
+fn list_my_digest_subscriptions() -> Vec<DigestSubscription> {
+    let caller = ic_cdk::caller();
+    DIGEST_SUBSCRIPTION_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, subscription)| subscription)
+            .filter(|subscription| subscription.owner == caller)
+            .collect()
+    })
+}
@@ -0,0 +1,188 @@
+use crate::aggregates::level_in_micrograms;
+use crate::{aliases, dedup, organizations, Error, Pollutant};
+
+const MIN_POINTS: u32 = 3;
+const MAX_POINTS: u32 = 5_000;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum DownsamplingMethod {
+    // Largest-Triangle-Three-Buckets: keeps actual readings that preserve the visual shape,
+    // peaks included
+    Lttb,
+    // Mean time and level of the readings in equal time buckets
+    BucketMean,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct SeriesPoint {
+    timestamp: u64,
+    // µg/m³
+    level: f64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct DownsampledSeries {
+    location: String,
+    pollutant: Pollutant,
+    method: DownsamplingMethod,
+    // Readings of the pollutant in the range, before downsampling
+    readings: u64,
+    points: Vec<SeriesPoint>,
+}
+
+fn lttb(points: &[SeriesPoint], threshold: usize) -> Vec<SeriesPoint> {
+    let n = points.len();
+    if threshold >= n {
+        return points.to_vec();
+    }
+    // Relative to the first reading, so the areas keep their precision
+    let origin = points[0].timestamp;
+    let x = |point: &SeriesPoint| (point.timestamp - origin) as f64;
+    let every = (n - 2) as f64 / (threshold - 2) as f64;
+    let mut sampled = Vec::with_capacity(threshold);
+    sampled.push(points[0]);
+    let mut a = 0;
+    for i in 0..threshold - 2 {
+        let next_start = ((i + 1) as f64 * every).floor() as usize + 1;
+        let next_end = (((i + 2) as f64 * every).floor() as usize + 1).min(n);
+        let next = &points[next_start..next_end];
+        let average_x = next.iter().map(x).sum::<f64>() / next.len() as f64;
+        let average_y = next.iter().map(|point| point.level).sum::<f64>() / next.len() as f64;
+
+        let start = (i as f64 * every).floor() as usize + 1;
+        let end = next_start;
+        let (ax, ay) = (x(&points[a]), points[a].level);
+        let mut largest = (start, -1.0);
+        for (j, point) in points.iter().enumerate().take(end).skip(start) {
+            let area =
+                ((ax - average_x) * (point.level - ay) - (ax - x(point)) * (average_y - ay)).abs();
+            if area > largest.1 {
+                largest = (j, area);
+            }
+        }
+        sampled.push(points[largest.0]);
+        a = largest.0;
+    }
+    sampled.push(points[n - 1]);
+    sampled
+}
+
+fn bucket_mean(
+    points: &[SeriesPoint],
+    buckets: usize,
+    start_timestamp: u64,
+    end_timestamp: u64,
+) -> Vec<SeriesPoint> {
+    if buckets >= points.len() {
+        return points.to_vec();
+    }
+    let width = (end_timestamp - start_timestamp) / buckets as u64 + 1;
+    let mut sums: Vec<(u128, f64, u64)> = vec![(0, 0.0, 0); buckets];
+    for point in points {
+        let bucket = ((point.timestamp - start_timestamp) / width) as usize;
+        let sum = &mut sums[bucket.min(buckets - 1)];
+        sum.0 += point.timestamp as u128;
+        sum.1 += point.level;
+        sum.2 += 1;
+    }
+    sums.into_iter()
+        .filter(|(_, _, count)| *count > 0)
+        .map(|(timestamps, levels, count)| SeriesPoint {
+            timestamp: (timestamps / count as u128) as u64,
+            level: levels / count as f64,
+        })
+        .collect()
+}
+
+// A pollutant's levels at a location over a period, reduced to at most `max_points` for
+// charting. Ranges with fewer readings are returned as they are.
+#[ic_cdk::query]
+fn get_downsampled_time_series(
+    location: String,
+    pollutant: Pollutant,
+    start_timestamp: u64,
+    end_timestamp: u64,
+    max_points: u32,
+    method: Option<DownsamplingMethod>,
+) -> Result<DownsampledSeries, Error> {
+    if end_timestamp < start_timestamp {
+        return Err(Error::InvalidInput {
+            msg: "end_timestamp must not be before start_timestamp".to_string(),
+            violations: None,
+        });
+    }
+    if !(MIN_POINTS..=MAX_POINTS).contains(&max_points) {
+        return Err(Error::InvalidInput {
+            msg: format!("max_points must be {} to {}", MIN_POINTS, MAX_POINTS),
+            violations: None,
+        });
+    }
+    let location = aliases::location(location);
+    let pollutant = pollutant.normalized();
+    let method = method.unwrap_or(DownsamplingMethod::Lttb);
+    let points: Vec<SeriesPoint> =
+        dedup::live_readings_between(&location, start_timestamp, end_timestamp)
+            .into_iter()
+            .filter(|data| {
+                data.is_published() && organizations::is_visible_to_caller(data.organization_id)
+            })
+            .filter_map(|data| {
+                Some(SeriesPoint {
+                    timestamp: data.timestamp,
+                    level: level_in_micrograms(&data, &pollutant)?,
+                })
+            })
+            .collect();
+    let readings = points.len() as u64;
+    let points = match method {
+        DownsamplingMethod::Lttb => lttb(&points, max_points as usize),
+        DownsamplingMethod::BucketMean => {
+            bucket_mean(&points, max_points as usize, start_timestamp, end_timestamp)
+        }
+    };
+    Ok(DownsampledSeries {
+        location,
+        pollutant,
+        method,
+        readings,
+        points,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(levels: &[f64]) -> Vec<SeriesPoint> {
+        levels
+            .iter()
+            .enumerate()
+            .map(|(i, level)| SeriesPoint {
+                timestamp: i as u64 * 60,
+                level: *level,
+            })
+            .collect()
+    }
+
+    fn timestamps(points: &[SeriesPoint]) -> Vec<u64> {
+        points.iter().map(|point| point.timestamp).collect()
+    }
+
+    #[test]
+    fn lttb_of_an_empty_series_is_empty() {
+        assert!(lttb(&[], 3).is_empty());
+    }
+
+    #[test]
+    fn lttb_keeps_series_of_one_or_two_points() {
+        assert_eq!(timestamps(&lttb(&series(&[1.0]), 3)), vec![0]);
+        assert_eq!(timestamps(&lttb(&series(&[1.0, 2.0]), 3)), vec![0, 60]);
+    }
+
+    #[test]
+    fn lttb_keeps_the_ends_and_the_peak() {
+        let points = series(&[1.0, 1.0, 1.0, 1.0, 50.0, 1.0, 1.0, 1.0, 1.0, 1.0]);
+        let sampled = lttb(&points, 3);
+        assert_eq!(timestamps(&sampled), vec![0, 240, 540]);
+    }
+}
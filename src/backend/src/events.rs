@@ -0,0 +1,92 @@
+use crate::capacity::CapacityLevel;
+use crate::{
+    get_memory, next_id, IdCell, Memory, EVENT_ID_COUNTER_MEMORY_ID, EVENT_STORAGE_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::ops::Bound;
+
+// Oldest events are dropped beyond this many
+const MAX_RETAINED_EVENTS: u64 = 10_000;
+const MAX_EVENTS_PER_PAGE: u32 = 500;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum Milestone {
+    StreakDays { days: u32 },
+    Readings { count: u64 },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum EventKind {
+    MilestoneReached {
+        contributor: Principal,
+        milestone: Milestone,
+    },
+    // The notice, with its reason, is listed by get_retractions
+    ReadingRetracted {
+        reading_id: u64,
+    },
+    // Stable memory usage moved to another capacity level
+    StableMemoryCapacity {
+        level: CapacityLevel,
+        used_pages: u64,
+        ceiling_pages: u64,
+    },
+    // The cycles balance fell under the configured floor
+    CyclesLow {
+        balance: u128,
+        floor_cycles: u128,
+    },
+}
+
+// Something subscribers (apps, alerting) may want to react to. Clients poll with the last id
+// they have seen.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Event {
+    id: u64,
+    kind: EventKind,
+    timestamp: u64,
+}
+
+impl_bounded_storable!(Event, 512);
+
+thread_local! {
+    static EVENT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(get_memory(EVENT_ID_COUNTER_MEMORY_ID), 0)
+            .expect("Cannot create a counter for events")
+    );
+
+    static EVENT_STORAGE: RefCell<StableBTreeMap<u64, Event, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(EVENT_STORAGE_MEMORY_ID)));
+}
+
+pub(crate) fn emit(kind: EventKind) {
+    let id = next_id(&EVENT_ID_COUNTER);
+    let event = Event {
+        id,
+        kind,
+        timestamp: time(),
+    };
+    EVENT_STORAGE.with(|s| {
+        let mut storage = s.borrow_mut();
+        storage.insert(id, event);
+        if let Some(expired) = id.checked_sub(MAX_RETAINED_EVENTS) {
+            storage.remove(&expired);
+        }
+    });
+}
+
+// Events with ids greater than `after`, oldest first
+#[ic_cdk::query]
+fn get_events(after: Option<u64>, limit: u32) -> Vec<Event> {
+    let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+    EVENT_STORAGE.with(|s| {
+        s.borrow()
+            .range((start, Bound::Unbounded))
+            .take(limit.min(MAX_EVENTS_PER_PAGE) as usize)
+            .map(|(_, event)| event)
+            .collect()
+    })
+}
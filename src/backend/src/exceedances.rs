@@ -0,0 +1,124 @@
+use crate::aliases;
+use crate::rollups;
+use crate::standards::{GuidelineStandard, Limit};
+use crate::{Error, Pollutant};
+use std::collections::{BTreeMap, BTreeSet};
+
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+const HOURS_PER_DAY: u64 = 24;
+
+// Exceedances of one limit over the period. For 1- and 8-hour limits an hour exceeds when the
+// window ending with it does, and a day when any of its hours does. For 24-hour limits a UTC day
+// exceeds when its mean does. Windows need data for 75% of their hours.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct LimitExceedance {
+    pollutant: Pollutant,
+    averaging_hours: u32,
+    // µg/m³
+    limit: f64,
+    // None for 24-hour limits
+    exceedance_hours: Option<u64>,
+    exceedance_days: u64,
+    // Days with enough data to be assessed
+    assessed_days: u64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ExceedanceReport {
+    location: String,
+    standard: GuidelineStandard,
+    start_timestamp: u64,
+    end_timestamp: u64,
+    limits: Vec<LimitExceedance>,
+}
+
+fn min_hours(averaging_hours: u32) -> usize {
+    (averaging_hours as usize * 3).div_ceil(4)
+}
+
+fn assess(
+    limit: &Limit,
+    hourly: &BTreeMap<u64, f64>,
+    start_timestamp: u64,
+    end_timestamp: u64,
+) -> LimitExceedance {
+    let in_period = |hour_start: u64| hour_start >= start_timestamp && hour_start <= end_timestamp;
+    let day_of = |hour_start: u64| hour_start / (HOURS_PER_DAY * NANOS_PER_HOUR);
+    let mut exceedance_hours = None;
+    let mut exceeding_days = BTreeSet::new();
+    let mut assessed_days = BTreeSet::new();
+
+    if limit.averaging_hours as u64 >= HOURS_PER_DAY {
+        let mut days: BTreeMap<u64, Vec<f64>> = BTreeMap::new();
+        for (hour_start, mean) in hourly.range(start_timestamp..=end_timestamp) {
+            days.entry(day_of(*hour_start)).or_default().push(*mean);
+        }
+        for (day, means) in days {
+            if means.len() < min_hours(HOURS_PER_DAY as u32) {
+                continue;
+            }
+            assessed_days.insert(day);
+            if means.iter().sum::<f64>() / means.len() as f64 > limit.micrograms_per_cubic_meter {
+                exceeding_days.insert(day);
+            }
+        }
+    } else {
+        let window = (limit.averaging_hours as u64 - 1) * NANOS_PER_HOUR;
+        let mut hours = 0;
+        for hour_start in hourly.keys().copied().filter(|hour| in_period(*hour)) {
+            let means: Vec<f64> = hourly
+                .range(hour_start.saturating_sub(window)..=hour_start)
+                .map(|(_, mean)| *mean)
+                .collect();
+            if means.len() < min_hours(limit.averaging_hours) {
+                continue;
+            }
+            assessed_days.insert(day_of(hour_start));
+            if means.iter().sum::<f64>() / means.len() as f64 > limit.micrograms_per_cubic_meter {
+                hours += 1;
+                exceeding_days.insert(day_of(hour_start));
+            }
+        }
+        exceedance_hours = Some(hours);
+    }
+
+    LimitExceedance {
+        pollutant: limit.pollutant.clone(),
+        averaging_hours: limit.averaging_hours,
+        limit: limit.micrograms_per_cubic_meter,
+        exceedance_hours,
+        exceedance_days: exceeding_days.len() as u64,
+        assessed_days: assessed_days.len() as u64,
+    }
+}
+
+// Hours and days above the short-term limits of the WHO 2021 guidelines or the NAAQS, per
+// pollutant, over a period of up to a year. Computed from the hourly rollups.
+#[ic_cdk::query]
+fn get_exceedance_report(
+    location: String,
+    standard: GuidelineStandard,
+    start_timestamp: u64,
+    end_timestamp: u64,
+) -> Result<ExceedanceReport, Error> {
+    let location = aliases::location(location);
+    // Windows reach back before the period, at most a day
+    let lookback_start = start_timestamp.saturating_sub((HOURS_PER_DAY - 1) * NANOS_PER_HOUR);
+    let mut limits = Vec::new();
+    for limit in standard.limits() {
+        let hourly = rollups::hourly_pollutant_averages(
+            &location,
+            &limit.pollutant,
+            lookback_start,
+            end_timestamp,
+        )?;
+        limits.push(assess(limit, &hourly, start_timestamp, end_timestamp));
+    }
+    Ok(ExceedanceReport {
+        location,
+        standard,
+        start_timestamp,
+        end_timestamp,
+        limits,
+    })
+}
@@ -0,0 +1,169 @@
+use crate::filters::{self, ReadingFilter};
+use crate::units;
+use crate::{
+    accepts_writes, capacity, clear_stable_map, get_memory, is_visible_to_caller, next_id,
+    AirQualityData, Error, IdCell, Memory, AIR_QUALITY_STORAGE, EXPORT_ID_COUNTER_MEMORY_ID,
+    EXPORT_STORAGE_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::ops::Bound;
+
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+const EXPORT_TTL_NANOS: u64 = 24 * NANOS_PER_HOUR;
+// Chunks cover this many ids. Records are at most 1 KiB, so a chunk stays well below the 2 MiB
+// reply limit.
+const IDS_PER_CHUNK: u64 = 1_000;
+const MAX_EXPORTS_PER_OWNER: usize = 10;
+const MAX_LOCATION_LEN: usize = 200;
+
+// A full-dataset pull split into chunks that each fit in one reply. Chunk `i` holds the
+// matching readings with ids from `i * 1000` to `i * 1000 + 999`, up to the last id stored when
+// the export started, as they are when the chunk is fetched.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Export {
+    id: u64,
+    owner: Principal,
+    filter: ReadingFilter,
+    // Highest id included; None if there were no readings
+    last_id: Option<u64>,
+    chunk_count: u64,
+    created_at: u64,
+    expires_at: u64,
+}
+
+impl_bounded_storable!(Export, 4096);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ExportChunk {
+    export_id: u64,
+    chunk_index: u64,
+    // Chunks may be empty where the filter matches nothing
+    readings: Vec<AirQualityData>,
+    is_last: bool,
+}
+
+thread_local! {
+    static EXPORT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(get_memory(EXPORT_ID_COUNTER_MEMORY_ID), 0)
+            .expect("Cannot create a counter for exports")
+    );
+
+    static EXPORT_STORAGE: RefCell<StableBTreeMap<u64, Export, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(EXPORT_STORAGE_MEMORY_ID)));
+}
+
+// Drops every export, for a storage reset
+pub(crate) fn reset() {
+    EXPORT_STORAGE.with(|s| clear_stable_map(s, EXPORT_STORAGE_MEMORY_ID));
+}
+
+// Checks the filter fits in an export record
+fn validate_filter(filter: &ReadingFilter) -> Result<(), Error> {
+    match filter {
+        ReadingFilter::Location { location, .. } if location.len() > MAX_LOCATION_LEN => {
+            Err(Error::InvalidInput {
+                msg: format!("location must be at most {} bytes", MAX_LOCATION_LEN),
+                violations: None,
+            })
+        }
+        ReadingFilter::PollutantLevels { conditions, .. } => {
+            filters::validate_conditions(conditions)
+        }
+        _ => Ok(()),
+    }
+}
+
+fn remove_expired(now: u64) {
+    EXPORT_STORAGE.with(|s| {
+        let expired: Vec<u64> = s
+            .borrow()
+            .iter()
+            .filter(|(_, export)| export.expires_at <= now)
+            .map(|(id, _)| id)
+            .collect();
+        let mut storage = s.borrow_mut();
+        for id in expired {
+            storage.remove(&id);
+        }
+    });
+}
+
+// Starts an export of the readings the filter matches. Fetch its chunks with get_export_chunk
+// within 24 hours.
+#[ic_cdk::update(guard = "accepts_writes")]
+fn start_export(filter: ReadingFilter) -> Result<Export, Error> {
+    validate_filter(&filter)?;
+    capacity::ensure_room_for_optional_write()?;
+    let now = time();
+    remove_expired(now);
+    let owner = ic_cdk::caller();
+    let exports = EXPORT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, export)| export.owner == owner)
+            .count()
+    });
+    if exports >= MAX_EXPORTS_PER_OWNER {
+        return Err(Error::TooLarge {
+            msg: format!(
+                "a principal can have at most {} exports at a time",
+                MAX_EXPORTS_PER_OWNER
+            ),
+        });
+    }
+    let last_id = AIR_QUALITY_STORAGE
+        .with(|service| service.borrow().last_key_value())
+        .map(|(id, _)| id);
+    let export = Export {
+        id: next_id(&EXPORT_ID_COUNTER),
+        owner,
+        filter,
+        last_id,
+        chunk_count: last_id.map_or(0, |id| id / IDS_PER_CHUNK + 1),
+        created_at: now,
+        expires_at: now + EXPORT_TTL_NANOS,
+    };
+    EXPORT_STORAGE.with(|s| s.borrow_mut().insert(export.id, export.clone()));
+    Ok(export)
+}
+
+// One chunk of an export, fetched by the principal that started it. Chunks can be fetched in
+// any order and again.
+#[ic_cdk::query]
+fn get_export_chunk(export_id: u64, chunk_index: u64) -> Result<ExportChunk, Error> {
+    let export = EXPORT_STORAGE
+        .with(|s| s.borrow().get(&export_id))
+        .filter(|export| export.expires_at > time() && export.owner == ic_cdk::caller())
+        .ok_or(Error::NotFound {
+            msg: format!("export with id={} not found", export_id),
+        })?;
+    let (Some(last_id), true) = (export.last_id, chunk_index < export.chunk_count) else {
+        return Err(Error::InvalidInput {
+            msg: format!("the export has {} chunks", export.chunk_count),
+            violations: None,
+        });
+    };
+    let start = chunk_index * IDS_PER_CHUNK;
+    let end = (start + IDS_PER_CHUNK - 1).min(last_id);
+    let unit = export.filter.unit();
+    let predicate = export.filter.predicate();
+    let readings = AIR_QUALITY_STORAGE.with(|service| {
+        service
+            .borrow()
+            .range((Bound::Included(start), Bound::Included(end)))
+            .map(|(_, data)| data)
+            .filter(|data| !data.is_deleted() && is_visible_to_caller(data))
+            .map(|data| units::in_unit(data, unit))
+            .filter(|data| predicate(data))
+            .collect()
+    });
+    Ok(ExportChunk {
+        export_id,
+        chunk_index,
+        readings,
+        is_last: chunk_index + 1 == export.chunk_count,
+    })
+}
@@ -0,0 +1,197 @@
+use crate::aliases;
+use crate::annotations::{self, AnnotationSource};
+use crate::stations::{self, Station};
+use crate::{
+    accepts_writes, get_memory, next_id, Error, IdCell, Memory, FIELD_VISIT_ID_COUNTER_MEMORY_ID,
+    FIELD_VISIT_STORAGE_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_TECHNICIAN_LEN: usize = 200;
+const MAX_NOTES_LEN: usize = 2000;
+// Hex digest of the photos, e.g. SHA-256
+const MAX_PHOTOS_HASH_LEN: usize = 128;
+// Readings this long after a visit starts may be affected by the work on the sensor
+const MAINTENANCE_WINDOW_NANOS: u64 = 60 * 60 * 1_000_000_000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum FieldVisitKind {
+    CheckIn {
+        technician: String,
+        notes: String,
+        photos_hash: Option<String>,
+    },
+    // The sensor of station `old_sensor` was replaced by that of `new_sensor`
+    SensorSwap {
+        old_sensor: String,
+        new_sensor: String,
+    },
+}
+
+// A technician's visit to a station. Its maintenance window is annotated on the station's
+// location, so the data shows when the sensor was being worked on.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct FieldVisit {
+    id: u64,
+    station_id: String,
+    location: String,
+    kind: FieldVisitKind,
+    maintenance_start: u64,
+    maintenance_end: u64,
+    annotation_id: u64,
+    recorded_by: Principal,
+    recorded_at: u64,
+}
+
+impl_bounded_storable!(FieldVisit, 4096);
+
+thread_local! {
+    static FIELD_VISIT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(get_memory(FIELD_VISIT_ID_COUNTER_MEMORY_ID), 0)
+            .expect("Cannot create a counter for field visits")
+    );
+
+    static FIELD_VISIT_STORAGE: RefCell<StableBTreeMap<u64, FieldVisit, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(FIELD_VISIT_STORAGE_MEMORY_ID)));
+}
+
+fn managed_station(station_id: &str) -> Result<Station, Error> {
+    let station = stations::get_station(station_id).ok_or(Error::NotFound {
+        msg: format!("station {} not found", station_id),
+    })?;
+    if !station.is_managed_by(&ic_cdk::caller()) {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "only the owner of station {} or a controller can record visits",
+                station_id
+            ),
+        });
+    }
+    Ok(station)
+}
+
+fn record_visit(
+    station_id: String,
+    location: String,
+    kind: FieldVisitKind,
+    text: &str,
+) -> FieldVisit {
+    let id = next_id(&FIELD_VISIT_ID_COUNTER);
+    let maintenance_start = time();
+    let maintenance_end = maintenance_start + MAINTENANCE_WINDOW_NANOS;
+    let annotation_id = annotations::annotate(
+        &location,
+        maintenance_start,
+        Some(maintenance_end),
+        AnnotationSource::FieldVisit { visit_id: id },
+        text,
+    );
+    let visit = FieldVisit {
+        id,
+        station_id,
+        location,
+        kind,
+        maintenance_start,
+        maintenance_end,
+        annotation_id,
+        recorded_by: ic_cdk::caller(),
+        recorded_at: maintenance_start,
+    };
+    FIELD_VISIT_STORAGE.with(|s| s.borrow_mut().insert(visit.id, visit.clone()));
+    visit
+}
+
+// Records a technician's visit to a station (its owner and controllers only)
+#[ic_cdk::update(guard = "accepts_writes")]
+fn check_in(
+    station_id: String,
+    technician: String,
+    notes: String,
+    photos_hash: Option<String>,
+) -> Result<FieldVisit, Error> {
+    let station = managed_station(&station_id)?;
+    if technician.is_empty() || technician.len() > MAX_TECHNICIAN_LEN || notes.len() > MAX_NOTES_LEN
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "technician must be 1 to {} bytes and notes at most {} bytes",
+                MAX_TECHNICIAN_LEN, MAX_NOTES_LEN
+            ),
+            violations: None,
+        });
+    }
+    if photos_hash.as_ref().is_some_and(|hash| {
+        hash.is_empty()
+            || hash.len() > MAX_PHOTOS_HASH_LEN
+            || !hash.chars().all(|c| c.is_ascii_hexdigit())
+    }) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "photos_hash must be 1 to {} hex digits",
+                MAX_PHOTOS_HASH_LEN
+            ),
+            violations: None,
+        });
+    }
+    let text = format!("Field visit by {}: {}", technician, notes);
+    Ok(record_visit(
+        station_id,
+        station.location().to_string(),
+        FieldVisitKind::CheckIn {
+            technician,
+            notes,
+            photos_hash,
+        },
+        &text,
+    ))
+}
+
+// Records that the sensor of one station was replaced by another's, at the old station's
+// location (owner and controllers of both stations only)
+#[ic_cdk::update(guard = "accepts_writes")]
+fn record_swap(old_sensor: String, new_sensor: String) -> Result<FieldVisit, Error> {
+    if old_sensor == new_sensor {
+        return Err(Error::InvalidInput {
+            msg: "old_sensor and new_sensor must differ".to_string(),
+            violations: None,
+        });
+    }
+    let old_station = managed_station(&old_sensor)?;
+    managed_station(&new_sensor)?;
+    let text = format!("Sensor {} replaced by {}", old_sensor, new_sensor);
+    Ok(record_visit(
+        old_sensor.clone(),
+        old_station.location().to_string(),
+        FieldVisitKind::SensorSwap {
+            old_sensor,
+            new_sensor,
+        },
+        &text,
+    ))
+}
+
+continued_page!(FieldVisitPage, FieldVisit);
+
+// Visits of a station, oldest first
+#[ic_cdk::query]
+fn get_field_visits(
+    station_id: String,
+    continuation: Option<String>,
+) -> Result<FieldVisitPage, Error> {
+    let station_id = aliases::station_id(station_id);
+    if !stations::is_station_visible(&station_id) {
+        return FieldVisitPage::new(Vec::new(), continuation);
+    }
+    FIELD_VISIT_STORAGE.with(|s| {
+        FieldVisitPage::new(
+            s.borrow()
+                .iter()
+                .filter(|(_, visit)| visit.station_id == station_id)
+                .map(|(_, visit)| visit),
+            continuation,
+        )
+    })
+}
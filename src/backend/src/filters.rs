@@ -0,0 +1,382 @@
+use crate::paging::{self, ReadingPage};
+use crate::sources::SourceFilter;
+use crate::units::{self, ConcentrationUnit};
+use crate::{
+    air_quality_page, aliases, aqi_index, is_visible_to_caller, location_search::LocationQuery,
+    AirQualityData, Error, Pollutant, AIR_QUALITY_STORAGE,
+};
+use std::cmp::Ordering;
+
+const MAX_POLLUTANT_RANGES: usize = 16;
+
+// Bounds are inclusive and in the unit of the query
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct PollutantCondition {
+    pollutant: Pollutant,
+    min_level: f64,
+    max_level: f64,
+}
+
+// The filters of the reading queries, for counting what they would return
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum ReadingFilter {
+    All,
+    Location {
+        location: String,
+        fuzzy: Option<bool>,
+    },
+    WeatherConditions {
+        min_temperature: f64,
+        max_temperature: f64,
+        min_humidity: f64,
+        max_humidity: f64,
+        min_wind_speed: f64,
+        max_wind_speed: f64,
+    },
+    // The bounds are in `unit`, as in get_air_quality_data_by_pollutant_level
+    PollutantLevel {
+        pollutant: Pollutant,
+        min_level: f64,
+        max_level: f64,
+        unit: Option<ConcentrationUnit>,
+    },
+    // Every condition must hold, as in get_air_quality_data_by_pollutant_levels
+    PollutantLevels {
+        conditions: Vec<PollutantCondition>,
+        unit: Option<ConcentrationUnit>,
+    },
+    TimestampRange {
+        start_timestamp: u64,
+        end_timestamp: u64,
+    },
+    Source(SourceFilter),
+}
+
+impl ReadingFilter {
+    // Whether a record, with its levels in the filter's unit, passes the filter
+    pub(crate) fn predicate(self) -> Box<dyn Fn(&AirQualityData) -> bool> {
+        match self {
+            ReadingFilter::All => Box::new(|_| true),
+            ReadingFilter::Location { location, fuzzy } => {
+                let location = aliases::location(location);
+                let query = LocationQuery::new(&location, fuzzy.unwrap_or(false));
+                Box::new(move |data| query.matches(&data.location))
+            }
+            ReadingFilter::WeatherConditions {
+                min_temperature,
+                max_temperature,
+                min_humidity,
+                max_humidity,
+                min_wind_speed,
+                max_wind_speed,
+            } => Box::new(move |data| {
+                let weather = &data.weather_conditions;
+                weather.temperature >= min_temperature
+                    && weather.temperature <= max_temperature
+                    && weather.humidity >= min_humidity
+                    && weather.humidity <= max_humidity
+                    && weather.wind_speed >= min_wind_speed
+                    && weather.wind_speed <= max_wind_speed
+            }),
+            ReadingFilter::PollutantLevel {
+                pollutant,
+                min_level,
+                max_level,
+                ..
+            } => levels_within(vec![PollutantCondition {
+                pollutant,
+                min_level,
+                max_level,
+            }]),
+            ReadingFilter::PollutantLevels { conditions, .. } => levels_within(conditions),
+            ReadingFilter::TimestampRange {
+                start_timestamp,
+                end_timestamp,
+            } => Box::new(move |data| {
+                data.timestamp >= start_timestamp && data.timestamp <= end_timestamp
+            }),
+            ReadingFilter::Source(source) => {
+                Box::new(move |data| source.matches(data.source.as_ref()))
+            }
+        }
+    }
+
+    pub(crate) fn unit(&self) -> Option<ConcentrationUnit> {
+        match self {
+            ReadingFilter::PollutantLevel { unit, .. }
+            | ReadingFilter::PollutantLevels { unit, .. } => *unit,
+            _ => None,
+        }
+    }
+}
+
+fn levels_within(conditions: Vec<PollutantCondition>) -> Box<dyn Fn(&AirQualityData) -> bool> {
+    let conditions: Vec<PollutantCondition> = conditions
+        .into_iter()
+        .map(|condition| PollutantCondition {
+            pollutant: condition.pollutant.normalized(),
+            ..condition
+        })
+        .collect();
+    Box::new(move |data| {
+        conditions.iter().all(|condition| {
+            data.pollutant_levels
+                .get(&condition.pollutant)
+                .is_some_and(|level| *level >= condition.min_level && *level <= condition.max_level)
+        })
+    })
+}
+
+pub(crate) fn validate_conditions(conditions: &[PollutantCondition]) -> Result<(), Error> {
+    if conditions.is_empty() || conditions.len() > MAX_POLLUTANT_RANGES {
+        return Err(Error::InvalidInput {
+            msg: format!("give 1 to {} pollutant conditions", MAX_POLLUTANT_RANGES),
+            violations: None,
+        });
+    }
+    Ok(())
+}
+
+// How many live records the caller may see pass the filter, for totals and pagination
+#[ic_cdk::query]
+fn count_readings(filter: ReadingFilter) -> u64 {
+    let unit = filter.unit();
+    let predicate = filter.predicate();
+    AIR_QUALITY_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, data)| data)
+            .filter(|data| !data.is_deleted() && is_visible_to_caller(data))
+            .filter(|data| predicate(&units::in_unit(data.clone(), unit)))
+            .count() as u64
+    })
+}
+
+// Bounds are inclusive; a missing bound is open
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct ValueRange {
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl ValueRange {
+    fn contains(&self, value: f64) -> bool {
+        self.min.is_none_or(|min| value >= min) && self.max.is_none_or(|max| value <= max)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.min.zip(self.max).is_some_and(|(min, max)| min > max)
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct PollutantRange {
+    pollutant: Pollutant,
+    // In the criteria's unit
+    range: ValueRange,
+}
+
+// Ties are broken by id
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum SortOrder {
+    IdAscending,
+    TimestampAscending,
+    TimestampDescending,
+    AirQualityIndexAscending,
+    AirQualityIndexDescending,
+    // Alphabetical
+    Location,
+}
+
+// What query_readings matches. Every criterion that is set must hold.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct QueryCriteria {
+    location: Option<String>,
+    fuzzy_location: Option<bool>,
+    station_id: Option<String>,
+    start_timestamp: Option<u64>,
+    end_timestamp: Option<u64>,
+    air_quality_index: Option<ValueRange>,
+    // A reading must report every listed pollutant within its range
+    pollutant_levels: Option<Vec<PollutantRange>>,
+    temperature: Option<ValueRange>,
+    humidity: Option<ValueRange>,
+    wind_speed: Option<ValueRange>,
+    source: Option<SourceFilter>,
+    // Unit of the pollutant ranges and of the returned levels
+    unit: Option<ConcentrationUnit>,
+    // Id order by default
+    sort: Option<SortOrder>,
+    // `next_cursor` of the previous page
+    cursor: Option<u64>,
+}
+
+impl QueryCriteria {
+    fn validate(&self) -> Result<(), Error> {
+        let pollutant_ranges = self.pollutant_levels.as_deref().unwrap_or_default();
+        if pollutant_ranges.len() > MAX_POLLUTANT_RANGES {
+            return Err(Error::InvalidInput {
+                msg: format!("at most {} pollutant ranges", MAX_POLLUTANT_RANGES),
+                violations: None,
+            });
+        }
+        let empty_range = [
+            self.air_quality_index,
+            self.temperature,
+            self.humidity,
+            self.wind_speed,
+        ]
+        .iter()
+        .flatten()
+        .chain(pollutant_ranges.iter().map(|range| &range.range))
+        .any(ValueRange::is_empty);
+        let empty_period = self
+            .start_timestamp
+            .zip(self.end_timestamp)
+            .is_some_and(|(start, end)| start > end);
+        if empty_range || empty_period {
+            return Err(Error::InvalidInput {
+                msg: "every range must have its minimum at most its maximum".to_string(),
+                violations: None,
+            });
+        }
+        Ok(())
+    }
+
+    fn predicate(self) -> Box<dyn Fn(&AirQualityData) -> bool> {
+        let location = self.location.map(|location| {
+            let location = aliases::location(location);
+            LocationQuery::new(&location, self.fuzzy_location.unwrap_or(false))
+        });
+        let station_id = self.station_id.map(aliases::station_id);
+        let pollutant_ranges: Vec<(Pollutant, ValueRange)> = self
+            .pollutant_levels
+            .unwrap_or_default()
+            .into_iter()
+            .map(|range| (range.pollutant.normalized(), range.range))
+            .collect();
+        let in_range =
+            |range: Option<ValueRange>, value: f64| range.is_none_or(|range| range.contains(value));
+        Box::new(move |data| {
+            let weather = &data.weather_conditions;
+            location
+                .as_ref()
+                .is_none_or(|query| query.matches(&data.location))
+                && station_id
+                    .as_ref()
+                    .is_none_or(|station_id| data.station_id.as_ref() == Some(station_id))
+                && self
+                    .start_timestamp
+                    .is_none_or(|start| data.timestamp >= start)
+                && self.end_timestamp.is_none_or(|end| data.timestamp <= end)
+                && in_range(self.air_quality_index, data.air_quality_index as f64)
+                && in_range(self.temperature, weather.temperature)
+                && in_range(self.humidity, weather.humidity)
+                && in_range(self.wind_speed, weather.wind_speed)
+                && pollutant_ranges.iter().all(|(pollutant, range)| {
+                    data.pollutant_levels
+                        .get(pollutant)
+                        .is_some_and(|level| range.contains(*level))
+                })
+                && self
+                    .source
+                    .as_ref()
+                    .is_none_or(|source| source.matches(data.source.as_ref()))
+        })
+    }
+}
+
+fn compare(a: &AirQualityData, b: &AirQualityData, sort: SortOrder) -> Ordering {
+    let ordering = match sort {
+        SortOrder::IdAscending => Ordering::Equal,
+        SortOrder::TimestampAscending => a.timestamp.cmp(&b.timestamp),
+        SortOrder::TimestampDescending => b.timestamp.cmp(&a.timestamp),
+        SortOrder::AirQualityIndexAscending => a.air_quality_index.cmp(&b.air_quality_index),
+        SortOrder::AirQualityIndexDescending => b.air_quality_index.cmp(&a.air_quality_index),
+        SortOrder::Location => a.location.cmp(&b.location),
+    };
+    ordering.then(a.id.cmp(&b.id))
+}
+
+// A page of the live records among `records` the caller may see that match `predicate`, in
+// `sort` order, after the record `cursor` names. A cursor record that has changed since the
+// previous page keeps its place by its current values.
+fn page_of<F>(
+    records: impl Iterator<Item = AirQualityData>,
+    cursor: Option<u64>,
+    unit: Option<ConcentrationUnit>,
+    sort: SortOrder,
+    predicate: F,
+) -> Result<ReadingPage, Error>
+where
+    F: Fn(&AirQualityData) -> bool,
+{
+    let after = match cursor {
+        Some(id) if sort != SortOrder::IdAscending => Some(
+            AIR_QUALITY_STORAGE
+                .with(|service| service.borrow().get(&id))
+                .ok_or(Error::InvalidInput {
+                    msg: format!(
+                        "the cursor names air quality data with id={}, which is gone",
+                        id
+                    ),
+                    violations: None,
+                })?,
+        ),
+        _ => None,
+    };
+    let mut matching: Vec<AirQualityData> = records
+        .filter(|data| !data.is_deleted() && is_visible_to_caller(data))
+        .filter(|data| match (&after, cursor) {
+            (Some(after), _) => compare(data, after, sort) == Ordering::Greater,
+            (None, Some(id)) => data.id > id,
+            (None, None) => true,
+        })
+        .map(|data| units::in_unit(data, unit))
+        .filter(|data| predicate(data))
+        .collect();
+    matching.sort_by(|a, b| compare(a, b, sort));
+    Ok(paging::reading_page(matching))
+}
+
+// Like `page_of`, over every record
+pub(crate) fn sorted_page<F>(
+    cursor: Option<u64>,
+    unit: Option<ConcentrationUnit>,
+    sort: SortOrder,
+    predicate: F,
+) -> Result<ReadingPage, Error>
+where
+    F: Fn(&AirQualityData) -> bool,
+{
+    AIR_QUALITY_STORAGE.with(|service| {
+        page_of(
+            service.borrow().iter().map(|(_, data)| data),
+            cursor,
+            unit,
+            sort,
+            predicate,
+        )
+    })
+}
+
+// Live readings the caller may see that meet all the criteria, sorted and in pages
+#[ic_cdk::query]
+fn query_readings(criteria: QueryCriteria) -> Result<ReadingPage, Error> {
+    criteria.validate()?;
+    let unit = criteria.unit;
+    let sort = criteria.sort;
+    let cursor = criteria.cursor;
+    // An AQI range seeks the AQI index instead of scanning every record
+    match criteria.air_quality_index {
+        Some(range) => page_of(
+            aqi_index::readings_within(range.min, range.max).into_iter(),
+            cursor,
+            unit,
+            sort.unwrap_or(SortOrder::IdAscending),
+            criteria.predicate(),
+        ),
+        None => air_quality_page(cursor, unit, sort, criteria.predicate()),
+    }
+}
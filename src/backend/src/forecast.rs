@@ -0,0 +1,103 @@
+use crate::aliases;
+use crate::rollups;
+use crate::standards::AqiCategory;
+use crate::Error;
+use ic_cdk::api::time;
+
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+const MAX_HORIZON_HOURS: u32 = 48;
+// Hours of history the model is fitted on
+const LOOKBACK_HOURS: u64 = 48;
+// Fewer hours than this are not enough to estimate a trend
+const MIN_SMOOTHING_HOURS: usize = 3;
+// Smoothing factors of the level and the trend
+const LEVEL_ALPHA: f64 = 0.5;
+const TREND_BETA: f64 = 0.3;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum ForecastModel {
+    // The last hourly average, unchanged
+    Persistence,
+    // Holt's linear exponential smoothing of the hourly averages
+    ExponentialSmoothing,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ForecastPoint {
+    hour_start: u64,
+    air_quality_index: f64,
+    category: AqiCategory,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct AqiForecast {
+    location: String,
+    generated_at: u64,
+    model: ForecastModel,
+    // Hours with readings the model was fitted on
+    history_hours: u64,
+    points: Vec<ForecastPoint>,
+}
+
+// Level and trend per hour after the last of `values`
+fn holt(values: &[f64]) -> (f64, f64) {
+    let mut level = values[0];
+    let mut trend = values[1] - values[0];
+    for value in &values[1..] {
+        let previous_level = level;
+        level = LEVEL_ALPHA * value + (1.0 - LEVEL_ALPHA) * (level + trend);
+        trend = TREND_BETA * (level - previous_level) + (1.0 - TREND_BETA) * trend;
+    }
+    (level, trend)
+}
+
+// Hourly AQI for the next `horizon_hours` (1 to 48) after the last hour with readings, from the
+// hourly averages of the last 48 hours. With fewer than three hours the last one persists.
+#[ic_cdk::query]
+pub(crate) fn forecast_aqi(location: String, horizon_hours: u32) -> Result<AqiForecast, Error> {
+    let location = aliases::location(location);
+    if horizon_hours == 0 || horizon_hours > MAX_HORIZON_HOURS {
+        return Err(Error::InvalidInput {
+            msg: format!("horizon_hours must be 1 to {}", MAX_HORIZON_HOURS),
+            violations: None,
+        });
+    }
+    let now = time();
+    let history = rollups::hourly_air_quality_index(
+        &location,
+        now.saturating_sub(LOOKBACK_HOURS * NANOS_PER_HOUR),
+        now,
+    )?;
+    let Some((last_hour, last_value)) = history.last().copied() else {
+        return Err(Error::NotFound {
+            msg: format!(
+                "no readings at {} in the last {} hours",
+                location, LOOKBACK_HOURS
+            ),
+        });
+    };
+    let values: Vec<f64> = history.iter().map(|(_, value)| *value).collect();
+    let (model, level, trend) = if values.len() >= MIN_SMOOTHING_HOURS {
+        let (level, trend) = holt(&values);
+        (ForecastModel::ExponentialSmoothing, level, trend)
+    } else {
+        (ForecastModel::Persistence, last_value, 0.0)
+    };
+    let points = (1..=horizon_hours as u64)
+        .map(|hour| {
+            let air_quality_index = (level + trend * hour as f64).max(0.0);
+            ForecastPoint {
+                hour_start: last_hour + hour * NANOS_PER_HOUR,
+                air_quality_index,
+                category: AqiCategory::of(air_quality_index),
+            }
+        })
+        .collect();
+    Ok(AqiForecast {
+        location,
+        generated_at: now,
+        model,
+        history_hours: values.len() as u64,
+        points,
+    })
+}
@@ -0,0 +1,187 @@
+use crate::aggregates::{civil_from_days, days_from_civil, level_in_micrograms};
+use crate::http::{bad_request, json, HttpGatewayResponse};
+use crate::{filter_air_quality_data, AirQualityData, Pollutant};
+use std::collections::BTreeSet;
+
+const NANOS_PER_MILLI: u64 = 1_000_000;
+const MAX_TARGETS: usize = 20;
+const MAX_SEARCH_RESULTS: usize = 1000;
+const MAX_DATAPOINTS: usize = 10_000;
+// Metric of a target that plots the AQI rather than a pollutant
+const AQI_METRIC: &str = "aqi";
+
+#[derive(Deserialize, Default)]
+struct SearchRequest {
+    #[serde(default)]
+    target: String,
+}
+
+#[derive(Deserialize)]
+struct TimeRange {
+    from: String,
+    to: String,
+}
+
+#[derive(Deserialize)]
+struct QueryTarget {
+    target: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QueryRequest {
+    range: TimeRange,
+    targets: Vec<QueryTarget>,
+    max_data_points: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct TimeSeriesResponse {
+    target: String,
+    // [value, milliseconds since the epoch]
+    datapoints: Vec<(f64, u64)>,
+}
+
+// Grafana Simple JSON datasource contract: GET / to test the connection, POST /search to list
+// targets and POST /query for their time series
+
+// Nanoseconds since the epoch of a UTC RFC 3339 time, e.g. "2024-01-31T06:33:44.866Z"
+pub(crate) fn parse_time(value: &str) -> Option<u64> {
+    let value = value.strip_suffix('Z')?;
+    let (date, time) = value.split_once('T')?;
+    let mut date_parts = date.splitn(3, '-');
+    let year: i64 = date_parts.next()?.parse().ok()?;
+    let month: u32 = date_parts.next()?.parse().ok()?;
+    let day: i64 = date_parts.next()?.parse().ok()?;
+    let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let mut time_parts = time.splitn(3, ':');
+    let hours: u64 = time_parts.next()?.parse().ok()?;
+    let minutes: u64 = time_parts.next()?.parse().ok()?;
+    let seconds: u64 = time_parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hours > 23 || minutes > 59 {
+        return None;
+    }
+    let nanos: u64 = if fraction.is_empty() {
+        0
+    } else {
+        let digits = &fraction[..fraction.len().min(9)];
+        digits.parse::<u64>().ok()? * 10u64.pow(9 - digits.len() as u32)
+    };
+    let days = u64::try_from(days_from_civil(year, month) + day - 1).ok()?;
+    Some(((days * 24 + hours) * 60 + minutes) * 60_000_000_000 + seconds * 1_000_000_000 + nanos)
+}
+
+// UTC RFC 3339 time of nanoseconds since the epoch, to the millisecond, e.g.
+// "2024-01-31T06:33:44.866Z"
+pub(crate) fn format_time(nanos: u64) -> String {
+    let millis = nanos / NANOS_PER_MILLI;
+    let seconds = millis / 1000;
+    let days = (seconds / 86_400) as i64;
+    let (year, month) = civil_from_days(days);
+    let day = days - days_from_civil(year, month) + 1;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds % 86_400 / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+        millis % 1000
+    )
+}
+
+fn metric_value(data: &AirQualityData, metric: &str) -> Option<f64> {
+    if metric == AQI_METRIC {
+        Some(data.air_quality_index as f64)
+    } else {
+        level_in_micrograms(data, &Pollutant::parse(metric))
+    }
+}
+
+// Targets are "<location>:aqi" and "<location>:<pollutant>" for every metric with readings
+fn search(request: SearchRequest) -> HttpGatewayResponse {
+    let filter = request.target.to_lowercase();
+    let mut targets = BTreeSet::new();
+    for data in filter_air_quality_data(|_| true) {
+        targets.insert(format!("{}:{}", data.location, AQI_METRIC));
+        for pollutant in data.pollutant_levels.keys() {
+            targets.insert(format!("{}:{}", data.location, pollutant.name()));
+        }
+    }
+    let targets: Vec<String> = targets
+        .into_iter()
+        .filter(|target| target.to_lowercase().contains(&filter))
+        .take(MAX_SEARCH_RESULTS)
+        .collect();
+    json(&targets)
+}
+
+fn query(request: QueryRequest) -> HttpGatewayResponse {
+    let (Some(from), Some(to)) = (
+        parse_time(&request.range.from),
+        parse_time(&request.range.to),
+    ) else {
+        return bad_request("range.from and range.to must be RFC 3339 UTC times".to_string());
+    };
+    if request.targets.len() > MAX_TARGETS {
+        return bad_request(format!("at most {} targets per query", MAX_TARGETS));
+    }
+    let max_points = request
+        .max_data_points
+        .unwrap_or(MAX_DATAPOINTS)
+        .clamp(1, MAX_DATAPOINTS);
+    let series: Vec<TimeSeriesResponse> = request
+        .targets
+        .into_iter()
+        .map(|QueryTarget { target }| {
+            let Some((location, metric)) = target.rsplit_once(':') else {
+                return TimeSeriesResponse {
+                    target,
+                    datapoints: Vec::new(),
+                };
+            };
+            let mut readings = filter_air_quality_data(|data| {
+                data.location == location && data.timestamp >= from && data.timestamp <= to
+            });
+            readings.sort_by_key(|data| data.timestamp);
+            let mut datapoints: Vec<(f64, u64)> = readings
+                .iter()
+                .filter_map(|data| {
+                    Some((
+                        metric_value(data, metric)?,
+                        data.timestamp / NANOS_PER_MILLI,
+                    ))
+                })
+                .collect();
+            // Thin evenly spaced points out down to what the panel can draw
+            if datapoints.len() > max_points {
+                let step = datapoints.len().div_ceil(max_points);
+                datapoints = datapoints.into_iter().step_by(step).collect();
+            }
+            TimeSeriesResponse { target, datapoints }
+        })
+        .collect();
+    json(&series)
+}
+
+// POST /search body; an empty body searches for everything
+pub(crate) fn handle_search(body: &[u8]) -> HttpGatewayResponse {
+    let request = if body.is_empty() {
+        Ok(SearchRequest::default())
+    } else {
+        serde_json::from_slice(body)
+    };
+    match request {
+        Ok(request) => search(request),
+        Err(e) => bad_request(format!("invalid search request: {}", e)),
+    }
+}
+
+// POST /query body
+pub(crate) fn handle_query(body: &[u8]) -> HttpGatewayResponse {
+    match serde_json::from_slice(body) {
+        Ok(request) => query(request),
+        Err(e) => bad_request(format!("invalid query request: {}", e)),
+    }
+}
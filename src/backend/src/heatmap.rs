@@ -0,0 +1,175 @@
+use crate::aggregates::level_in_micrograms;
+use crate::rankings::RankingMetric;
+use crate::{organizations, stations, Error, AIR_QUALITY_STORAGE};
+use std::collections::BTreeMap;
+
+const MIN_CELL_DEGREES: f64 = 0.001;
+const MAX_CELLS: u64 = 10_000;
+
+// Crossing the antimeridian is not supported: `min_longitude` must not exceed `max_longitude`
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct BoundingBox {
+    min_latitude: f64,
+    min_longitude: f64,
+    max_latitude: f64,
+    max_longitude: f64,
+}
+
+impl BoundingBox {
+    fn contains(&self, latitude: f64, longitude: f64) -> bool {
+        (self.min_latitude..=self.max_latitude).contains(&latitude)
+            && (self.min_longitude..=self.max_longitude).contains(&longitude)
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        let latitudes = (-90.0..=90.0).contains(&self.min_latitude)
+            && (-90.0..=90.0).contains(&self.max_latitude)
+            && self.min_latitude <= self.max_latitude;
+        let longitudes = (-180.0..=180.0).contains(&self.min_longitude)
+            && (-180.0..=180.0).contains(&self.max_longitude)
+            && self.min_longitude <= self.max_longitude;
+        if !latitudes || !longitudes {
+            return Err(Error::InvalidInput {
+                msg: "the box must lie within ±90° latitude and ±180° longitude, minimums first"
+                    .to_string(),
+                violations: None,
+            });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn west_south_east_north(&self) -> (f64, f64, f64, f64) {
+        (
+            self.min_longitude,
+            self.min_latitude,
+            self.max_longitude,
+            self.max_latitude,
+        )
+    }
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct HeatmapCell {
+    // Counted from the south-west corner of the box
+    row: u32,
+    column: u32,
+    // South-west corner of the cell
+    latitude: f64,
+    longitude: f64,
+    readings: u64,
+    mean: f64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct Heatmap {
+    bbox: BoundingBox,
+    cell_size_degrees: f64,
+    rows: u32,
+    columns: u32,
+    // Only cells with readings, by row, then column
+    cells: Vec<HeatmapCell>,
+}
+
+fn validate(bbox: &BoundingBox, cell_size_degrees: f64) -> Result<(u32, u32), Error> {
+    bbox.validate()?;
+    if !cell_size_degrees.is_finite() || cell_size_degrees < MIN_CELL_DEGREES {
+        return Err(Error::InvalidInput {
+            msg: format!("cells must be at least {}° wide", MIN_CELL_DEGREES),
+            violations: None,
+        });
+    }
+    let cells_along = |min: f64, max: f64| (((max - min) / cell_size_degrees).floor() as u64) + 1;
+    let rows = cells_along(bbox.min_latitude, bbox.max_latitude);
+    let columns = cells_along(bbox.min_longitude, bbox.max_longitude);
+    if rows * columns > MAX_CELLS {
+        return Err(Error::TooLarge {
+            msg: format!(
+                "the grid would have {} cells; use larger cells or a smaller box for at most {}",
+                rows * columns,
+                MAX_CELLS
+            ),
+        });
+    }
+    Ok((rows as u32, columns as u32))
+}
+
+// Published readings in the period binned by where their station is, with the mean AQI or
+// pollutant level of each cell. Readings are placed at their station's public coordinates, so
+// private stations fall in the cell of their grid centre; readings without a station are left
+// out. Like other aggregates, aggregate-only stations count and embargoed readings do not.
+#[ic_cdk::query]
+fn get_heatmap(
+    bbox: BoundingBox,
+    cell_size_degrees: f64,
+    metric: RankingMetric,
+    start_timestamp: u64,
+    end_timestamp: u64,
+) -> Result<Heatmap, Error> {
+    let (rows, columns) = validate(&bbox, cell_size_degrees)?;
+    if end_timestamp < start_timestamp {
+        return Err(Error::InvalidInput {
+            msg: "end_timestamp must not be before start_timestamp".to_string(),
+            violations: None,
+        });
+    }
+    let cell_of = |latitude: f64, longitude: f64| {
+        let row = ((latitude - bbox.min_latitude) / cell_size_degrees).floor() as u32;
+        let column = ((longitude - bbox.min_longitude) / cell_size_degrees).floor() as u32;
+        (row.min(rows - 1), column.min(columns - 1))
+    };
+    let cells_of_stations: BTreeMap<String, (u32, u32)> = stations::coordinates()
+        .into_iter()
+        .filter(|(_, (latitude, longitude))| bbox.contains(*latitude, *longitude))
+        .map(|(station_id, (latitude, longitude))| (station_id, cell_of(latitude, longitude)))
+        .collect();
+
+    let mut sums: BTreeMap<(u32, u32), (f64, u64)> = BTreeMap::new();
+    AIR_QUALITY_STORAGE.with(|service| {
+        for (_, data) in service.borrow().iter() {
+            if data.is_deleted()
+                || !data.is_published()
+                || !organizations::is_visible_to_caller(data.organization_id)
+                || data.timestamp < start_timestamp
+                || data.timestamp > end_timestamp
+            {
+                continue;
+            }
+            let Some(station_id) = data.station_id.as_deref() else {
+                continue;
+            };
+            let Some(cell) = cells_of_stations.get(station_id) else {
+                continue;
+            };
+            if stations::is_embargoed(station_id, data.timestamp) {
+                continue;
+            }
+            let value = match &metric {
+                RankingMetric::AirQualityIndex => Some(data.air_quality_index as f64),
+                RankingMetric::Pollutant(pollutant) => level_in_micrograms(&data, pollutant),
+            };
+            if let Some(value) = value {
+                let entry = sums.entry(*cell).or_default();
+                entry.0 += value;
+                entry.1 += 1;
+            }
+        }
+    });
+
+    Ok(Heatmap {
+        bbox,
+        cell_size_degrees,
+        rows,
+        columns,
+        cells: sums
+            .into_iter()
+            .map(|((row, column), (sum, readings))| HeatmapCell {
+                row,
+                column,
+                latitude: bbox.min_latitude + row as f64 * cell_size_degrees,
+                longitude: bbox.min_longitude + column as f64 * cell_size_degrees,
+                readings,
+                mean: sum / readings as f64,
+            })
+            .collect(),
+    })
+}
@@ -0,0 +1,89 @@
+use crate::{
+    clear_stable_map, get_memory, is_visible_to_caller, AirQualityData, Error, Memory,
+    AIR_QUALITY_STORAGE, RECORD_HISTORY_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::ops::Bound;
+
+// A record as it was before a write gave it a new version
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RecordVersion {
+    data: AirQualityData,
+    replaced_at: u64,
+    // Caller of the write; the canister itself for background tasks
+    replaced_by: Principal,
+}
+
+impl_bounded_storable!(RecordVersion, 2048);
+
+thread_local! {
+    // Keyed by (record id, version)
+    static RECORD_HISTORY: RefCell<StableBTreeMap<(u64, u64), RecordVersion, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(RECORD_HISTORY_MEMORY_ID)));
+}
+
+pub(crate) fn reset() {
+    RECORD_HISTORY.with(|s| clear_stable_map(s, RECORD_HISTORY_MEMORY_ID));
+}
+
+// Keeps the previous state of a record whose version a write changed. Writes that keep the
+// version, such as index backfills, leave no history.
+pub(crate) fn on_write(previous: Option<&AirQualityData>, current: &AirQualityData) {
+    let Some(previous) = previous.filter(|previous| previous.version != current.version) else {
+        return;
+    };
+    let version = RecordVersion {
+        data: previous.clone(),
+        replaced_at: time(),
+        replaced_by: ic_cdk::caller(),
+    };
+    RECORD_HISTORY.with(|s| {
+        s.borrow_mut()
+            .insert((previous.id, previous.version), version)
+    });
+}
+
+// Drops the history of a record removed for good
+pub(crate) fn on_remove(id: u64) {
+    let versions = versions_of(id);
+    RECORD_HISTORY.with(|s| {
+        let mut storage = s.borrow_mut();
+        for version in versions {
+            storage.remove(&(id, version.data.version));
+        }
+    });
+}
+
+continued_page!(RecordVersionPage, RecordVersion);
+
+fn versions_of(id: u64) -> Vec<RecordVersion> {
+    RECORD_HISTORY.with(|s| {
+        s.borrow()
+            .range((Bound::Included((id, 0)), Bound::Included((id, u64::MAX))))
+            .map(|(_, version)| version)
+            .collect()
+    })
+}
+
+// The earlier versions of a record, oldest first. The current version is returned by
+// get_air_quality_data. The history of an archived record stays here, and is shown to those
+// its last version here was visible to.
+#[ic_cdk::query]
+fn get_record_history(id: u64, continuation: Option<String>) -> Result<RecordVersionPage, Error> {
+    let versions = versions_of(id);
+    let visible = match AIR_QUALITY_STORAGE.with(|s| s.borrow().get(&id)) {
+        Some(current) => !current.is_deleted() && is_visible_to_caller(&current),
+        None => versions
+            .last()
+            .is_some_and(|version| is_visible_to_caller(&version.data)),
+    };
+    if !visible {
+        return Err(Error::NotFound {
+            msg: format!("air quality data with id={} not found", id),
+        });
+    }
+    RecordVersionPage::new(versions, continuation)
+}
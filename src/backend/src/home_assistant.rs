@@ -0,0 +1,108 @@
+use crate::aggregates::level_in_micrograms;
+use crate::branding::{self, Branding};
+use crate::http::{error, json, HttpGatewayResponse};
+use crate::standards::AqiCategory;
+use crate::{filter_air_quality_data, Pollutant};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// Clients should not poll more often than this; also sent as the cache lifetime
+const POLL_INTERVAL_SECONDS: u64 = 60;
+// Home Assistant truncates states to 255 characters
+const MAX_STATE_LEN: usize = 255;
+const MICROGRAMS_PER_CUBIC_METER: &str = "µg/m³";
+
+// One Home Assistant entity: `state` plus the metadata a RESTful sensor needs
+#[derive(Serialize)]
+struct Entity {
+    key: &'static str,
+    name: String,
+    state: Option<serde_json::Value>,
+    unit_of_measurement: Option<&'static str>,
+    device_class: Option<&'static str>,
+    state_class: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+struct SensorResponse {
+    location: String,
+    // Seconds since the epoch of the latest reading
+    observed_at: u64,
+    aqi: u32,
+    category: AqiCategory,
+    pm25: Option<f64>,
+    pm10: Option<f64>,
+    advisory: String,
+    entities: Vec<Entity>,
+    poll_interval_seconds: u64,
+    // Of the organization owning the reading's station, if it set any
+    branding: Option<Branding>,
+}
+
+fn truncated(text: &str) -> String {
+    text.chars().take(MAX_STATE_LEN).collect()
+}
+
+fn measurement(
+    key: &'static str,
+    name: String,
+    value: Option<f64>,
+    device_class: &'static str,
+) -> Entity {
+    Entity {
+        key,
+        name,
+        state: value.map(|value| serde_json::json!(value)),
+        unit_of_measurement: Some(MICROGRAMS_PER_CUBIC_METER),
+        device_class: Some(device_class),
+        state_class: Some("measurement"),
+    }
+}
+
+// GET /ha/{location}/sensor.json: the latest reading of a location as Home Assistant entities
+pub(crate) fn sensor(location: &str) -> HttpGatewayResponse {
+    let Some(latest) = filter_air_quality_data(|data| data.location == location)
+        .into_iter()
+        .max_by_key(|data| (data.timestamp, data.id))
+    else {
+        return error(404, "no readings for this location");
+    };
+    let pm25 = level_in_micrograms(&latest, &Pollutant::PM25);
+    let pm10 = level_in_micrograms(&latest, &Pollutant::PM10);
+    let advisory = truncated(&latest.health_recommendations);
+    let entities = vec![
+        Entity {
+            key: "aqi",
+            name: format!("{} AQI", location),
+            state: Some(serde_json::json!(latest.air_quality_index)),
+            unit_of_measurement: None,
+            device_class: Some("aqi"),
+            state_class: Some("measurement"),
+        },
+        measurement("pm25", format!("{} PM2.5", location), pm25, "pm25"),
+        measurement("pm10", format!("{} PM10", location), pm10, "pm10"),
+        Entity {
+            key: "advisory",
+            name: format!("{} advisory", location),
+            state: Some(serde_json::json!(advisory)),
+            unit_of_measurement: None,
+            device_class: None,
+            state_class: None,
+        },
+    ];
+    json(&SensorResponse {
+        location: location.to_string(),
+        observed_at: latest.timestamp / NANOS_PER_SECOND,
+        aqi: latest.air_quality_index,
+        category: AqiCategory::of(latest.air_quality_index as f64),
+        pm25,
+        pm10,
+        advisory,
+        entities,
+        poll_interval_seconds: POLL_INTERVAL_SECONDS,
+        branding: latest.station_id.as_deref().and_then(branding::of_station),
+    })
+    .with_header(
+        "Cache-Control",
+        &format!("public, max-age={}", POLL_INTERVAL_SECONDS),
+    )
+}
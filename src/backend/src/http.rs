@@ -0,0 +1,172 @@
+use crate::api_keys::{self, ApiScope};
+use crate::{accepts_writes, aliases, branding, grafana, home_assistant, sensorthings, tools};
+
+// Request and response of the HTTP gateway interface
+#[derive(candid::CandidType, Deserialize)]
+pub(crate) struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[derive(candid::CandidType, Serialize)]
+pub(crate) struct HttpGatewayResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    // Asks the gateway to send the request again to http_request_update
+    upgrade: Option<bool>,
+}
+
+impl HttpGatewayResponse {
+    pub(crate) fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+pub(crate) fn respond(status_code: u16, body: Vec<u8>) -> HttpGatewayResponse {
+    HttpGatewayResponse {
+        status_code,
+        headers: vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("Access-Control-Allow-Origin".to_string(), "*".to_string()),
+        ],
+        body,
+        upgrade: None,
+    }
+}
+
+pub(crate) fn json(value: &impl serde::Serialize) -> HttpGatewayResponse {
+    respond(
+        200,
+        serde_json::to_vec(value).expect("responses serialize to JSON"),
+    )
+}
+
+pub(crate) fn error(status_code: u16, msg: &str) -> HttpGatewayResponse {
+    respond(
+        status_code,
+        serde_json::json!({ "error": msg }).to_string().into_bytes(),
+    )
+}
+
+// Sends clients of a renamed slug to the path with its current slug
+fn moved_permanently(location: &str) -> HttpGatewayResponse {
+    respond(301, Vec::new()).with_header("Location", location)
+}
+
+// Serves a path segment that names something by slug or by its own name. Renamed slugs are
+// redirected to `path` with the current slug.
+fn by_slug(
+    segment: &str,
+    path: impl Fn(&str) -> String,
+    serve: impl Fn(String) -> HttpGatewayResponse,
+) -> HttpGatewayResponse {
+    let Some(name) = percent_decode(segment) else {
+        return bad_request("the path is not valid percent-encoded UTF-8".to_string());
+    };
+    match aliases::lookup(&name) {
+        Some((alias, true)) => moved_permanently(&path(aliases::slug(&alias))),
+        _ => serve(name),
+    }
+}
+
+pub(crate) fn bad_request(msg: String) -> HttpGatewayResponse {
+    error(400, &msg)
+}
+
+// Decodes %XX escapes of a path segment; None if they do not decode to UTF-8
+pub(crate) fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = segment.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// The scope a key needs for a route; None for routes every key may use
+fn scope_of(method: &str, segments: &[&str]) -> Option<ApiScope> {
+    match (method, segments) {
+        ("POST", ["search"] | ["query"]) => Some(ApiScope::Grafana),
+        ("GET", ["tools"]) | ("POST", ["tools", _]) => Some(ApiScope::Tools),
+        ("GET", ["stations", _, "branding.json"]) => Some(ApiScope::Branding),
+        ("GET", ["ha", _, "sensor.json"]) => Some(ApiScope::HomeAssistant),
+        ("GET", ["v1.1", ..]) => Some(ApiScope::SensorThings),
+        _ => None,
+    }
+}
+
+fn route(request: &HttpRequest) -> HttpGatewayResponse {
+    let (path, query) = request.url.split_once('?').unwrap_or((&request.url, ""));
+    let path = path.trim_end_matches('/');
+    let segments: Vec<&str> = path.split('/').skip(1).collect();
+    match (request.method.to_uppercase().as_str(), segments.as_slice()) {
+        // Browsers ask before sending an API key
+        ("OPTIONS", _) => respond(204, Vec::new())
+            .with_header("Access-Control-Allow-Headers", "Authorization, X-API-Key")
+            .with_header("Access-Control-Allow-Methods", "GET, POST, OPTIONS"),
+        ("GET", []) => json(&"ok"),
+        ("POST", ["search"]) => grafana::handle_search(&request.body),
+        ("POST", ["query"]) => grafana::handle_query(&request.body),
+        ("GET", ["tools"]) => tools::handle_manifest(),
+        ("POST", ["tools", name]) => tools::handle_call(name, &request.body),
+        ("GET", ["stations", station_id, "branding.json"]) => by_slug(
+            station_id,
+            |slug| format!("/stations/{}/branding.json", slug),
+            |name| branding::handle_station(&aliases::station_id(name)),
+        ),
+        ("GET", ["ha", location, "sensor.json"]) => by_slug(
+            location,
+            |slug| format!("/ha/{}/sensor.json", slug),
+            |name| home_assistant::sensor(&aliases::location(name)),
+        ),
+        ("GET", ["v1.1", rest @ ..]) => sensorthings::handle(
+            rest,
+            query,
+            request
+                .headers
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case("host"))
+                .map(|(_, value)| value.as_str()),
+        ),
+        _ => error(404, "not found"),
+    }
+}
+
+// Read-only, so every route is served as a query. Requests with an API key are upgraded to
+// http_request_update, so their use is counted.
+#[ic_cdk::query]
+fn http_request(request: HttpRequest) -> HttpGatewayResponse {
+    if api_keys::presented_secret(&request.headers).is_some() {
+        return HttpGatewayResponse {
+            upgrade: Some(true),
+            ..respond(200, Vec::new())
+        };
+    }
+    route(&request)
+}
+
+#[ic_cdk::update(guard = "accepts_writes")]
+fn http_request_update(request: HttpRequest) -> HttpGatewayResponse {
+    let Some(secret) = api_keys::presented_secret(&request.headers) else {
+        return route(&request);
+    };
+    let path = request.url.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_end_matches('/').split('/').skip(1).collect();
+    let scope = scope_of(request.method.to_uppercase().as_str(), &segments);
+    match api_keys::authenticate(secret, scope) {
+        Ok(()) => route(&request),
+        Err(response) => response,
+    }
+}
@@ -0,0 +1,85 @@
+use crate::{
+    clear_stable_map, get_memory, principal_key, Error, Memory, PrincipalKey, StringKey,
+    IDEMPOTENCY_EXPIRY_MEMORY_ID, IDEMPOTENCY_KEY_MEMORY_ID,
+};
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_KEY_LEN: usize = 64;
+// Keys are remembered this long; retries after that create a new record
+const KEY_TTL_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MAX_EXPIRIES_PER_TICK: usize = 500;
+
+// Keys are scoped to the caller, so gateways cannot collide with each other
+type ScopedKey = (PrincipalKey, StringKey);
+
+thread_local! {
+    // Scoped key -> (record id, time the key was first used)
+    static IDEMPOTENCY_KEYS: RefCell<StableBTreeMap<ScopedKey, (u64, u64), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(IDEMPOTENCY_KEY_MEMORY_ID)));
+
+    // (time the key was first used, record id) -> scoped key, oldest first for expiry
+    static IDEMPOTENCY_EXPIRY: RefCell<StableBTreeMap<(u64, u64), ScopedKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(IDEMPOTENCY_EXPIRY_MEMORY_ID)));
+}
+
+// Forgets every key, for a storage reset
+pub(crate) fn reset() {
+    IDEMPOTENCY_KEYS.with(|s| clear_stable_map(s, IDEMPOTENCY_KEY_MEMORY_ID));
+    IDEMPOTENCY_EXPIRY.with(|s| clear_stable_map(s, IDEMPOTENCY_EXPIRY_MEMORY_ID));
+}
+
+fn scoped_key(key: &str) -> ScopedKey {
+    (principal_key(&ic_cdk::caller()), StringKey(key.to_string()))
+}
+
+pub(crate) fn validate_key(key: &str) -> Result<(), Error> {
+    if key.is_empty() || key.len() > MAX_KEY_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!("idempotency_key must be 1 to {} bytes", MAX_KEY_LEN),
+            violations: None,
+        });
+    }
+    Ok(())
+}
+
+// The record created by an earlier call of the caller with this key, if it is still remembered
+pub(crate) fn lookup(key: &str) -> Option<u64> {
+    let now = time();
+    IDEMPOTENCY_KEYS
+        .with(|s| s.borrow().get(&scoped_key(key)))
+        .filter(|(_, used_at)| now < used_at + KEY_TTL_NANOS)
+        .map(|(record_id, _)| record_id)
+}
+
+pub(crate) fn remember(key: &str, record_id: u64) {
+    let now = time();
+    let scoped = scoped_key(key);
+    IDEMPOTENCY_KEYS.with(|s| s.borrow_mut().insert(scoped.clone(), (record_id, now)));
+    IDEMPOTENCY_EXPIRY.with(|s| s.borrow_mut().insert((now, record_id), scoped));
+}
+
+// Forgets keys older than the TTL
+pub(crate) fn on_timer() {
+    let cutoff = time().saturating_sub(KEY_TTL_NANOS);
+    let expired: Vec<((u64, u64), ScopedKey)> = IDEMPOTENCY_EXPIRY.with(|s| {
+        s.borrow()
+            .range(..(cutoff, 0))
+            .take(MAX_EXPIRIES_PER_TICK)
+            .collect()
+    });
+    for (expiry, scoped) in expired {
+        IDEMPOTENCY_EXPIRY.with(|s| s.borrow_mut().remove(&expiry));
+        IDEMPOTENCY_KEYS.with(|s| {
+            let mut keys = s.borrow_mut();
+            // The key may have been reused for a newer record after it expired
+            if keys
+                .get(&scoped)
+                .is_some_and(|(record_id, _)| record_id == expiry.1)
+            {
+                keys.remove(&scoped);
+            }
+        });
+    }
+}
@@ -0,0 +1,364 @@
+use crate::{
+    accepts_writes, annotations, ensure_admin, get_memory, locations, next_id, Error, IdCell,
+    Memory, INCIDENT_ID_COUNTER_MEMORY_ID, INCIDENT_STORAGE_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::cell::RefCell;
+
+const MAX_TITLE_LEN: usize = 200;
+const MAX_TEXT_LEN: usize = 2000;
+const MAX_NOTE_LEN: usize = 300;
+const MAX_LOCATIONS: usize = 20;
+const MAX_LOCATION_LEN: usize = 200;
+const MAX_UPDATES: usize = 20;
+// The bound of the stored map cannot grow, so the limits above are chosen to fit it: a title,
+// description, postmortem, locations and updates at their limits come to about 15 KiB
+const MAX_INCIDENT_SIZE: u32 = 16 * 1024;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum IncidentSeverity {
+    Minor,
+    Major,
+    Critical,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum IncidentStatus {
+    Investigating,
+    Monitoring,
+    Resolved,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct IncidentUpdate {
+    timestamp: u64,
+    author: Principal,
+    note: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Incident {
+    id: u64,
+    title: String,
+    description: String,
+    severity: IncidentSeverity,
+    status: IncidentStatus,
+    affected_locations: Vec<String>,
+    period_start: u64,
+    period_end: Option<u64>,
+    opened_by: Principal,
+    opened_at: u64,
+    updated_at: u64,
+    resolved_at: Option<u64>,
+    updates: Vec<IncidentUpdate>,
+    postmortem: Option<String>,
+    annotation_ids: Vec<u64>,
+}
+
+impl_bounded_storable!(Incident, MAX_INCIDENT_SIZE);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct IncidentPayload {
+    title: String,
+    description: String,
+    severity: IncidentSeverity,
+    affected_locations: Vec<String>,
+    period_start: Option<u64>,
+    period_end: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct IncidentUpdatePayload {
+    note: String,
+    status: Option<IncidentStatus>,
+    severity: Option<IncidentSeverity>,
+    affected_locations: Option<Vec<String>>,
+    period_start: Option<u64>,
+    period_end: Option<u64>,
+}
+
+thread_local! {
+    static INCIDENT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(get_memory(INCIDENT_ID_COUNTER_MEMORY_ID), 0)
+            .expect("Cannot create a counter for incidents")
+    );
+
+    static INCIDENT_STORAGE: RefCell<StableBTreeMap<u64, Incident, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(INCIDENT_STORAGE_MEMORY_ID)));
+}
+
+fn validate_text(field: &str, value: &str, max_len: usize) -> Result<(), Error> {
+    if value.len() > max_len {
+        return Err(Error::InvalidInput {
+            msg: format!("{} must be at most {} bytes", field, max_len),
+            violations: None,
+        });
+    }
+    Ok(())
+}
+
+fn validate_title(title: &str) -> Result<(), Error> {
+    if title.trim().is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "title must not be empty".to_string(),
+            violations: None,
+        });
+    }
+    validate_text("title", title, MAX_TITLE_LEN)
+}
+
+// Alias names are replaced by their location, as on readings, so the annotations match the
+// location get_annotations looks up; entries naming the same location are kept once
+fn canonical_locations(affected_locations: Vec<String>) -> Vec<String> {
+    let mut canonical: Vec<String> = Vec::with_capacity(affected_locations.len());
+    for location in affected_locations.into_iter().map(locations::canonical) {
+        if !canonical.contains(&location) {
+            canonical.push(location);
+        }
+    }
+    canonical
+}
+
+fn validate_locations(locations: &[String]) -> Result<(), Error> {
+    if locations.is_empty() || locations.len() > MAX_LOCATIONS {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "an incident must affect between 1 and {} locations",
+                MAX_LOCATIONS
+            ),
+            violations: None,
+        });
+    }
+    if locations
+        .iter()
+        .any(|location| location.is_empty() || location.len() > MAX_LOCATION_LEN)
+    {
+        return Err(Error::InvalidInput {
+            msg: format!("locations must be 1 to {} bytes", MAX_LOCATION_LEN),
+            violations: None,
+        });
+    }
+    Ok(())
+}
+
+// Stable storage traps on incidents over MAX_SIZE, so every change is checked first. Room is
+// left for the annotation ids of every location, which are only known once it is annotated.
+fn check_size(incident: &Incident) -> Result<(), Error> {
+    let size = incident.to_bytes().len() + 8 * MAX_LOCATIONS;
+    if size > Incident::MAX_SIZE as usize {
+        return Err(Error::TooLarge {
+            msg: format!(
+                "the encoded incident is {} bytes, the limit is {} bytes",
+                size,
+                Incident::MAX_SIZE
+            ),
+        });
+    }
+    Ok(())
+}
+
+fn validate_period(start: u64, end: Option<u64>) -> Result<(), Error> {
+    match end {
+        Some(end) if end < start => Err(Error::InvalidInput {
+            msg: "incident period ends before it starts".to_string(),
+            violations: None,
+        }),
+        _ => Ok(()),
+    }
+}
+
+fn annotation_text(incident: &Incident) -> String {
+    match &incident.postmortem {
+        Some(postmortem) => format!(
+            "Incident #{}: {}. Postmortem: {}",
+            incident.id, incident.title, postmortem
+        ),
+        None => format!("Incident #{}: {}", incident.id, incident.title),
+    }
+}
+
+// Replaces the incident's annotations so they match its current locations and period
+fn sync_annotations(incident: &mut Incident) {
+    annotations::remove_annotations(&incident.annotation_ids);
+    incident.annotation_ids = annotations::annotate_incident(
+        incident.id,
+        &incident.affected_locations,
+        incident.period_start,
+        incident.period_end,
+        &annotation_text(incident),
+    );
+}
+
+fn get_incident_for_change(id: u64) -> Result<Incident, Error> {
+    match INCIDENT_STORAGE.with(|s| s.borrow().get(&id)) {
+        Some(incident) if incident.status == IncidentStatus::Resolved => Err(Error::InvalidInput {
+            msg: format!("incident with id={} is already resolved", id),
+            violations: None,
+        }),
+        Some(incident) => Ok(incident),
+        None => Err(Error::NotFound {
+            msg: format!("incident with id={} not found", id),
+        }),
+    }
+}
+
+fn do_insert_incident(incident: &Incident) {
+    INCIDENT_STORAGE.with(|s| s.borrow_mut().insert(incident.id, incident.clone()));
+}
+
+pub(crate) fn open_incidents() -> Vec<Incident> {
+    INCIDENT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, incident)| incident.status != IncidentStatus::Resolved)
+            .map(|(_, incident)| incident)
+            .collect()
+    })
+}
+
+#[ic_cdk::update(guard = "accepts_writes")]
+fn open_incident(payload: IncidentPayload) -> Result<Incident, Error> {
+    ensure_admin()?;
+    validate_title(&payload.title)?;
+    validate_text("description", &payload.description, MAX_TEXT_LEN)?;
+    let affected_locations = canonical_locations(payload.affected_locations);
+    validate_locations(&affected_locations)?;
+
+    let now = time();
+    let period_start = payload.period_start.unwrap_or(now);
+    validate_period(period_start, payload.period_end)?;
+
+    let mut incident = Incident {
+        id: next_id(&INCIDENT_ID_COUNTER),
+        title: payload.title,
+        description: payload.description,
+        severity: payload.severity,
+        status: IncidentStatus::Investigating,
+        affected_locations,
+        period_start,
+        period_end: payload.period_end,
+        opened_by: ic_cdk::caller(),
+        opened_at: now,
+        updated_at: now,
+        resolved_at: None,
+        updates: Vec::new(),
+        postmortem: None,
+        annotation_ids: Vec::new(),
+    };
+    check_size(&incident)?;
+    sync_annotations(&mut incident);
+
+    do_insert_incident(&incident);
+    Ok(incident)
+}
+
+#[ic_cdk::update(guard = "accepts_writes")]
+fn update_incident(id: u64, payload: IncidentUpdatePayload) -> Result<Incident, Error> {
+    ensure_admin()?;
+    validate_text("note", &payload.note, MAX_NOTE_LEN)?;
+    if payload.status == Some(IncidentStatus::Resolved) {
+        return Err(Error::InvalidInput {
+            msg: "use resolve_incident to resolve an incident".to_string(),
+            violations: None,
+        });
+    }
+
+    let mut incident = get_incident_for_change(id)?;
+    if incident.updates.len() >= MAX_UPDATES {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "incident with id={} already has {} updates",
+                id, MAX_UPDATES
+            ),
+            violations: None,
+        });
+    }
+
+    let now = time();
+    let affected_changed = payload.affected_locations.is_some()
+        || payload.period_start.is_some()
+        || payload.period_end.is_some();
+
+    if let Some(affected_locations) = payload.affected_locations {
+        let affected_locations = canonical_locations(affected_locations);
+        validate_locations(&affected_locations)?;
+        incident.affected_locations = affected_locations;
+    }
+    if let Some(period_start) = payload.period_start {
+        incident.period_start = period_start;
+    }
+    if let Some(period_end) = payload.period_end {
+        incident.period_end = Some(period_end);
+    }
+    validate_period(incident.period_start, incident.period_end)?;
+
+    if let Some(status) = payload.status {
+        incident.status = status;
+    }
+    if let Some(severity) = payload.severity {
+        incident.severity = severity;
+    }
+    incident.updates.push(IncidentUpdate {
+        timestamp: now,
+        author: ic_cdk::caller(),
+        note: payload.note,
+    });
+    incident.updated_at = now;
+    check_size(&incident)?;
+
+    if affected_changed {
+        sync_annotations(&mut incident);
+    }
+
+    do_insert_incident(&incident);
+    Ok(incident)
+}
+
+#[ic_cdk::update(guard = "accepts_writes")]
+fn resolve_incident(
+    id: u64,
+    postmortem: String,
+    period_end: Option<u64>,
+) -> Result<Incident, Error> {
+    ensure_admin()?;
+    validate_text("postmortem", &postmortem, MAX_TEXT_LEN)?;
+
+    let mut incident = get_incident_for_change(id)?;
+    let now = time();
+    incident.period_end = Some(period_end.or(incident.period_end).unwrap_or(now));
+    validate_period(incident.period_start, incident.period_end)?;
+
+    incident.status = IncidentStatus::Resolved;
+    incident.resolved_at = Some(now);
+    incident.updated_at = now;
+    incident.postmortem = Some(postmortem);
+    check_size(&incident)?;
+    sync_annotations(&mut incident);
+
+    do_insert_incident(&incident);
+    Ok(incident)
+}
+
+#[ic_cdk::query]
+fn get_incident(id: u64) -> Result<Incident, Error> {
+    match INCIDENT_STORAGE.with(|s| s.borrow().get(&id)) {
+        Some(incident) => Ok(incident),
+        None => Err(Error::NotFound {
+            msg: format!("incident with id={} not found", id),
+        }),
+    }
+}
+
+continued_page!(IncidentPage, Incident);
+
+#[ic_cdk::query]
+fn list_incidents(continuation: Option<String>) -> Result<IncidentPage, Error> {
+    INCIDENT_STORAGE.with(|s| {
+        IncidentPage::new(
+            s.borrow().iter().map(|(_, incident)| incident),
+            continuation,
+        )
+    })
+}
@@ -0,0 +1,204 @@
+use crate::Error;
+use std::collections::BTreeMap;
+
+// Type aliases are expanded this deep; recursive types stop expanding here
+const MAX_EXPANSION_DEPTH: usize = 8;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct MethodChange {
+    name: String,
+    previous: String,
+    current: String,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct InterfaceCompatibilityReport {
+    // True when every previous method still exists with an identical signature.
+    // Changed signatures are listed for review rather than checked for subtyping.
+    compatible: bool,
+    added: Vec<String>,
+    removed: Vec<String>,
+    changed: Vec<MethodChange>,
+    current_interface: String,
+}
+
+fn current_interface() -> String {
+    crate::__export_service()
+}
+
+// Splits `text` on `separator` where it is not nested in (), {} or quotes
+fn split_top_level(text: &str, separator: char) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0i32;
+    let mut in_quotes = false;
+    for c in text.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '(' | '{' if !in_quotes => depth += 1,
+            ')' | '}' if !in_quotes => depth -= 1,
+            _ => {}
+        }
+        if c == separator && depth == 0 && !in_quotes {
+            parts.push(std::mem::take(&mut current));
+        } else {
+            current.push(c);
+        }
+    }
+    parts.push(current);
+    parts
+        .into_iter()
+        .map(|part| part.trim().to_string())
+        .filter(|part| !part.is_empty())
+        .collect()
+}
+
+fn strip_comments(did: &str) -> String {
+    did.lines()
+        .map(|line| line.split("//").next().unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+struct ParsedInterface {
+    types: BTreeMap<String, String>,
+    methods: BTreeMap<String, String>,
+}
+
+fn parse_interface(did: &str) -> Result<ParsedInterface, String> {
+    let mut types = BTreeMap::new();
+    let mut service = None;
+    for statement in split_top_level(&strip_comments(did), ';') {
+        if let Some(definition) = statement.strip_prefix("type ") {
+            let (name, body) = definition
+                .split_once('=')
+                .ok_or(format!("malformed type definition: {}", statement))?;
+            types.insert(name.trim().to_string(), body.trim().to_string());
+        } else if statement.starts_with("service") {
+            service = Some(statement);
+        }
+    }
+
+    let service = service.ok_or("no service definition found".to_string())?;
+    // Skips init arguments of a service class: `service : (Init) -> { ... }`
+    let body_start = service
+        .rfind("-> {")
+        .map(|i| i + 3)
+        .or_else(|| service.find('{'))
+        .ok_or("service has no method list".to_string())?;
+    let body_end = service
+        .rfind('}')
+        .ok_or("service method list is not closed".to_string())?;
+
+    let mut methods = BTreeMap::new();
+    for method in split_top_level(&service[body_start + 1..body_end], ';') {
+        let (name, signature) = method
+            .split_once(':')
+            .ok_or(format!("malformed method: {}", method))?;
+        methods.insert(
+            name.trim().trim_matches('"').to_string(),
+            signature.trim().to_string(),
+        );
+    }
+    Ok(ParsedInterface { types, methods })
+}
+
+// Replaces type names with their definitions so renumbered aliases (Result_1 -> Result_2)
+// do not show up as changes, then drops formatting differences
+fn normalize_signature(signature: &str, types: &BTreeMap<String, String>) -> String {
+    let expanded = expand(signature, types, 0);
+    let compact: String = expanded.split_whitespace().collect::<Vec<_>>().join(" ");
+    compact
+        .replace(" ,", ",")
+        .replace(", )", ")")
+        .replace(",)", ")")
+        .replace("; }", " }")
+        .replace(";}", "}")
+}
+
+fn expand(text: &str, types: &BTreeMap<String, String>, depth: usize) -> String {
+    let mut result = String::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if !(c.is_alphanumeric() || c == '_') {
+            result.push(c);
+            continue;
+        }
+        let mut end = start + c.len_utf8();
+        while let Some(&(i, next)) = chars.peek() {
+            if next.is_alphanumeric() || next == '_' {
+                end = i + next.len_utf8();
+                chars.next();
+            } else {
+                break;
+            }
+        }
+        let word = &text[start..end];
+        // A word followed by ':' is a field or argument label, not a type
+        let is_label = text[end..].trim_start().starts_with(':');
+        match types.get(word) {
+            Some(definition) if !is_label && depth < MAX_EXPANSION_DEPTH => {
+                result.push('(');
+                result.push_str(&expand(definition, types, depth + 1));
+                result.push(')');
+            }
+            _ => result.push_str(word),
+        }
+    }
+    result
+}
+
+// The Candid interface exported by the running wasm
+#[ic_cdk::query]
+fn get_candid_interface() -> String {
+    current_interface()
+}
+
+// Compares the exported interface with a previous .did, method by method
+#[ic_cdk::query]
+fn check_interface_compatibility(
+    previous_did: String,
+) -> Result<InterfaceCompatibilityReport, Error> {
+    let current_did = current_interface();
+    let previous = parse_interface(&previous_did).map_err(|msg| Error::InvalidInput {
+        msg,
+        violations: None,
+    })?;
+    let current = parse_interface(&current_did).map_err(|msg| Error::InvalidInput {
+        msg: format!("cannot parse the current interface: {}", msg),
+        violations: None,
+    })?;
+
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    let mut changed = Vec::new();
+    for (name, signature) in &current.methods {
+        match previous.methods.get(name) {
+            None => added.push(name.clone()),
+            Some(previous_signature) => {
+                if normalize_signature(previous_signature, &previous.types)
+                    != normalize_signature(signature, &current.types)
+                {
+                    changed.push(MethodChange {
+                        name: name.clone(),
+                        previous: previous_signature.clone(),
+                        current: signature.clone(),
+                    });
+                }
+            }
+        }
+    }
+    for name in previous.methods.keys() {
+        if !current.methods.contains_key(name) {
+            removed.push(name.clone());
+        }
+    }
+
+    Ok(InterfaceCompatibilityReport {
+        compatible: removed.is_empty() && changed.is_empty(),
+        added,
+        removed,
+        changed,
+        current_interface: current_did,
+    })
+}
@@ -0,0 +1,120 @@
+use crate::filters::ReadingFilter;
+use crate::grafana::format_time;
+use crate::paging::{take_within, ReplyBudget};
+use crate::publication::{self, PublicationState};
+use crate::units::{self, ConcentrationUnit};
+use crate::{is_visible_to_caller, AirQualityData, AIR_QUALITY_STORAGE};
+use std::ops::Bound;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct JsonLinesChunk {
+    // One JSON object per reading, each ending with a newline
+    body: String,
+    lines: u32,
+    // More readings match; pass `next_cursor` to fetch them
+    truncated: bool,
+    next_cursor: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct JsonLevel<'a> {
+    pollutant: &'a str,
+    level: f64,
+    unit: Option<ConcentrationUnit>,
+}
+
+#[derive(Serialize)]
+struct JsonWeather {
+    temperature: f64,
+    humidity: f64,
+    wind_speed: f64,
+    pressure: Option<f64>,
+}
+
+// A reading as one flat object. Levels are a list rather than an object keyed by pollutant, so
+// loaders that infer a schema see one column per field whatever a reading reports.
+#[derive(Serialize)]
+struct JsonReading<'a> {
+    id: u64,
+    location: &'a str,
+    station_id: Option<&'a str>,
+    // Nanoseconds since the epoch, and the same as RFC 3339 for loaders that parse timestamps
+    timestamp: u64,
+    observed_at: String,
+    ingested_at: Option<String>,
+    air_quality_index: u32,
+    health_recommendations: &'a str,
+    pollutant_levels: Vec<JsonLevel<'a>>,
+    weather_conditions: JsonWeather,
+    publication_state: PublicationState,
+    version: u64,
+}
+
+fn json_line(data: &AirQualityData) -> String {
+    let mut pollutant_levels: Vec<JsonLevel> = data
+        .pollutant_levels
+        .iter()
+        .map(|(pollutant, level)| JsonLevel {
+            pollutant: pollutant.name(),
+            level: *level,
+            unit: data
+                .pollutant_units
+                .as_ref()
+                .and_then(|units| units.get(pollutant))
+                .copied(),
+        })
+        .collect();
+    pollutant_levels.sort_by(|a, b| a.pollutant.cmp(b.pollutant));
+    let weather = &data.weather_conditions;
+    let reading = JsonReading {
+        id: data.id,
+        location: &data.location,
+        station_id: data.station_id.as_deref(),
+        timestamp: data.timestamp,
+        observed_at: format_time(data.timestamp),
+        ingested_at: data.ingested_at.map(format_time),
+        air_quality_index: data.air_quality_index,
+        health_recommendations: &data.health_recommendations,
+        pollutant_levels,
+        weather_conditions: JsonWeather {
+            temperature: weather.temperature,
+            humidity: weather.humidity,
+            wind_speed: weather.wind_speed,
+            pressure: weather.pressure,
+        },
+        publication_state: publication::state_of(data),
+        version: data.version,
+    };
+    let mut line = serde_json::to_string(&reading).expect("readings serialize to JSON");
+    line.push('\n');
+    line
+}
+
+// The readings the filter matches, in id order, as newline-delimited JSON for loaders such as
+// Spark and BigQuery load jobs. Each call returns the chunk that fits in one reply; chunks
+// continue after the reading with id `cursor`.
+#[ic_cdk::query]
+fn export_jsonl(filter: ReadingFilter, cursor: Option<u64>) -> JsonLinesChunk {
+    let unit = filter.unit();
+    let predicate = filter.predicate();
+    let start = cursor.map_or(Bound::Unbounded, Bound::Excluded);
+    let (lines, truncated) = AIR_QUALITY_STORAGE.with(|service| {
+        take_within(
+            service
+                .borrow()
+                .range((start, Bound::Unbounded))
+                .map(|(_, data)| data)
+                .filter(|data| !data.is_deleted() && is_visible_to_caller(data))
+                .map(|data| units::in_unit(data, unit))
+                .filter(|data| predicate(data))
+                .map(|data| (data.id, json_line(&data))),
+            &mut ReplyBudget::new(),
+        )
+    });
+    JsonLinesChunk {
+        next_cursor: lines.last().filter(|_| truncated).map(|(id, _)| *id),
+        lines: lines.len() as u32,
+        body: lines.into_iter().map(|(_, line)| line).collect(),
+        truncated,
+    }
+}
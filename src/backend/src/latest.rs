@@ -0,0 +1,132 @@
+use crate::paging::{take_within, ReplyBudget};
+use crate::{
+    aliases, clear_stable_map, dedup, get_memory, is_visible_to_caller, AirQualityData, Error,
+    Memory, StringKey, AIR_QUALITY_STORAGE, LATEST_INDEX_MEMORY_ID,
+};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::ops::Bound;
+
+// Longer locations are not indexed, like in the duplicate index this falls back on
+const MAX_LOCATION_LEN: usize = 200;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct LatestReadingPage {
+    // One reading per location, in location order
+    readings: Vec<AirQualityData>,
+    // Last location returned, if more follow
+    next_cursor: Option<String>,
+}
+
+thread_local! {
+    // Id of the last observed live, published reading of each location
+    static LATEST_INDEX: RefCell<StableBTreeMap<StringKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(LATEST_INDEX_MEMORY_ID)));
+}
+
+pub(crate) fn reset() {
+    LATEST_INDEX.with(|s| clear_stable_map(s, LATEST_INDEX_MEMORY_ID));
+}
+
+fn counts(data: &AirQualityData) -> bool {
+    !data.is_deleted() && data.is_published() && data.location.len() <= MAX_LOCATION_LEN
+}
+
+fn get_record(id: u64) -> Option<AirQualityData> {
+    AIR_QUALITY_STORAGE.with(|service| service.borrow().get(&id))
+}
+
+fn indexed(location: &str) -> Option<u64> {
+    LATEST_INDEX.with(|s| s.borrow().get(&StringKey(location.to_string())))
+}
+
+// Looks the latest reading of a location up again, after the indexed one stopped counting
+fn refresh(location: &str) {
+    let key = StringKey(location.to_string());
+    match dedup::latest_reading_where(location, counts) {
+        Some(latest) => LATEST_INDEX.with(|s| s.borrow_mut().insert(key, latest.id)),
+        None => LATEST_INDEX.with(|s| s.borrow_mut().remove(&key)),
+    };
+}
+
+// Keeps the index in step with a write; `previous` is the record it replaced. Runs after the
+// duplicate index is updated.
+pub(crate) fn on_write(previous: Option<&AirQualityData>, data: &AirQualityData) {
+    if let Some(previous) = previous {
+        let was_latest = indexed(&previous.location) == Some(previous.id);
+        if was_latest
+            && (previous.location != data.location
+                || !counts(data)
+                || data.timestamp < previous.timestamp)
+        {
+            refresh(&previous.location);
+        }
+    }
+    if !counts(data) {
+        return;
+    }
+    let newer = indexed(&data.location)
+        .and_then(get_record)
+        .is_none_or(|latest| latest.timestamp <= data.timestamp);
+    if newer {
+        LATEST_INDEX.with(|s| {
+            s.borrow_mut()
+                .insert(StringKey(data.location.clone()), data.id)
+        });
+    }
+}
+
+// Adds a reading stored before the index existed
+pub(crate) fn backfill(data: &AirQualityData) {
+    on_write(None, data);
+}
+
+// The latest reading of a location the caller may see. Readings removed without a write, or
+// hidden from the caller, are looked past through the duplicate index.
+pub(crate) fn latest(location: &str) -> Option<AirQualityData> {
+    indexed(location)
+        .and_then(get_record)
+        .filter(|data| counts(data) && is_visible_to_caller(data))
+        .or_else(|| {
+            dedup::latest_reading_where(location, |data| counts(data) && is_visible_to_caller(data))
+        })
+}
+
+// Locations with a latest reading after `after`, in order
+pub(crate) fn indexed_locations(after: Option<String>) -> Vec<String> {
+    let start = after.map_or(Bound::Unbounded, |location| {
+        Bound::Excluded(StringKey(location))
+    });
+    LATEST_INDEX.with(|s| {
+        s.borrow()
+            .range((start, Bound::Unbounded))
+            .map(|(StringKey(location), _)| location)
+            .collect()
+    })
+}
+
+// The most recently observed published reading of a location
+#[ic_cdk::query]
+fn get_latest_reading(location: String) -> Result<AirQualityData, Error> {
+    let location = aliases::location(location);
+    latest(&location).ok_or(Error::NotFound {
+        msg: format!("no readings of {}", location),
+    })
+}
+
+// The latest reading of every location after `cursor`, for dashboards
+#[ic_cdk::query]
+fn get_latest_readings_all_locations(cursor: Option<String>) -> LatestReadingPage {
+    let locations = indexed_locations(cursor);
+    let (readings, truncated) = take_within(
+        locations.iter().filter_map(|location| latest(location)),
+        &mut ReplyBudget::new(),
+    );
+    LatestReadingPage {
+        next_cursor: readings
+            .last()
+            .filter(|_| truncated)
+            .map(|data| data.location.clone()),
+        readings,
+    }
+}
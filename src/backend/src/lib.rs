@@ -1,14 +1,358 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
+use candid::Encode;
 use ic_cdk::api::time;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
+use ic_stable_structures::{
+    storable::Blob, BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable,
+};
 use std::collections::HashMap;
+use std::ops::Bound;
 use std::{borrow::Cow, cell::RefCell};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
+// Principals are at most 29 bytes long
+type PrincipalKey = Blob<29>;
+
+// String key for stable maps, ordered like the string itself. Callers validate the length.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct StringKey(String);
+
+impl Storable for StringKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        StringKey(String::from_utf8(bytes.into_owned()).expect("string keys are valid UTF-8"))
+    }
+}
+
+impl BoundedStorable for StringKey {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implements Storable/BoundedStorable for a candid type, mirroring AirQualityData
+macro_rules! impl_bounded_storable {
+    ($type:ty, $max_size:expr) => {
+        const _: () = {
+            use candid::{Decode, Encode};
+            use ic_stable_structures::{BoundedStorable, Storable};
+            use std::borrow::Cow;
+
+            impl Storable for $type {
+                fn to_bytes(&self) -> Cow<'_, [u8]> {
+                    Cow::Owned(Encode!(self).unwrap())
+                }
+
+                fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                    Decode!(bytes.as_ref(), Self).unwrap()
+                }
+            }
+
+            impl BoundedStorable for $type {
+                const MAX_SIZE: u32 = $max_size;
+                const IS_FIXED_SIZE: bool = false;
+            }
+        };
+    };
+}
+
+// Defines a page of a list query that is cut short of the reply limit, e.g.
+// `continued_page!(IncidentPage, Incident)`
+macro_rules! continued_page {
+    ($page:ident, $item:ty) => {
+        #[derive(candid::CandidType, Serialize, Deserialize)]
+        pub(crate) struct $page {
+            items: Vec<$item>,
+            // More items follow; pass it back as `continuation` to fetch them
+            continuation: Option<String>,
+        }
+
+        impl $page {
+            fn new(
+                items: impl IntoIterator<Item = $item>,
+                continuation: Option<String>,
+            ) -> Result<Self, $crate::Error> {
+                let (items, continuation) = $crate::paging::continue_within(items, continuation)?;
+                Ok($page {
+                    items,
+                    continuation,
+                })
+            }
+        }
+    };
+}
+
+// Writes a structured log entry, e.g. `log!(Warn, "chunk failed", "job" => id)`
+macro_rules! log {
+    ($level:ident, $message:expr $(, $key:literal => $value:expr)* $(,)?) => {
+        $crate::logging::write(
+            $crate::logging::LogLevel::$level,
+            module_path!(),
+            $message,
+            vec![$(($key, $value.to_string())),*],
+        )
+    };
+}
+
+mod aggregates;
+mod airnow;
+mod alerts;
+mod aliases;
+mod annotations;
+mod announcements;
+mod anomalies;
+mod api_keys;
+mod aqi_index;
+mod archive;
+mod ask;
+mod backfill;
+mod backups;
+mod bans;
+mod bootstrap;
+mod branding;
+mod calibration;
+mod capacity;
+mod categories;
+mod changelog;
+mod changes;
+mod compaction;
+mod consumers;
+mod contributors;
+mod cycles;
+mod dedup;
+mod digest;
+mod downsampling;
+mod events;
+mod exceedances;
+mod exports;
+mod field_visits;
+mod filters;
+mod forecast;
+mod grafana;
+mod heatmap;
+mod history;
+mod home_assistant;
+mod http;
+mod idempotency;
+mod incidents;
+mod interface;
+mod jsonl;
+mod latest;
+mod line_protocol;
+mod location_deletion;
+mod location_search;
+mod locations;
+mod logging;
+mod maintenance;
+mod merge;
+mod methodology;
+mod migrations;
+mod openaq;
+mod organizations;
+mod paging;
+mod pollutant;
+mod publication;
+mod purpleair;
+mod quotas;
+mod rankings;
+mod retention;
+mod rollups;
+mod scheduler;
+mod schema;
+mod sensor_community;
+mod sensorthings;
+mod sharding;
+mod slo;
+mod snapshots;
+mod sources;
+mod standards;
+mod stations;
+mod text_search;
+mod time_index;
+mod tools;
+mod units;
+mod validation;
+mod writers;
+
+use aggregates::{
+    AggregationBucket, AggregationStat, AqiHistogram, LocationAggregate, LocationSummary,
+    PollutantPercentiles, RateOfChange, ReadingBucket, Trend,
+};
+use airnow::{AirNowArea, AirNowIngestReport};
+use alerts::{Alert, AlertPage, Subscription, SubscriptionPayload};
+use aliases::{Alias, AliasTarget};
+use annotations::AnnotationPage;
+use announcements::{Announcement, AnnouncementSeverity};
+use anomalies::FlaggedReadingPage;
+use api_keys::{ApiKey, ApiKeyPage, ApiKeyPayload, IssuedApiKey};
+use archive::{ArchiveSettings, ArchiveStatus};
+use ask::Answer;
+use backfill::{BackfillJob, BackfillJobPage, BackfillSourceConfig};
+use backups::{BackupChunk, BackupManifest, RestoreStatus};
+use bans::{not_banned, Ban};
+use bootstrap::BootstrapStatus;
+use branding::{Branding, BrandingPayload};
+use calibration::{Calibration, CalibrationPage, CalibrationPayload};
+use candid::Principal;
+use capacity::{CapacitySettings, StableMemoryUsage};
+use changelog::{ChangelogEntry, ChangelogPage};
+use changes::ChangePage;
+use compaction::DailyAggregatePage;
+use consumers::{Consumer, ConsumerDeliveryPage, ConsumerPayload};
+use contributors::ContributorStats;
+use cycles::{CyclesSettings, CyclesStatus};
+use digest::{DailyDigest, DigestSubscription};
+use downsampling::{DownsampledSeries, DownsamplingMethod};
+use events::Event;
+use exceedances::ExceedanceReport;
+use exports::{Export, ExportChunk};
+use field_visits::{FieldVisit, FieldVisitPage};
+use filters::{PollutantCondition, QueryCriteria, ReadingFilter, SortOrder};
+use forecast::AqiForecast;
+use heatmap::{BoundingBox, Heatmap};
+use history::RecordVersionPage;
+use http::{HttpGatewayResponse, HttpRequest};
+use ic_cdk::api::management_canister::http_request::{HttpResponse, TransformArgs};
+use incidents::{Incident, IncidentPage, IncidentPayload, IncidentUpdatePayload};
+use interface::InterfaceCompatibilityReport;
+use jsonl::JsonLinesChunk;
+use latest::LatestReadingPage;
+use line_protocol::LineProtocolPage;
+use location_deletion::{LocationDeletionJob, LocationDeletionJobPage};
+use locations::{LocationAlias, LocationAliasPage};
+use logging::{LogEntry, LogLevel};
+use methodology::{MethodologyNote, MethodologyNotePage, MethodologyScope, TimeSeries};
+use migrations::MigrationStatus;
+use openaq::OpenAqPage;
+use organizations::{
+    Organization, OrganizationMember, OrganizationMemberPage, OrganizationPage,
+    OrganizationPayload, OrganizationRole,
+};
+use paging::ReadingPage;
+use pollutant::Pollutant;
+use publication::{PublicationState, RetractionPage};
+use purpleair::PurpleAirIngestReport;
+use quotas::{OrganizationQuota, OrganizationUsage};
+use rankings::{RankedLocation, RankingMetric, RankingWindow};
+use retention::{RetentionPolicy, RetentionReport};
+use rollups::{HourlyRollupPage, PollutantRatio, RollingAverages};
+use scheduler::SchedulerState;
+use schema::SchemaVersion;
+use sensor_community::{SensorMapping, SensorMappingPage};
+use sharding::{Shard, ShardCursor, ShardedAirQualityData, ShardedReadingPage};
+use slo::SloReport;
+use snapshots::SnapshotDiff;
+use sources::ReadingSource;
+use standards::{AqiCategory, AqiStandard, GuidelineStandard, StandardDetails};
+use stations::{PublicStation, PublicStationPage, Station, StationPayload, StationProvisioning};
+use units::{ConcentrationUnit, PollutantUnit, PollutantUnits};
+use validation::{RuleViolation, ValidationRule};
+use writers::AllowedWriter;
+
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+// Bounds of client-provided observation times: not before 2000-01-01, not in the future
+// beyond clock skew
+const EARLIEST_OBSERVATION: u64 = 946_684_800 * 1_000_000_000;
+const MAX_CLOCK_SKEW_NANOS: u64 = 5 * 60 * 1_000_000_000;
+
+// Memory ids handed out by the memory manager. Never reuse or reorder them.
+const AIR_QUALITY_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(0);
+const AIR_QUALITY_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(1);
+const INCIDENT_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(2);
+const INCIDENT_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(3);
+const ANNOTATION_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(4);
+const ANNOTATION_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(5);
+const BACKFILL_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(6);
+const BACKFILL_JOB_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(7);
+const BACKFILL_CHUNK_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(8);
+const RETENTION_POLICY_MEMORY_ID: MemoryId = MemoryId::new(9);
+const RETENTION_REPORT_MEMORY_ID: MemoryId = MemoryId::new(10);
+const ARCHIVE_STATE_MEMORY_ID: MemoryId = MemoryId::new(11);
+const ARCHIVE_WASM_MEMORY_ID: MemoryId = MemoryId::new(12);
+const MIGRATION_STATE_MEMORY_ID: MemoryId = MemoryId::new(13);
+const SHARD_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(14);
+const STATION_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(15);
+const SCHEMA_VERSION_MEMORY_ID: MemoryId = MemoryId::new(16);
+const CONTRIBUTOR_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(17);
+const EVENT_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(18);
+const EVENT_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(19);
+const ANNOUNCEMENT_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(20);
+const ANNOUNCEMENT_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(21);
+const IDEMPOTENCY_KEY_MEMORY_ID: MemoryId = MemoryId::new(22);
+const IDEMPOTENCY_EXPIRY_MEMORY_ID: MemoryId = MemoryId::new(23);
+const METHODOLOGY_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(24);
+const METHODOLOGY_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(25);
+const POLLUTANT_UNIT_MEMORY_ID: MemoryId = MemoryId::new(26);
+const ROLLUP_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(27);
+const ROLLUP_BACKFILL_MEMORY_ID: MemoryId = MemoryId::new(28);
+const CALIBRATION_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(29);
+const CALIBRATION_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(30);
+const LOG_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(31);
+const LOG_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(32);
+const LOG_READER_MEMORY_ID: MemoryId = MemoryId::new(33);
+const VALIDATION_RULES_MEMORY_ID: MemoryId = MemoryId::new(34);
+const SLO_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(35);
+const SLO_LATENCY_MEMORY_ID: MemoryId = MemoryId::new(36);
+const ANOMALY_WINDOW_MEMORY_ID: MemoryId = MemoryId::new(37);
+const ANOMALY_FLAG_MEMORY_ID: MemoryId = MemoryId::new(38);
+const DEDUP_INDEX_MEMORY_ID: MemoryId = MemoryId::new(39);
+const LOCATION_DELETION_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(40);
+const LOCATION_DELETION_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(41);
+const CHANGELOG_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(42);
+const CHANGELOG_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(43);
+const FIELD_VISIT_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(44);
+const FIELD_VISIT_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(45);
+const SUBSCRIPTION_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(46);
+const SUBSCRIPTION_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(47);
+const SUBSCRIPTION_INDEX_MEMORY_ID: MemoryId = MemoryId::new(48);
+const ALERT_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(49);
+const ALERT_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(50);
+const ALERT_DELIVERY_QUEUE_MEMORY_ID: MemoryId = MemoryId::new(51);
+const RETRACTION_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(52);
+const DIGEST_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(53);
+const DIGEST_SUBSCRIPTION_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(54);
+const DIGEST_SUBSCRIPTION_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(55);
+const BRANDING_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(56);
+const ALIAS_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(57);
+const TEXT_INDEX_MEMORY_ID: MemoryId = MemoryId::new(58);
+const LOCATION_ALIAS_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(59);
+const BOOTSTRAP_STATE_MEMORY_ID: MemoryId = MemoryId::new(60);
+const LATEST_INDEX_MEMORY_ID: MemoryId = MemoryId::new(61);
+const CATEGORY_INDEX_MEMORY_ID: MemoryId = MemoryId::new(62);
+const AQI_INDEX_MEMORY_ID: MemoryId = MemoryId::new(63);
+const EXPORT_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(64);
+const EXPORT_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(65);
+const DAILY_AGGREGATE_MEMORY_ID: MemoryId = MemoryId::new(66);
+const RECORD_HISTORY_MEMORY_ID: MemoryId = MemoryId::new(67);
+const CHANGE_SEQ_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(68);
+const CHANGE_LOG_MEMORY_ID: MemoryId = MemoryId::new(69);
+const CONSUMER_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(70);
+const CONSUMER_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(71);
+const CONSUMER_DELIVERY_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(72);
+const CONSUMER_DELIVERY_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(73);
+const CONSUMER_DELIVERY_QUEUE_MEMORY_ID: MemoryId = MemoryId::new(74);
+const SENSOR_COMMUNITY_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(75);
+const CAPACITY_SETTINGS_MEMORY_ID: MemoryId = MemoryId::new(76);
+const CYCLES_SETTINGS_MEMORY_ID: MemoryId = MemoryId::new(77);
+const API_KEY_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(78);
+const API_KEY_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(79);
+const ORGANIZATION_ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(80);
+const ORGANIZATION_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(81);
+const ORGANIZATION_MEMBER_STORAGE_MEMORY_ID: MemoryId = MemoryId::new(82);
+const ORGANIZATION_QUOTA_MEMORY_ID: MemoryId = MemoryId::new(83);
+const ORGANIZATION_RECORD_COUNT_MEMORY_ID: MemoryId = MemoryId::new(84);
+const ORGANIZATION_WRITE_COUNT_MEMORY_ID: MemoryId = MemoryId::new(85);
+const ALLOWED_WRITER_MEMORY_ID: MemoryId = MemoryId::new(86);
+const WRITER_ALLOWLIST_ENFORCED_MEMORY_ID: MemoryId = MemoryId::new(87);
+const BAN_MEMORY_ID: MemoryId = MemoryId::new(88);
+const TIME_INDEX_MEMORY_ID: MemoryId = MemoryId::new(89);
+const ORGANIZATION_ROLLUP_MEMORY_ID: MemoryId = MemoryId::new(90);
+const ORGANIZATION_ROLLUP_BACKFILL_MEMORY_ID: MemoryId = MemoryId::new(91);
+const ORGANIZATION_DAILY_AGGREGATE_MEMORY_ID: MemoryId = MemoryId::new(92);
 // ... (existing imports and types)
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
@@ -18,18 +362,64 @@ struct AirQualityData {
     timestamp: u64,
     air_quality_index: u32,
     health_recommendations: String,
-    pollutant_levels: HashMap<String, f64>,
+    pollutant_levels: HashMap<Pollutant, f64>,
     weather_conditions: WeatherData,
+    // Tombstone: set when the record is deleted, until it is restored or purged
+    deleted_at: Option<u64>,
+    station_id: Option<String>,
+    // When the canister received the reading; `timestamp` is when it was measured.
+    // None for readings stored before the two were told apart, whose timestamp is the ingestion time.
+    ingested_at: Option<u64>,
+    // Incremented on every change; writers pass the version they read to detect lost updates
+    version: u64,
+    // Unit each pollutant level is stored in
+    pollutant_units: Option<PollutantUnits>,
+    // Levels as reported, before the sensor's calibration corrected `pollutant_levels`.
+    // None for readings stored without calibration.
+    raw_pollutant_levels: Option<HashMap<Pollutant, f64>>,
+    // None for readings stored before drafts existed, which are published
+    publication_state: Option<PublicationState>,
+    // Readings of the station merged into this one; None for a single reading
+    merged_readings: Option<u32>,
+    // Tenant the reading belongs to, that of its station if it has one
+    organization_id: Option<u64>,
+    // How the reading entered the canister; None for readings stored before sources were
+    // recorded. Edits keep it.
+    source: Option<ReadingSource>,
+}
+
+impl AirQualityData {
+    fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    // Published and corrected readings are part of the public record
+    fn is_published(&self) -> bool {
+        publication::state_of(self).is_public()
+    }
+
+    fn check_version(&self, expected_version: u64) -> Result<(), Error> {
+        if self.version != expected_version {
+            return Err(Error::Conflict {
+                msg: format!(
+                    "air quality data with id={} is at version {}, not {}",
+                    self.id, self.version, expected_version
+                ),
+            });
+        }
+        Ok(())
+    }
 }
 
 impl Storable for AirQualityData {
     // Implement Storable trait methods for serialization and deserialization
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
+    // Records written with an older layout are converted on read
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        schema::decode_air_quality_data(bytes.as_ref())
     }
 }
 
@@ -44,27 +434,197 @@ thread_local! {
     );
 
     static AIR_QUALITY_ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(AIR_QUALITY_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
+        IdCell::init(AIR_QUALITY_MEMORY_MANAGER.with(|m| m.borrow().get(AIR_QUALITY_ID_COUNTER_MEMORY_ID)), 0)
             .expect("Cannot create a counter for air quality data")
     );
 
     static AIR_QUALITY_STORAGE: RefCell<StableBTreeMap<u64, AirQualityData, Memory>> =
         RefCell::new(StableBTreeMap::init(
-            AIR_QUALITY_MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+            AIR_QUALITY_MEMORY_MANAGER.with(|m| m.borrow().get(AIR_QUALITY_STORAGE_MEMORY_ID))
     ));
 }
 
+fn get_memory(id: MemoryId) -> Memory {
+    AIR_QUALITY_MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
+// Empties a stable map by writing a fresh one over its memory. The memory's pages stay
+// allocated to it and are reused.
+fn clear_stable_map<K, V>(map: &RefCell<StableBTreeMap<K, V, Memory>>, id: MemoryId)
+where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+{
+    *map.borrow_mut() = StableBTreeMap::new(get_memory(id));
+}
+
+fn principal_key(principal: &Principal) -> PrincipalKey {
+    PrincipalKey::try_from(principal.as_slice()).expect("principals are at most 29 bytes")
+}
+
+// Returns the current value of an id counter and advances it
+fn next_id(counter: &'static std::thread::LocalKey<RefCell<IdCell>>) -> u64 {
+    counter
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("cannot increment id counter")
+}
+
+// Runs before every update call from a user, and refuses banned callers and calls that would
+// write while a backup holds writes
+#[ic_cdk::inspect_message]
+fn inspect_message() {
+    // Bans are not looked up once a restore is under way, as it may have overwritten them;
+    // only the restore methods are let through then
+    if backups::accepts_update(&ic_cdk::api::call::method_name())
+        && (backups::is_restoring() || !bans::is_banned(&ic_cdk::caller()))
+    {
+        ic_cdk::api::call::accept_message();
+    }
+}
+
+// Guard of every update method that writes: refuses banned callers, and every call while a
+// backup or a restore holds writes. inspect_message only sees ingress messages.
+fn accepts_writes() -> Result<(), String> {
+    not_banned()?;
+    if backups::is_frozen() {
+        return Err("writes are held while a backup is open or a restore is under way".to_string());
+    }
+    Ok(())
+}
+
+// Only controllers of the canister may perform administrative actions
+fn ensure_admin() -> Result<(), Error> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: "caller is not a controller of this canister".to_string(),
+        })
+    }
+}
+
+// The observation time of a reading, checked against sane bounds
+fn observation_time(observed_at: Option<u64>) -> Result<u64, Error> {
+    let now = time();
+    match observed_at {
+        None => Ok(now),
+        Some(observed_at)
+            if observed_at >= EARLIEST_OBSERVATION
+                && observed_at <= now.saturating_add(MAX_CLOCK_SKEW_NANOS) =>
+        {
+            Ok(observed_at)
+        }
+        Some(observed_at) => Err(Error::InvalidInput {
+            msg: format!(
+                "observed_at={} is before 2000-01-01 or in the future",
+                observed_at
+            ),
+            violations: None,
+        }),
+    }
+}
+
+// Stable storage traps on records over MAX_SIZE, so every write path checks first
+fn check_record_size(data: &AirQualityData) -> Result<(), Error> {
+    let size = data.to_bytes().len();
+    if size > AirQualityData::MAX_SIZE as usize {
+        return Err(Error::TooLarge {
+            msg: format!(
+                "the encoded record is {} bytes, the limit is {} bytes; send fewer pollutants or a shorter health recommendation",
+                size,
+                AirQualityData::MAX_SIZE
+            ),
+        });
+    }
+    Ok(())
+}
+
 // Helper method to perform insert for AirQualityData
 fn do_insert_air_quality(data: &AirQualityData) {
-    AIR_QUALITY_STORAGE.with(|service| service.borrow_mut().insert(data.id, data.clone()));
+    let previous =
+        AIR_QUALITY_STORAGE.with(|service| service.borrow_mut().insert(data.id, data.clone()));
+    rollups::on_write(previous.as_ref(), data);
+    dedup::on_write(previous.as_ref(), data);
+    text_search::on_write(previous.as_ref(), data);
+    latest::on_write(previous.as_ref(), data);
+    categories::on_write(previous.as_ref(), data);
+    aqi_index::on_write(previous.as_ref(), data);
+    history::on_write(previous.as_ref(), data);
+    changes::on_write(previous.as_ref(), data);
+    quotas::on_write(previous.as_ref(), data);
+    time_index::on_write(previous.as_ref(), data);
+}
+
+// What is kept of a record removed from the storage
+#[derive(Clone, Copy, PartialEq)]
+enum Removal {
+    // Deleted for good, its hour leaving the hourly rollups too
+    Purged,
+    // Expired under the retention policy; its hour stays in the hourly rollups, so long-term
+    // aggregates outlive it
+    Expired,
+    // Moved to the archive canister, which serves it from then on. Its hour stays in the
+    // rollups and its history here, and the change feed does not report it as removed.
+    Archived,
+}
+
+// Removes a record with its suspect flag and quota share and, unless it was archived, drops its
+// history and records the removal in the change feed. The lookup indexes skip removed records on
+// their own.
+fn remove_air_quality(data: &AirQualityData, removal: Removal) {
+    AIR_QUALITY_STORAGE.with(|service| service.borrow_mut().remove(&data.id));
+    time_index::on_remove(data);
+    if removal == Removal::Purged {
+        rollups::on_remove(data);
+    }
+    quotas::on_remove(data);
+    anomalies::on_remove(data.id);
+    if removal != Removal::Archived {
+        history::on_remove(data.id);
+        changes::on_remove(data.id);
+    }
+}
+
+// Readings of aggregate-only stations are hidden from everyone but the station owner and
+// controllers; they still count towards aggregates. Embargoed readings are hidden from them too.
+// Unpublished readings are only visible to their reviewers, and readings of members-only
+// organizations to their members.
+fn is_visible_to_caller(data: &AirQualityData) -> bool {
+    publication::is_visible_to_caller(data)
+        && organizations::is_visible_to_caller(data.organization_id)
+        && data.station_id.as_deref().is_none_or(|station_id| {
+            stations::is_station_visible(station_id)
+                && !stations::is_embargoed(station_id, data.timestamp)
+        })
+}
+
+// Collects every record visible to the caller that is not deleted and matches the predicate
+fn filter_air_quality_data<F>(predicate: F) -> Vec<AirQualityData>
+where
+    F: Fn(&AirQualityData) -> bool,
+{
+    AIR_QUALITY_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, data)| !data.is_deleted() && is_visible_to_caller(data) && predicate(data))
+            .map(|(_, data)| data)
+            .collect()
+    })
 }
 
 // Existing struct for weather conditions
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct WeatherData {
+    // °C
     temperature: f64,
     humidity: f64,
     wind_speed: f64,
+    // hPa; used to convert gas concentrations, which assume 1013.25 hPa without it
+    pressure: Option<f64>,
 }
 
 // ... (existing thread-local variables and payload structure)
@@ -74,17 +634,67 @@ struct AirQualityUpdatePayload {
     location: String,
     air_quality_index: u32,
     health_recommendations: String,
-    pollutant_levels: Option<HashMap<String, f64>>,
+    pollutant_levels: Option<HashMap<Pollutant, f64>>,
+    // Units the levels are reported in; converted to each pollutant's unit of record
+    pollutant_units: Option<PollutantUnits>,
     weather_conditions: Option<WeatherData>,
+    // Registered station that produced the reading
+    station_id: Option<String>,
+    // When the reading was measured; defaults to the time it is received
+    observed_at: Option<u64>,
+    // Retries of an add with the same key return the original record instead of a duplicate
+    idempotency_key: Option<String>,
+    // Corrects the levels with the calibrations of the station active at the observation time
+    apply_calibration: Option<bool>,
+    // Stores the reading as a draft, hidden until it is published with publish_reading
+    draft: Option<bool>,
+    // Organization of a reading without a station; readings of a station belong to its
+    // organization. The caller must be a member.
+    organization_id: Option<u64>,
 }
 
 // ... (existing functions)
 
+// How a patch changes the pollutant levels of a record
+#[derive(candid::CandidType, Serialize, Deserialize)]
+enum PollutantPatch {
+    Replace(HashMap<Pollutant, f64>),
+    // Sets the given levels and removes the named pollutants, leaving the others as they are
+    Merge {
+        set: HashMap<Pollutant, f64>,
+        remove: Vec<Pollutant>,
+    },
+}
+
+// Fields left as None are not changed
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct AirQualityPatchPayload {
+    location: Option<String>,
+    air_quality_index: Option<u32>,
+    health_recommendations: Option<String>,
+    pollutant_levels: Option<PollutantPatch>,
+    // Units the patched levels are reported in
+    pollutant_units: Option<PollutantUnits>,
+    weather_conditions: Option<WeatherData>,
+    station_id: Option<String>,
+    observed_at: Option<u64>,
+    // Defaults to whether the record is calibrated. Levels of calibrated records are patched
+    // as reported and corrected again.
+    apply_calibration: Option<bool>,
+}
+
 // 2.7.8 get_air_quality_data Function:
-#[ic_cdk::query]
-fn get_air_quality_data(id: u64) -> Result<AirQualityData, Error> {
-    match _get_air_quality_data(&id) {
-        Some(data) => Ok(data),
+// Falls through to the archive canister for records moved out of this one
+#[ic_cdk::query(composite = true)]
+async fn get_air_quality_data(
+    id: u64,
+    unit: Option<ConcentrationUnit>,
+) -> Result<AirQualityData, Error> {
+    if let Some(data) = _get_air_quality_data(&id) {
+        return Ok(units::in_unit(data, unit));
+    }
+    match archive::get_archived(id).await.filter(is_visible_to_caller) {
+        Some(data) => Ok(units::in_unit(data, unit)),
         None => Err(Error::NotFound {
             msg: format!("air quality data with id={} not found", id),
         }),
@@ -93,103 +703,508 @@ fn get_air_quality_data(id: u64) -> Result<AirQualityData, Error> {
 
 // 2.7.9 _get_air_quality_data Function:
 fn _get_air_quality_data(id: &u64) -> Option<AirQualityData> {
-    AIR_QUALITY_STORAGE.with(|s| s.borrow().get(id))
+    AIR_QUALITY_STORAGE
+        .with(|s| s.borrow().get(id))
+        .filter(|data| !data.is_deleted() && is_visible_to_caller(data))
 }
 
 // 2.7.10 add_air_quality_data Function:
-#[ic_cdk::update]
-fn add_air_quality_data(data: AirQualityUpdatePayload) -> Option<AirQualityData> {
-    let id = AIR_QUALITY_ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("cannot increment id counter for air quality data");
+#[ic_cdk::update(guard = "accepts_writes")]
+fn add_air_quality_data(data: AirQualityUpdatePayload) -> Result<AirQualityData, Error> {
+    slo::tracked_ingestion("add_air_quality_data", || {
+        writers::ensure_allowed_writer(data.station_id.as_deref())?;
+        if let Some(key) = &data.idempotency_key {
+            idempotency::validate_key(key)?;
+            if let Some(id) = idempotency::lookup(key) {
+                // A record deleted or hidden from the caller since is not handed back
+                return _get_air_quality_data(&id).ok_or(Error::Conflict {
+                    msg: format!(
+                        "idempotency_key was used for air quality data with id={}, which no longer exists",
+                        id
+                    ),
+                });
+            }
+        }
+        if let Some(station_id) = &data.station_id {
+            if stations::get_station(station_id).is_none() {
+                return Err(Error::NotFound {
+                    msg: format!("station {} not found", station_id),
+                });
+            }
+        }
+        organizations::ensure_can_write(organizations::of_reading(
+            data.station_id.as_deref(),
+            data.organization_id,
+        )?)?;
+        let idempotency_key = data.idempotency_key.clone();
+        let source = sources::of_caller(data.station_id.as_deref());
+        let data = _add_air_quality_data(data, source)?;
+        if let Some(key) = &idempotency_key {
+            idempotency::remember(key, data.id);
+        }
+        contributors::record_contribution(ic_cdk::caller());
+        Ok(data)
+    })
+}
 
-    let pollutant_levels = data.pollutant_levels.unwrap_or_default();
+// Shared by every ingestion path (direct calls, backfills, ...)
+fn _add_air_quality_data(
+    data: AirQualityUpdatePayload,
+    source: ReadingSource,
+) -> Result<AirQualityData, Error> {
+    let timestamp = observation_time(data.observed_at)?;
+    _add_air_quality_data_at(data, timestamp, source)
+}
+
+fn _add_air_quality_data_at(
+    data: AirQualityUpdatePayload,
+    timestamp: u64,
+    source: ReadingSource,
+) -> Result<AirQualityData, Error> {
+    bootstrap::ensure_not_seeding()?;
+    backups::ensure_not_frozen()?;
+    capacity::ensure_room_for_reading()?;
+    // Levels are converted with the conditions the record is stored with, so reads convert back
     let weather_conditions = data.weather_conditions.unwrap_or_default();
+    let (pollutant_levels, pollutant_units) = units::normalize_levels(
+        data.pollutant_levels.unwrap_or_default(),
+        data.pollutant_units.as_ref(),
+        Some(&weather_conditions),
+    )?;
+    let (pollutant_levels, raw_pollutant_levels) = calibrated_levels(
+        pollutant_levels,
+        data.apply_calibration.unwrap_or(false),
+        data.station_id.as_deref(),
+        timestamp,
+    )?;
+    let organization_id =
+        organizations::of_reading(data.station_id.as_deref(), data.organization_id)?;
+    quotas::ensure_room(organization_id)?;
 
-    let air_quality_data = AirQualityData {
-        id,
-        location: data.location,
-        timestamp: time(),
+    let mut air_quality_data = AirQualityData {
+        id: 0,
+        location: locations::canonical(data.location),
+        timestamp,
         air_quality_index: data.air_quality_index,
         health_recommendations: data.health_recommendations,
         pollutant_levels,
         weather_conditions,
+        deleted_at: None,
+        station_id: data.station_id,
+        ingested_at: Some(time()),
+        version: 1,
+        pollutant_units: Some(pollutant_units),
+        raw_pollutant_levels,
+        publication_state: Some(if data.draft.unwrap_or(false) {
+            PublicationState::Draft
+        } else {
+            PublicationState::Published
+        }),
+        merged_readings: None,
+        organization_id,
+        source: Some(source),
     };
+    validation::check(&air_quality_data)?;
+    dedup::check(&air_quality_data)?;
+    // A merged reading replaces the one it was merged into, and is checked like a new one
+    let merged = merge::merge_near(&air_quality_data);
+    if let Some(merged) = &merged {
+        validation::check(merged)?;
+        air_quality_data = merged.clone();
+    }
+    // Ids encode to a fixed width, so the size is checked before one is used up
+    check_record_size(&air_quality_data)?;
+
+    if merged.is_none() {
+        air_quality_data.id = AIR_QUALITY_ID_COUNTER
+            .with(|counter| {
+                let current_value = *counter.borrow().get();
+                counter.borrow_mut().set(current_value + 1)
+            })
+            .expect("cannot increment id counter for air quality data");
+    }
 
     do_insert_air_quality(&air_quality_data);
-    Some(air_quality_data)
+    if let Some(merged) = &merged {
+        log!(
+            Info,
+            "reading merged",
+            "id" => merged.id,
+            "station" => merged.station_id.as_deref().unwrap_or_default(),
+            "readings" => merged.merged_readings.unwrap_or_default(),
+        );
+    }
+    quotas::record_write(organization_id);
+    anomalies::on_insert(&air_quality_data);
+    // Drafts trigger alerts when they are published
+    if air_quality_data.is_published() {
+        alerts::on_insert(&air_quality_data);
+        consumers::on_insert(&air_quality_data);
+    }
+    Ok(air_quality_data)
 }
 
 // 2.7.11 update_air_quality_data Function:
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "accepts_writes")]
 fn update_air_quality_data(
     id: u64,
     payload: AirQualityUpdatePayload,
+    expected_version: Option<u64>,
 ) -> Result<AirQualityData, Error> {
-    match AIR_QUALITY_STORAGE.with(|service| service.borrow().get(&id)) {
-        Some(mut data) => {
-            data.location = payload.location;
-            data.air_quality_index = payload.air_quality_index;
-            data.health_recommendations = payload.health_recommendations;
-            data.pollutant_levels = payload.pollutant_levels.unwrap_or_default();
-            data.weather_conditions = payload.weather_conditions.unwrap_or_default();
-            data.timestamp = time();
-
-            do_insert_air_quality(&data);
-            Ok(data)
+    slo::tracked("update_air_quality_data", || {
+        writers::ensure_allowed_writer(payload.station_id.as_deref())?;
+        if let Some(station_id) = &payload.station_id {
+            if stations::get_station(station_id).is_none() {
+                return Err(Error::NotFound {
+                    msg: format!("station {} not found", station_id),
+                });
+            }
         }
-        None => Err(Error::NotFound {
+        match _get_air_quality_data(&id) {
+            Some(mut data) => {
+                writers::ensure_allowed_writer(data.station_id.as_deref())?;
+                if let Some(expected_version) = expected_version {
+                    data.check_version(expected_version)?;
+                }
+                let timestamp = observation_time(payload.observed_at)?;
+                replace_air_quality_data(&mut data, payload, timestamp)?;
+                Ok(data)
+            }
+            None => Err(Error::NotFound {
+                msg: format!(
+                    "couldn't update air quality data with id={}. data not found",
+                    id
+                ),
+            }),
+        }
+    })
+}
+
+#[ic_cdk::update(guard = "accepts_writes")]
+fn patch_air_quality_data(
+    id: u64,
+    patch: AirQualityPatchPayload,
+    expected_version: Option<u64>,
+) -> Result<AirQualityData, Error> {
+    slo::tracked("patch_air_quality_data", || {
+        writers::ensure_allowed_writer(patch.station_id.as_deref())?;
+        let mut data = _get_air_quality_data(&id).ok_or(Error::NotFound {
             msg: format!(
-                "couldn't update air quality data with id={}. data not found",
+                "couldn't patch air quality data with id={}. data not found",
                 id
             ),
-        }),
+        })?;
+        writers::ensure_allowed_writer(data.station_id.as_deref())?;
+        if let Some(expected_version) = expected_version {
+            data.check_version(expected_version)?;
+        }
+        organizations::ensure_can_write(data.organization_id)?;
+        publication::on_edit(&mut data)?;
+        if let Some(station_id) = &patch.station_id {
+            if stations::get_station(station_id).is_none() {
+                return Err(Error::NotFound {
+                    msg: format!("station {} not found", station_id),
+                });
+            }
+            // Moving a reading to a station of another organization moves it there too
+            let organization_id =
+                organizations::of_reading(Some(station_id), None)?.or(data.organization_id);
+            organizations::ensure_can_write(organization_id)?;
+            data.organization_id = organization_id;
+        }
+
+        if let Some(location) = patch.location {
+            data.location = locations::canonical(location);
+        }
+        if let Some(air_quality_index) = patch.air_quality_index {
+            data.air_quality_index = air_quality_index;
+        }
+        if let Some(health_recommendations) = patch.health_recommendations {
+            data.health_recommendations = health_recommendations;
+        }
+        // Weather first: the new levels are converted with the record's resulting conditions
+        if let Some(weather_conditions) = patch.weather_conditions {
+            data.weather_conditions = weather_conditions;
+        }
+        let apply_calibration = patch
+            .apply_calibration
+            .unwrap_or(data.raw_pollutant_levels.is_some());
+        if let Some(raw_pollutant_levels) = data.raw_pollutant_levels.take() {
+            data.pollutant_levels = raw_pollutant_levels;
+        }
+        let normalize = |levels| {
+            units::normalize_levels(
+                levels,
+                patch.pollutant_units.as_ref(),
+                Some(&data.weather_conditions),
+            )
+        };
+        match patch.pollutant_levels {
+            Some(PollutantPatch::Replace(levels)) => {
+                let (levels, units) = normalize(levels)?;
+                data.pollutant_levels = levels;
+                data.pollutant_units = Some(units);
+            }
+            Some(PollutantPatch::Merge { set, remove }) => {
+                let (set, set_units) = normalize(set)?;
+                let stored_units = data.pollutant_units.get_or_insert_with(HashMap::new);
+                for pollutant in remove {
+                    let pollutant = pollutant.normalized();
+                    data.pollutant_levels.remove(&pollutant);
+                    stored_units.remove(&pollutant);
+                }
+                for pollutant in set.keys() {
+                    stored_units.remove(pollutant);
+                }
+                stored_units.extend(set_units);
+                data.pollutant_levels.extend(set);
+            }
+            None => {}
+        }
+        if patch.station_id.is_some() {
+            data.station_id = patch.station_id;
+        }
+        if patch.observed_at.is_some() {
+            data.timestamp = observation_time(patch.observed_at)?;
+        }
+        (data.pollutant_levels, data.raw_pollutant_levels) = calibrated_levels(
+            std::mem::take(&mut data.pollutant_levels),
+            apply_calibration,
+            data.station_id.as_deref(),
+            data.timestamp,
+        )?;
+        data.version += 1;
+
+        validation::check(&data)?;
+        check_record_size(&data)?;
+        do_insert_air_quality(&data);
+        Ok(data)
+    })
+}
+
+type PollutantLevels = HashMap<Pollutant, f64>;
+
+// Levels to store with, if they were calibrated, the levels as reported
+fn calibrated_levels(
+    levels: PollutantLevels,
+    apply_calibration: bool,
+    station_id: Option<&str>,
+    observed_at: u64,
+) -> Result<(PollutantLevels, Option<PollutantLevels>), Error> {
+    if !apply_calibration {
+        return Ok((levels, None));
     }
+    let corrected = calibration::calibrate(station_id, &levels, observed_at)?;
+    Ok((corrected, Some(levels)))
+}
+
+// Replaces every field of a record with the payload and stores it as a new version
+fn replace_air_quality_data(
+    data: &mut AirQualityData,
+    payload: AirQualityUpdatePayload,
+    timestamp: u64,
+) -> Result<(), Error> {
+    organizations::ensure_can_write(data.organization_id)?;
+    let organization_id =
+        organizations::of_reading(payload.station_id.as_deref(), payload.organization_id)?;
+    organizations::ensure_can_write(organization_id)?;
+    publication::on_edit(data)?;
+    data.location = locations::canonical(payload.location);
+    data.air_quality_index = payload.air_quality_index;
+    data.health_recommendations = payload.health_recommendations;
+    data.weather_conditions = payload.weather_conditions.unwrap_or_default();
+    let (pollutant_levels, pollutant_units) = units::normalize_levels(
+        payload.pollutant_levels.unwrap_or_default(),
+        payload.pollutant_units.as_ref(),
+        Some(&data.weather_conditions),
+    )?;
+    (data.pollutant_levels, data.raw_pollutant_levels) = calibrated_levels(
+        pollutant_levels,
+        payload.apply_calibration.unwrap_or(false),
+        payload.station_id.as_deref(),
+        timestamp,
+    )?;
+    data.pollutant_units = Some(pollutant_units);
+    data.station_id = payload.station_id;
+    data.organization_id = organization_id;
+    data.timestamp = timestamp;
+    data.version += 1;
+
+    validation::check(data)?;
+    check_record_size(data)?;
+    do_insert_air_quality(data);
+    Ok(())
+}
+
+// Replaces the reading of the location for the hour of `timestamp`, or inserts one, so
+// re-running an import does not multiply the dataset
+#[ic_cdk::update(guard = "accepts_writes")]
+fn upsert_reading(
+    location: String,
+    timestamp: u64,
+    payload: AirQualityUpdatePayload,
+) -> Result<AirQualityData, Error> {
+    slo::tracked_ingestion("upsert_reading", || {
+        writers::ensure_allowed_writer(payload.station_id.as_deref())?;
+        if let Some(station_id) = &payload.station_id {
+            if stations::get_station(station_id).is_none() {
+                return Err(Error::NotFound {
+                    msg: format!("station {} not found", station_id),
+                });
+            }
+        }
+        observation_time(Some(timestamp))?;
+        let organization_id =
+            organizations::of_reading(payload.station_id.as_deref(), payload.organization_id)?;
+        organizations::ensure_can_write(organization_id)?;
+        let payload = AirQualityUpdatePayload {
+            location: locations::canonical(location),
+            ..payload
+        };
+        let hour = timestamp / NANOS_PER_HOUR;
+        // Organizations sharing a location keep their readings apart
+        let existing = filter_air_quality_data(|data| {
+            data.location == payload.location
+                && data.timestamp / NANOS_PER_HOUR == hour
+                && data.organization_id == organization_id
+        })
+        .into_iter()
+        .next();
+
+        match existing {
+            Some(mut data) => {
+                writers::ensure_allowed_writer(data.station_id.as_deref())?;
+                replace_air_quality_data(&mut data, payload, timestamp)?;
+                Ok(data)
+            }
+            None => {
+                let source = sources::of_caller(payload.station_id.as_deref());
+                let data = _add_air_quality_data_at(payload, timestamp, source)?;
+                contributors::record_contribution(ic_cdk::caller());
+                Ok(data)
+            }
+        }
+    })
 }
 
 // 2.7.12 delete_air_quality_data Function:
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "accepts_writes")]
 fn delete_air_quality_data(id: u64) -> Result<AirQualityData, Error> {
-    match AIR_QUALITY_STORAGE.with(|service| service.borrow_mut().remove(&id)) {
-        Some(data) => Ok(data),
-        None => Err(Error::NotFound {
-            msg: format!(
-                "couldn't delete air quality data with id={}. data not found.",
-                id
-            ),
-        }),
-    }
+    slo::tracked("delete_air_quality_data", || {
+        writers::ensure_allowed_writer(None)?;
+        match _get_air_quality_data(&id) {
+            Some(mut data) => {
+                writers::ensure_allowed_writer(data.station_id.as_deref())?;
+                organizations::ensure_can_write(data.organization_id)?;
+                data.deleted_at = Some(time());
+                data.version += 1;
+                do_insert_air_quality(&data);
+                Ok(data)
+            }
+            None => Err(Error::NotFound {
+                msg: format!(
+                    "couldn't delete air quality data with id={}. data not found.",
+                    id
+                ),
+            }),
+        }
+    })
 }
 
-#[ic_cdk::query]
-fn get_all_air_quality_data() -> Result<Vec<AirQualityData>, Error> {
-    Ok(AIR_QUALITY_STORAGE.with(|service| {
-        let storage = service.borrow_mut();
-        storage.iter().map(|(_, item)| item.clone()).collect()
-    }))
+#[ic_cdk::update(guard = "accepts_writes")]
+fn restore_air_quality_data(id: u64) -> Result<AirQualityData, Error> {
+    slo::tracked("restore_air_quality_data", || {
+        ensure_admin()?;
+        match AIR_QUALITY_STORAGE.with(|service| service.borrow().get(&id)) {
+            Some(mut data) if data.is_deleted() => {
+                data.deleted_at = None;
+                data.version += 1;
+                do_insert_air_quality(&data);
+                Ok(data)
+            }
+            _ => Err(Error::NotFound {
+                msg: format!(
+                    "couldn't restore air quality data with id={}. no deleted data found.",
+                    id
+                ),
+            }),
+        }
+    })
 }
 
-#[ic_cdk::query]
-fn search_air_quality_data_by_location(location: String) -> Result<Vec<AirQualityData>, Error> {
-    Ok(AIR_QUALITY_STORAGE.with(|service| {
-        let borrow = &*service.borrow();
-        borrow
+// Permanently removes records that were deleted before the given timestamp
+#[ic_cdk::update(guard = "accepts_writes")]
+fn purge_deleted(before_ts: u64) -> Result<u64, Error> {
+    ensure_admin()?;
+    let expired: Vec<AirQualityData> = AIR_QUALITY_STORAGE.with(|service| {
+        service
+            .borrow()
             .iter()
-            .filter_map(|(_, space)| {
-                if space.location.contains(&location) {
-                    Some(space.clone())
-                } else {
-                    None
-                }
+            .map(|(_, data)| data)
+            .filter(|data| {
+                data.deleted_at
+                    .is_some_and(|deleted_at| deleted_at < before_ts)
             })
             .collect()
+    });
+    for data in &expired {
+        remove_air_quality(data, Removal::Purged);
+    }
+    Ok(expired.len() as u64)
+}
+
+// A page of live, visible records after `cursor` in `sort` order, id order by default, with
+// their levels expressed in `unit` where possible, that match `predicate`
+fn air_quality_page<F>(
+    cursor: Option<u64>,
+    unit: Option<ConcentrationUnit>,
+    sort: Option<SortOrder>,
+    predicate: F,
+) -> Result<ReadingPage, Error>
+where
+    F: Fn(&AirQualityData) -> bool,
+{
+    let sort = sort.unwrap_or(SortOrder::IdAscending);
+    if sort != SortOrder::IdAscending {
+        return filters::sorted_page(cursor, unit, sort, predicate);
+    }
+    let start = cursor.map_or(Bound::Unbounded, Bound::Excluded);
+    Ok(AIR_QUALITY_STORAGE.with(|service| {
+        paging::reading_page(
+            service
+                .borrow()
+                .range((start, Bound::Unbounded))
+                .map(|(_, data)| data)
+                .filter(|data| !data.is_deleted() && is_visible_to_caller(data))
+                .map(|data| units::in_unit(data, unit))
+                .filter(|data| predicate(data)),
+        )
     }))
 }
 
 #[ic_cdk::query]
+fn get_all_air_quality_data(
+    unit: Option<ConcentrationUnit>,
+    cursor: Option<u64>,
+    sort_by: Option<SortOrder>,
+) -> Result<ReadingPage, Error> {
+    air_quality_page(cursor, unit, sort_by, |_| true)
+}
+
+#[ic_cdk::query]
+fn search_air_quality_data_by_location(
+    location: String,
+    unit: Option<ConcentrationUnit>,
+    cursor: Option<u64>,
+    fuzzy: Option<bool>,
+    sort_by: Option<SortOrder>,
+) -> Result<ReadingPage, Error> {
+    let filter = ReadingFilter::Location { location, fuzzy };
+    air_quality_page(cursor, unit, sort_by, filter.predicate())
+}
+
+#[ic_cdk::query]
+#[allow(clippy::too_many_arguments)]
 fn get_air_quality_data_by_weather_conditions(
     min_temperature: f64,
     max_temperature: f64,
@@ -197,78 +1212,139 @@ fn get_air_quality_data_by_weather_conditions(
     max_humidity: f64,
     min_wind_speed: f64,
     max_wind_speed: f64,
-) -> Result<Vec<AirQualityData>, Error> {
-    Ok(AIR_QUALITY_STORAGE.with(|service| {
-        let borrow = service.borrow();
-        borrow
-            .iter()
-            .filter_map(|(_, data)| {
-                let weather = &data.weather_conditions;
-                if weather.temperature >= min_temperature
-                    && weather.temperature <= max_temperature
-                    && weather.humidity >= min_humidity
-                    && weather.humidity <= max_humidity
-                    && weather.wind_speed >= min_wind_speed
-                    && weather.wind_speed <= max_wind_speed
-                {
-                    Some(data.clone())
-                } else {
-                    None
-                }
-            })
-            .collect()
-    }))
+    unit: Option<ConcentrationUnit>,
+    cursor: Option<u64>,
+    sort_by: Option<SortOrder>,
+) -> Result<ReadingPage, Error> {
+    let filter = ReadingFilter::WeatherConditions {
+        min_temperature,
+        max_temperature,
+        min_humidity,
+        max_humidity,
+        min_wind_speed,
+        max_wind_speed,
+    };
+    air_quality_page(cursor, unit, sort_by, filter.predicate())
 }
 
+// The bounds are in the requested unit: records are converted before they are compared
 #[ic_cdk::query]
 fn get_air_quality_data_by_pollutant_level(
-    pollutant: String,
+    pollutant: Pollutant,
     min_level: f64,
     max_level: f64,
-) -> Result<Vec<AirQualityData>, Error> {
-    Ok(AIR_QUALITY_STORAGE.with(|service| {
-        let borrow = service.borrow();
-        borrow
-            .iter()
-            .filter_map(|(_, data)| {
-                if let Some(level) = data.pollutant_levels.get(&pollutant) {
-                    if *level >= min_level && *level <= max_level {
-                        Some(data.clone())
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            })
-            .collect()
-    }))
+    unit: Option<ConcentrationUnit>,
+    cursor: Option<u64>,
+    sort_by: Option<SortOrder>,
+) -> Result<ReadingPage, Error> {
+    let filter = ReadingFilter::PollutantLevel {
+        pollutant,
+        min_level,
+        max_level,
+        unit,
+    };
+    air_quality_page(cursor, unit, sort_by, filter.predicate())
+}
+
+// Readings within the ranges of all the given pollutants, such as high PM2.5 and high NO2
+#[ic_cdk::query]
+fn get_air_quality_data_by_pollutant_levels(
+    conditions: Vec<PollutantCondition>,
+    unit: Option<ConcentrationUnit>,
+    cursor: Option<u64>,
+    sort_by: Option<SortOrder>,
+) -> Result<ReadingPage, Error> {
+    filters::validate_conditions(&conditions)?;
+    let filter = ReadingFilter::PollutantLevels { conditions, unit };
+    air_quality_page(cursor, unit, sort_by, filter.predicate())
 }
 
 #[ic_cdk::query]
 fn get_air_quality_data_by_timestamp_range(
     start_timestamp: u64,
     end_timestamp: u64,
-) -> Result<Vec<AirQualityData>, Error> {
-    Ok(AIR_QUALITY_STORAGE.with(|service| {
-        let borrow = service.borrow();
-        borrow
-            .iter()
-            .filter_map(|(_, data)| {
-                if data.timestamp >= start_timestamp && data.timestamp <= end_timestamp {
-                    Some(data.clone())
-                } else {
-                    None
-                }
-            })
-            .collect()
-    }))
+    unit: Option<ConcentrationUnit>,
+    cursor: Option<u64>,
+    sort_by: Option<SortOrder>,
+) -> Result<ReadingPage, Error> {
+    let filter = ReadingFilter::TimestampRange {
+        start_timestamp,
+        end_timestamp,
+    };
+    air_quality_page(cursor, unit, sort_by, filter.predicate())
+}
+
+#[ic_cdk::init]
+fn init() {
+    migrations::mark_all_applied();
+    rollups::mark_backfilled();
+    schema::set_stored_version(schema::CURRENT_SCHEMA_VERSION);
+    scheduler::start();
+}
+
+// Stable structures survive upgrades on their own; pending migrations need running, and the
+// timers of background work setting again
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    migrations::on_post_upgrade();
+    scheduler::start();
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ServiceStatus {
+    timestamp: u64,
+    total_readings: u64,
+    open_incidents: Vec<Incident>,
+    announcements: Vec<Announcement>,
+    stable_memory: StableMemoryUsage,
+}
+
+#[ic_cdk::query]
+fn get_status() -> ServiceStatus {
+    ServiceStatus {
+        timestamp: time(),
+        total_readings: AIR_QUALITY_STORAGE.with(|service| {
+            service
+                .borrow()
+                .iter()
+                .filter(|(_, data)| !data.is_deleted())
+                .count() as u64
+        }),
+        open_incidents: incidents::open_incidents(),
+        announcements: announcements::active_announcements(),
+        stable_memory: capacity::usage(false),
+    }
 }
 
 // Enum for error handling
 #[derive(candid::CandidType, Deserialize, Serialize)]
 enum Error {
-    NotFound { msg: String },
+    NotFound {
+        msg: String,
+    },
+    Unauthorized {
+        msg: String,
+    },
+    InvalidInput {
+        msg: String,
+        // Every rule a rejected reading broke; None for other invalid input
+        violations: Option<Vec<RuleViolation>>,
+    },
+    TooLarge {
+        msg: String,
+    },
+    Conflict {
+        msg: String,
+    },
+    // An identical reading is already stored; its id if the caller may see it
+    Duplicate {
+        msg: String,
+        existing_id: Option<u64>,
+    },
+    // The organization is at one of its quotas
+    QuotaExceeded {
+        msg: String,
+    },
 }
 
 // Export Candid interface definitions for the canister
@@ -0,0 +1,124 @@
+use crate::filters::ReadingFilter;
+use crate::paging::{take_within, ReplyBudget};
+use crate::units::{self, ConcentrationUnit};
+use crate::{is_visible_to_caller, AirQualityData, AIR_QUALITY_STORAGE};
+use std::ops::Bound;
+
+const MEASUREMENT: &str = "air_quality";
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct LineProtocolPage {
+    // One line per reading, each ending with a newline
+    body: String,
+    lines: u32,
+    // More readings match; pass `next_cursor` to fetch them
+    truncated: bool,
+    next_cursor: Option<u64>,
+}
+
+// Escapes the characters line protocol gives a meaning in tag keys, tag values and field keys
+fn escape_key(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ',' | '=' | ' ' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            // Lines cannot contain newlines, even escaped ones
+            '\n' | '\r' => escaped.push(' '),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn escape_string(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace(['\n', '\r'], " ")
+}
+
+fn unit_suffix(unit: ConcentrationUnit) -> &'static str {
+    match unit {
+        ConcentrationUnit::Ppm => "ppm",
+        ConcentrationUnit::Ppb => "ppb",
+        ConcentrationUnit::MicrogramsPerCubicMeter => "ug_m3",
+        ConcentrationUnit::MilligramsPerCubicMeter => "mg_m3",
+    }
+}
+
+// `air_quality,location=Delhi,station_id=DL-7 aqi=153i,pm2.5_ug_m3=55.2,... 1700000000000000000`.
+// Levels with a recorded unit are fields named after the pollutant and the unit, so a field
+// never mixes units; non-finite numbers have no line protocol form and are left out.
+fn line(data: &AirQualityData) -> String {
+    let mut tags = format!("{},location={}", MEASUREMENT, escape_key(&data.location));
+    if let Some(station_id) = &data.station_id {
+        tags.push_str(&format!(",station_id={}", escape_key(station_id)));
+    }
+    let mut fields = vec![
+        format!("aqi={}i", data.air_quality_index),
+        format!("id={}i", data.id),
+    ];
+    let mut levels: Vec<_> = data.pollutant_levels.iter().collect();
+    levels.sort_by_key(|(pollutant, _)| *pollutant);
+    for (pollutant, level) in levels.into_iter().filter(|(_, level)| level.is_finite()) {
+        let unit = data
+            .pollutant_units
+            .as_ref()
+            .and_then(|units| units.get(pollutant));
+        let key = match unit {
+            Some(unit) => format!("{}_{}", pollutant.name(), unit_suffix(*unit)),
+            None => pollutant.name().to_string(),
+        };
+        fields.push(format!("{}={}", escape_key(&key), level));
+    }
+    let weather = &data.weather_conditions;
+    for (key, value) in [
+        ("temperature", Some(weather.temperature)),
+        ("humidity", Some(weather.humidity)),
+        ("wind_speed", Some(weather.wind_speed)),
+        ("pressure", weather.pressure),
+    ] {
+        if let Some(value) = value.filter(|value| value.is_finite()) {
+            fields.push(format!("{}={}", key, value));
+        }
+    }
+    if !data.health_recommendations.is_empty() {
+        fields.push(format!(
+            "health_recommendations=\"{}\"",
+            escape_string(&data.health_recommendations)
+        ));
+    }
+    format!("{} {} {}\n", tags, fields.join(","), data.timestamp)
+}
+
+// The readings the filter matches, in id order, as InfluxDB line protocol with nanosecond
+// timestamps, for `influx write --precision ns`. Pages continue after the reading with id
+// `cursor`.
+#[ic_cdk::query]
+fn export_line_protocol(filter: ReadingFilter, cursor: Option<u64>) -> LineProtocolPage {
+    let unit = filter.unit();
+    let predicate = filter.predicate();
+    let start = cursor.map_or(Bound::Unbounded, Bound::Excluded);
+    let (lines, truncated) = AIR_QUALITY_STORAGE.with(|service| {
+        take_within(
+            service
+                .borrow()
+                .range((start, Bound::Unbounded))
+                .map(|(_, data)| data)
+                .filter(|data| !data.is_deleted() && is_visible_to_caller(data))
+                .map(|data| units::in_unit(data, unit))
+                .filter(|data| predicate(data))
+                .map(|data| (data.id, line(&data))),
+            &mut ReplyBudget::new(),
+        )
+    });
+    LineProtocolPage {
+        next_cursor: lines.last().filter(|_| truncated).map(|(id, _)| *id),
+        lines: lines.len() as u32,
+        body: lines.into_iter().map(|(_, line)| line).collect(),
+        truncated,
+    }
+}
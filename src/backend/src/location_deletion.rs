@@ -0,0 +1,165 @@
+use crate::{
+    accepts_writes, ensure_admin, get_memory, next_id, remove_air_quality, AirQualityData, Error,
+    IdCell, Memory, Removal, AIR_QUALITY_STORAGE, LOCATION_DELETION_ID_COUNTER_MEMORY_ID,
+    LOCATION_DELETION_STORAGE_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::ops::Bound;
+
+const MAX_LOCATION_LEN: usize = 1024;
+// Records examined per tick; deleting is bounded by the same number
+const SCAN_PER_TICK: usize = 2_000;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum LocationDeletionState {
+    Running,
+    Completed,
+}
+
+// Removes every reading of a location, a batch per tick. Completed jobs are kept as the audit
+// record of the deletion.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct LocationDeletionJob {
+    id: u64,
+    location: String,
+    state: LocationDeletionState,
+    // Highest record id examined so far
+    cursor: Option<u64>,
+    records_scanned: u64,
+    records_deleted: u64,
+    requested_by: Principal,
+    requested_at: u64,
+    completed_at: Option<u64>,
+}
+
+impl_bounded_storable!(LocationDeletionJob, 2048);
+
+thread_local! {
+    static LOCATION_DELETION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(get_memory(LOCATION_DELETION_ID_COUNTER_MEMORY_ID), 0)
+            .expect("Cannot create a counter for location deletions")
+    );
+
+    static LOCATION_DELETION_STORAGE: RefCell<StableBTreeMap<u64, LocationDeletionJob, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(LOCATION_DELETION_STORAGE_MEMORY_ID)));
+}
+
+fn jobs_where<F>(predicate: F) -> Vec<LocationDeletionJob>
+where
+    F: Fn(&LocationDeletionJob) -> bool,
+{
+    LOCATION_DELETION_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, job)| job)
+            .filter(|job| predicate(job))
+            .collect()
+    })
+}
+
+fn do_insert_job(job: &LocationDeletionJob) {
+    LOCATION_DELETION_STORAGE.with(|s| s.borrow_mut().insert(job.id, job.clone()));
+}
+
+// Running jobs scan from the first record again once the readings are reset, as record ids
+// start at 0 again
+pub(crate) fn reset() {
+    for mut job in jobs_where(|job| job.state == LocationDeletionState::Running) {
+        job.cursor = None;
+        do_insert_job(&job);
+    }
+}
+
+// Advances the oldest running job by one batch
+pub(crate) fn on_timer() {
+    let Some(mut job) = jobs_where(|job| job.state == LocationDeletionState::Running)
+        .into_iter()
+        .next()
+    else {
+        return;
+    };
+    let start = job.cursor.map_or(Bound::Unbounded, Bound::Excluded);
+    let batch: Vec<AirQualityData> = AIR_QUALITY_STORAGE.with(|service| {
+        service
+            .borrow()
+            .range((start, Bound::Unbounded))
+            .take(SCAN_PER_TICK)
+            .map(|(_, data)| data)
+            .collect()
+    });
+    for data in batch.iter().filter(|data| data.location == job.location) {
+        remove_air_quality(data, Removal::Purged);
+        job.records_deleted += 1;
+    }
+    job.records_scanned += batch.len() as u64;
+    job.cursor = batch.last().map(|data| data.id).or(job.cursor);
+    if batch.len() < SCAN_PER_TICK {
+        job.state = LocationDeletionState::Completed;
+        job.completed_at = Some(time());
+        log!(
+            Info,
+            "location history deleted",
+            "job" => job.id,
+            "location" => job.location,
+            "records" => job.records_deleted,
+            "requested_by" => job.requested_by,
+        );
+    }
+    do_insert_job(&job);
+}
+
+// Starts deleting every reading of a location, tombstones included (controllers only). Large
+// histories take many ticks; follow progress with get_location_deletion.
+#[ic_cdk::update(guard = "accepts_writes")]
+fn delete_location_history(location: String) -> Result<LocationDeletionJob, Error> {
+    ensure_admin()?;
+    if location.is_empty() || location.len() > MAX_LOCATION_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!("location must be 1 to {} bytes", MAX_LOCATION_LEN),
+            violations: None,
+        });
+    }
+    if let Some(running) =
+        jobs_where(|job| job.state == LocationDeletionState::Running && job.location == location)
+            .first()
+    {
+        return Err(Error::Conflict {
+            msg: format!(
+                "location deletion job with id={} is already deleting this location",
+                running.id
+            ),
+        });
+    }
+    let job = LocationDeletionJob {
+        id: next_id(&LOCATION_DELETION_ID_COUNTER),
+        location,
+        state: LocationDeletionState::Running,
+        cursor: None,
+        records_scanned: 0,
+        records_deleted: 0,
+        requested_by: ic_cdk::caller(),
+        requested_at: time(),
+        completed_at: None,
+    };
+    do_insert_job(&job);
+    Ok(job)
+}
+
+#[ic_cdk::query]
+fn get_location_deletion(id: u64) -> Result<LocationDeletionJob, Error> {
+    LOCATION_DELETION_STORAGE
+        .with(|s| s.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("location deletion job with id={} not found", id),
+        })
+}
+
+continued_page!(LocationDeletionJobPage, LocationDeletionJob);
+
+#[ic_cdk::query]
+fn list_location_deletions(continuation: Option<String>) -> Result<LocationDeletionJobPage, Error> {
+    LocationDeletionJobPage::new(jobs_where(|_| true), continuation)
+}
@@ -0,0 +1,98 @@
+// Fuzzy queries allow one edit per 4 characters, at most 2, so short queries stay strict
+const CHARS_PER_EDIT: usize = 4;
+const MAX_EDITS: usize = 2;
+
+// A lowercased search, prepared once for matching many locations
+pub(crate) struct LocationQuery {
+    text: String,
+    words: Vec<String>,
+    fuzzy: bool,
+}
+
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+// Optimal string alignment distance: Levenshtein edits plus swaps of adjacent characters, so
+// "dehli" is one edit from "delhi"
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut rows = vec![(0..=b.len()).collect::<Vec<usize>>()];
+    for i in 1..=a.len() {
+        let mut row = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let substitution = rows[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            row[j] = substitution.min(rows[i - 1][j] + 1).min(row[j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(rows[i - 2][j - 2] + 1);
+            }
+        }
+        rows.push(row);
+    }
+    rows[a.len()][b.len()]
+}
+
+impl LocationQuery {
+    pub(crate) fn new(text: &str, fuzzy: bool) -> Self {
+        let text = text.to_lowercase();
+        LocationQuery {
+            words: words(&text),
+            text,
+            fuzzy,
+        }
+    }
+
+    // Case-insensitive substring match; fuzzy queries also match runs of as many words of the
+    // location that are a few edits away
+    pub(crate) fn matches(&self, location: &str) -> bool {
+        let location = location.to_lowercase();
+        if location.contains(&self.text) {
+            return true;
+        }
+        if !self.fuzzy || self.words.is_empty() {
+            return false;
+        }
+        let query: Vec<char> = self.words.join(" ").chars().collect();
+        let max_edits = (query.len() / CHARS_PER_EDIT).min(MAX_EDITS);
+        let location_words = words(&location);
+        location_words.windows(self.words.len()).any(|window| {
+            let candidate: Vec<char> = window.join(" ").chars().collect();
+            edit_distance(&query, &candidate) <= max_edits
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn distance(a: &str, b: &str) -> usize {
+        let a: Vec<char> = a.chars().collect();
+        let b: Vec<char> = b.chars().collect();
+        edit_distance(&a, &b)
+    }
+
+    #[test]
+    fn edit_distance_with_empty_strings() {
+        assert_eq!(distance("", ""), 0);
+        assert_eq!(distance("", "delhi"), 5);
+        assert_eq!(distance("delhi", ""), 5);
+    }
+
+    #[test]
+    fn edit_distance_counts_swaps_as_one_edit() {
+        assert_eq!(distance("dehli", "delhi"), 1);
+        assert_eq!(distance("delhi", "delhi"), 0);
+        assert_eq!(distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn an_empty_fuzzy_query_matches_every_location() {
+        // Every location contains the empty string
+        assert!(LocationQuery::new("", true).matches("Delhi"));
+        assert!(LocationQuery::new("dehli", true).matches("New Delhi"));
+        assert!(!LocationQuery::new("dehli", false).matches("New Delhi"));
+    }
+}
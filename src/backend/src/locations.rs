@@ -0,0 +1,220 @@
+use crate::{
+    accepts_writes, check_record_size, do_insert_air_quality, ensure_admin, get_memory,
+    AirQualityData, Error, Memory, StringKey, AIR_QUALITY_STORAGE,
+    LOCATION_ALIAS_STORAGE_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap};
+use std::cell::RefCell;
+use std::ops::Bound;
+
+const MAX_LOCATION_LEN: usize = 200;
+// Records examined per tick while relabelling
+const SCAN_PER_TICK: usize = 2_000;
+
+// Another spelling of a location, such as "NYC" for "New York City". Aliases match ignoring
+// case and extra whitespace, and are replaced by the canonical name on ingest and query.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct LocationAlias {
+    alias: String,
+    canonical: String,
+    created_by: Principal,
+    created_at: u64,
+    // Readings stored under the alias before it was added are relabelled in the background.
+    // Highest record id examined so far.
+    relabel_cursor: Option<u64>,
+    records_relabeled: u64,
+    // None while relabelling
+    relabeled_at: Option<u64>,
+}
+
+impl_bounded_storable!(LocationAlias, 1024);
+
+thread_local! {
+    // Keyed by the normalized alias
+    static LOCATION_ALIAS_STORAGE: RefCell<StableBTreeMap<StringKey, LocationAlias, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(LOCATION_ALIAS_STORAGE_MEMORY_ID)));
+}
+
+// Lowercase, with runs of whitespace made one space. None if that does not fit a key, as
+// lowercasing can lengthen a name; such a location is no alias.
+fn key(location: &str) -> Option<StringKey> {
+    let normalized = location
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase();
+    (normalized.len() <= StringKey::MAX_SIZE as usize).then_some(StringKey(normalized))
+}
+
+fn get_alias(location: &str) -> Option<LocationAlias> {
+    let key = key(location)?;
+    LOCATION_ALIAS_STORAGE.with(|s| s.borrow().get(&key))
+}
+
+fn do_insert_alias(alias: &LocationAlias) {
+    if let Some(key) = key(&alias.alias) {
+        LOCATION_ALIAS_STORAGE.with(|s| s.borrow_mut().insert(key, alias.clone()));
+    }
+}
+
+// Relabelling starts over once the readings are reset, as record ids start at 0 again
+pub(crate) fn reset() {
+    let aliases: Vec<LocationAlias> =
+        LOCATION_ALIAS_STORAGE.with(|s| s.borrow().iter().map(|(_, alias)| alias).collect());
+    for mut alias in aliases {
+        alias.relabel_cursor = None;
+        do_insert_alias(&alias);
+    }
+}
+
+// The canonical name of a location, or the location itself if it is no alias
+pub(crate) fn canonical(location: String) -> String {
+    match get_alias(&location) {
+        Some(alias) => alias.canonical,
+        None => location,
+    }
+}
+
+// Advances the oldest alias still relabelling by one batch
+pub(crate) fn on_timer() {
+    let Some(mut alias) = LOCATION_ALIAS_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, alias)| alias)
+            .find(|alias| alias.relabeled_at.is_none())
+    }) else {
+        return;
+    };
+    let start = alias
+        .relabel_cursor
+        .map_or(Bound::Unbounded, Bound::Excluded);
+    let batch: Vec<AirQualityData> = AIR_QUALITY_STORAGE.with(|service| {
+        service
+            .borrow()
+            .range((start, Bound::Unbounded))
+            .take(SCAN_PER_TICK)
+            .map(|(_, data)| data)
+            .collect()
+    });
+    let alias_key = key(&alias.alias);
+    for data in batch.iter().filter(|data| key(&data.location) == alias_key) {
+        let relabeled = AirQualityData {
+            location: alias.canonical.clone(),
+            version: data.version + 1,
+            ..data.clone()
+        };
+        // A longer canonical name can push a record over the size limit; it keeps the alias
+        if check_record_size(&relabeled).is_err() {
+            log!(Warn, "reading too large to relabel", "id" => data.id, "alias" => alias.alias);
+            continue;
+        }
+        do_insert_air_quality(&relabeled);
+        alias.records_relabeled += 1;
+    }
+    alias.relabel_cursor = batch.last().map(|data| data.id).or(alias.relabel_cursor);
+    if batch.len() < SCAN_PER_TICK {
+        alias.relabeled_at = Some(time());
+        log!(
+            Info,
+            "location alias relabelled",
+            "alias" => alias.alias,
+            "canonical" => alias.canonical,
+            "records" => alias.records_relabeled,
+        );
+    }
+    do_insert_alias(&alias);
+}
+
+fn validate_location(name: &str, location: &str) -> Result<(), Error> {
+    if location.trim().is_empty() || location.len() > MAX_LOCATION_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!("{} must be 1 to {} bytes", name, MAX_LOCATION_LEN),
+            violations: None,
+        });
+    }
+    Ok(())
+}
+
+// Makes `alias` another spelling of `canonical` (controllers only). Aliases do not chain: a
+// canonical name cannot itself be an alias.
+#[ic_cdk::update(guard = "accepts_writes")]
+fn add_location_alias(alias: String, canonical: String) -> Result<LocationAlias, Error> {
+    ensure_admin()?;
+    validate_location("alias", &alias)?;
+    validate_location("canonical", &canonical)?;
+    let (alias, canonical) = (alias.trim().to_string(), canonical.trim().to_string());
+    let Some(alias_key) = key(&alias) else {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "the lowercased alias must be at most {} bytes",
+                StringKey::MAX_SIZE
+            ),
+            violations: None,
+        });
+    };
+    if Some(&alias_key) == key(&canonical).as_ref() {
+        return Err(Error::InvalidInput {
+            msg: "an alias must differ from its canonical name".to_string(),
+            violations: None,
+        });
+    }
+    if let Some(existing) = get_alias(&alias) {
+        return Err(Error::Conflict {
+            msg: format!(
+                "{} is already an alias of {}",
+                existing.alias, existing.canonical
+            ),
+        });
+    }
+    if let Some(existing) = get_alias(&canonical) {
+        return Err(Error::Conflict {
+            msg: format!(
+                "{} is itself an alias of {}",
+                existing.alias, existing.canonical
+            ),
+        });
+    }
+    let is_canonical = LOCATION_ALIAS_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .any(|(_, existing)| key(&existing.canonical).as_ref() == Some(&alias_key))
+    });
+    if is_canonical {
+        return Err(Error::Conflict {
+            msg: format!("{} is the canonical name of other aliases", alias),
+        });
+    }
+    let alias = LocationAlias {
+        alias,
+        canonical,
+        created_by: ic_cdk::caller(),
+        created_at: time(),
+        relabel_cursor: None,
+        records_relabeled: 0,
+        relabeled_at: None,
+    };
+    do_insert_alias(&alias);
+    Ok(alias)
+}
+
+// Stops rewriting an alias (controllers only). Readings already relabelled keep the canonical
+// name.
+#[ic_cdk::update(guard = "accepts_writes")]
+fn remove_location_alias(alias: String) -> Result<LocationAlias, Error> {
+    ensure_admin()?;
+    key(&alias)
+        .and_then(|key| LOCATION_ALIAS_STORAGE.with(|s| s.borrow_mut().remove(&key)))
+        .ok_or(Error::NotFound {
+            msg: format!("location alias {} not found", alias),
+        })
+}
+
+continued_page!(LocationAliasPage, LocationAlias);
+
+#[ic_cdk::query]
+fn list_location_aliases(continuation: Option<String>) -> Result<LocationAliasPage, Error> {
+    LOCATION_ALIAS_STORAGE
+        .with(|s| LocationAliasPage::new(s.borrow().iter().map(|(_, alias)| alias), continuation))
+}
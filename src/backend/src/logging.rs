@@ -0,0 +1,144 @@
+use crate::{
+    accepts_writes, ensure_admin, get_memory, next_id, principal_key, Error, IdCell, Memory,
+    PrincipalKey, LOG_ID_COUNTER_MEMORY_ID, LOG_READER_MEMORY_ID, LOG_STORAGE_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+// Oldest entries are dropped beyond this many
+const MAX_RETAINED_LOGS: u64 = 5_000;
+const MAX_LOGS_PER_PAGE: u32 = 500;
+// Longer fields are cut so an entry always fits its slot
+const MAX_MESSAGE_LEN: usize = 512;
+const MAX_CONTEXT_ENTRIES: usize = 8;
+const MAX_CONTEXT_KEY_LEN: usize = 32;
+const MAX_CONTEXT_VALUE_LEN: usize = 128;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, PartialOrd)]
+pub(crate) enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct LogEntry {
+    id: u64,
+    timestamp: u64,
+    level: LogLevel,
+    // Module that wrote the entry, e.g. "backfill"
+    module: String,
+    message: String,
+    context: Vec<(String, String)>,
+}
+
+impl_bounded_storable!(LogEntry, 4096);
+
+thread_local! {
+    static LOG_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(get_memory(LOG_ID_COUNTER_MEMORY_ID), 0)
+            .expect("Cannot create a counter for log entries")
+    );
+
+    static LOG_STORAGE: RefCell<StableBTreeMap<u64, LogEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(LOG_STORAGE_MEMORY_ID)));
+
+    // Principals other than controllers allowed to read the logs, with when they were granted
+    static LOG_READERS: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(LOG_READER_MEMORY_ID)));
+}
+
+fn truncated(text: &str, max_len: usize) -> String {
+    let mut end = text.len().min(max_len);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text[..end].to_string()
+}
+
+// Use the `log!` macro, which fills in the module
+pub(crate) fn write(
+    level: LogLevel,
+    module_path: &str,
+    message: &str,
+    context: Vec<(&str, String)>,
+) {
+    let module = module_path.rsplit("::").next().unwrap_or(module_path);
+    let id = next_id(&LOG_ID_COUNTER);
+    let entry = LogEntry {
+        id,
+        timestamp: time(),
+        level,
+        module: truncated(module, MAX_CONTEXT_KEY_LEN),
+        message: truncated(message, MAX_MESSAGE_LEN),
+        context: context
+            .into_iter()
+            .take(MAX_CONTEXT_ENTRIES)
+            .map(|(key, value)| {
+                (
+                    truncated(key, MAX_CONTEXT_KEY_LEN),
+                    truncated(&value, MAX_CONTEXT_VALUE_LEN),
+                )
+            })
+            .collect(),
+    };
+    LOG_STORAGE.with(|s| {
+        let mut storage = s.borrow_mut();
+        storage.insert(id, entry);
+        if let Some(expired) = id.checked_sub(MAX_RETAINED_LOGS) {
+            storage.remove(&expired);
+        }
+    });
+}
+
+fn ensure_log_reader() -> Result<(), Error> {
+    let caller = ic_cdk::caller();
+    if ic_cdk::api::is_controller(&caller)
+        || LOG_READERS.with(|r| r.borrow().contains_key(&principal_key(&caller)))
+    {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: "caller may not read the logs".to_string(),
+        })
+    }
+}
+
+// Lets an operator read the logs without controlling the canister (controllers only)
+#[ic_cdk::update(guard = "accepts_writes")]
+fn grant_log_access(reader: Principal) -> Result<(), Error> {
+    ensure_admin()?;
+    LOG_READERS.with(|r| r.borrow_mut().insert(principal_key(&reader), time()));
+    Ok(())
+}
+
+#[ic_cdk::update(guard = "accepts_writes")]
+fn revoke_log_access(reader: Principal) -> Result<(), Error> {
+    ensure_admin()?;
+    LOG_READERS.with(|r| r.borrow_mut().remove(&principal_key(&reader)));
+    Ok(())
+}
+
+// Entries at or above `level` written at or after `since`, oldest first
+#[ic_cdk::query]
+fn get_logs(
+    level: Option<LogLevel>,
+    since: Option<u64>,
+    limit: u32,
+) -> Result<Vec<LogEntry>, Error> {
+    ensure_log_reader()?;
+    Ok(LOG_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| {
+                level.is_none_or(|level| entry.level >= level)
+                    && since.is_none_or(|since| entry.timestamp >= since)
+            })
+            .take(limit.min(MAX_LOGS_PER_PAGE) as usize)
+            .collect()
+    }))
+}
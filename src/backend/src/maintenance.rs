@@ -0,0 +1,93 @@
+use crate::{
+    accepts_writes, alerts, anomalies, aqi_index, archive, backfill, bootstrap, categories,
+    changes, clear_stable_map, compaction, consumers, dedup, digest, ensure_admin, exports,
+    history, idempotency, latest, location_deletion, locations, publication, quotas, retention,
+    rollups, text_search, time_index, Error, AIR_QUALITY_ID_COUNTER, AIR_QUALITY_STORAGE,
+    AIR_QUALITY_STORAGE_MEMORY_ID,
+};
+
+fn confirm_phrase() -> String {
+    format!("delete every reading of {}", ic_cdk::id())
+}
+
+// Deletes every reading, with its indexes, rollups, history, suspect flags, daily aggregates,
+// digests and change feed, and starts ids at 0 again (controllers only). Everything that refers
+// to readings by id goes too: idempotency keys, retractions, alerts, exports, consumer
+// deliveries and backfill chunk records. The cursors of relabelling, location deletions,
+// backfills, archiving and retention start over. Meant for test and staging deployments: `confirm_phrase` must be "delete every
+// reading of <canister id>", so a call aimed at another deployment fails. Stations,
+// subscriptions, consumers and settings are kept. Returns the number of records deleted.
+#[ic_cdk::update(guard = "accepts_writes")]
+fn reset_storage(confirm_phrase: String) -> Result<u64, Error> {
+    ensure_admin()?;
+    if confirm_phrase != self::confirm_phrase() {
+        return Err(Error::InvalidInput {
+            msg: format!("confirm_phrase must be \"{}\"", self::confirm_phrase()),
+            violations: None,
+        });
+    }
+    bootstrap::ensure_not_seeding()?;
+    let records = AIR_QUALITY_STORAGE.with(|service| service.borrow().len());
+    AIR_QUALITY_STORAGE.with(|service| clear_stable_map(service, AIR_QUALITY_STORAGE_MEMORY_ID));
+    rollups::reset();
+    dedup::reset();
+    text_search::reset();
+    latest::reset();
+    categories::reset();
+    aqi_index::reset();
+    history::reset();
+    changes::reset();
+    quotas::reset();
+    anomalies::reset();
+    compaction::reset();
+    time_index::reset();
+    idempotency::reset();
+    publication::reset();
+    alerts::reset();
+    exports::reset();
+    consumers::reset();
+    digest::reset();
+    // Background work that walks the readings by id or time starts over
+    locations::reset();
+    location_deletion::reset();
+    backfill::reset();
+    archive::reset();
+    retention::reset();
+    AIR_QUALITY_ID_COUNTER
+        .with(|counter| counter.borrow_mut().set(0))
+        .expect("cannot reset the id counter for air quality data");
+    log!(
+        Warn,
+        "storage reset",
+        "records" => records,
+        "caller" => ic_cdk::caller(),
+    );
+    Ok(records)
+}
+
+// Sets the id the next reading gets, to recover from a counter that fell behind the stored
+// readings (controllers only). It must be above every stored id; ids of readings moved to the
+// archive canister are not checked. Returns the previous value.
+#[ic_cdk::update(guard = "accepts_writes")]
+fn set_id_counter(value: u64) -> Result<u64, Error> {
+    ensure_admin()?;
+    bootstrap::ensure_not_seeding()?;
+    let highest = AIR_QUALITY_STORAGE.with(|service| service.borrow().last_key_value());
+    if let Some((id, _)) = highest.filter(|(id, _)| value <= *id) {
+        return Err(Error::InvalidInput {
+            msg: format!("the counter must be above the highest stored id, {}", id),
+            violations: None,
+        });
+    }
+    let previous = AIR_QUALITY_ID_COUNTER
+        .with(|counter| counter.borrow_mut().set(value))
+        .expect("cannot set the id counter for air quality data");
+    log!(
+        Warn,
+        "id counter set",
+        "from" => previous,
+        "to" => value,
+        "caller" => ic_cdk::caller(),
+    );
+    Ok(previous)
+}
@@ -0,0 +1,200 @@
+use crate::{dedup, stations, AirQualityData, PollutantLevels};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+pub(crate) const MAX_MERGE_WINDOW_SECONDS: u32 = 300;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum MergeStrategy {
+    // Levels and AQI become the mean of all merged readings
+    Average,
+    // The reading with more pollutant levels wins; the first one on a tie
+    KeepMoreComplete,
+}
+
+// Readings of a station observed within `window_seconds` of an earlier one are merged into it
+// instead of being stored twice, for firmware that reports the same measurement more than once
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct MergePolicy {
+    pub(crate) window_seconds: u32,
+    pub(crate) strategy: MergeStrategy,
+}
+
+// Mean of `levels` weighted by `count`, with one more value per pollutant. Pollutants the
+// existing levels lack, or hold in another unit, are taken as they are or left alone.
+fn merge_levels(
+    existing: &mut AirQualityData,
+    levels: &PollutantLevels,
+    candidate: &AirQualityData,
+    count: f64,
+) {
+    for (pollutant, level) in levels {
+        let unit = |data: &AirQualityData| {
+            data.pollutant_units
+                .as_ref()
+                .and_then(|units| units.get(pollutant))
+                .copied()
+        };
+        if unit(existing) != unit(candidate) {
+            continue;
+        }
+        existing
+            .pollutant_levels
+            .entry(pollutant.clone())
+            .and_modify(|mean| *mean = (*mean * count + level) / (count + 1.0))
+            .or_insert(*level);
+    }
+}
+
+fn average(mut existing: AirQualityData, candidate: &AirQualityData) -> AirQualityData {
+    let count = existing.merged_readings.unwrap_or(1) as f64;
+    existing.air_quality_index = ((existing.air_quality_index as f64 * count
+        + candidate.air_quality_index as f64)
+        / (count + 1.0))
+        .round() as u32;
+    merge_levels(
+        &mut existing,
+        &candidate.pollutant_levels.clone(),
+        candidate,
+        count,
+    );
+    if let Some(units) = &candidate.pollutant_units {
+        let stored = existing
+            .pollutant_units
+            .get_or_insert_with(Default::default);
+        for (pollutant, unit) in units {
+            stored.entry(pollutant.clone()).or_insert(*unit);
+        }
+    }
+    // Raw levels are only kept while both readings have them
+    existing.raw_pollutant_levels = match (
+        &existing.raw_pollutant_levels,
+        &candidate.raw_pollutant_levels,
+    ) {
+        (Some(raw), Some(candidate_raw)) => {
+            let mut raw = raw.clone();
+            for (pollutant, level) in candidate_raw {
+                raw.entry(pollutant.clone())
+                    .and_modify(|mean| *mean = (*mean * count + level) / (count + 1.0))
+                    .or_insert(*level);
+            }
+            Some(raw)
+        }
+        _ => None,
+    };
+    existing
+}
+
+fn keep_more_complete(existing: AirQualityData, candidate: &AirQualityData) -> AirQualityData {
+    if candidate.pollutant_levels.len() <= existing.pollutant_levels.len() {
+        return existing;
+    }
+    AirQualityData {
+        id: existing.id,
+        timestamp: existing.timestamp,
+        ingested_at: existing.ingested_at,
+        version: existing.version,
+        publication_state: existing.publication_state,
+        merged_readings: existing.merged_readings,
+        ..candidate.clone()
+    }
+}
+
+// Only published readings of the same organization and station take part in merges; drafts
+// and retracted readings are kept as they were stored
+fn can_merge_into(existing: &AirQualityData, candidate: &AirQualityData) -> bool {
+    !existing.is_deleted()
+        && existing.is_published()
+        && candidate.is_published()
+        && existing.organization_id == candidate.organization_id
+        && existing.station_id == candidate.station_id
+}
+
+// Merges a new reading into a live, published reading of the same station within the
+// station's merge window, the closest one if there are several. Returns the merged reading for
+// the caller to validate and store like a new one, or None if the new one is to be stored on
+// its own.
+pub(crate) fn merge_near(candidate: &AirQualityData) -> Option<AirQualityData> {
+    let policy = candidate
+        .station_id
+        .as_deref()
+        .and_then(stations::merge_policy)?;
+    let existing = dedup::live_readings_near(
+        &candidate.location,
+        candidate.timestamp,
+        policy.window_seconds as u64 * NANOS_PER_SECOND,
+    )
+    .into_iter()
+    .filter(|data| can_merge_into(data, candidate))
+    .min_by_key(|data| data.timestamp.abs_diff(candidate.timestamp))?;
+    let merged_readings = existing.merged_readings.unwrap_or(1) + 1;
+    let mut merged = match policy.strategy {
+        MergeStrategy::Average => average(existing, candidate),
+        MergeStrategy::KeepMoreComplete => keep_more_complete(existing, candidate),
+    };
+    merged.merged_readings = Some(merged_readings);
+    merged.version += 1;
+    Some(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::publication::PublicationState;
+    use crate::Pollutant;
+    use std::collections::HashMap;
+
+    fn reading(pm25: f64, air_quality_index: u32) -> AirQualityData {
+        AirQualityData {
+            location: "Lyon".to_string(),
+            station_id: Some("lyon-1".to_string()),
+            organization_id: Some(1),
+            air_quality_index,
+            pollutant_levels: HashMap::from([(Pollutant::PM25, pm25)]),
+            ..AirQualityData::default()
+        }
+    }
+
+    #[test]
+    fn drafts_retracted_and_deleted_readings_are_not_merged_into() {
+        let candidate = reading(10.0, 40);
+        for state in [PublicationState::Draft, PublicationState::Retracted] {
+            let existing = AirQualityData {
+                publication_state: Some(state),
+                ..reading(12.0, 50)
+            };
+            assert!(!can_merge_into(&existing, &candidate));
+        }
+        let deleted = AirQualityData {
+            deleted_at: Some(1),
+            ..reading(12.0, 50)
+        };
+        assert!(!can_merge_into(&deleted, &candidate));
+        assert!(can_merge_into(&reading(12.0, 50), &candidate));
+    }
+
+    #[test]
+    fn readings_of_other_stations_or_organizations_are_not_merged_into() {
+        let candidate = reading(10.0, 40);
+        let other_station = AirQualityData {
+            station_id: Some("lyon-2".to_string()),
+            ..reading(12.0, 50)
+        };
+        let other_organization = AirQualityData {
+            organization_id: Some(2),
+            ..reading(12.0, 50)
+        };
+        assert!(!can_merge_into(&other_station, &candidate));
+        assert!(!can_merge_into(&other_organization, &candidate));
+    }
+
+    #[test]
+    fn averaging_weighs_the_readings_already_merged() {
+        let existing = AirQualityData {
+            merged_readings: Some(3),
+            ..reading(10.0, 40)
+        };
+        let merged = average(existing, &reading(30.0, 80));
+        assert_eq!(merged.pollutant_levels[&Pollutant::PM25], 15.0);
+        assert_eq!(merged.air_quality_index, 50);
+    }
+}
@@ -0,0 +1,190 @@
+use crate::aliases;
+use crate::paging::ReplyBudget;
+use crate::units::ConcentrationUnit;
+use crate::{
+    accepts_writes, ensure_admin, filter_air_quality_data, get_memory, next_id, paging, stations,
+    units, AirQualityData, Error, IdCell, Memory, Pollutant, AIR_QUALITY_STORAGE,
+    METHODOLOGY_ID_COUNTER_MEMORY_ID, METHODOLOGY_STORAGE_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_SCOPE_LEN: usize = 200;
+const MAX_TEXT_LEN: usize = 2000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub(crate) enum MethodologyScope {
+    Location(String),
+    Station(String),
+}
+
+// Records a change in how data was produced, e.g. "switched correction model"
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct MethodologyNote {
+    id: u64,
+    scope: MethodologyScope,
+    // 1 for the first note of a scope, then counting up
+    version: u32,
+    effective_from: u64,
+    text: String,
+    created_by: Principal,
+    created_at: u64,
+}
+
+impl_bounded_storable!(MethodologyNote, 4096);
+
+// Readings of a location together with the methodology changes that took effect during them
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct TimeSeries {
+    readings: Vec<AirQualityData>,
+    // Unit of each pollutant over the whole range, not just this page
+    units: Vec<(Pollutant, Option<ConcentrationUnit>)>,
+    methodology_changes: Vec<MethodologyNote>,
+    // More readings are in the range; pass `next_cursor` to fetch them
+    truncated: bool,
+    next_cursor: Option<u64>,
+}
+
+thread_local! {
+    static METHODOLOGY_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(get_memory(METHODOLOGY_ID_COUNTER_MEMORY_ID), 0)
+            .expect("Cannot create a counter for methodology notes")
+    );
+
+    static METHODOLOGY_STORAGE: RefCell<StableBTreeMap<u64, MethodologyNote, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(METHODOLOGY_STORAGE_MEMORY_ID)));
+}
+
+fn notes_where<F>(predicate: F) -> Vec<MethodologyNote>
+where
+    F: Fn(&MethodologyNote) -> bool,
+{
+    METHODOLOGY_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, note)| predicate(note))
+            .map(|(_, note)| note)
+            .collect()
+    })
+}
+
+#[ic_cdk::update(guard = "accepts_writes")]
+fn add_methodology_note(
+    scope: MethodologyScope,
+    effective_from: u64,
+    text: String,
+) -> Result<MethodologyNote, Error> {
+    ensure_admin()?;
+    let name = match &scope {
+        MethodologyScope::Location(location) => location,
+        MethodologyScope::Station(station_id) => {
+            if stations::get_station(station_id).is_none() {
+                return Err(Error::NotFound {
+                    msg: format!("station {} not found", station_id),
+                });
+            }
+            station_id
+        }
+    };
+    if name.is_empty() || name.len() > MAX_SCOPE_LEN || text.is_empty() || text.len() > MAX_TEXT_LEN
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "scope must be 1 to {} bytes and text 1 to {} bytes",
+                MAX_SCOPE_LEN, MAX_TEXT_LEN
+            ),
+            violations: None,
+        });
+    }
+
+    let version = notes_where(|note| note.scope == scope).len() as u32 + 1;
+    let note = MethodologyNote {
+        id: next_id(&METHODOLOGY_ID_COUNTER),
+        scope,
+        version,
+        effective_from,
+        text,
+        created_by: ic_cdk::caller(),
+        created_at: time(),
+    };
+    METHODOLOGY_STORAGE.with(|s| s.borrow_mut().insert(note.id, note.clone()));
+    Ok(note)
+}
+
+continued_page!(MethodologyNotePage, MethodologyNote);
+
+// Every note of a scope, oldest version first
+#[ic_cdk::query]
+fn list_methodology_notes(
+    scope: MethodologyScope,
+    continuation: Option<String>,
+) -> Result<MethodologyNotePage, Error> {
+    MethodologyNotePage::new(notes_where(|note| note.scope == scope), continuation)
+}
+
+// Readings at a location within the range, ordered by timestamp, with the methodology notes
+// of the location and its stations that took effect within the range. Pages continue after
+// the reading named by `cursor`; every page carries all notes of the range.
+#[ic_cdk::query]
+fn get_time_series(
+    location: String,
+    start_timestamp: u64,
+    end_timestamp: u64,
+    unit: Option<ConcentrationUnit>,
+    cursor: Option<u64>,
+) -> Result<TimeSeries, Error> {
+    let location = aliases::location(location);
+    let after = match cursor {
+        Some(id) => Some(
+            AIR_QUALITY_STORAGE
+                .with(|service| service.borrow().get(&id))
+                .map(|data| (data.timestamp, data.id))
+                .ok_or(Error::InvalidInput {
+                    msg: format!("cursor {} does not name a stored reading", id),
+                    violations: None,
+                })?,
+        ),
+        None => None,
+    };
+
+    let mut readings: Vec<AirQualityData> = filter_air_quality_data(|data| {
+        data.location == location
+            && data.timestamp >= start_timestamp
+            && data.timestamp <= end_timestamp
+    })
+    .into_iter()
+    .map(|data| units::in_unit(data, unit))
+    .collect();
+    readings.sort_by_key(|data| (data.timestamp, data.id));
+    let series_units = units::series_units(&readings)?;
+
+    let mut methodology_changes = notes_where(|note| {
+        let in_scope = match &note.scope {
+            MethodologyScope::Location(note_location) => *note_location == location,
+            MethodologyScope::Station(station_id) => readings
+                .iter()
+                .any(|data| data.station_id.as_ref() == Some(station_id)),
+        };
+        in_scope && note.effective_from > start_timestamp && note.effective_from <= end_timestamp
+    });
+    methodology_changes.sort_by_key(|note| (note.effective_from, note.id));
+
+    let mut budget = ReplyBudget::new();
+    budget.admit(&methodology_changes);
+    budget.admit(&series_units);
+    let (readings, truncated) = paging::take_within(
+        readings
+            .into_iter()
+            .filter(|data| after.is_none_or(|after| (data.timestamp, data.id) > after)),
+        &mut budget,
+    );
+    Ok(TimeSeries {
+        next_cursor: readings.last().filter(|_| truncated).map(|data| data.id),
+        readings,
+        units: series_units,
+        methodology_changes,
+        truncated,
+    })
+}
@@ -0,0 +1,393 @@
+use crate::{
+    accepts_writes, aqi_index, categories, check_record_size, dedup, ensure_admin, get_memory,
+    latest, rollups, schema, text_search, time_index, AirQualityData, Error, Memory,
+    AIR_QUALITY_STORAGE, MIGRATION_STATE_MEMORY_ID,
+};
+use ic_cdk::api::time;
+use ic_stable_structures::Cell;
+use std::cell::RefCell;
+use std::ops::Bound;
+
+// Records processed per message by each entry point
+const POST_UPGRADE_BUDGET: u64 = 5_000;
+const CONTINUE_BUDGET: u64 = 2_000;
+const TIMER_BUDGET: u64 = 500;
+
+enum MigrationProgress {
+    // More records remain after the given cursor
+    Continue { cursor: u64, processed: u64 },
+    Done { processed: u64 },
+}
+
+// A migration processes records after `cursor` and stops once it has processed `budget` of them
+struct Migration {
+    version: u32,
+    name: &'static str,
+    step: fn(cursor: Option<u64>, budget: u64) -> MigrationProgress,
+}
+
+// Ordered by version. Append new migrations at the end and never edit applied ones.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "reencode_air_quality_records",
+        step: reencode_air_quality_records,
+    },
+    Migration {
+        version: 2,
+        name: "record_schema_v2",
+        step: record_schema_v2,
+    },
+    Migration {
+        version: 3,
+        name: "record_schema_v3",
+        step: record_schema_v3,
+    },
+    Migration {
+        version: 4,
+        name: "build_hourly_rollups",
+        step: build_hourly_rollups,
+    },
+    Migration {
+        version: 5,
+        name: "record_schema_v4",
+        step: record_schema_v4,
+    },
+    Migration {
+        version: 6,
+        name: "build_dedup_index",
+        step: build_dedup_index,
+    },
+    Migration {
+        version: 7,
+        name: "build_text_index",
+        step: build_text_index,
+    },
+    Migration {
+        version: 8,
+        name: "build_latest_index",
+        step: build_latest_index,
+    },
+    Migration {
+        version: 9,
+        name: "build_category_index",
+        step: build_category_index,
+    },
+    Migration {
+        version: 10,
+        name: "build_aqi_index",
+        step: build_aqi_index,
+    },
+    Migration {
+        version: 11,
+        name: "build_time_index",
+        step: build_time_index,
+    },
+    Migration {
+        version: 12,
+        name: "move_organization_readings_in_rollups",
+        step: move_organization_readings_in_rollups,
+    },
+];
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RunningMigration {
+    version: u32,
+    cursor: Option<u64>,
+    processed: u64,
+    started_at: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AppliedMigration {
+    version: u32,
+    name: String,
+    completed_at: u64,
+    records_processed: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct MigrationStatus {
+    applied_version: u32,
+    latest_version: u32,
+    running: Option<RunningMigration>,
+    history: Vec<AppliedMigration>,
+}
+
+impl_bounded_storable!(MigrationStatus, 8 * 1024);
+
+thread_local! {
+    static MIGRATION_STATE: RefCell<Cell<MigrationStatus, Memory>> = RefCell::new(
+        Cell::init(get_memory(MIGRATION_STATE_MEMORY_ID), MigrationStatus::default())
+            .expect("Cannot create the migration state cell")
+    );
+}
+
+fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+fn state() -> MigrationStatus {
+    let mut state = MIGRATION_STATE.with(|s| s.borrow().get().clone());
+    state.latest_version = latest_version();
+    state
+}
+
+fn set_state(state: MigrationStatus) {
+    MIGRATION_STATE
+        .with(|s| s.borrow_mut().set(state))
+        .expect("cannot store the migration state");
+}
+
+pub(crate) fn is_pending() -> bool {
+    state().applied_version < latest_version()
+}
+
+// A freshly installed canister has no data in older formats
+pub(crate) fn mark_all_applied() {
+    let mut state = state();
+    state.applied_version = latest_version();
+    set_state(state);
+}
+
+// Runs pending migrations in order until `budget` records have been processed
+fn run_pending(mut budget: u64) -> MigrationStatus {
+    let mut state = state();
+    while budget > 0 {
+        let Some(migration) = MIGRATIONS
+            .iter()
+            .find(|migration| migration.version > state.applied_version)
+        else {
+            break;
+        };
+        let running = state.running.take().unwrap_or(RunningMigration {
+            version: migration.version,
+            cursor: None,
+            processed: 0,
+            started_at: time(),
+        });
+
+        match (migration.step)(running.cursor, budget) {
+            MigrationProgress::Continue { cursor, processed } => {
+                budget = budget.saturating_sub(processed.max(1));
+                state.running = Some(RunningMigration {
+                    cursor: Some(cursor),
+                    processed: running.processed + processed,
+                    ..running
+                });
+            }
+            MigrationProgress::Done { processed } => {
+                budget = budget.saturating_sub(processed);
+                state.applied_version = migration.version;
+                log!(
+                    Info,
+                    "migration applied",
+                    "version" => migration.version,
+                    "name" => migration.name,
+                    "records" => running.processed + processed,
+                );
+                state.history.push(AppliedMigration {
+                    version: migration.version,
+                    name: migration.name.to_string(),
+                    completed_at: time(),
+                    records_processed: running.processed + processed,
+                });
+            }
+        }
+    }
+    set_state(state.clone());
+    state
+}
+
+pub(crate) fn on_post_upgrade() {
+    run_pending(POST_UPGRADE_BUDGET);
+}
+
+pub(crate) fn on_timer() {
+    if is_pending() {
+        run_pending(TIMER_BUDGET);
+    }
+}
+
+// Continues migrations that did not fit in post_upgrade
+#[ic_cdk::update(guard = "accepts_writes")]
+fn continue_migration() -> Result<MigrationStatus, Error> {
+    ensure_admin()?;
+    Ok(run_pending(CONTINUE_BUDGET))
+}
+
+#[ic_cdk::query]
+fn get_migration_status() -> MigrationStatus {
+    state()
+}
+
+// Runs `hook` on the next `budget` records after `cursor`, in id order. Shared by the
+// migrations that visit every stored record once. Records that cannot be decoded are logged
+// and left alone.
+fn backfill_index(
+    cursor: Option<u64>,
+    budget: u64,
+    hook: fn(&AirQualityData),
+) -> MigrationProgress {
+    let start = cursor.map_or(Bound::Unbounded, Bound::Excluded);
+    let batch: Vec<(u64, AirQualityData)> = AIR_QUALITY_STORAGE.with(|service| {
+        service
+            .borrow()
+            .range((start, Bound::Unbounded))
+            .take(budget as usize)
+            .collect()
+    });
+    let processed = batch.len() as u64;
+    for (id, data) in &batch {
+        if data.id == schema::UNREADABLE_RECORD_ID {
+            log!(Warn, "unreadable record skipped by a migration", "id" => id);
+            continue;
+        }
+        hook(data);
+    }
+    match batch.last() {
+        Some((last, _)) if processed == budget => MigrationProgress::Continue {
+            cursor: *last,
+            processed,
+        },
+        _ => MigrationProgress::Done { processed },
+    }
+}
+
+// Rewrites every record so it is stored in the current encoding
+fn reencode_air_quality_records(cursor: Option<u64>, budget: u64) -> MigrationProgress {
+    // Written directly: re-encoding does not change a record, so rollups stay untouched
+    backfill_index(cursor, budget, |data| {
+        // New fields can push a legacy record over the bound; it stays in its old layout,
+        // which is still decoded on read
+        if check_record_size(data).is_err() {
+            log!(Warn, "record too large to re-encode", "id" => data.id);
+            return;
+        }
+        AIR_QUALITY_STORAGE.with(|service| service.borrow_mut().insert(data.id, data.clone()));
+    })
+}
+
+// Rewrites records in the current layout and, once all are done, records the schema version
+fn reencode_to_schema(cursor: Option<u64>, budget: u64, version: u32) -> MigrationProgress {
+    let progress = reencode_air_quality_records(cursor, budget);
+    if let MigrationProgress::Done { .. } = progress {
+        schema::set_stored_version(version);
+    }
+    progress
+}
+
+// Rewrites v1 records with tombstone and station fields
+fn record_schema_v2(cursor: Option<u64>, budget: u64) -> MigrationProgress {
+    reencode_to_schema(cursor, budget, 2)
+}
+
+// Rewrites v2 records with a concurrency version
+fn record_schema_v3(cursor: Option<u64>, budget: u64) -> MigrationProgress {
+    reencode_to_schema(cursor, budget, 3)
+}
+
+// Rewrites v3 records with typed pollutant names
+fn record_schema_v4(cursor: Option<u64>, budget: u64) -> MigrationProgress {
+    reencode_to_schema(cursor, budget, 4)
+}
+
+// Adds records stored before rollups existed to the hourly rollups
+fn build_hourly_rollups(cursor: Option<u64>, budget: u64) -> MigrationProgress {
+    let progress = backfill_index(cursor, budget, rollups::backfill);
+    if let MigrationProgress::Done { .. } = progress {
+        rollups::mark_backfilled();
+    }
+    progress
+}
+
+// Indexes records stored before duplicate detection, so new readings are checked against them
+fn build_dedup_index(cursor: Option<u64>, budget: u64) -> MigrationProgress {
+    backfill_index(cursor, budget, dedup::backfill)
+}
+
+// Indexes the text of records stored before full-text search
+fn build_text_index(cursor: Option<u64>, budget: u64) -> MigrationProgress {
+    backfill_index(cursor, budget, text_search::backfill)
+}
+
+// Indexes the latest reading of each location from records stored before the index
+fn build_latest_index(cursor: Option<u64>, budget: u64) -> MigrationProgress {
+    backfill_index(cursor, budget, latest::backfill)
+}
+
+// Indexes records stored before the AQI category index by their category
+fn build_category_index(cursor: Option<u64>, budget: u64) -> MigrationProgress {
+    backfill_index(cursor, budget, categories::backfill)
+}
+
+// Indexes records stored before the AQI index by their AQI
+fn build_aqi_index(cursor: Option<u64>, budget: u64) -> MigrationProgress {
+    backfill_index(cursor, budget, aqi_index::backfill)
+}
+
+// Indexes records stored before the time index by their observation time
+fn build_time_index(cursor: Option<u64>, budget: u64) -> MigrationProgress {
+    backfill_index(cursor, budget, time_index::backfill)
+}
+
+// Moves the readings of organizations from the shared hourly rollups to those of their
+// organization, so callers only count the organizations they may see
+fn move_organization_readings_in_rollups(cursor: Option<u64>, budget: u64) -> MigrationProgress {
+    let progress = backfill_index(cursor, budget, rollups::move_to_organization);
+    if let MigrationProgress::Done { .. } = progress {
+        rollups::mark_organizations_moved();
+    }
+    progress
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    thread_local! {
+        static VISITED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn visit(data: &AirQualityData) {
+        VISITED.with(|v| v.borrow_mut().push(data.id));
+    }
+
+    fn store(ids: &[u64]) {
+        for &id in ids {
+            let data = AirQualityData {
+                id,
+                ..AirQualityData::default()
+            };
+            AIR_QUALITY_STORAGE.with(|service| service.borrow_mut().insert(id, data));
+        }
+    }
+
+    #[test]
+    fn backfills_resume_after_their_cursor_until_the_store_is_done() {
+        store(&[3, 7, 8, 12, 20]);
+        let MigrationProgress::Continue { cursor, processed } = backfill_index(None, 2, visit)
+        else {
+            panic!("three records remain");
+        };
+        assert_eq!((cursor, processed), (7, 2));
+        let MigrationProgress::Continue { cursor, .. } = backfill_index(Some(cursor), 2, visit)
+        else {
+            panic!("one record remains");
+        };
+        assert_eq!(cursor, 12);
+        let MigrationProgress::Done { processed } = backfill_index(Some(cursor), 2, visit) else {
+            panic!("no record remains");
+        };
+        assert_eq!(processed, 1);
+        assert_eq!(VISITED.with(|v| v.borrow().clone()), vec![3, 7, 8, 12, 20]);
+    }
+
+    #[test]
+    fn migrations_are_registered_in_version_order() {
+        assert!(MIGRATIONS
+            .windows(2)
+            .all(|pair| pair[0].version < pair[1].version));
+        assert_eq!(latest_version(), MIGRATIONS.len() as u32);
+    }
+}
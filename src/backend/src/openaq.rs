@@ -0,0 +1,174 @@
+use crate::filters::ReadingFilter;
+use crate::grafana::format_time;
+use crate::paging::ReplyBudget;
+use crate::stations;
+use crate::units::{self, ConcentrationUnit};
+use crate::{is_visible_to_caller, AirQualityData, AIR_QUALITY_STORAGE};
+use std::ops::Bound;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct OpenAqPage {
+    // `{"meta": {"found": n}, "results": [...]}`, the envelope of OpenAQ's v2 measurements
+    body: String,
+    measurements: u32,
+    // More readings match; pass `next_cursor` to fetch them
+    truncated: bool,
+    next_cursor: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct OpenAqDate {
+    utc: String,
+    // Readings do not record their time zone, so this is UTC too
+    local: String,
+}
+
+#[derive(Serialize, Clone)]
+struct OpenAqCoordinates {
+    latitude: f64,
+    longitude: f64,
+}
+
+// One pollutant level of a reading, with OpenAQ's field names
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OpenAqMeasurement {
+    // OpenAQ's numeric location ids have no counterpart here
+    location_id: Option<u64>,
+    location: String,
+    parameter: String,
+    value: f64,
+    date: OpenAqDate,
+    unit: &'static str,
+    // The public coordinates of the reading's station, if it has one
+    coordinates: Option<OpenAqCoordinates>,
+    country: Option<String>,
+    city: Option<String>,
+    is_mobile: bool,
+    is_analysis: Option<bool>,
+    entity: Option<String>,
+    sensor_type: Option<String>,
+}
+
+#[derive(Serialize)]
+struct OpenAqMeta {
+    found: u32,
+}
+
+#[derive(Serialize)]
+struct OpenAqResponse {
+    meta: OpenAqMeta,
+    results: Vec<OpenAqMeasurement>,
+}
+
+// OpenAQ gives times to the second with an explicit offset, e.g. "2024-01-31T06:33:44+00:00"
+fn openaq_time(nanos: u64) -> String {
+    format!("{}+00:00", &format_time(nanos)[..19])
+}
+
+// OpenAQ's parameter names are the lowercase formulas without dots: "pm25", "no2", ...
+fn parameter(name: &str) -> String {
+    name.replace('.', "")
+}
+
+// Levels without a recorded unit are in µg/m³; mg/m³ is not an OpenAQ unit and is scaled
+fn openaq_unit(level: f64, unit: Option<ConcentrationUnit>) -> (f64, &'static str) {
+    match unit {
+        Some(ConcentrationUnit::Ppm) => (level, "ppm"),
+        Some(ConcentrationUnit::Ppb) => (level, "ppb"),
+        Some(ConcentrationUnit::MilligramsPerCubicMeter) => (level * 1000.0, "µg/m³"),
+        Some(ConcentrationUnit::MicrogramsPerCubicMeter) | None => (level, "µg/m³"),
+    }
+}
+
+fn measurements(data: &AirQualityData) -> Vec<OpenAqMeasurement> {
+    let coordinates = data
+        .station_id
+        .as_deref()
+        .and_then(stations::public_station)
+        .map(|station| {
+            let (latitude, longitude) = station.coordinates();
+            OpenAqCoordinates {
+                latitude,
+                longitude,
+            }
+        });
+    let time = openaq_time(data.timestamp);
+    let mut levels: Vec<_> = data.pollutant_levels.iter().collect();
+    levels.sort_by_key(|(pollutant, _)| *pollutant);
+    levels
+        .into_iter()
+        .filter(|(_, level)| level.is_finite())
+        .map(|(pollutant, level)| {
+            let unit = data
+                .pollutant_units
+                .as_ref()
+                .and_then(|units| units.get(pollutant))
+                .copied();
+            let (value, unit) = openaq_unit(*level, unit);
+            OpenAqMeasurement {
+                location_id: None,
+                location: data.location.clone(),
+                parameter: parameter(pollutant.name()),
+                value,
+                date: OpenAqDate {
+                    utc: time.clone(),
+                    local: time.clone(),
+                },
+                unit,
+                coordinates: coordinates.clone(),
+                country: None,
+                city: None,
+                is_mobile: false,
+                is_analysis: None,
+                entity: None,
+                sensor_type: None,
+            }
+        })
+        .collect()
+}
+
+// The pollutant levels of the readings the filter matches, in reading id order, as JSON in
+// OpenAQ's v2 measurement schema. Pages continue after the reading with id `cursor`.
+#[ic_cdk::query]
+fn export_openaq(filter: ReadingFilter, cursor: Option<u64>) -> OpenAqPage {
+    let unit = filter.unit();
+    let predicate = filter.predicate();
+    let start = cursor.map_or(Bound::Unbounded, Bound::Excluded);
+    let mut budget = ReplyBudget::new();
+    let mut results = Vec::new();
+    let mut last_id = None;
+    let mut truncated = false;
+    AIR_QUALITY_STORAGE.with(|service| {
+        let storage = service.borrow();
+        let matching = storage
+            .range((start, Bound::Unbounded))
+            .map(|(_, data)| data)
+            .filter(|data| !data.is_deleted() && is_visible_to_caller(data))
+            .map(|data| units::in_unit(data, unit))
+            .filter(|data| predicate(data));
+        for data in matching {
+            let measurements = measurements(&data);
+            // A reading's share of the reply is about the size of its JSON
+            let rendered =
+                serde_json::to_string(&measurements).expect("measurements serialize to JSON");
+            if !budget.admit(&rendered) {
+                truncated = true;
+                break;
+            }
+            results.extend(measurements);
+            last_id = Some(data.id);
+        }
+    });
+    let found = results.len() as u32;
+    OpenAqPage {
+        body: serde_json::to_string(&OpenAqResponse {
+            meta: OpenAqMeta { found },
+            results,
+        })
+        .expect("responses serialize to JSON"),
+        measurements: found,
+        next_cursor: last_id.filter(|_| truncated),
+        truncated,
+    }
+}
@@ -0,0 +1,373 @@
+use crate::{
+    accepts_writes, ensure_admin, get_memory, next_id, principal_key, stations, Error, IdCell,
+    Memory, PrincipalKey, ORGANIZATION_ID_COUNTER_MEMORY_ID, ORGANIZATION_MEMBER_STORAGE_MEMORY_ID,
+    ORGANIZATION_STORAGE_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_NAME_LEN: usize = 200;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum OrganizationVisibility {
+    // Everyone sees the stations and readings; only members write them
+    Public,
+    // Stations and readings are shown to members and controllers alone
+    MembersOnly,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum OrganizationRole {
+    // Manages the members and every station of the organization
+    Admin,
+    // Writes readings of the organization
+    Member,
+}
+
+// A tenant of the canister, such as a city, that stations and readings belong to
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Organization {
+    id: u64,
+    name: String,
+    visibility: OrganizationVisibility,
+    created_by: Principal,
+    created_at: u64,
+    updated_at: u64,
+}
+
+impl_bounded_storable!(Organization, 512);
+
+impl Organization {
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct OrganizationPayload {
+    name: String,
+    visibility: OrganizationVisibility,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct OrganizationMember {
+    organization_id: u64,
+    principal: Principal,
+    role: OrganizationRole,
+    added_by: Principal,
+    added_at: u64,
+}
+
+impl_bounded_storable!(OrganizationMember, 256);
+
+thread_local! {
+    static ORGANIZATION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(get_memory(ORGANIZATION_ID_COUNTER_MEMORY_ID), 0)
+            .expect("Cannot create a counter for organizations")
+    );
+
+    static ORGANIZATION_STORAGE: RefCell<StableBTreeMap<u64, Organization, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ORGANIZATION_STORAGE_MEMORY_ID)));
+
+    // (organization id, member) -> membership, so an organization's members are one range
+    static ORGANIZATION_MEMBER_STORAGE: RefCell<StableBTreeMap<(u64, PrincipalKey), OrganizationMember, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ORGANIZATION_MEMBER_STORAGE_MEMORY_ID)));
+}
+
+pub(crate) fn get_organization_record(id: u64) -> Option<Organization> {
+    ORGANIZATION_STORAGE.with(|s| s.borrow().get(&id))
+}
+
+pub(crate) fn all() -> Vec<Organization> {
+    ORGANIZATION_STORAGE.with(|s| s.borrow().iter().map(|(_, org)| org).collect())
+}
+
+fn role_of(organization_id: u64, principal: &Principal) -> Option<OrganizationRole> {
+    ORGANIZATION_MEMBER_STORAGE.with(|s| {
+        s.borrow()
+            .get(&(organization_id, principal_key(principal)))
+            .map(|member| member.role)
+    })
+}
+
+fn existing(organization_id: u64) -> Result<Organization, Error> {
+    get_organization_record(organization_id).ok_or(Error::NotFound {
+        msg: format!("organization with id={} not found", organization_id),
+    })
+}
+
+// Whether the principal may see the stations and readings of an organization; those of no
+// organization are seen by everyone
+pub(crate) fn is_visible_to(organization_id: Option<u64>, principal: &Principal) -> bool {
+    is_shown_to_member(organization_id, principal) || ic_cdk::api::is_controller(principal)
+}
+
+// Visibility leaving controllers aside: public organizations, and the members of the others
+fn is_shown_to_member(organization_id: Option<u64>, principal: &Principal) -> bool {
+    organization_id.is_none_or(|id| {
+        get_organization_record(id)
+            .is_none_or(|org| org.visibility == OrganizationVisibility::Public)
+            || role_of(id, principal).is_some()
+    })
+}
+
+pub(crate) fn is_visible_to_caller(organization_id: Option<u64>) -> bool {
+    is_visible_to(organization_id, &ic_cdk::caller())
+}
+
+// Whether the principal administers the organization
+pub(crate) fn is_admin_of(organization_id: u64, principal: &Principal) -> bool {
+    role_of(organization_id, principal) == Some(OrganizationRole::Admin)
+        || ic_cdk::api::is_controller(principal)
+}
+
+// Readings and stations of an organization are written by its members and controllers only
+pub(crate) fn ensure_can_write(organization_id: Option<u64>) -> Result<(), Error> {
+    let Some(id) = organization_id else {
+        return Ok(());
+    };
+    existing(id)?;
+    let caller = ic_cdk::caller();
+    if role_of(id, &caller).is_none() && !ic_cdk::api::is_controller(&caller) {
+        return Err(Error::Unauthorized {
+            msg: format!("caller is not a member of organization {}", id),
+        });
+    }
+    Ok(())
+}
+
+fn ensure_admin_of(organization_id: u64) -> Result<(), Error> {
+    if !is_admin_of(organization_id, &ic_cdk::caller()) {
+        return Err(Error::Unauthorized {
+            msg: format!("caller is not an admin of organization {}", organization_id),
+        });
+    }
+    Ok(())
+}
+
+// The organization a reading belongs to: that of its station, or the one requested for a
+// reading without a station of an organization
+pub(crate) fn of_reading(
+    station_id: Option<&str>,
+    requested: Option<u64>,
+) -> Result<Option<u64>, Error> {
+    let of_station = station_id
+        .and_then(stations::get_station)
+        .and_then(|station| station.organization_id());
+    match (of_station, requested) {
+        (Some(of_station), Some(requested)) if of_station != requested => {
+            Err(Error::InvalidInput {
+                msg: format!(
+                    "the station belongs to organization {}, not {}",
+                    of_station, requested
+                ),
+                violations: None,
+            })
+        }
+        (Some(of_station), _) => Ok(Some(of_station)),
+        (None, Some(requested)) => existing(requested).map(|org| Some(org.id)),
+        (None, None) => Ok(None),
+    }
+}
+
+fn validate_payload(payload: &OrganizationPayload) -> Result<(), Error> {
+    if payload.name.trim().is_empty() || payload.name.len() > MAX_NAME_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!("name must be 1 to {} bytes", MAX_NAME_LEN),
+            violations: None,
+        });
+    }
+    Ok(())
+}
+
+// Creates an organization (controllers only). Add its admins with add_organization_member.
+#[ic_cdk::update(guard = "accepts_writes")]
+fn create_organization(payload: OrganizationPayload) -> Result<Organization, Error> {
+    ensure_admin()?;
+    validate_payload(&payload)?;
+    let now = time();
+    let org = Organization {
+        id: next_id(&ORGANIZATION_ID_COUNTER),
+        name: payload.name.trim().to_string(),
+        visibility: payload.visibility,
+        created_by: ic_cdk::caller(),
+        created_at: now,
+        updated_at: now,
+    };
+    ORGANIZATION_STORAGE.with(|s| s.borrow_mut().insert(org.id, org.clone()));
+    Ok(org)
+}
+
+// Renames an organization or changes its visibility (its admins and controllers only)
+#[ic_cdk::update(guard = "accepts_writes")]
+fn update_organization(id: u64, payload: OrganizationPayload) -> Result<Organization, Error> {
+    validate_payload(&payload)?;
+    let mut org = existing(id)?;
+    ensure_admin_of(id)?;
+    org.name = payload.name.trim().to_string();
+    org.visibility = payload.visibility;
+    org.updated_at = time();
+    ORGANIZATION_STORAGE.with(|s| s.borrow_mut().insert(id, org.clone()));
+    Ok(org)
+}
+
+// Adds a member, or changes the role of one (its admins and controllers only)
+#[ic_cdk::update(guard = "accepts_writes")]
+fn add_organization_member(
+    organization_id: u64,
+    principal: Principal,
+    role: OrganizationRole,
+) -> Result<OrganizationMember, Error> {
+    existing(organization_id)?;
+    ensure_admin_of(organization_id)?;
+    let member = OrganizationMember {
+        organization_id,
+        principal,
+        role,
+        added_by: ic_cdk::caller(),
+        added_at: time(),
+    };
+    ORGANIZATION_MEMBER_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert((organization_id, principal_key(&principal)), member.clone())
+    });
+    Ok(member)
+}
+
+// Removes a member (its admins and controllers only). Stations the member owns stay in the
+// organization.
+#[ic_cdk::update(guard = "accepts_writes")]
+fn remove_organization_member(
+    organization_id: u64,
+    principal: Principal,
+) -> Result<OrganizationMember, Error> {
+    existing(organization_id)?;
+    ensure_admin_of(organization_id)?;
+    ORGANIZATION_MEMBER_STORAGE
+        .with(|s| {
+            s.borrow_mut()
+                .remove(&(organization_id, principal_key(&principal)))
+        })
+        .ok_or(Error::NotFound {
+            msg: format!(
+                "{} is not a member of organization {}",
+                principal, organization_id
+            ),
+        })
+}
+
+#[ic_cdk::query]
+fn get_organization(id: u64) -> Result<Organization, Error> {
+    existing(id)
+        .ok()
+        .filter(|org| is_visible_to_caller(Some(org.id)))
+        .ok_or(Error::NotFound {
+            msg: format!("organization with id={} not found", id),
+        })
+}
+
+continued_page!(OrganizationPage, Organization);
+
+// Public organizations and those the caller is a member of
+#[ic_cdk::query]
+fn list_organizations(continuation: Option<String>) -> Result<OrganizationPage, Error> {
+    ORGANIZATION_STORAGE.with(|s| {
+        OrganizationPage::new(
+            s.borrow()
+                .iter()
+                .map(|(_, org)| org)
+                .filter(|org| is_visible_to_caller(Some(org.id))),
+            continuation,
+        )
+    })
+}
+
+continued_page!(OrganizationMemberPage, OrganizationMember);
+
+// The members of an organization (its members and controllers only)
+#[ic_cdk::query]
+fn list_organization_members(
+    organization_id: u64,
+    continuation: Option<String>,
+) -> Result<OrganizationMemberPage, Error> {
+    ensure_can_write(Some(organization_id))?;
+    ORGANIZATION_MEMBER_STORAGE.with(|s| {
+        OrganizationMemberPage::new(
+            s.borrow()
+                .range((organization_id, PrincipalKey::default())..)
+                .take_while(|((id, _), _)| *id == organization_id)
+                .map(|(_, member)| member),
+            continuation,
+        )
+    })
+}
+
+// The caller's memberships
+#[ic_cdk::query]
+fn get_my_organizations() -> Vec<OrganizationMember> {
+    let caller = ic_cdk::caller();
+    ORGANIZATION_MEMBER_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, member)| member)
+            .filter(|member| member.principal == caller)
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(id: u64, visibility: OrganizationVisibility) {
+        let organization = Organization {
+            id,
+            name: format!("organization {}", id),
+            visibility,
+            created_by: Principal::anonymous(),
+            created_at: 0,
+            updated_at: 0,
+        };
+        ORGANIZATION_STORAGE.with(|s| s.borrow_mut().insert(id, organization));
+    }
+
+    fn join(organization_id: u64, principal: Principal) {
+        let member = OrganizationMember {
+            organization_id,
+            principal,
+            role: OrganizationRole::Member,
+            added_by: Principal::anonymous(),
+            added_at: 0,
+        };
+        ORGANIZATION_MEMBER_STORAGE.with(|s| {
+            s.borrow_mut()
+                .insert((organization_id, principal_key(&principal)), member)
+        });
+    }
+
+    #[test]
+    fn members_only_readings_are_hidden_from_outsiders() {
+        let member = Principal::from_slice(&[1]);
+        let outsider = Principal::from_slice(&[2]);
+        store(1, OrganizationVisibility::MembersOnly);
+        join(1, member);
+        assert!(is_shown_to_member(Some(1), &member));
+        assert!(!is_shown_to_member(Some(1), &outsider));
+    }
+
+    #[test]
+    fn public_and_unowned_readings_are_shown_to_everyone() {
+        let outsider = Principal::from_slice(&[3]);
+        store(2, OrganizationVisibility::Public);
+        assert!(is_shown_to_member(Some(2), &outsider));
+        assert!(is_shown_to_member(None, &outsider));
+        // A reading of an organization that no longer exists is not held back
+        assert!(is_shown_to_member(Some(99), &outsider));
+    }
+}
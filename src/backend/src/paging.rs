@@ -0,0 +1,86 @@
+use crate::{AirQualityData, Error};
+use candid::{CandidType, Encode};
+
+// The replica rejects replies over 2 MiB. Pages stop short of that, leaving room for the
+// envelope and the fields around the paged items.
+const MAX_REPLY_BYTES: usize = 1_900_000;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ReadingPage {
+    pub(crate) readings: Vec<AirQualityData>,
+    // More readings match; pass `next_cursor` to fetch them
+    pub(crate) truncated: bool,
+    pub(crate) next_cursor: Option<u64>,
+}
+
+// Space left in one reply. Items are measured as if encoded on their own, which overestimates
+// their share of the reply.
+pub(crate) struct ReplyBudget {
+    remaining: usize,
+}
+
+impl ReplyBudget {
+    pub(crate) fn new() -> Self {
+        ReplyBudget {
+            remaining: MAX_REPLY_BYTES,
+        }
+    }
+
+    // Reserves room for `item`. Once an item does not fit, nothing more is admitted, so a
+    // page is always a prefix of the results.
+    pub(crate) fn admit<T: CandidType>(&mut self, item: &T) -> bool {
+        let size = Encode!(item).map_or(usize::MAX, |bytes| bytes.len());
+        if size > self.remaining {
+            self.remaining = 0;
+            return false;
+        }
+        self.remaining -= size;
+        true
+    }
+}
+
+// Takes items while they fit in `budget`. Returns them and whether any were left out.
+pub(crate) fn take_within<T: CandidType>(
+    items: impl IntoIterator<Item = T>,
+    budget: &mut ReplyBudget,
+) -> (Vec<T>, bool) {
+    let mut taken = Vec::new();
+    for item in items {
+        if !budget.admit(&item) {
+            return (taken, true);
+        }
+        taken.push(item);
+    }
+    (taken, false)
+}
+
+// A page of readings in id order. Its cursor is the id of the last reading returned.
+pub(crate) fn reading_page(records: impl IntoIterator<Item = AirQualityData>) -> ReadingPage {
+    let (readings, truncated) = take_within(records, &mut ReplyBudget::new());
+    ReadingPage {
+        next_cursor: readings.last().filter(|_| truncated).map(|data| data.id),
+        readings,
+        truncated,
+    }
+}
+
+// The items after the position `continuation` names that fit in one reply, and the
+// continuation of the rest. Continuations are positions in the list, so items added or removed
+// between calls may be skipped or returned twice.
+pub(crate) fn continue_within<T: CandidType>(
+    items: impl IntoIterator<Item = T>,
+    continuation: Option<String>,
+) -> Result<(Vec<T>, Option<String>), Error> {
+    let skip = match continuation {
+        Some(continuation) => continuation
+            .parse::<usize>()
+            .map_err(|_| Error::InvalidInput {
+                msg: format!("{} is not a continuation of this query", continuation),
+                violations: None,
+            })?,
+        None => 0,
+    };
+    let (taken, truncated) = take_within(items.into_iter().skip(skip), &mut ReplyBudget::new());
+    let continuation = truncated.then(|| (skip + taken.len()).to_string());
+    Ok((taken, continuation))
+}
@@ -0,0 +1,115 @@
+use serde::de::{self, Deserialize, Deserializer, EnumAccess, VariantAccess, Visitor};
+use std::collections::HashMap;
+use std::fmt;
+
+// Pollutant names are typed so "PM2.5", "pm25" and "PM2_5" cannot coexist
+#[derive(candid::CandidType, Clone, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub(crate) enum Pollutant {
+    PM25,
+    PM10,
+    O3,
+    NO2,
+    SO2,
+    CO,
+    // Any other pollutant, by lowercase name
+    Other(String),
+}
+
+impl Pollutant {
+    // Accepts common spellings: "PM2.5", "pm25", "PM2_5", "no2", ...
+    pub(crate) fn parse(name: &str) -> Pollutant {
+        let compact: String = name
+            .trim()
+            .to_ascii_lowercase()
+            .chars()
+            .filter(|c| !matches!(c, '.' | '_' | '-' | ' '))
+            .collect();
+        match compact.as_str() {
+            "pm25" => Pollutant::PM25,
+            "pm10" => Pollutant::PM10,
+            "o3" => Pollutant::O3,
+            "no2" => Pollutant::NO2,
+            "so2" => Pollutant::SO2,
+            "co" => Pollutant::CO,
+            _ => Pollutant::Other(name.trim().to_ascii_lowercase()),
+        }
+    }
+
+    // An `Other` spelling of a named pollutant becomes that pollutant
+    pub(crate) fn normalized(self) -> Pollutant {
+        match self {
+            Pollutant::Other(name) => Pollutant::parse(&name),
+            pollutant => pollutant,
+        }
+    }
+
+    // Stable lowercase name, used as a storage key
+    pub(crate) fn name(&self) -> &str {
+        match self {
+            Pollutant::PM25 => "pm2.5",
+            Pollutant::PM10 => "pm10",
+            Pollutant::O3 => "o3",
+            Pollutant::NO2 => "no2",
+            Pollutant::SO2 => "so2",
+            Pollutant::CO => "co",
+            Pollutant::Other(name) => name,
+        }
+    }
+}
+
+// Records stored before pollutants were typed keyed levels by free-form names
+pub(crate) fn parse_levels<V>(levels: HashMap<String, V>) -> HashMap<Pollutant, V> {
+    levels
+        .into_iter()
+        .map(|(name, value)| (Pollutant::parse(&name), value))
+        .collect()
+}
+
+// Candid sends the variant. JSON backfill sources key their maps by name, which serde_json
+// can only hand over as a string, so names are accepted too.
+impl<'de> Deserialize<'de> for Pollutant {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(PollutantVisitor)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(field_identifier)]
+enum Tag {
+    PM25,
+    PM10,
+    O3,
+    NO2,
+    SO2,
+    CO,
+    Other,
+}
+
+struct PollutantVisitor;
+
+impl<'de> Visitor<'de> for PollutantVisitor {
+    type Value = Pollutant;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a pollutant variant or name")
+    }
+
+    fn visit_str<E: de::Error>(self, name: &str) -> Result<Pollutant, E> {
+        Ok(Pollutant::parse(name))
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Pollutant, A::Error> {
+        let (tag, variant) = data.variant::<Tag>()?;
+        let pollutant = match tag {
+            Tag::PM25 => Pollutant::PM25,
+            Tag::PM10 => Pollutant::PM10,
+            Tag::O3 => Pollutant::O3,
+            Tag::NO2 => Pollutant::NO2,
+            Tag::SO2 => Pollutant::SO2,
+            Tag::CO => Pollutant::CO,
+            Tag::Other => return Ok(Pollutant::Other(variant.newtype_variant()?)),
+        };
+        variant.unit_variant()?;
+        Ok(pollutant)
+    }
+}
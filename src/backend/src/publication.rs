@@ -0,0 +1,244 @@
+use crate::events::{self, EventKind};
+use crate::{
+    _get_air_quality_data, accepts_writes, alerts, clear_stable_map, consumers,
+    do_insert_air_quality, get_memory, organizations, stations, AirQualityData, Error, Memory,
+    RETRACTION_STORAGE_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::ops::Bound;
+
+const MAX_REASON_LEN: usize = 500;
+
+// Where a reading is in the quality assurance process. Only published and corrected readings
+// are public and counted in rollups, aggregates and alerts.
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Debug,
+)]
+pub(crate) enum PublicationState {
+    // Stored but awaiting review
+    Draft,
+    Published,
+    // Published, then changed
+    Corrected,
+    // Withdrawn after publication; kept for the record
+    Retracted,
+}
+
+impl PublicationState {
+    pub(crate) fn is_public(self) -> bool {
+        matches!(
+            self,
+            PublicationState::Published | PublicationState::Corrected
+        )
+    }
+}
+
+// Public notice of a retracted reading
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Retraction {
+    reading_id: u64,
+    location: String,
+    // When the retracted reading was observed
+    observed_at: u64,
+    reason: String,
+    retracted_by: Principal,
+    retracted_at: u64,
+    // Of the retracted reading; unset on notices stored before they were recorded
+    organization_id: Option<u64>,
+    station_id: Option<String>,
+}
+
+impl_bounded_storable!(Retraction, 2048);
+
+impl Retraction {
+    // Notices show the reading's location, so they are shown only to those who may see the
+    // reading: outside its organization's members and during its station's embargo, they are
+    // hidden like the reading is
+    fn is_visible_to_caller(&self) -> bool {
+        let (organization_id, station_id) =
+            if self.organization_id.is_none() && self.station_id.is_none() {
+                crate::AIR_QUALITY_STORAGE
+                    .with(|service| service.borrow().get(&self.reading_id))
+                    .map_or((None, None), |data| (data.organization_id, data.station_id))
+            } else {
+                (self.organization_id, self.station_id.clone())
+            };
+        organizations::is_visible_to_caller(organization_id)
+            && station_id
+                .as_deref()
+                .is_none_or(|station_id| !stations::is_embargoed(station_id, self.observed_at))
+    }
+}
+
+thread_local! {
+    // Keyed by (retraction time, reading id)
+    static RETRACTION_STORAGE: RefCell<StableBTreeMap<(u64, u64), Retraction, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(RETRACTION_STORAGE_MEMORY_ID)));
+}
+
+// Drops every retraction notice, for a storage reset
+pub(crate) fn reset() {
+    RETRACTION_STORAGE.with(|s| clear_stable_map(s, RETRACTION_STORAGE_MEMORY_ID));
+}
+
+// Readings stored before publication states existed are published
+pub(crate) fn state_of(data: &AirQualityData) -> PublicationState {
+    data.publication_state
+        .unwrap_or(PublicationState::Published)
+}
+
+// Controllers and the owner of the reading's station may see and move unpublished readings
+fn is_reviewer(data: &AirQualityData) -> bool {
+    let caller = ic_cdk::caller();
+    ic_cdk::api::is_controller(&caller)
+        || data
+            .station_id
+            .as_deref()
+            .and_then(stations::get_station)
+            .is_some_and(|station| station.is_managed_by(&caller))
+}
+
+pub(crate) fn is_visible_to_caller(data: &AirQualityData) -> bool {
+    state_of(data).is_public() || is_reviewer(data)
+}
+
+// Called before a reading's fields are changed: published readings become corrected, and
+// retracted ones stay as they were withdrawn
+pub(crate) fn on_edit(data: &mut AirQualityData) -> Result<(), Error> {
+    match state_of(data) {
+        PublicationState::Retracted => Err(Error::Conflict {
+            msg: format!(
+                "air quality data with id={} is retracted and cannot be changed",
+                data.id
+            ),
+        }),
+        PublicationState::Published => {
+            data.publication_state = Some(PublicationState::Corrected);
+            Ok(())
+        }
+        PublicationState::Draft | PublicationState::Corrected => Ok(()),
+    }
+}
+
+fn transition(
+    id: u64,
+    expected_version: u64,
+    from: &[PublicationState],
+    to: PublicationState,
+) -> Result<AirQualityData, Error> {
+    let mut data = _get_air_quality_data(&id).ok_or(Error::NotFound {
+        msg: format!("air quality data with id={} not found", id),
+    })?;
+    if !is_reviewer(&data) {
+        return Err(Error::Unauthorized {
+            msg: "only controllers and the station owner may change the publication state"
+                .to_string(),
+        });
+    }
+    data.check_version(expected_version)?;
+    let state = state_of(&data);
+    if !from.contains(&state) {
+        return Err(Error::Conflict {
+            msg: format!(
+                "air quality data with id={} is {:?} and cannot become {:?}",
+                id, state, to
+            ),
+        });
+    }
+    data.publication_state = Some(to);
+    data.version += 1;
+    do_insert_air_quality(&data);
+    log!(
+        Info,
+        "publication state changed",
+        "id" => id,
+        "from" => format!("{:?}", state),
+        "to" => format!("{:?}", to),
+        "caller" => ic_cdk::caller(),
+    );
+    Ok(data)
+}
+
+// Makes a draft reading public after review; it then counts in rollups and may trigger alerts
+#[ic_cdk::update(guard = "accepts_writes")]
+fn publish_reading(id: u64, expected_version: u64) -> Result<AirQualityData, Error> {
+    let data = transition(
+        id,
+        expected_version,
+        &[PublicationState::Draft],
+        PublicationState::Published,
+    )?;
+    alerts::on_insert(&data);
+    consumers::on_insert(&data);
+    Ok(data)
+}
+
+// Withdraws a published or corrected reading from the public record. The record is kept, and
+// a public notice of the retraction and its reason is listed by get_retractions.
+#[ic_cdk::update(guard = "accepts_writes")]
+fn retract_reading(
+    id: u64,
+    reason: String,
+    expected_version: u64,
+) -> Result<AirQualityData, Error> {
+    if reason.trim().is_empty() || reason.len() > MAX_REASON_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!("reason must be 1 to {} bytes", MAX_REASON_LEN),
+            violations: None,
+        });
+    }
+    let data = transition(
+        id,
+        expected_version,
+        &[PublicationState::Published, PublicationState::Corrected],
+        PublicationState::Retracted,
+    )?;
+    let retraction = Retraction {
+        reading_id: id,
+        location: data.location.clone(),
+        observed_at: data.timestamp,
+        reason,
+        retracted_by: ic_cdk::caller(),
+        retracted_at: time(),
+        organization_id: data.organization_id,
+        station_id: data.station_id.clone(),
+    };
+    RETRACTION_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert((retraction.retracted_at, id), retraction)
+    });
+    events::emit(EventKind::ReadingRetracted { reading_id: id });
+    Ok(data)
+}
+
+continued_page!(RetractionPage, Retraction);
+
+// Retractions made between the two timestamps, both included, oldest first
+#[ic_cdk::query]
+fn get_retractions(
+    start_timestamp: u64,
+    end_timestamp: u64,
+    continuation: Option<String>,
+) -> Result<RetractionPage, Error> {
+    if end_timestamp < start_timestamp {
+        return Err(Error::InvalidInput {
+            msg: "end_timestamp must not be before start_timestamp".to_string(),
+            violations: None,
+        });
+    }
+    RETRACTION_STORAGE.with(|s| {
+        RetractionPage::new(
+            s.borrow()
+                .range((
+                    Bound::Included((start_timestamp, 0)),
+                    Bound::Included((end_timestamp, u64::MAX)),
+                ))
+                .map(|(_, retraction)| retraction)
+                .filter(Retraction::is_visible_to_caller),
+            continuation,
+        )
+    })
+}
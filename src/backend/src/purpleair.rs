@@ -0,0 +1,224 @@
+use crate::sources::{self, ReadingSource};
+use crate::standards::{us_epa_particulate_index, AqiCategory};
+use crate::{
+    _add_air_quality_data, accepts_writes, ensure_admin, AirQualityUpdatePayload, Error, Pollutant,
+};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, TransformContext,
+};
+use std::collections::HashMap;
+
+const API_URL: &str = "https://api.purpleair.com/v1/sensors";
+const FIELDS: &str = "name,humidity,pm2.5_cf_1_a,pm2.5_cf_1_b,pm10.0_atm,last_seen";
+const MAX_SENSORS: usize = 100;
+const MAX_API_KEY_LEN: usize = 64;
+const MAX_RESPONSE_BYTES: u64 = 500_000;
+// Generous upper bound for a 500kB outcall on a 13-node subnet; unused cycles are refunded
+const OUTCALL_CYCLES: u128 = 15_000_000_000;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+// The channels of a sensor disagree when they differ by more than both of these
+const MAX_CHANNEL_DIFFERENCE: f64 = 5.0;
+const MAX_CHANNEL_RELATIVE_DIFFERENCE: f64 = 0.7;
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct PurpleAirIngestReport {
+    // Sensors in PurpleAir's response
+    sensors: u32,
+    stored_ids: Vec<u64>,
+    // Sensors without both channels or humidity, or whose channels disagree
+    failed_checks: u32,
+    // Readings refused, mostly duplicates of ones ingested before
+    skipped: u32,
+}
+
+// PurpleAir's /v1/sensors response: rows of values in the order of `fields`
+#[derive(Deserialize)]
+struct SensorsResponse {
+    fields: Vec<String>,
+    data: Vec<Vec<serde_json::Value>>,
+}
+
+// The US-wide correction of PurpleAir PM2.5 (CF=1, mean of both channels) published by the US
+// EPA in 2021, with its extension for smoke. Humidity is the sensor's relative humidity in %.
+fn epa_corrected_pm25(cf_1: f64, humidity: f64) -> f64 {
+    let corrected = if cf_1 < 30.0 {
+        0.524 * cf_1 - 0.0862 * humidity + 5.75
+    } else if cf_1 < 50.0 {
+        let weight = cf_1 / 20.0 - 1.5;
+        (0.786 * weight + 0.524 * (1.0 - weight)) * cf_1 - 0.0862 * humidity + 5.75
+    } else if cf_1 < 210.0 {
+        0.786 * cf_1 - 0.0862 * humidity + 5.75
+    } else if cf_1 < 260.0 {
+        let weight = cf_1 / 50.0 - 4.2;
+        (0.69 * weight + 0.786 * (1.0 - weight)) * cf_1 - 0.0862 * humidity * (1.0 - weight)
+            + 2.966 * weight
+            + 5.75 * (1.0 - weight)
+            + 8.84e-4 * cf_1 * cf_1 * weight
+    } else {
+        2.966 + 0.69 * cf_1 + 8.84e-4 * cf_1 * cf_1
+    };
+    corrected.max(0.0)
+}
+
+fn channels_agree(a: f64, b: f64) -> bool {
+    let difference = (a - b).abs();
+    difference <= MAX_CHANNEL_DIFFERENCE
+        || difference <= MAX_CHANNEL_RELATIVE_DIFFERENCE * (a + b) / 2.0
+}
+
+pub(crate) fn category_name(category: AqiCategory) -> &'static str {
+    match category {
+        AqiCategory::Good => "Good",
+        AqiCategory::Moderate => "Moderate",
+        AqiCategory::UnhealthyForSensitiveGroups => "Unhealthy for Sensitive Groups",
+        AqiCategory::Unhealthy => "Unhealthy",
+        AqiCategory::VeryUnhealthy => "Very Unhealthy",
+        AqiCategory::Hazardous => "Hazardous",
+    }
+}
+
+// A sensor's reading, or None if it fails the checks
+fn reading(
+    row: &[serde_json::Value],
+    column: &HashMap<&str, usize>,
+) -> Option<AirQualityUpdatePayload> {
+    let number = |field: &str| row.get(*column.get(field)?)?.as_f64();
+    let name = row.get(*column.get("name")?)?.as_str()?.trim();
+    let last_seen = row.get(*column.get("last_seen")?)?.as_u64()?;
+    let (a, b, humidity) = (
+        number("pm2.5_cf_1_a")?,
+        number("pm2.5_cf_1_b")?,
+        number("humidity")?,
+    );
+    if name.is_empty() || !channels_agree(a, b) {
+        return None;
+    }
+    let pm25 = epa_corrected_pm25((a + b) / 2.0, humidity);
+    let mut levels = HashMap::from([(Pollutant::PM25, pm25)]);
+    // There is no correction for PM10; it is stored as the sensor reports it
+    if let Some(pm10) = number("pm10.0_atm") {
+        levels.insert(Pollutant::PM10, pm10);
+    }
+    let air_quality_index = levels
+        .iter()
+        .filter_map(|(pollutant, level)| us_epa_particulate_index(pollutant, *level))
+        .max()?;
+    Some(AirQualityUpdatePayload {
+        location: name.to_string(),
+        air_quality_index,
+        health_recommendations: format!(
+            "US EPA category: {}",
+            category_name(AqiCategory::of(air_quality_index as f64))
+        ),
+        pollutant_levels: Some(levels),
+        observed_at: Some(last_seen * NANOS_PER_SECOND),
+        ..Default::default()
+    })
+}
+
+// Fetches the latest PM2.5 and PM10 of PurpleAir community sensors and stores them as readings
+// (controllers only). PM2.5 is corrected with the US EPA formula. The API key is sent with the
+// request and not kept.
+#[ic_cdk::update(guard = "accepts_writes")]
+async fn ingest_from_purpleair(
+    sensor_indices: Vec<u64>,
+    api_key: String,
+) -> Result<PurpleAirIngestReport, Error> {
+    ensure_admin()?;
+    if sensor_indices.is_empty() || sensor_indices.len() > MAX_SENSORS {
+        return Err(Error::InvalidInput {
+            msg: format!("sensor_indices must list 1 to {} sensors", MAX_SENSORS),
+            violations: None,
+        });
+    }
+    let key_valid = !api_key.is_empty()
+        && api_key.len() <= MAX_API_KEY_LEN
+        && api_key
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-');
+    if !key_valid {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "api_key must be 1 to {} letters, digits and hyphens",
+                MAX_API_KEY_LEN
+            ),
+            violations: None,
+        });
+    }
+    let show_only: Vec<String> = sensor_indices.iter().map(u64::to_string).collect();
+    let request = CanisterHttpRequestArgument {
+        url: format!(
+            "{}?fields={}&show_only={}",
+            API_URL,
+            FIELDS,
+            show_only.join(",")
+        ),
+        max_response_bytes: Some(MAX_RESPONSE_BYTES),
+        method: HttpMethod::GET,
+        headers: vec![HttpHeader {
+            name: "X-API-Key".to_string(),
+            value: api_key,
+        }],
+        body: None,
+        // Backfill's transform keeps only the status and body, which is all this needs too
+        transform: Some(TransformContext::from_name(
+            "transform_backfill_response".to_string(),
+            vec![],
+        )),
+    };
+    let (response,) = http_request(request, OUTCALL_CYCLES)
+        .await
+        .map_err(|(code, msg)| Error::InvalidInput {
+            msg: format!("the PurpleAir request failed: {:?} {}", code, msg),
+            violations: None,
+        })?;
+    if response.status != 200u16 {
+        return Err(Error::InvalidInput {
+            msg: format!("PurpleAir responded with status {}", response.status),
+            violations: None,
+        });
+    }
+    let sensors: SensorsResponse =
+        serde_json::from_slice(&response.body).map_err(|e| Error::InvalidInput {
+            msg: format!("cannot parse PurpleAir's response: {}", e),
+            violations: None,
+        })?;
+    let column: HashMap<&str, usize> = sensors
+        .fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| (field.as_str(), i))
+        .collect();
+    let mut report = PurpleAirIngestReport {
+        sensors: sensors.data.len() as u32,
+        stored_ids: Vec::new(),
+        failed_checks: 0,
+        skipped: 0,
+    };
+    for row in &sensors.data {
+        let Some(payload) = reading(row, &column) else {
+            report.failed_checks += 1;
+            continue;
+        };
+        // PurpleAir returns the sensor index with every row, asked for or not
+        let external_id = column
+            .get("sensor_index")
+            .and_then(|i| row.get(*i))
+            .and_then(serde_json::Value::as_u64)
+            .map_or_else(|| payload.location.clone(), |index| index.to_string());
+        let source = ReadingSource::import(sources::PURPLEAIR_CONNECTOR, external_id);
+        match _add_air_quality_data(payload, source) {
+            Ok(data) => report.stored_ids.push(data.id),
+            Err(_) => report.skipped += 1,
+        }
+    }
+    log!(
+        Info,
+        "PurpleAir readings ingested",
+        "sensors" => report.sensors,
+        "stored" => report.stored_ids.len(),
+        "failed_checks" => report.failed_checks,
+        "skipped" => report.skipped,
+    );
+    Ok(report)
+}
@@ -0,0 +1,181 @@
+use crate::organizations;
+use crate::{
+    accepts_writes, clear_stable_map, ensure_admin, get_memory, AirQualityData, Error, Memory,
+    ORGANIZATION_QUOTA_MEMORY_ID, ORGANIZATION_RECORD_COUNT_MEMORY_ID,
+    ORGANIZATION_WRITE_COUNT_MEMORY_ID,
+};
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Limits of one organization; None for no limit
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct OrganizationQuota {
+    // Live readings stored in this canister; archived and deleted ones do not count
+    max_records: Option<u64>,
+    // Readings added per UTC day, merged ones included
+    max_writes_per_day: Option<u64>,
+}
+
+impl_bounded_storable!(OrganizationQuota, 64);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct OrganizationUsage {
+    organization_id: u64,
+    name: String,
+    quota: OrganizationQuota,
+    records: u64,
+    writes_today: u64,
+}
+
+thread_local! {
+    static ORGANIZATION_QUOTAS: RefCell<StableBTreeMap<u64, OrganizationQuota, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ORGANIZATION_QUOTA_MEMORY_ID)));
+
+    // Organization id -> live readings
+    static RECORD_COUNTS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ORGANIZATION_RECORD_COUNT_MEMORY_ID)));
+
+    // (organization id, UTC day) -> readings added; only the latest day of each is kept
+    static WRITE_COUNTS: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ORGANIZATION_WRITE_COUNT_MEMORY_ID)));
+}
+
+fn today() -> u64 {
+    time() / NANOS_PER_DAY
+}
+
+fn quota_of(organization_id: u64) -> OrganizationQuota {
+    ORGANIZATION_QUOTAS
+        .with(|s| s.borrow().get(&organization_id))
+        .unwrap_or_default()
+}
+
+fn records_of(organization_id: u64) -> u64 {
+    RECORD_COUNTS
+        .with(|s| s.borrow().get(&organization_id))
+        .unwrap_or(0)
+}
+
+fn writes_today_of(organization_id: u64) -> u64 {
+    WRITE_COUNTS
+        .with(|s| s.borrow().get(&(organization_id, today())))
+        .unwrap_or(0)
+}
+
+fn add_records(organization_id: u64, delta: i64) {
+    RECORD_COUNTS.with(|s| {
+        let mut counts = s.borrow_mut();
+        let count = counts.get(&organization_id).unwrap_or(0);
+        counts.insert(organization_id, count.saturating_add_signed(delta));
+    });
+}
+
+fn counted_organization(data: &AirQualityData) -> Option<u64> {
+    data.organization_id.filter(|_| !data.is_deleted())
+}
+
+pub(crate) fn reset() {
+    RECORD_COUNTS.with(|s| clear_stable_map(s, ORGANIZATION_RECORD_COUNT_MEMORY_ID));
+}
+
+pub(crate) fn on_write(previous: Option<&AirQualityData>, current: &AirQualityData) {
+    let before = previous.and_then(counted_organization);
+    let after = counted_organization(current);
+    if before == after {
+        return;
+    }
+    if let Some(id) = before {
+        add_records(id, -1);
+    }
+    if let Some(id) = after {
+        add_records(id, 1);
+    }
+}
+
+// For records taken out of the storage without a write, e.g. by retention or archiving
+pub(crate) fn on_remove(data: &AirQualityData) {
+    if let Some(id) = counted_organization(data) {
+        add_records(id, -1);
+    }
+}
+
+// Refuses a new reading of an organization at either of its limits
+pub(crate) fn ensure_room(organization_id: Option<u64>) -> Result<(), Error> {
+    let Some(id) = organization_id else {
+        return Ok(());
+    };
+    let quota = quota_of(id);
+    if let Some(max_records) = quota.max_records.filter(|max| records_of(id) >= *max) {
+        return Err(Error::QuotaExceeded {
+            msg: format!(
+                "organization {} stores its limit of {} readings",
+                id, max_records
+            ),
+        });
+    }
+    if let Some(max_writes) = quota
+        .max_writes_per_day
+        .filter(|max| writes_today_of(id) >= *max)
+    {
+        return Err(Error::QuotaExceeded {
+            msg: format!(
+                "organization {} added its limit of {} readings today",
+                id, max_writes
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Counts a reading added for an organization, and forgets its counts of past days
+pub(crate) fn record_write(organization_id: Option<u64>) {
+    let Some(id) = organization_id else {
+        return;
+    };
+    let day = today();
+    WRITE_COUNTS.with(|s| {
+        let mut counts = s.borrow_mut();
+        let past: Vec<(u64, u64)> = counts
+            .range((id, 0)..(id, day))
+            .map(|(key, _)| key)
+            .collect();
+        for key in past {
+            counts.remove(&key);
+        }
+        let count = counts.get(&(id, day)).unwrap_or(0);
+        counts.insert((id, day), count + 1);
+    });
+}
+
+// Sets the limits of an organization (controllers only)
+#[ic_cdk::update(guard = "accepts_writes")]
+fn set_organization_quota(
+    organization_id: u64,
+    quota: OrganizationQuota,
+) -> Result<OrganizationQuota, Error> {
+    ensure_admin()?;
+    organizations::get_organization_record(organization_id).ok_or(Error::NotFound {
+        msg: format!("organization with id={} not found", organization_id),
+    })?;
+    ORGANIZATION_QUOTAS.with(|s| s.borrow_mut().insert(organization_id, quota.clone()));
+    Ok(quota)
+}
+
+// Limits and usage of every organization (controllers only)
+#[ic_cdk::query]
+fn get_quota_report() -> Result<Vec<OrganizationUsage>, Error> {
+    ensure_admin()?;
+    Ok(organizations::all()
+        .into_iter()
+        .map(|org| OrganizationUsage {
+            organization_id: org.id(),
+            name: org.name().to_string(),
+            quota: quota_of(org.id()),
+            records: records_of(org.id()),
+            writes_today: writes_today_of(org.id()),
+        })
+        .collect())
+}
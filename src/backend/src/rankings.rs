@@ -0,0 +1,117 @@
+use crate::aggregates::level_in_micrograms;
+use crate::{latest, rollups, Error, Pollutant};
+use ic_cdk::api::time;
+
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+const MAX_RANKED_LOCATIONS: u32 = 100;
+const MAX_WINDOW_HOURS: u32 = 30 * 24;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum RankingMetric {
+    AirQualityIndex,
+    // In µg/m³
+    Pollutant(Pollutant),
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub(crate) enum RankingWindow {
+    // Each location's latest published reading
+    Latest,
+    // Mean of the hourly means over the last hours
+    Hours(u32),
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct RankedLocation {
+    // 1 for the most polluted
+    rank: u32,
+    location: String,
+    value: f64,
+    // Observation time of the latest reading, or the end of the window
+    as_of: u64,
+}
+
+fn latest_value(location: &str, metric: &RankingMetric) -> Option<(f64, u64)> {
+    let data = latest::latest(location)?;
+    let value = match metric {
+        RankingMetric::AirQualityIndex => data.air_quality_index as f64,
+        RankingMetric::Pollutant(pollutant) => level_in_micrograms(&data, pollutant)?,
+    };
+    Some((value, data.timestamp))
+}
+
+fn windowed_value(location: &str, metric: &RankingMetric, start: u64, end: u64) -> Option<f64> {
+    let means: Vec<f64> = match metric {
+        RankingMetric::AirQualityIndex => rollups::hourly_air_quality_index(location, start, end)
+            .ok()?
+            .into_iter()
+            .map(|(_, index)| index)
+            .collect(),
+        RankingMetric::Pollutant(pollutant) => {
+            rollups::hourly_pollutant_averages(location, pollutant, start, end)
+                .ok()?
+                .into_values()
+                .collect()
+        }
+    };
+    (!means.is_empty()).then(|| means.iter().sum::<f64>() / means.len() as f64)
+}
+
+// The `n` most polluted locations by AQI or by a pollutant, highest first. The latest window
+// ranks the readings the caller may see; hour windows rank the hourly rollups, which include
+// every station.
+#[ic_cdk::query]
+fn get_top_polluted(
+    n: u32,
+    metric: RankingMetric,
+    window: RankingWindow,
+) -> Result<Vec<RankedLocation>, Error> {
+    if n == 0 || n > MAX_RANKED_LOCATIONS {
+        return Err(Error::InvalidInput {
+            msg: format!("n must be 1 to {}", MAX_RANKED_LOCATIONS),
+            violations: None,
+        });
+    }
+    let mut values: Vec<(String, f64, u64)> = match window {
+        RankingWindow::Latest => latest::indexed_locations(None)
+            .into_iter()
+            .filter_map(|location| {
+                let (value, as_of) = latest_value(&location, &metric)?;
+                Some((location, value, as_of))
+            })
+            .collect(),
+        RankingWindow::Hours(hours) => {
+            if hours == 0 || hours > MAX_WINDOW_HOURS {
+                return Err(Error::InvalidInput {
+                    msg: format!("the window must be 1 to {} hours", MAX_WINDOW_HOURS),
+                    violations: None,
+                });
+            }
+            let end = time();
+            let start = end.saturating_sub(hours as u64 * NANOS_PER_HOUR);
+            let mut values = Vec::new();
+            let mut cursor = None;
+            while let Some(location) = rollups::next_location(cursor.as_deref()) {
+                if let Some(value) = windowed_value(&location, &metric, start, end) {
+                    values.push((location.clone(), value, end));
+                }
+                cursor = Some(location);
+            }
+            values
+        }
+    };
+    values.sort_by(|(location_a, a, _), (location_b, b, _)| {
+        b.total_cmp(a).then_with(|| location_a.cmp(location_b))
+    });
+    Ok(values
+        .into_iter()
+        .take(n as usize)
+        .zip(1..)
+        .map(|((location, value, as_of), rank)| RankedLocation {
+            rank,
+            location,
+            value,
+            as_of,
+        })
+        .collect())
+}
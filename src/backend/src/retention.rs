@@ -0,0 +1,530 @@
+use crate::publication::{self, PublicationState};
+use crate::time_index::{self, TimeKey};
+use crate::{
+    accepts_writes, check_record_size, compaction, do_insert_air_quality, ensure_admin, get_memory,
+    migrations, remove_air_quality, AirQualityData, Error, Memory, Pollutant, Removal,
+    AIR_QUALITY_STORAGE, RETENTION_POLICY_MEMORY_ID, RETENTION_REPORT_MEMORY_ID,
+};
+use ic_cdk::api::time;
+use ic_stable_structures::{Cell, Storable};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const NANOS_PER_DAY: u64 = 86_400 * NANOS_PER_SECOND;
+// Upper bound of records removed per tick so a run never exceeds the instruction limit
+const MAX_REMOVALS_PER_TICK: usize = 500;
+// Bounds in seconds that keep bucket and interval lengths in nanoseconds within a u64
+const MAX_BUCKET_SECONDS: u64 = 366 * 86_400;
+const MAX_RUN_INTERVAL_SECONDS: u64 = 366 * 86_400;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum RetentionAction {
+    // Expired readings are removed
+    Purge,
+    // Expired readings are merged into one averaged reading per location and bucket
+    Downsample { bucket_seconds: u64 },
+    // Expired readings are folded into daily aggregates, kept apart from the readings, and
+    // removed. Their hours stay in the hourly rollups.
+    Compact,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RetentionPolicy {
+    enabled: bool,
+    max_age_days: u32,
+    action: RetentionAction,
+    run_interval_seconds: u64,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            enabled: false,
+            max_age_days: 365,
+            action: RetentionAction::Purge,
+            run_interval_seconds: 86_400,
+        }
+    }
+}
+
+impl_bounded_storable!(RetentionPolicy, 256);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct RetentionReport {
+    // Start of the last run; a run may span several ticks
+    last_run_started_at: Option<u64>,
+    last_run_completed_at: Option<u64>,
+    in_progress: bool,
+    last_run_records_removed: u64,
+    last_run_records_downsampled: u64,
+    last_run_bytes_reclaimed: u64,
+    total_records_removed: u64,
+    total_bytes_reclaimed: u64,
+}
+
+impl_bounded_storable!(RetentionReport, 256);
+
+thread_local! {
+    static RETENTION_POLICY: RefCell<Cell<RetentionPolicy, Memory>> = RefCell::new(
+        Cell::init(get_memory(RETENTION_POLICY_MEMORY_ID), RetentionPolicy::default())
+            .expect("Cannot create the retention policy cell")
+    );
+
+    static RETENTION_REPORT: RefCell<Cell<RetentionReport, Memory>> = RefCell::new(
+        Cell::init(get_memory(RETENTION_REPORT_MEMORY_ID), RetentionReport::default())
+            .expect("Cannot create the retention report cell")
+    );
+
+    // Time index key of the last reading the current run has processed. Kept on the heap: a run
+    // cut short by an upgrade only goes over its readings again.
+    static RUN_CURSOR: RefCell<Option<TimeKey>> = const { RefCell::new(None) };
+
+    // Buckets of the current downsampling run that may go on past the last batch
+    static RUN_BUCKETS: RefCell<BTreeMap<BucketKey, Bucket>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+// A run under way goes on from the oldest reading
+pub(crate) fn reset() {
+    RUN_CURSOR.with(|c| *c.borrow_mut() = None);
+    RUN_BUCKETS.with(|b| b.borrow_mut().clear());
+}
+
+fn policy() -> RetentionPolicy {
+    RETENTION_POLICY.with(|p| p.borrow().get().clone())
+}
+
+fn report() -> RetentionReport {
+    RETENTION_REPORT.with(|r| r.borrow().get().clone())
+}
+
+fn set_report(report: RetentionReport) {
+    RETENTION_REPORT
+        .with(|r| r.borrow_mut().set(report))
+        .expect("cannot store the retention report");
+}
+
+#[ic_cdk::update(guard = "accepts_writes")]
+fn set_retention_policy(policy: RetentionPolicy) -> Result<RetentionPolicy, Error> {
+    ensure_admin()?;
+    if policy.max_age_days == 0 {
+        return Err(Error::InvalidInput {
+            msg: "max_age_days must be at least 1".to_string(),
+            violations: None,
+        });
+    }
+    if let RetentionAction::Downsample { bucket_seconds } = policy.action {
+        if bucket_seconds == 0 || bucket_seconds > MAX_BUCKET_SECONDS {
+            return Err(Error::InvalidInput {
+                msg: format!("bucket_seconds must be 1 to {}", MAX_BUCKET_SECONDS),
+                violations: None,
+            });
+        }
+    }
+    if policy.run_interval_seconds > MAX_RUN_INTERVAL_SECONDS {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "run_interval_seconds must be at most {}",
+                MAX_RUN_INTERVAL_SECONDS
+            ),
+            violations: None,
+        });
+    }
+    RETENTION_POLICY
+        .with(|p| p.borrow_mut().set(policy.clone()))
+        .expect("cannot store the retention policy");
+    Ok(policy)
+}
+
+#[ic_cdk::query]
+fn get_retention_policy() -> RetentionPolicy {
+    policy()
+}
+
+#[ic_cdk::query]
+fn get_retention_report() -> RetentionReport {
+    report()
+}
+
+// Starts a retention run immediately instead of waiting for the next interval
+#[ic_cdk::update(guard = "accepts_writes")]
+fn run_retention_now() -> Result<RetentionReport, Error> {
+    ensure_admin()?;
+    let policy = policy();
+    if !policy.enabled {
+        return Err(Error::InvalidInput {
+            msg: "the retention policy is disabled".to_string(),
+            violations: None,
+        });
+    }
+    start_run();
+    run_batch(&policy);
+    Ok(report())
+}
+
+pub(crate) fn on_timer() {
+    let policy = policy();
+    if !policy.enabled {
+        return;
+    }
+    let report = report();
+    let due = match report.last_run_started_at {
+        Some(started_at) => {
+            time()
+                >= started_at
+                    .saturating_add(policy.run_interval_seconds.saturating_mul(NANOS_PER_SECOND))
+        }
+        None => true,
+    };
+    if report.in_progress {
+        run_batch(&policy);
+    } else if due {
+        start_run();
+        run_batch(&policy);
+    }
+}
+
+fn start_run() {
+    let mut report = report();
+    report.last_run_started_at = Some(time());
+    report.in_progress = true;
+    report.last_run_records_removed = 0;
+    report.last_run_records_downsampled = 0;
+    report.last_run_bytes_reclaimed = 0;
+    set_report(report);
+    RUN_CURSOR.with(|c| *c.borrow_mut() = None);
+    RUN_BUCKETS.with(|b| b.borrow_mut().clear());
+}
+
+fn record_size(data: &AirQualityData) -> u64 {
+    data.to_bytes().len() as u64
+}
+
+// Processes at most MAX_REMOVALS_PER_TICK records of the current run
+fn run_batch(policy: &RetentionPolicy) {
+    // Readings are found through the time index, which is incomplete until it is built
+    if migrations::is_pending() {
+        return;
+    }
+    let cutoff = time().saturating_sub(policy.max_age_days as u64 * NANOS_PER_DAY);
+    let (removed, downsampled, bytes, finished) = match policy.action {
+        RetentionAction::Purge => purge_batch(cutoff, false),
+        RetentionAction::Compact => purge_batch(cutoff, true),
+        RetentionAction::Downsample { bucket_seconds } => {
+            match bucket_seconds.checked_mul(NANOS_PER_SECOND) {
+                Some(bucket) => downsample_batch(cutoff, bucket),
+                // Only a policy stored before buckets were bounded can get here
+                None => {
+                    log!(Error, "retention bucket too large", "bucket_seconds" => bucket_seconds);
+                    (0, 0, 0, true)
+                }
+            }
+        }
+    };
+
+    if finished {
+        RUN_CURSOR.with(|c| *c.borrow_mut() = None);
+    }
+    let mut report = report();
+    report.last_run_records_removed += removed;
+    report.last_run_records_downsampled += downsampled;
+    report.last_run_bytes_reclaimed += bytes;
+    report.total_records_removed += removed;
+    report.total_bytes_reclaimed += bytes;
+    if finished {
+        report.in_progress = false;
+        report.last_run_completed_at = Some(time());
+        log!(
+            Info,
+            "retention run completed",
+            "removed" => report.last_run_records_removed,
+            "downsampled" => report.last_run_records_downsampled,
+            "bytes" => report.last_run_bytes_reclaimed,
+        );
+    }
+    set_report(report);
+}
+
+fn cursor() -> Option<TimeKey> {
+    RUN_CURSOR.with(|c| *c.borrow())
+}
+
+fn set_cursor(key: TimeKey) {
+    RUN_CURSOR.with(|c| *c.borrow_mut() = Some(key));
+}
+
+// Compacting folds each reading into its daily aggregate before removing it
+fn purge_batch(cutoff: u64, compact: bool) -> (u64, u64, u64, bool) {
+    let mut expired = time_index::readings_before(cutoff, cursor(), MAX_REMOVALS_PER_TICK + 1);
+    let finished = expired.len() <= MAX_REMOVALS_PER_TICK;
+    expired.truncate(MAX_REMOVALS_PER_TICK);
+
+    let mut bytes = 0;
+    for data in &expired {
+        if compact {
+            compaction::compact(data);
+        }
+        remove_air_quality(data, Removal::Expired);
+        bytes += record_size(data);
+    }
+    if let Some(last) = expired.last() {
+        set_cursor(time_index::key_of(last));
+    }
+    (expired.len() as u64, 0, bytes, finished)
+}
+
+// Readings merged into one: same location, organization, station and publication state, and
+// observed in the same bucket
+type BucketKey = (String, Option<u64>, Option<String>, PublicationState, u64);
+
+// The reading a bucket is merged into, as last written, with the weights of its means. A
+// reading counts as many times as the readings merged into it.
+#[derive(Clone)]
+struct Bucket {
+    merged: AirQualityData,
+    readings: f64,
+    pollutant_weights: BTreeMap<Pollutant, f64>,
+    pressure_weight: f64,
+    // Whether any reading has been merged into `merged` yet
+    changed: bool,
+}
+
+impl Bucket {
+    fn new(data: AirQualityData) -> Bucket {
+        let weight = data.merged_readings.unwrap_or(1) as f64;
+        Bucket {
+            pollutant_weights: data
+                .pollutant_levels
+                .keys()
+                .map(|pollutant| (pollutant.clone(), weight))
+                .collect(),
+            pressure_weight: if data.weather_conditions.pressure.is_some() {
+                weight
+            } else {
+                0.0
+            },
+            readings: weight,
+            merged: data,
+            changed: false,
+        }
+    }
+}
+
+fn weighted_mean(mean: f64, weight: f64, value: f64, value_weight: f64) -> f64 {
+    (mean * weight + value * value_weight) / (weight + value_weight)
+}
+
+// The bucket with `data` merged in, or None if it cannot be: readings stored in different units
+// are not averaged, and merging the pollutant maps must not outgrow the record bound
+fn fold(bucket: &Bucket, data: &AirQualityData) -> Option<Bucket> {
+    let mut next = bucket.clone();
+    let merged = &mut next.merged;
+    let stored_units = merged.pollutant_units.get_or_insert_with(Default::default);
+    for (pollutant, unit) in data.pollutant_units.iter().flatten() {
+        if *stored_units.entry(pollutant.clone()).or_insert(*unit) != *unit {
+            return None;
+        }
+    }
+    if stored_units.is_empty() {
+        merged.pollutant_units = None;
+    }
+    let weight = data.merged_readings.unwrap_or(1) as f64;
+    let readings = bucket.readings;
+    merged.air_quality_index = weighted_mean(
+        merged.air_quality_index as f64,
+        readings,
+        data.air_quality_index as f64,
+        weight,
+    )
+    .round() as u32;
+    let (weather, other) = (&mut merged.weather_conditions, &data.weather_conditions);
+    weather.temperature = weighted_mean(weather.temperature, readings, other.temperature, weight);
+    weather.humidity = weighted_mean(weather.humidity, readings, other.humidity, weight);
+    weather.wind_speed = weighted_mean(weather.wind_speed, readings, other.wind_speed, weight);
+    if let Some(pressure) = other.pressure {
+        weather.pressure = Some(match weather.pressure {
+            Some(mean) => weighted_mean(mean, next.pressure_weight, pressure, weight),
+            None => pressure,
+        });
+        next.pressure_weight += weight;
+    }
+    for (pollutant, level) in &data.pollutant_levels {
+        let pollutant_weight = next
+            .pollutant_weights
+            .entry(pollutant.clone())
+            .or_insert(0.0);
+        merged
+            .pollutant_levels
+            .entry(pollutant.clone())
+            .and_modify(|mean| *mean = weighted_mean(*mean, *pollutant_weight, *level, weight))
+            .or_insert(*level);
+        *pollutant_weight += weight;
+    }
+    // Averages are no longer readings of a sensor as reported
+    merged.raw_pollutant_levels = None;
+    next.readings += weight;
+    merged.merged_readings = Some(next.readings as u32);
+    next.changed = true;
+    check_record_size(&next.merged).ok()?;
+    Some(next)
+}
+
+// Merges the expired readings of each bucket into the first one found, a batch at a time. A
+// bucket that goes on past the batch is carried over to the next one, so buckets of any size
+// are merged without loading them whole.
+fn downsample_batch(cutoff: u64, bucket: u64) -> (u64, u64, u64, bool) {
+    let bucket_start = |data: &AirQualityData| data.timestamp - data.timestamp % bucket;
+    let mut batch = time_index::readings_before(cutoff, cursor(), MAX_REMOVALS_PER_TICK + 1);
+    let finished = batch.len() <= MAX_REMOVALS_PER_TICK;
+    batch.truncate(MAX_REMOVALS_PER_TICK);
+    let Some(last) = batch.last() else {
+        RUN_BUCKETS.with(|b| b.borrow_mut().clear());
+        return (0, 0, 0, finished);
+    };
+    set_cursor(time_index::key_of(last));
+    let last_window = bucket_start(last);
+
+    let mut removed = 0;
+    let mut downsampled = 0;
+    let mut bytes = 0;
+    let mut buckets = RUN_BUCKETS.with(|b| std::mem::take(&mut *b.borrow_mut()));
+    // A reading changed since the last batch is not merged into any more; the rest of its
+    // bucket starts over from the next reading
+    buckets.retain(|_, carried| {
+        AIR_QUALITY_STORAGE
+            .with(|service| service.borrow().get(&carried.merged.id))
+            .is_some_and(|stored| !stored.is_deleted() && stored.version == carried.merged.version)
+    });
+    let mut touched = BTreeSet::new();
+    for data in batch {
+        if data.is_deleted() {
+            remove_air_quality(&data, Removal::Purged);
+            bytes += record_size(&data);
+            removed += 1;
+            continue;
+        }
+        let key = (
+            data.location.clone(),
+            data.organization_id,
+            data.station_id.clone(),
+            publication::state_of(&data),
+            bucket_start(&data),
+        );
+        let Some(current) = buckets.get(&key) else {
+            let mut first = data;
+            first.timestamp = key.4;
+            buckets.insert(key, Bucket::new(first));
+            continue;
+        };
+        // Readings that cannot be merged are kept as they are
+        let Some(next) = fold(current, &data) else {
+            continue;
+        };
+        if !current.changed {
+            downsampled += 1;
+        }
+        downsampled += 1;
+        // The merged reading stands in for the others, in the rollups too
+        remove_air_quality(&data, Removal::Purged);
+        bytes += record_size(&data);
+        removed += 1;
+        buckets.insert(key.clone(), next);
+        touched.insert(key);
+    }
+    for key in touched {
+        let merged = &mut buckets
+            .get_mut(&key)
+            .expect("touched buckets are kept")
+            .merged;
+        merged.version += 1;
+        do_insert_air_quality(merged);
+    }
+    // Readings come in observation order, so only the buckets of the last window go on
+    if !finished {
+        buckets.retain(|key, _| key.4 >= last_window);
+        RUN_BUCKETS.with(|b| *b.borrow_mut() = buckets);
+    }
+
+    (removed, downsampled, bytes, finished)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::units::ConcentrationUnit;
+    use std::collections::HashMap;
+
+    fn reading(id: u64, pm25: f64, air_quality_index: u32) -> AirQualityData {
+        AirQualityData {
+            id,
+            location: "Lyon".to_string(),
+            air_quality_index,
+            pollutant_levels: HashMap::from([(Pollutant::PM25, pm25)]),
+            ..AirQualityData::default()
+        }
+    }
+
+    fn fold_all(readings: Vec<AirQualityData>) -> Option<Bucket> {
+        let mut readings = readings.into_iter();
+        let mut bucket = Bucket::new(readings.next()?);
+        for data in readings {
+            bucket = fold(&bucket, &data)?;
+        }
+        Some(bucket)
+    }
+
+    #[test]
+    fn a_bucket_folded_a_reading_at_a_time_is_the_mean() {
+        let bucket = fold_all(vec![
+            reading(1, 10.0, 40),
+            reading(2, 20.0, 50),
+            reading(3, 60.0, 90),
+        ])
+        .expect("the readings can be merged");
+        assert_eq!(bucket.merged.id, 1);
+        assert_eq!(bucket.merged.pollutant_levels[&Pollutant::PM25], 30.0);
+        assert_eq!(bucket.merged.air_quality_index, 60);
+        assert_eq!(bucket.merged.merged_readings, Some(3));
+    }
+
+    #[test]
+    fn a_merged_reading_counts_as_the_readings_it_holds() {
+        // A run cut short by an upgrade meets its own merged readings again
+        let merged = AirQualityData {
+            merged_readings: Some(3),
+            ..reading(1, 10.0, 40)
+        };
+        let bucket = fold_all(vec![merged, reading(2, 50.0, 80)]).expect("merged");
+        assert_eq!(bucket.merged.pollutant_levels[&Pollutant::PM25], 20.0);
+        assert_eq!(bucket.merged.air_quality_index, 50);
+        assert_eq!(bucket.merged.merged_readings, Some(4));
+    }
+
+    #[test]
+    fn pollutants_missing_from_some_readings_average_over_those_that_have_them() {
+        let mut with_no2 = reading(2, 20.0, 50);
+        with_no2.pollutant_levels.insert(Pollutant::NO2, 8.0);
+        let bucket = fold_all(vec![reading(1, 10.0, 40), with_no2]).expect("merged");
+        assert_eq!(bucket.merged.pollutant_levels[&Pollutant::NO2], 8.0);
+        assert_eq!(bucket.merged.pollutant_levels[&Pollutant::PM25], 15.0);
+    }
+
+    #[test]
+    fn readings_in_other_units_are_not_merged() {
+        let in_unit = |id, unit| AirQualityData {
+            pollutant_units: Some(HashMap::from([(Pollutant::PM25, unit)])),
+            ..reading(id, 10.0, 40)
+        };
+        let bucket = Bucket::new(in_unit(1, ConcentrationUnit::MicrogramsPerCubicMeter));
+        assert!(fold(
+            &bucket,
+            &in_unit(2, ConcentrationUnit::MilligramsPerCubicMeter)
+        )
+        .is_none());
+        assert!(fold(
+            &bucket,
+            &in_unit(2, ConcentrationUnit::MicrogramsPerCubicMeter)
+        )
+        .is_some());
+    }
+}
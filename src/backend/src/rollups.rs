@@ -0,0 +1,550 @@
+use crate::aggregates::level_in_micrograms;
+use crate::aliases;
+use crate::paging::{self, ReplyBudget};
+use crate::{
+    clear_stable_map, get_memory, organizations, AirQualityData, Error, Memory, Pollutant,
+    StringKey, ORGANIZATION_ROLLUP_BACKFILL_MEMORY_ID, ORGANIZATION_ROLLUP_MEMORY_ID,
+    ROLLUP_BACKFILL_MEMORY_ID, ROLLUP_STORAGE_MEMORY_ID,
+};
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+// Longer locations do not fit a rollup key and are left out of rollups
+const MAX_LOCATION_LEN: usize = 200;
+// A rollup keeps at most this many pollutants, with names of up to 64 bytes, so it stays within
+// its bound; others are left out
+const MAX_POLLUTANTS: usize = 32;
+const MAX_POLLUTANT_NAME_LEN: usize = 64;
+const MAX_ROLLUP_HOURS: u64 = 24 * 366;
+// Hours a rolling average needs data for, out of its window (75% completeness, as regulators
+// require)
+const PM_WINDOW_HOURS: u64 = 24;
+const PM_MIN_HOURS: usize = 18;
+const OZONE_WINDOW_HOURS: u64 = 8;
+const OZONE_MIN_HOURS: usize = 6;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+pub(crate) struct PollutantSum {
+    // µg/m³
+    sum: f64,
+    count: u64,
+}
+
+// Sums over the live readings of one location and hour, kept up to date on every write
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct HourlyRollup {
+    readings: u64,
+    air_quality_index_sum: f64,
+    // Keyed by `Pollutant::name`
+    pollutants: BTreeMap<String, PollutantSum>,
+}
+
+impl_bounded_storable!(HourlyRollup, 4096);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct HourlyRollupView {
+    hour_start: u64,
+    readings: u64,
+    average_air_quality_index: f64,
+    // Mean µg/m³ per pollutant
+    pollutant_averages: Vec<(Pollutant, f64)>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct HourlyRollupPage {
+    rollups: Vec<HourlyRollupView>,
+    // More hours are in the period; pass `next_cursor` to fetch them
+    truncated: bool,
+    next_cursor: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct PollutantRatio {
+    hour_start: u64,
+    name: String,
+    ratio: f64,
+}
+
+// Rolling averages of the window ending with an hour, in µg/m³. None when too few hours of the
+// window have data.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct RollingAverages {
+    hour_start: u64,
+    pm25_24h: Option<f64>,
+    pm10_24h: Option<f64>,
+    o3_8h: Option<f64>,
+}
+
+// (location, hour start)
+type RollupKey = (StringKey, u64);
+// ((location, hour start), organization id)
+type OrganizationRollupKey = (RollupKey, u64);
+
+thread_local! {
+    // Readings without an organization
+    static ROLLUP_STORAGE: RefCell<StableBTreeMap<RollupKey, HourlyRollup, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ROLLUP_STORAGE_MEMORY_ID)));
+
+    // Readings of organizations, one rollup per organization, so queries only count the
+    // organizations the caller may see
+    static ORGANIZATION_ROLLUP_STORAGE: RefCell<StableBTreeMap<OrganizationRollupKey, HourlyRollup, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ORGANIZATION_ROLLUP_MEMORY_ID)));
+
+    // Readings of organizations with lower ids are in the organization rollups. Those stored
+    // before the organization rollups existed were rolled up with the shared ones, and stay
+    // there until a migration moves them.
+    static ORGANIZATION_ROLLUP_NEXT_ID: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(get_memory(ORGANIZATION_ROLLUP_BACKFILL_MEMORY_ID), 0)
+            .expect("Cannot create the organization rollup cursor cell")
+    );
+
+    // Records with lower ids are in the rollups. Records stored before rollups existed are
+    // added by a migration; until it reaches them, writes to them leave the rollups alone.
+    static ROLLUP_BACKFILL_NEXT_ID: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(get_memory(ROLLUP_BACKFILL_MEMORY_ID), 0)
+            .expect("Cannot create the rollup backfill cell")
+    );
+}
+
+pub(crate) fn reset() {
+    ROLLUP_STORAGE.with(|s| clear_stable_map(s, ROLLUP_STORAGE_MEMORY_ID));
+    ORGANIZATION_ROLLUP_STORAGE.with(|s| clear_stable_map(s, ORGANIZATION_ROLLUP_MEMORY_ID));
+    mark_backfilled();
+}
+
+// Ratios used to tell sources apart: a high PM2.5/PM10 points to combustion, a low one to dust;
+// NO2/NOx falls with distance to traffic. NOx is NO + NO2.
+const RATIOS: &[(&str, &[&str], &[&str])] = &[
+    ("pm2.5/pm10", &["pm2.5"], &["pm10"]),
+    ("no2/nox", &["no2"], &["no", "no2"]),
+];
+
+fn rollup_key(data: &AirQualityData) -> Option<RollupKey> {
+    (data.location.len() <= MAX_LOCATION_LEN).then(|| {
+        (
+            StringKey(data.location.clone()),
+            data.timestamp - data.timestamp % NANOS_PER_HOUR,
+        )
+    })
+}
+
+impl HourlyRollup {
+    // Adds (sign 1.0) or removes (sign -1.0) a reading
+    fn apply(&mut self, data: &AirQualityData, sign: f64) {
+        let step = |n: u64| {
+            if sign > 0.0 {
+                n + 1
+            } else {
+                n.saturating_sub(1)
+            }
+        };
+        self.readings = step(self.readings);
+        self.air_quality_index_sum += sign * data.air_quality_index as f64;
+        for pollutant in data.pollutant_levels.keys() {
+            let Some(level) = level_in_micrograms(data, pollutant) else {
+                continue;
+            };
+            let name = pollutant.name();
+            let tracked = self.pollutants.contains_key(name);
+            // Removing a reading only touches the pollutants its addition counted
+            if name.len() > MAX_POLLUTANT_NAME_LEN
+                || (!tracked && (sign < 0.0 || self.pollutants.len() >= MAX_POLLUTANTS))
+            {
+                continue;
+            }
+            let entry = self.pollutants.entry(name.to_string()).or_default();
+            entry.sum += sign * level;
+            entry.count = step(entry.count);
+        }
+        self.pollutants.retain(|_, sum| sum.count > 0);
+    }
+
+    // Adds the sums of another rollup of the same hour
+    fn merge(&mut self, other: &HourlyRollup) {
+        self.readings += other.readings;
+        self.air_quality_index_sum += other.air_quality_index_sum;
+        for (name, sum) in &other.pollutants {
+            let entry = self.pollutants.entry(name.clone()).or_default();
+            entry.sum += sum.sum;
+            entry.count += sum.count;
+        }
+    }
+
+    fn average(&self, pollutant: &str) -> Option<f64> {
+        self.pollutants
+            .get(pollutant)
+            .map(|sum| sum.sum / sum.count as f64)
+    }
+
+    fn view(&self, hour_start: u64) -> HourlyRollupView {
+        HourlyRollupView {
+            hour_start,
+            readings: self.readings,
+            average_air_quality_index: self.air_quality_index_sum / self.readings.max(1) as f64,
+            pollutant_averages: self
+                .pollutants
+                .iter()
+                .map(|(name, sum)| (Pollutant::parse(name), sum.sum / sum.count as f64))
+                .collect(),
+        }
+    }
+}
+
+fn update<K: BoundedStorable + Ord + Clone>(
+    storage: &mut StableBTreeMap<K, HourlyRollup, Memory>,
+    key: K,
+    data: &AirQualityData,
+    sign: f64,
+) {
+    let mut rollup = storage.get(&key).unwrap_or_default();
+    rollup.apply(data, sign);
+    if rollup.readings == 0 {
+        storage.remove(&key);
+    } else {
+        storage.insert(key, rollup);
+    }
+}
+
+fn organization_next_id() -> u64 {
+    ORGANIZATION_ROLLUP_NEXT_ID.with(|c| *c.borrow().get())
+}
+
+fn set_organization_next_id(id: u64) {
+    ORGANIZATION_ROLLUP_NEXT_ID
+        .with(|c| c.borrow_mut().set(id))
+        .expect("cannot store the organization rollup cursor");
+}
+
+// The organization whose rollups hold the reading; None for the shared rollups
+fn rolled_up_organization(data: &AirQualityData) -> Option<u64> {
+    data.organization_id
+        .filter(|_| data.id < organization_next_id())
+}
+
+fn counts(data: &AirQualityData) -> bool {
+    !data.is_deleted() && data.is_published()
+}
+
+fn apply(data: &AirQualityData, sign: f64) {
+    if !counts(data) {
+        return;
+    }
+    apply_to(data, rolled_up_organization(data), sign);
+}
+
+fn apply_to(data: &AirQualityData, organization_id: Option<u64>, sign: f64) {
+    let Some(key) = rollup_key(data) else {
+        return;
+    };
+    match organization_id {
+        Some(organization_id) => ORGANIZATION_ROLLUP_STORAGE
+            .with(|s| update(&mut s.borrow_mut(), (key, organization_id), data, sign)),
+        None => ROLLUP_STORAGE.with(|s| update(&mut s.borrow_mut(), key, data, sign)),
+    }
+}
+
+fn backfill_next_id() -> u64 {
+    ROLLUP_BACKFILL_NEXT_ID.with(|c| *c.borrow().get())
+}
+
+fn set_backfill_next_id(id: u64) {
+    ROLLUP_BACKFILL_NEXT_ID
+        .with(|c| c.borrow_mut().set(id))
+        .expect("cannot store the rollup backfill cursor");
+}
+
+// Moves a record's contribution from its previous state to its new one. Tombstoned and
+// unpublished records do not count. Records removed from storage by retention or archiving stay in the rollups.
+pub(crate) fn on_write(previous: Option<&AirQualityData>, current: &AirQualityData) {
+    if current.id >= backfill_next_id() {
+        return;
+    }
+    if let Some(previous) = previous {
+        apply(previous, -1.0);
+    }
+    apply(current, 1.0);
+}
+
+// Takes a record removed from storage out of the rollups
+pub(crate) fn on_remove(data: &AirQualityData) {
+    if data.id < backfill_next_id() {
+        apply(data, -1.0);
+    }
+}
+
+// Adds a record stored before rollups existed; called in id order
+pub(crate) fn backfill(data: &AirQualityData) {
+    apply(data, 1.0);
+    set_backfill_next_id(data.id + 1);
+}
+
+// Moves a reading of an organization from the shared rollups, where it was added before the
+// organization rollups existed, to those of its organization; called in id order
+pub(crate) fn move_to_organization(data: &AirQualityData) {
+    let in_rollups = counts(data) && data.id < backfill_next_id();
+    if let Some(organization_id) = data.organization_id.filter(|_| in_rollups) {
+        apply_to(data, None, -1.0);
+        apply_to(data, Some(organization_id), 1.0);
+    }
+    set_organization_next_id(data.id + 1);
+}
+
+// Every reading of an organization is in the rollups of its organization
+pub(crate) fn mark_organizations_moved() {
+    set_organization_next_id(u64::MAX);
+}
+
+// Every stored record is in the rollups, each in those of its organization
+pub(crate) fn mark_backfilled() {
+    set_backfill_next_id(u64::MAX);
+    mark_organizations_moved();
+}
+
+fn rollups_between(
+    location: &str,
+    start_timestamp: u64,
+    end_timestamp: u64,
+) -> Result<Vec<(u64, HourlyRollup)>, Error> {
+    if location.len() > MAX_LOCATION_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!("locations over {} bytes have no rollups", MAX_LOCATION_LEN),
+            violations: None,
+        });
+    }
+    if end_timestamp < start_timestamp
+        || (end_timestamp - start_timestamp) / NANOS_PER_HOUR > MAX_ROLLUP_HOURS
+    {
+        return Err(Error::InvalidInput {
+            msg: format!("the period must be at most {} hours", MAX_ROLLUP_HOURS),
+            violations: None,
+        });
+    }
+    Ok(visible_rollups(
+        location,
+        start_timestamp - start_timestamp % NANOS_PER_HOUR,
+        end_timestamp,
+    )
+    .into_iter()
+    .collect())
+}
+
+// Rollups of the hours of a location starting in the period: the shared ones with those of the
+// organizations the caller may see added in
+fn visible_rollups(
+    location: &str,
+    first_hour: u64,
+    end_timestamp: u64,
+) -> BTreeMap<u64, HourlyRollup> {
+    let start = (StringKey(location.to_string()), first_hour);
+    let end = (StringKey(location.to_string()), end_timestamp);
+    let mut hourly: BTreeMap<u64, HourlyRollup> = ROLLUP_STORAGE.with(|s| {
+        s.borrow()
+            .range((Bound::Included(start.clone()), Bound::Included(end.clone())))
+            .map(|((_, hour_start), rollup)| (hour_start, rollup))
+            .collect()
+    });
+    ORGANIZATION_ROLLUP_STORAGE.with(|s| {
+        for (((_, hour_start), organization_id), rollup) in s.borrow().range((
+            Bound::Included((start, 0)),
+            Bound::Included((end, u64::MAX)),
+        )) {
+            if organizations::is_visible_to_caller(Some(organization_id)) {
+                hourly.entry(hour_start).or_default().merge(&rollup);
+            }
+        }
+    });
+    hourly
+}
+
+// Mean µg/m³ of a pollutant per hour that reports it, keyed by hour start
+pub(crate) fn hourly_pollutant_averages(
+    location: &str,
+    pollutant: &Pollutant,
+    start_timestamp: u64,
+    end_timestamp: u64,
+) -> Result<BTreeMap<u64, f64>, Error> {
+    Ok(rollups_between(location, start_timestamp, end_timestamp)?
+        .into_iter()
+        .filter_map(|(hour_start, rollup)| Some((hour_start, rollup.average(pollutant.name())?)))
+        .collect())
+}
+
+// Pollutants some reading of the location reported in the period
+pub(crate) fn pollutants_reported(
+    location: &str,
+    start_timestamp: u64,
+    end_timestamp: u64,
+) -> Result<BTreeSet<Pollutant>, Error> {
+    Ok(rollups_between(location, start_timestamp, end_timestamp)?
+        .into_iter()
+        .flat_map(|(_, rollup)| {
+            rollup
+                .pollutants
+                .into_iter()
+                .filter(|(_, sum)| sum.count > 0)
+                .map(|(name, _)| Pollutant::parse(&name))
+        })
+        .collect())
+}
+
+// The first location with rollups after `after`, in key order
+pub(crate) fn next_location(after: Option<&str>) -> Option<String> {
+    let last_key = |location: &str| (StringKey(location.to_string()), u64::MAX);
+    let start = after.map_or(Bound::Unbounded, |location| {
+        Bound::Excluded(last_key(location))
+    });
+    let shared = ROLLUP_STORAGE.with(|s| {
+        s.borrow()
+            .range((start, Bound::Unbounded))
+            .next()
+            .map(|((StringKey(location), _), _)| location)
+    });
+    let start = after.map_or(Bound::Unbounded, |location| {
+        Bound::Excluded((last_key(location), u64::MAX))
+    });
+    let of_organizations = ORGANIZATION_ROLLUP_STORAGE.with(|s| {
+        s.borrow()
+            .range((start, Bound::Unbounded))
+            .next()
+            .map(|(((StringKey(location), _), _), _)| location)
+    });
+    match (shared, of_organizations) {
+        (Some(shared), Some(of_organizations)) => Some(shared.min(of_organizations)),
+        (shared, of_organizations) => shared.or(of_organizations),
+    }
+}
+
+// Average AQI per hour with readings, oldest first
+pub(crate) fn hourly_air_quality_index(
+    location: &str,
+    start_timestamp: u64,
+    end_timestamp: u64,
+) -> Result<Vec<(u64, f64)>, Error> {
+    Ok(rollups_between(location, start_timestamp, end_timestamp)?
+        .into_iter()
+        .filter(|(_, rollup)| rollup.readings > 0)
+        .map(|(hour_start, rollup)| {
+            (
+                hour_start,
+                rollup.air_quality_index_sum / rollup.readings as f64,
+            )
+        })
+        .collect())
+}
+
+// Pages continue after the hour starting at `cursor`
+#[ic_cdk::query]
+fn get_hourly_rollups(
+    location: String,
+    start_timestamp: u64,
+    end_timestamp: u64,
+    cursor: Option<u64>,
+) -> Result<HourlyRollupPage, Error> {
+    let location = aliases::location(location);
+    let (rollups, truncated) = paging::take_within(
+        rollups_between(&location, start_timestamp, end_timestamp)?
+            .into_iter()
+            .filter(|(hour_start, _)| cursor.is_none_or(|cursor| *hour_start > cursor))
+            .map(|(hour_start, rollup)| rollup.view(hour_start)),
+        &mut ReplyBudget::new(),
+    );
+    Ok(HourlyRollupPage {
+        next_cursor: rollups
+            .last()
+            .filter(|_| truncated)
+            .map(|rollup| rollup.hour_start),
+        rollups,
+        truncated,
+    })
+}
+
+// Mean of the hourly means of a pollutant over the `window_hours` ending with `hour_start`
+fn rolling_average(
+    hourly: &BTreeMap<u64, HourlyRollup>,
+    pollutant: &str,
+    hour_start: u64,
+    window_hours: u64,
+    min_hours: usize,
+) -> Option<f64> {
+    let window_start = hour_start.saturating_sub((window_hours - 1) * NANOS_PER_HOUR);
+    let means: Vec<f64> = hourly
+        .range(window_start..=hour_start)
+        .filter_map(|(_, rollup)| rollup.average(pollutant))
+        .collect();
+    (means.len() >= min_hours).then(|| means.iter().sum::<f64>() / means.len() as f64)
+}
+
+// 24-hour PM2.5 and PM10 and 8-hour ozone rolling averages for every hour of the period,
+// computed from the hourly rollups. Hours where none of them is available are left out.
+#[ic_cdk::query]
+fn get_rolling_averages(
+    location: String,
+    start_timestamp: u64,
+    end_timestamp: u64,
+) -> Result<Vec<RollingAverages>, Error> {
+    let location = aliases::location(location);
+    rollups_between(&location, start_timestamp, end_timestamp)?;
+    let first_hour = start_timestamp - start_timestamp % NANOS_PER_HOUR;
+    let lookback = (PM_WINDOW_HOURS - 1) * NANOS_PER_HOUR;
+    let hourly = visible_rollups(
+        &location,
+        first_hour.saturating_sub(lookback),
+        end_timestamp,
+    );
+    let hours = (end_timestamp - first_hour) / NANOS_PER_HOUR;
+    Ok((0..=hours)
+        .map(|hour| first_hour + hour * NANOS_PER_HOUR)
+        .map(|hour_start| RollingAverages {
+            hour_start,
+            pm25_24h: rolling_average(&hourly, "pm2.5", hour_start, PM_WINDOW_HOURS, PM_MIN_HOURS),
+            pm10_24h: rolling_average(&hourly, "pm10", hour_start, PM_WINDOW_HOURS, PM_MIN_HOURS),
+            o3_8h: rolling_average(
+                &hourly,
+                "o3",
+                hour_start,
+                OZONE_WINDOW_HOURS,
+                OZONE_MIN_HOURS,
+            ),
+        })
+        .filter(|averages| {
+            averages.pm25_24h.is_some() || averages.pm10_24h.is_some() || averages.o3_8h.is_some()
+        })
+        .collect())
+}
+
+// Hourly source-apportionment ratios from mean mass concentrations. Hours missing a pollutant
+// of a ratio, or with a zero denominator, are left out.
+#[ic_cdk::query]
+fn get_pollutant_ratios(
+    location: String,
+    start_timestamp: u64,
+    end_timestamp: u64,
+) -> Result<Vec<PollutantRatio>, Error> {
+    let location = aliases::location(location);
+    let sum_of = |rollup: &HourlyRollup, pollutants: &[&str]| {
+        pollutants
+            .iter()
+            .map(|pollutant| rollup.average(pollutant))
+            .sum::<Option<f64>>()
+    };
+    let mut ratios = Vec::new();
+    for (hour_start, rollup) in rollups_between(&location, start_timestamp, end_timestamp)? {
+        for (name, numerator, denominator) in RATIOS {
+            if let (Some(numerator), Some(denominator)) =
+                (sum_of(&rollup, numerator), sum_of(&rollup, denominator))
+            {
+                if denominator > 0.0 {
+                    ratios.push(PollutantRatio {
+                        hour_start,
+                        name: name.to_string(),
+                        ratio: numerator / denominator,
+                    });
+                }
+            }
+        }
+    }
+    Ok(ratios)
+}
@@ -0,0 +1,200 @@
+use crate::{
+    alerts, archive, backfill, backups, bootstrap, capacity, consumers, cycles, digest,
+    idempotency, location_deletion, locations, migrations, retention, sensor_community,
+};
+use ic_cdk::api::{instruction_counter, time};
+use ic_cdk_timers::set_timer_interval;
+use std::cell::RefCell;
+use std::time::Duration;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+// Periodic background work. Each task runs on its own interval timer, so every run is a
+// message of its own. Tasks bound their own work per run to stay within their budget.
+struct Task {
+    name: &'static str,
+    interval_seconds: u64,
+    max_instructions: u64,
+    run: fn(),
+}
+
+const TASKS: &[Task] = &[
+    Task {
+        name: "migrations",
+        interval_seconds: 1,
+        max_instructions: 3_000_000_000,
+        run: migrations::on_timer,
+    },
+    Task {
+        name: "backfill",
+        interval_seconds: 1,
+        max_instructions: 500_000_000,
+        run: backfill::on_timer,
+    },
+    Task {
+        name: "bootstrap",
+        interval_seconds: 1,
+        max_instructions: 50_000_000,
+        run: bootstrap::on_timer,
+    },
+    Task {
+        name: "location_deletion",
+        interval_seconds: 1,
+        max_instructions: 1_500_000_000,
+        run: location_deletion::on_timer,
+    },
+    Task {
+        name: "location_relabel",
+        interval_seconds: 5,
+        max_instructions: 1_000_000_000,
+        run: locations::on_timer,
+    },
+    Task {
+        name: "alert_delivery",
+        interval_seconds: 1,
+        max_instructions: 300_000_000,
+        run: alerts::on_timer,
+    },
+    Task {
+        name: "consumer_delivery",
+        interval_seconds: 1,
+        max_instructions: 500_000_000,
+        run: consumers::on_timer,
+    },
+    Task {
+        name: "sensor_community",
+        interval_seconds: 30,
+        max_instructions: 300_000_000,
+        run: sensor_community::on_timer,
+    },
+    Task {
+        name: "retention",
+        interval_seconds: 5,
+        max_instructions: 2_000_000_000,
+        run: retention::on_timer,
+    },
+    Task {
+        name: "archive",
+        interval_seconds: 10,
+        max_instructions: 1_000_000_000,
+        run: archive::on_timer,
+    },
+    Task {
+        name: "daily_digest",
+        interval_seconds: 60,
+        max_instructions: 500_000_000,
+        run: digest::on_timer,
+    },
+    Task {
+        name: "capacity",
+        interval_seconds: 60,
+        max_instructions: 50_000_000,
+        run: capacity::on_timer,
+    },
+    Task {
+        name: "cycles",
+        interval_seconds: 300,
+        max_instructions: 10_000_000,
+        run: cycles::on_timer,
+    },
+    Task {
+        name: "idempotency_expiry",
+        interval_seconds: 60,
+        max_instructions: 200_000_000,
+        run: idempotency::on_timer,
+    },
+];
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TaskState {
+    name: String,
+    interval_seconds: u64,
+    max_instructions: u64,
+    next_run_at: u64,
+    last_run_at: Option<u64>,
+    last_instructions: u64,
+    runs: u64,
+    // Runs that took more than `max_instructions`
+    overruns: u64,
+    // Runs skipped while a backup or restore held writes
+    skips: u64,
+}
+
+// Kept on the heap: timers do not survive an upgrade, and are set again by post_upgrade
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SchedulerState {
+    tasks: Vec<TaskState>,
+}
+
+thread_local! {
+    static SCHEDULER_STATE: RefCell<SchedulerState> = RefCell::new(SchedulerState {
+        tasks: TASKS
+            .iter()
+            .map(|task| TaskState {
+                name: task.name.to_string(),
+                interval_seconds: task.interval_seconds,
+                max_instructions: task.max_instructions,
+                next_run_at: 0,
+                last_run_at: None,
+                last_instructions: 0,
+                runs: 0,
+                overruns: 0,
+                skips: 0,
+            })
+            .collect(),
+    });
+}
+
+// Sets a timer for every task; called from init and post_upgrade
+pub(crate) fn start() {
+    let now = time();
+    for (index, task) in TASKS.iter().enumerate() {
+        SCHEDULER_STATE.with(|s| {
+            s.borrow_mut().tasks[index].next_run_at = now + task.interval_seconds * NANOS_PER_SECOND
+        });
+        set_timer_interval(Duration::from_secs(task.interval_seconds), move || {
+            run(index)
+        });
+    }
+}
+
+fn run(index: usize) {
+    let task = &TASKS[index];
+    let now = time();
+    SCHEDULER_STATE.with(|s| {
+        s.borrow_mut().tasks[index].next_run_at = now + task.interval_seconds * NANOS_PER_SECOND
+    });
+    // Background tasks write too, so they wait while a backup or restore holds writes
+    if backups::is_frozen() {
+        SCHEDULER_STATE.with(|s| s.borrow_mut().tasks[index].skips += 1);
+        return;
+    }
+
+    let before = instruction_counter();
+    (task.run)();
+    let spent = instruction_counter() - before;
+
+    SCHEDULER_STATE.with(|s| {
+        let state = &mut s.borrow_mut().tasks[index];
+        state.last_run_at = Some(now);
+        state.last_instructions = spent;
+        state.runs += 1;
+        if spent > task.max_instructions {
+            state.overruns += 1;
+        }
+    });
+    if spent > task.max_instructions {
+        log!(
+            Warn,
+            "task exceeded its instruction budget",
+            "task" => task.name,
+            "instructions" => spent,
+            "budget" => task.max_instructions,
+        );
+    }
+}
+
+#[ic_cdk::query]
+fn get_scheduler_state() -> SchedulerState {
+    SCHEDULER_STATE.with(|s| s.borrow().clone())
+}
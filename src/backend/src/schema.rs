@@ -0,0 +1,204 @@
+use crate::pollutant::parse_levels;
+use crate::units::ConcentrationUnit;
+use crate::{get_memory, AirQualityData, Memory, WeatherData, SCHEMA_VERSION_MEMORY_ID};
+use candid::Decode;
+use ic_stable_structures::Cell;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+// Layout version of AirQualityData written by this code.
+//
+// Adding an `opt` field needs no new version: records written without it decode with `None`.
+// Any other layout change must bump this, keep the previous layout below as a legacy struct
+// with a conversion, and register a migration that rewrites the stored records.
+pub(crate) const CURRENT_SCHEMA_VERSION: u32 = 4;
+
+// v1: the original layout, before tombstones and stations
+#[derive(candid::CandidType, Deserialize)]
+struct AirQualityDataV1 {
+    id: u64,
+    location: String,
+    timestamp: u64,
+    air_quality_index: u32,
+    health_recommendations: String,
+    pollutant_levels: HashMap<String, f64>,
+    weather_conditions: WeatherData,
+}
+
+impl From<AirQualityDataV1> for AirQualityData {
+    fn from(data: AirQualityDataV1) -> Self {
+        AirQualityData {
+            id: data.id,
+            location: data.location,
+            timestamp: data.timestamp,
+            air_quality_index: data.air_quality_index,
+            health_recommendations: data.health_recommendations,
+            pollutant_levels: parse_levels(data.pollutant_levels),
+            weather_conditions: data.weather_conditions,
+            deleted_at: None,
+            station_id: None,
+            ingested_at: None,
+            version: 1,
+            pollutant_units: None,
+            raw_pollutant_levels: None,
+            publication_state: None,
+            merged_readings: None,
+            organization_id: None,
+            source: None,
+        }
+    }
+}
+
+// v2: before optimistic concurrency versions
+#[derive(candid::CandidType, Deserialize)]
+struct AirQualityDataV2 {
+    id: u64,
+    location: String,
+    timestamp: u64,
+    air_quality_index: u32,
+    health_recommendations: String,
+    pollutant_levels: HashMap<String, f64>,
+    weather_conditions: WeatherData,
+    deleted_at: Option<u64>,
+    station_id: Option<String>,
+}
+
+impl From<AirQualityDataV2> for AirQualityData {
+    fn from(data: AirQualityDataV2) -> Self {
+        AirQualityData {
+            id: data.id,
+            location: data.location,
+            timestamp: data.timestamp,
+            air_quality_index: data.air_quality_index,
+            health_recommendations: data.health_recommendations,
+            pollutant_levels: parse_levels(data.pollutant_levels),
+            weather_conditions: data.weather_conditions,
+            deleted_at: data.deleted_at,
+            station_id: data.station_id,
+            ingested_at: None,
+            version: 1,
+            pollutant_units: None,
+            raw_pollutant_levels: None,
+            publication_state: None,
+            merged_readings: None,
+            organization_id: None,
+            source: None,
+        }
+    }
+}
+
+// v3: before typed pollutants
+#[derive(candid::CandidType, Deserialize)]
+struct AirQualityDataV3 {
+    id: u64,
+    location: String,
+    timestamp: u64,
+    air_quality_index: u32,
+    health_recommendations: String,
+    pollutant_levels: HashMap<String, f64>,
+    weather_conditions: WeatherData,
+    deleted_at: Option<u64>,
+    station_id: Option<String>,
+    ingested_at: Option<u64>,
+    version: u64,
+    pollutant_units: Option<HashMap<String, ConcentrationUnit>>,
+}
+
+impl From<AirQualityDataV3> for AirQualityData {
+    fn from(data: AirQualityDataV3) -> Self {
+        AirQualityData {
+            id: data.id,
+            location: data.location,
+            timestamp: data.timestamp,
+            air_quality_index: data.air_quality_index,
+            health_recommendations: data.health_recommendations,
+            pollutant_levels: parse_levels(data.pollutant_levels),
+            weather_conditions: data.weather_conditions,
+            deleted_at: data.deleted_at,
+            station_id: data.station_id,
+            ingested_at: data.ingested_at,
+            version: data.version,
+            pollutant_units: data.pollutant_units.map(parse_levels),
+            raw_pollutant_levels: None,
+            publication_state: None,
+            merged_readings: None,
+            organization_id: None,
+            source: None,
+        }
+    }
+}
+
+type LegacyDecoder = fn(&[u8]) -> Option<AirQualityData>;
+
+// Decoders for past layouts, newest first
+const LEGACY_DECODERS: &[LegacyDecoder] = &[decode_v3, decode_v2, decode_v1];
+
+fn decode_v3(bytes: &[u8]) -> Option<AirQualityData> {
+    Decode!(bytes, AirQualityDataV3)
+        .ok()
+        .map(AirQualityData::from)
+}
+
+fn decode_v2(bytes: &[u8]) -> Option<AirQualityData> {
+    Decode!(bytes, AirQualityDataV2)
+        .ok()
+        .map(AirQualityData::from)
+}
+
+fn decode_v1(bytes: &[u8]) -> Option<AirQualityData> {
+    Decode!(bytes, AirQualityDataV1)
+        .ok()
+        .map(AirQualityData::from)
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct SchemaVersion {
+    current: u32,
+    // Every stored record is at least at this version, but for those too large to re-encode
+    stored: u32,
+}
+
+thread_local! {
+    // Stores of canisters deployed before versioning hold v1 records
+    static STORED_SCHEMA_VERSION: RefCell<Cell<u32, Memory>> = RefCell::new(
+        Cell::init(get_memory(SCHEMA_VERSION_MEMORY_ID), 1)
+            .expect("Cannot create the schema version cell")
+    );
+}
+
+// A record that matches no known layout is read as a tombstone with this id, so reads skip it
+// and migrations can tell it from the record stored under its key, instead of trapping
+pub(crate) const UNREADABLE_RECORD_ID: u64 = u64::MAX;
+
+pub(crate) fn decode_air_quality_data(bytes: &[u8]) -> AirQualityData {
+    if let Ok(data) = Decode!(bytes, AirQualityData) {
+        return data;
+    }
+    if let Some(data) = LEGACY_DECODERS.iter().find_map(|decode| decode(bytes)) {
+        return data;
+    }
+    log!(
+        Error,
+        "air quality data does not match any known schema version",
+        "bytes" => bytes.len(),
+    );
+    AirQualityData {
+        id: UNREADABLE_RECORD_ID,
+        deleted_at: Some(0),
+        ..AirQualityData::default()
+    }
+}
+
+pub(crate) fn set_stored_version(version: u32) {
+    STORED_SCHEMA_VERSION
+        .with(|v| v.borrow_mut().set(version))
+        .expect("cannot store the schema version");
+}
+
+#[ic_cdk::query]
+fn get_schema_version() -> SchemaVersion {
+    SchemaVersion {
+        current: CURRENT_SCHEMA_VERSION,
+        stored: STORED_SCHEMA_VERSION.with(|v| *v.borrow().get()),
+    }
+}
@@ -0,0 +1,266 @@
+use crate::grafana::parse_time;
+use crate::purpleair::category_name;
+use crate::sources::{self, ReadingSource};
+use crate::standards::{us_epa_particulate_index, AqiCategory};
+use crate::{
+    _add_air_quality_data, accepts_writes, capacity, get_memory, stations, AirQualityUpdatePayload,
+    Error, Memory, Pollutant, SENSOR_COMMUNITY_STORAGE_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpMethod, TransformContext,
+};
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+
+const API_URL: &str = "https://data.sensor.community/airrohr/v1/sensor";
+const POLL_INTERVAL_SECONDS: u64 = 300;
+const MAX_POLLS_PER_TICK: usize = 10;
+const MAX_SENSORS: u64 = 1_000;
+const MAX_RESPONSE_BYTES: u64 = 100_000;
+// Generous upper bound for a 100kB outcall on a 13-node subnet; unused cycles are refunded
+const OUTCALL_CYCLES: u128 = 5_000_000_000;
+const MAX_ERROR_LEN: usize = 256;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+// A Sensor.Community sensor whose particulate readings are stored as readings of a station
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SensorMapping {
+    sensor_id: u64,
+    station_id: String,
+    mapped_by: Principal,
+    mapped_at: u64,
+    last_polled_at: Option<u64>,
+    // Observation time of the newest reading stored
+    last_observed_at: Option<u64>,
+    readings_stored: u64,
+    last_error: Option<String>,
+}
+
+impl_bounded_storable!(SensorMapping, 1024);
+
+// A measurement of Sensor.Community's per-sensor feed
+#[derive(Deserialize)]
+struct Measurement {
+    // UTC, "YYYY-MM-DD HH:MM:SS"
+    timestamp: String,
+    sensordatavalues: Vec<DataValue>,
+}
+
+#[derive(Deserialize)]
+struct DataValue {
+    value: String,
+    // P1 is PM10 and P2 is PM2.5, in µg/m³
+    value_type: String,
+}
+
+thread_local! {
+    static SENSOR_COMMUNITY_STORAGE: RefCell<StableBTreeMap<u64, SensorMapping, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(SENSOR_COMMUNITY_STORAGE_MEMORY_ID)));
+
+    // Deliberately not stable: after an upgrade no request can still be in flight
+    static POLLS_IN_FLIGHT: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+fn do_insert_mapping(mapping: &SensorMapping) {
+    SENSOR_COMMUNITY_STORAGE.with(|s| s.borrow_mut().insert(mapping.sensor_id, mapping.clone()));
+}
+
+// None for measurements without particulates, such as those of weather sensors
+fn payload(
+    mapping: &SensorMapping,
+    measurement: &Measurement,
+) -> Option<(u64, AirQualityUpdatePayload)> {
+    let observed_at = parse_time(&format!("{}Z", measurement.timestamp.replacen(' ', "T", 1)))?;
+    let levels: HashMap<Pollutant, f64> = measurement
+        .sensordatavalues
+        .iter()
+        .filter_map(|value| {
+            let pollutant = match value.value_type.as_str() {
+                "P1" => Pollutant::PM10,
+                "P2" => Pollutant::PM25,
+                _ => return None,
+            };
+            let level: f64 = value.value.parse().ok()?;
+            (level.is_finite() && level >= 0.0).then_some((pollutant, level))
+        })
+        .collect();
+    let air_quality_index = levels
+        .iter()
+        .filter_map(|(pollutant, level)| us_epa_particulate_index(pollutant, *level))
+        .max()?;
+    let location = stations::get_station(&mapping.station_id)?
+        .location()
+        .to_string();
+    Some((
+        observed_at,
+        AirQualityUpdatePayload {
+            location,
+            air_quality_index,
+            health_recommendations: format!(
+                "US EPA category: {}",
+                category_name(AqiCategory::of(air_quality_index as f64))
+            ),
+            pollutant_levels: Some(levels),
+            station_id: Some(mapping.station_id.clone()),
+            observed_at: Some(observed_at),
+            apply_calibration: Some(true),
+            ..Default::default()
+        },
+    ))
+}
+
+async fn fetch(sensor_id: u64) -> Result<Vec<Measurement>, String> {
+    let request = CanisterHttpRequestArgument {
+        url: format!("{}/{}/", API_URL, sensor_id),
+        max_response_bytes: Some(MAX_RESPONSE_BYTES),
+        method: HttpMethod::GET,
+        headers: vec![],
+        body: None,
+        // Backfill's transform keeps only the status and body, which is all this needs too
+        transform: Some(TransformContext::from_name(
+            "transform_backfill_response".to_string(),
+            vec![],
+        )),
+    };
+    let (response,) = http_request(request, OUTCALL_CYCLES)
+        .await
+        .map_err(|(code, msg)| format!("outcall failed: {:?} {}", code, msg))?;
+    if response.status != 200u16 {
+        return Err(format!(
+            "Sensor.Community responded with status {}",
+            response.status
+        ));
+    }
+    serde_json::from_slice(&response.body).map_err(|e| format!("cannot parse the feed: {}", e))
+}
+
+// Stores the measurements newer than the last one stored
+fn apply(sensor_id: u64, result: Result<Vec<Measurement>, String>) {
+    let Some(mut mapping) = SENSOR_COMMUNITY_STORAGE.with(|s| s.borrow().get(&sensor_id)) else {
+        return;
+    };
+    match result {
+        Ok(measurements) => {
+            let mut payloads: Vec<(u64, AirQualityUpdatePayload)> = measurements
+                .iter()
+                .filter_map(|measurement| payload(&mapping, measurement))
+                .filter(|(observed_at, _)| {
+                    mapping
+                        .last_observed_at
+                        .is_none_or(|last| *observed_at > last)
+                })
+                .collect();
+            payloads.sort_by_key(|(observed_at, _)| *observed_at);
+            for (observed_at, payload) in payloads {
+                // Readings refused, e.g. by validation rules, are not retried
+                let source =
+                    ReadingSource::import(sources::SENSOR_COMMUNITY_CONNECTOR, mapping.sensor_id);
+                if _add_air_quality_data(payload, source).is_ok() {
+                    mapping.readings_stored += 1;
+                }
+                mapping.last_observed_at = Some(observed_at);
+            }
+            mapping.last_error = None;
+        }
+        Err(error) => {
+            log!(Warn, "Sensor.Community poll failed", "sensor" => sensor_id, "error" => error);
+            mapping.last_error = Some(error.chars().take(MAX_ERROR_LEN).collect());
+        }
+    }
+    do_insert_mapping(&mapping);
+}
+
+// Polls the mapped sensors not polled in the last five minutes, a few per tick
+pub(crate) fn on_timer() {
+    let now = time();
+    let due: Vec<SensorMapping> = SENSOR_COMMUNITY_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, mapping)| mapping)
+            .filter(|mapping| {
+                mapping
+                    .last_polled_at
+                    .is_none_or(|at| now >= at + POLL_INTERVAL_SECONDS * NANOS_PER_SECOND)
+                    && !POLLS_IN_FLIGHT.with(|f| f.borrow().contains(&mapping.sensor_id))
+            })
+            .take(MAX_POLLS_PER_TICK)
+            .collect()
+    });
+    for mut mapping in due {
+        mapping.last_polled_at = Some(now);
+        do_insert_mapping(&mapping);
+        let sensor_id = mapping.sensor_id;
+        POLLS_IN_FLIGHT.with(|f| f.borrow_mut().insert(sensor_id));
+        ic_cdk::spawn(async move {
+            let result = fetch(sensor_id).await;
+            apply(sensor_id, result);
+            POLLS_IN_FLIGHT.with(|f| f.borrow_mut().remove(&sensor_id));
+        });
+    }
+}
+
+fn ensure_manages(station_id: &str) -> Result<(), Error> {
+    let station = stations::get_station(station_id).ok_or(Error::NotFound {
+        msg: format!("station {} not found", station_id),
+    })?;
+    if !station.is_managed_by(&ic_cdk::caller()) {
+        return Err(Error::Unauthorized {
+            msg: format!("caller does not own station {}", station_id),
+        });
+    }
+    Ok(())
+}
+
+// Imports the PM2.5 and PM10 of a Sensor.Community sensor as readings of a registered station,
+// polled every five minutes (station owner or controllers). Mapping a mapped sensor again moves
+// it to the new station.
+#[ic_cdk::update(guard = "accepts_writes")]
+fn map_sensor_community_sensor(sensor_id: u64, station_id: String) -> Result<SensorMapping, Error> {
+    ensure_manages(&station_id)?;
+    capacity::ensure_room_for_optional_write()?;
+    let existing = SENSOR_COMMUNITY_STORAGE.with(|s| s.borrow().get(&sensor_id));
+    if let Some(existing) = &existing {
+        ensure_manages(&existing.station_id)?;
+    } else if SENSOR_COMMUNITY_STORAGE.with(|s| s.borrow().len()) >= MAX_SENSORS {
+        return Err(Error::TooLarge {
+            msg: format!("at most {} sensors can be mapped", MAX_SENSORS),
+        });
+    }
+    let mapping = SensorMapping {
+        sensor_id,
+        station_id,
+        mapped_by: ic_cdk::caller(),
+        mapped_at: time(),
+        last_polled_at: None,
+        last_observed_at: existing.and_then(|existing| existing.last_observed_at),
+        readings_stored: 0,
+        last_error: None,
+    };
+    do_insert_mapping(&mapping);
+    Ok(mapping)
+}
+
+// Stops importing a sensor (owner of its station or controllers); its readings are kept
+#[ic_cdk::update(guard = "accepts_writes")]
+fn unmap_sensor_community_sensor(sensor_id: u64) -> Result<SensorMapping, Error> {
+    let mapping = SENSOR_COMMUNITY_STORAGE
+        .with(|s| s.borrow().get(&sensor_id))
+        .ok_or(Error::NotFound {
+            msg: format!("sensor {} is not mapped", sensor_id),
+        })?;
+    ensure_manages(&mapping.station_id)?;
+    SENSOR_COMMUNITY_STORAGE.with(|s| s.borrow_mut().remove(&sensor_id));
+    Ok(mapping)
+}
+
+continued_page!(SensorMappingPage, SensorMapping);
+
+#[ic_cdk::query]
+fn list_sensor_community_sensors(continuation: Option<String>) -> Result<SensorMappingPage, Error> {
+    SENSOR_COMMUNITY_STORAGE.with(|s| {
+        SensorMappingPage::new(s.borrow().iter().map(|(_, mapping)| mapping), continuation)
+    })
+}
@@ -0,0 +1,527 @@
+use crate::aggregates::level_in_micrograms;
+use crate::grafana::format_time;
+use crate::http::{error, json, percent_decode, HttpGatewayResponse};
+use crate::stations::{self, uri_component, PublicStation};
+use crate::{
+    aliases, dedup, is_visible_to_caller, latest, rollups, AirQualityData, Pollutant,
+    AIR_QUALITY_STORAGE,
+};
+use ic_cdk::api::time;
+
+const VERSION_PATH: &str = "/v1.1";
+const DEFAULT_TOP: usize = 100;
+const MAX_TOP: usize = 1000;
+// Every skipped entity is still read, so deep pages are refused rather than run out of
+// instructions
+const MAX_SKIP: usize = 10_000;
+// A location's datastreams are the AQI and the pollutants it reported in this many days
+const DATASTREAM_DAYS: u64 = 30;
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const AQI_STREAM: &str = "aqi";
+const OM_MEASUREMENT: &str = "http://www.opengis.net/def/observationType/OGC-OM/2.0/OM_Measurement";
+// OGC's "unknown" nil value: readings do not record which AQI scale they use
+const UNKNOWN_DEFINITION: &str = "http://www.opengis.net/def/nil/OGC/0/unknown";
+const MICROGRAMS_DEFINITION: &str = "http://unitsofmeasure.org/ucum.html#ug/m3";
+
+// OGC SensorThings API 1.1 read surface under /v1.1: a location is a Thing, the AQI and each
+// pollutant it reports are its Datastreams, and a reading's values are Observations. Its
+// stations are its Locations. $top and $skip are supported; $filter, $expand and $orderby are
+// not. A datastream's observations are listed last observed first, /Observations in the order
+// readings were stored.
+
+#[derive(Serialize)]
+struct EntitySet {
+    name: &'static str,
+    url: String,
+}
+
+#[derive(Serialize)]
+struct ServerSettings {
+    conformance: Vec<&'static str>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ServiceRoot {
+    value: Vec<EntitySet>,
+    server_settings: ServerSettings,
+}
+
+#[derive(Serialize)]
+struct Collection<T> {
+    value: Vec<T>,
+    #[serde(rename = "@iot.nextLink", skip_serializing_if = "Option::is_none")]
+    next_link: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ThingProperties {
+    // Observation time of the location's latest reading
+    latest_observation: String,
+}
+
+#[derive(Serialize)]
+struct Thing {
+    #[serde(rename = "@iot.id")]
+    id: String,
+    #[serde(rename = "@iot.selfLink")]
+    self_link: String,
+    name: String,
+    description: String,
+    properties: ThingProperties,
+    #[serde(rename = "Datastreams@iot.navigationLink")]
+    datastreams_link: String,
+    #[serde(rename = "Locations@iot.navigationLink")]
+    locations_link: String,
+}
+
+#[derive(Serialize)]
+struct UnitOfMeasurement {
+    name: &'static str,
+    symbol: &'static str,
+    definition: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Datastream {
+    #[serde(rename = "@iot.id")]
+    id: String,
+    #[serde(rename = "@iot.selfLink")]
+    self_link: String,
+    name: String,
+    description: String,
+    unit_of_measurement: UnitOfMeasurement,
+    observation_type: &'static str,
+    #[serde(rename = "Thing@iot.navigationLink")]
+    thing_link: String,
+    #[serde(rename = "Observations@iot.navigationLink")]
+    observations_link: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Observation {
+    #[serde(rename = "@iot.id")]
+    id: String,
+    #[serde(rename = "@iot.selfLink")]
+    self_link: String,
+    phenomenon_time: String,
+    // When the canister received the reading
+    result_time: String,
+    result: f64,
+    #[serde(rename = "Datastream@iot.navigationLink")]
+    datastream_link: String,
+}
+
+#[derive(Serialize)]
+struct GeoJsonPoint {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    // [longitude, latitude]
+    coordinates: [f64; 2],
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Location {
+    #[serde(rename = "@iot.id")]
+    id: String,
+    #[serde(rename = "@iot.selfLink")]
+    self_link: String,
+    name: String,
+    description: String,
+    encoding_type: &'static str,
+    location: GeoJsonPoint,
+    #[serde(rename = "Things@iot.navigationLink")]
+    things_link: String,
+}
+
+// What a datastream of a location measures
+enum Stream {
+    Aqi,
+    Pollutant(Pollutant),
+}
+
+impl Stream {
+    fn parse(name: &str) -> Stream {
+        if name == AQI_STREAM {
+            Stream::Aqi
+        } else {
+            Stream::Pollutant(Pollutant::parse(name))
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Stream::Aqi => AQI_STREAM,
+            Stream::Pollutant(pollutant) => pollutant.name(),
+        }
+    }
+
+    fn result(&self, data: &AirQualityData) -> Option<f64> {
+        match self {
+            Stream::Aqi => Some(data.air_quality_index as f64),
+            // In µg/m³ whatever unit the reading was stored in
+            Stream::Pollutant(pollutant) => level_in_micrograms(data, pollutant),
+        }
+    }
+}
+
+// Links, paging and the path of the resource being served
+struct Context {
+    base: String,
+    path: String,
+    top: usize,
+    skip: usize,
+}
+
+impl Context {
+    // `Things('Delhi')`, with the key quoted and escaped as OData requires
+    fn link(&self, entity_set: &str, id: &str) -> String {
+        format!(
+            "{}/{}('{}')",
+            self.base,
+            entity_set,
+            uri_component(&id.replace('\'', "''"))
+        )
+    }
+
+    fn collection<T: serde::Serialize>(
+        &self,
+        items: impl Iterator<Item = T>,
+    ) -> HttpGatewayResponse {
+        let mut value: Vec<T> = items.skip(self.skip).take(self.top + 1).collect();
+        let next_link = (value.len() > self.top).then(|| {
+            format!(
+                "{}{}?$top={}&$skip={}",
+                self.base,
+                self.path,
+                self.top,
+                self.skip + self.top
+            )
+        });
+        value.truncate(self.top);
+        json(&Collection { value, next_link })
+    }
+
+    fn thing(&self, location: &str, latest: &AirQualityData) -> Thing {
+        let self_link = self.link("Things", location);
+        Thing {
+            id: location.to_string(),
+            name: location.to_string(),
+            description: format!("Air quality readings of {}", location),
+            properties: ThingProperties {
+                latest_observation: format_time(latest.timestamp),
+            },
+            datastreams_link: format!("{}/Datastreams", self_link),
+            locations_link: format!("{}/Locations", self_link),
+            self_link,
+        }
+    }
+
+    fn datastream(&self, location: &str, stream: &Stream) -> Datastream {
+        let id = format!("{}:{}", location, stream.name());
+        let self_link = self.link("Datastreams", &id);
+        let (name, unit_of_measurement) = match stream {
+            Stream::Aqi => (
+                "AQI".to_string(),
+                UnitOfMeasurement {
+                    name: "Air quality index",
+                    symbol: "AQI",
+                    definition: UNKNOWN_DEFINITION,
+                },
+            ),
+            Stream::Pollutant(pollutant) => (
+                pollutant.name().to_uppercase(),
+                UnitOfMeasurement {
+                    name: "Microgram per cubic meter",
+                    symbol: "µg/m³",
+                    definition: MICROGRAMS_DEFINITION,
+                },
+            ),
+        };
+        Datastream {
+            id,
+            name: format!("{} {}", location, name),
+            description: format!("{} readings of {}", name, location),
+            unit_of_measurement,
+            observation_type: OM_MEASUREMENT,
+            thing_link: format!("{}/Thing", self_link),
+            observations_link: format!("{}/Observations", self_link),
+            self_link,
+        }
+    }
+
+    fn observation(&self, data: &AirQualityData, stream: &Stream) -> Option<Observation> {
+        let result = stream.result(data)?;
+        let id = format!("{}:{}", data.id, stream.name());
+        let self_link = self.link("Observations", &id);
+        Some(Observation {
+            id,
+            phenomenon_time: format_time(data.timestamp),
+            result_time: format_time(data.ingested_at.unwrap_or(data.timestamp)),
+            result,
+            datastream_link: format!("{}/Datastream", self_link),
+            self_link,
+        })
+    }
+
+    fn location(&self, station: &PublicStation) -> Location {
+        let self_link = self.link("Locations", station.station_id());
+        let (latitude, longitude) = station.coordinates();
+        Location {
+            id: station.station_id().to_string(),
+            name: station.name().to_string(),
+            description: format!("Station {}", station.station_id()),
+            encoding_type: "application/geo+json",
+            location: GeoJsonPoint {
+                kind: "Point",
+                coordinates: [longitude, latitude],
+            },
+            things_link: format!("{}/Things", self_link),
+            self_link,
+        }
+    }
+}
+
+fn counts(data: &AirQualityData) -> bool {
+    data.is_published() && is_visible_to_caller(data)
+}
+
+// The location a Thing id names, if it has a reading the caller may see
+fn thing_location(id: &str) -> Option<(String, AirQualityData)> {
+    let location = aliases::location(id.to_string());
+    let latest = latest::latest(&location)?;
+    Some((location, latest))
+}
+
+fn streams_of(location: &str) -> Vec<Stream> {
+    let now = time();
+    let reported = rollups::pollutants_reported(
+        location,
+        now.saturating_sub(DATASTREAM_DAYS * NANOS_PER_DAY),
+        now,
+    )
+    .unwrap_or_default();
+    std::iter::once(Stream::Aqi)
+        .chain(reported.into_iter().map(Stream::Pollutant))
+        .collect()
+}
+
+// The location and stream a Datastream id such as "Delhi:pm2.5" names
+fn datastream_of(id: &str) -> Option<(String, Stream)> {
+    let (location, name) = id.rsplit_once(':')?;
+    let (location, _) = thing_location(location)?;
+    let stream = Stream::parse(name);
+    streams_of(&location)
+        .iter()
+        .any(|known| known.name() == stream.name())
+        .then_some((location, stream))
+}
+
+// The reading and stream an Observation id such as "42:aqi" names
+fn observation_of(id: &str) -> Option<(AirQualityData, Stream)> {
+    let (reading_id, name) = id.split_once(':')?;
+    let reading_id: u64 = reading_id.parse().ok()?;
+    let data = AIR_QUALITY_STORAGE
+        .with(|service| service.borrow().get(&reading_id))
+        .filter(|data| !data.is_deleted() && counts(data))?;
+    Some((data, Stream::parse(name)))
+}
+
+fn observations_of<'a>(
+    context: &'a Context,
+    location: &str,
+    stream: &'a Stream,
+) -> impl Iterator<Item = Observation> + 'a {
+    dedup::live_readings_newest_first(location)
+        .filter(counts)
+        .filter_map(move |data| context.observation(&data, stream))
+}
+
+// `Things`, or `Things('Delhi')` with its key unquoted and unescaped
+fn parse_segment(segment: &str) -> Option<(&str, Option<String>)> {
+    let Some((name, key)) = segment.split_once('(') else {
+        return Some((segment, None));
+    };
+    let key = percent_decode(key.strip_suffix(')')?)?;
+    let key = match key
+        .strip_prefix('\'')
+        .and_then(|key| key.strip_suffix('\''))
+    {
+        Some(quoted) => quoted.replace("''", "'"),
+        None => key,
+    };
+    Some((name, Some(key)))
+}
+
+fn parse_paging(query: &str) -> Result<(usize, usize), String> {
+    let (mut top, mut skip) = (DEFAULT_TOP, 0);
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let name = percent_decode(name).unwrap_or_default();
+        match name.as_str() {
+            "$top" => {
+                top = value
+                    .parse()
+                    .ok()
+                    .filter(|top| (1..=MAX_TOP).contains(top))
+                    .ok_or(format!("$top must be 1 to {}", MAX_TOP))?
+            }
+            "$skip" => {
+                skip = value
+                    .parse()
+                    .ok()
+                    .filter(|skip| *skip <= MAX_SKIP)
+                    .ok_or(format!("$skip must be at most {}", MAX_SKIP))?
+            }
+            _ if name.starts_with('$') => {
+                return Err(format!("the query option {} is not supported", name))
+            }
+            _ => {}
+        }
+    }
+    Ok((top, skip))
+}
+
+fn not_found() -> HttpGatewayResponse {
+    error(404, "no such entity")
+}
+
+// GET /v1.1/...: `segments` follow the version, `host` is the Host header
+pub(crate) fn handle(segments: &[&str], query: &str, host: Option<&str>) -> HttpGatewayResponse {
+    let (top, skip) = match parse_paging(query) {
+        Ok(paging) => paging,
+        Err(msg) => return error(400, &msg),
+    };
+    let Some(path) = segments
+        .iter()
+        .map(|s| parse_segment(s))
+        .collect::<Option<Vec<_>>>()
+    else {
+        return error(400, "the path is not a valid resource path");
+    };
+    let context = Context {
+        base: host.map_or(VERSION_PATH.to_string(), |host| {
+            format!("https://{}{}", host, VERSION_PATH)
+        }),
+        path: segments.iter().map(|s| format!("/{}", s)).collect(),
+        top,
+        skip,
+    };
+    match path.as_slice() {
+        [] => json(&ServiceRoot {
+            value: ["Things", "Locations", "Datastreams", "Observations"]
+                .into_iter()
+                .map(|name| EntitySet {
+                    name,
+                    url: format!("{}/{}", context.base, name),
+                })
+                .collect(),
+            server_settings: ServerSettings {
+                conformance: vec!["http://www.opengis.net/spec/iot_sensing/1.1/req/datamodel"],
+            },
+        }),
+        [("Things", None)] => context.collection(
+            latest::indexed_locations(None)
+                .into_iter()
+                .filter_map(|location| {
+                    let latest = latest::latest(&location)?;
+                    Some(context.thing(&location, &latest))
+                }),
+        ),
+        [("Things", Some(id))] => match thing_location(id) {
+            Some((location, latest)) => json(&context.thing(&location, &latest)),
+            None => not_found(),
+        },
+        [("Things", Some(id)), ("Datastreams", None)] => match thing_location(id) {
+            Some((location, _)) => context.collection(
+                streams_of(&location)
+                    .into_iter()
+                    .map(|stream| context.datastream(&location, &stream)),
+            ),
+            None => not_found(),
+        },
+        [("Things", Some(id)), ("Locations", None)] => match thing_location(id) {
+            Some((location, _)) => context.collection(
+                stations::public_stations_at(&location)
+                    .iter()
+                    .map(|station| context.location(station)),
+            ),
+            None => not_found(),
+        },
+        [("Locations", Some(id))] => match stations::public_station(id) {
+            Some(station) => json(&context.location(&station)),
+            None => not_found(),
+        },
+        [("Datastreams", None)] => context.collection(
+            latest::indexed_locations(None)
+                .into_iter()
+                .filter(|location| latest::latest(location).is_some())
+                .flat_map(|location| {
+                    streams_of(&location)
+                        .into_iter()
+                        .map(|stream| context.datastream(&location, &stream))
+                        .collect::<Vec<_>>()
+                }),
+        ),
+        [("Datastreams", Some(id))] => match datastream_of(id) {
+            Some((location, stream)) => json(&context.datastream(&location, &stream)),
+            None => not_found(),
+        },
+        [("Datastreams", Some(id)), ("Thing", None)] => {
+            match datastream_of(id).and_then(|(location, _)| thing_location(&location)) {
+                Some((location, latest)) => json(&context.thing(&location, &latest)),
+                None => not_found(),
+            }
+        }
+        [("Datastreams", Some(id)), ("Observations", None)] => match datastream_of(id) {
+            Some((location, stream)) => {
+                context.collection(observations_of(&context, &location, &stream))
+            }
+            None => not_found(),
+        },
+        [("Observations", None)] => context.collection(
+            AIR_QUALITY_STORAGE
+                .with(|service| {
+                    service
+                        .borrow()
+                        .iter()
+                        .map(|(_, data)| data)
+                        .filter(|data| !data.is_deleted() && counts(data))
+                        .take(skip + top + 1)
+                        .collect::<Vec<_>>()
+                })
+                .into_iter()
+                .flat_map(|data| {
+                    streams_of_reading(&data)
+                        .iter()
+                        .filter_map(|stream| context.observation(&data, stream))
+                        .collect::<Vec<_>>()
+                }),
+        ),
+        [("Observations", Some(id))] => match observation_of(id)
+            .and_then(|(data, stream)| context.observation(&data, &stream))
+        {
+            Some(observation) => json(&observation),
+            None => not_found(),
+        },
+        [("Observations", Some(id)), ("Datastream", None)] => {
+            match observation_of(id).filter(|(data, stream)| stream.result(data).is_some()) {
+                Some((data, stream)) => json(&context.datastream(&data.location, &stream)),
+                None => not_found(),
+            }
+        }
+        _ => not_found(),
+    }
+}
+
+// The AQI and the pollutants of a reading, in pollutant order
+fn streams_of_reading(data: &AirQualityData) -> Vec<Stream> {
+    let mut pollutants: Vec<Pollutant> = data.pollutant_levels.keys().cloned().collect();
+    pollutants.sort();
+    std::iter::once(Stream::Aqi)
+        .chain(pollutants.into_iter().map(Stream::Pollutant))
+        .collect()
+}
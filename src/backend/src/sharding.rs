@@ -0,0 +1,263 @@
+use crate::paging::{self, ReadingPage, ReplyBudget};
+use crate::units::ConcentrationUnit;
+use crate::{
+    accepts_writes, ensure_admin, get_memory, principal_key, AirQualityData,
+    AirQualityUpdatePayload, Error, Memory, PrincipalKey, SHARD_STORAGE_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Shard {
+    canister_id: Principal,
+    label: String,
+    registered_at: u64,
+}
+
+impl_bounded_storable!(Shard, 256);
+
+// Ids are only unique within a shard, so routed results carry the shard they came from
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ShardedAirQualityData {
+    shard: Principal,
+    data: AirQualityData,
+}
+
+thread_local! {
+    static SHARD_STORAGE: RefCell<StableBTreeMap<PrincipalKey, Shard, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(SHARD_STORAGE_MEMORY_ID)));
+}
+
+// FNV-1a: stable across compiler versions, unlike std's hashers
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn shards() -> Vec<Shard> {
+    SHARD_STORAGE.with(|s| s.borrow().iter().map(|(_, shard)| shard).collect())
+}
+
+// Rendezvous hashing: registering or removing a shard only moves the
+// locations that hash highest to that shard
+fn shard_for_location(location: &str) -> Result<Principal, Error> {
+    shards()
+        .into_iter()
+        .max_by_key(|shard| {
+            let mut key = location.as_bytes().to_vec();
+            key.extend_from_slice(shard.canister_id.as_slice());
+            fnv1a(&key)
+        })
+        .map(|shard| shard.canister_id)
+        .ok_or(Error::NotFound {
+            msg: "no shards are registered".to_string(),
+        })
+}
+
+fn call_error(shard: Principal, (code, msg): (ic_cdk::api::call::RejectionCode, String)) -> Error {
+    Error::InvalidInput {
+        msg: format!("call to shard {} failed: {:?} {}", shard, code, msg),
+        violations: None,
+    }
+}
+
+#[ic_cdk::update(guard = "accepts_writes")]
+fn register_shard(canister_id: Principal, label: String) -> Result<Shard, Error> {
+    ensure_admin()?;
+    let shard = Shard {
+        canister_id,
+        label,
+        registered_at: time(),
+    };
+    SHARD_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert(principal_key(&canister_id), shard.clone())
+    });
+    Ok(shard)
+}
+
+#[ic_cdk::update(guard = "accepts_writes")]
+fn remove_shard(canister_id: Principal) -> Result<Shard, Error> {
+    ensure_admin()?;
+    SHARD_STORAGE
+        .with(|s| s.borrow_mut().remove(&principal_key(&canister_id)))
+        .ok_or(Error::NotFound {
+            msg: format!("shard {} is not registered", canister_id),
+        })
+}
+
+#[ic_cdk::query]
+fn list_shards() -> Vec<Shard> {
+    shards()
+}
+
+#[ic_cdk::query]
+fn get_shard_for_location(location: String) -> Result<Principal, Error> {
+    shard_for_location(&location)
+}
+
+// Forwards a new reading to the shard owning its location
+#[ic_cdk::update(guard = "accepts_writes")]
+async fn route_add_air_quality_data(
+    data: AirQualityUpdatePayload,
+) -> Result<ShardedAirQualityData, Error> {
+    let shard = shard_for_location(&data.location)?;
+    let (result,): (Result<AirQualityData, Error>,) =
+        ic_cdk::call(shard, "add_air_quality_data", (data,))
+            .await
+            .map_err(|e| call_error(shard, e))?;
+    result.map(|data| ShardedAirQualityData { shard, data })
+}
+
+#[ic_cdk::update(guard = "accepts_writes")]
+async fn route_update_air_quality_data(
+    shard: Principal,
+    id: u64,
+    payload: AirQualityUpdatePayload,
+    expected_version: Option<u64>,
+) -> Result<ShardedAirQualityData, Error> {
+    if shard_for_location(&payload.location)? != shard {
+        return Err(Error::InvalidInput {
+            msg: "the updated location belongs to another shard; delete and re-add the reading"
+                .to_string(),
+            violations: None,
+        });
+    }
+    let (result,): (Result<AirQualityData, Error>,) = ic_cdk::call(
+        shard,
+        "update_air_quality_data",
+        (id, payload, expected_version),
+    )
+    .await
+    .map_err(|e| call_error(shard, e))?;
+    result.map(|data| ShardedAirQualityData { shard, data })
+}
+
+#[ic_cdk::update(guard = "accepts_writes")]
+async fn route_delete_air_quality_data(
+    shard: Principal,
+    id: u64,
+) -> Result<ShardedAirQualityData, Error> {
+    let (result,): (Result<AirQualityData, Error>,) =
+        ic_cdk::call(shard, "delete_air_quality_data", (id,))
+            .await
+            .map_err(|e| call_error(shard, e))?;
+    result.map(|data| ShardedAirQualityData { shard, data })
+}
+
+// Where a routed query continues: after reading `after` of `shard`, or at the start of it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ShardCursor {
+    shard: Principal,
+    after: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ShardedReadingPage {
+    readings: Vec<ShardedAirQualityData>,
+    // More readings match; pass `next_cursor` to fetch them
+    truncated: bool,
+    next_cursor: Option<ShardCursor>,
+}
+
+// Calls the same list query on the shards in turn, starting at `cursor`, until a reply is full.
+// `args` builds the query's arguments from the cursor within a shard.
+async fn fan_out<A>(
+    method: &str,
+    args: impl Fn(Option<u64>) -> A,
+    cursor: Option<ShardCursor>,
+) -> Result<ShardedReadingPage, Error>
+where
+    A: candid::utils::ArgumentEncoder,
+{
+    let shards = shards();
+    let first = match &cursor {
+        Some(cursor) => shards
+            .iter()
+            .position(|shard| shard.canister_id == cursor.shard)
+            .ok_or(Error::InvalidInput {
+                msg: format!("shard {} is no longer registered", cursor.shard),
+                violations: None,
+            })?,
+        None => 0,
+    };
+    let mut after = cursor.and_then(|cursor| cursor.after);
+    let mut budget = ReplyBudget::new();
+    let mut readings = Vec::new();
+    let mut next_cursor = None;
+    for shard in &shards[first..] {
+        let shard = shard.canister_id;
+        let shard_after = after.take();
+        let (result,): (Result<ReadingPage, Error>,) =
+            ic_cdk::call(shard, method, args(shard_after))
+                .await
+                .map_err(|e| call_error(shard, e))?;
+        let page = result?;
+        let (taken, cut) = paging::take_within(
+            page.readings
+                .into_iter()
+                .map(|data| ShardedAirQualityData { shard, data }),
+            &mut budget,
+        );
+        let last = taken.last().map(|item| item.data.id);
+        readings.extend(taken);
+        if cut {
+            next_cursor = Some(ShardCursor {
+                shard,
+                after: last.or(shard_after),
+            });
+            break;
+        }
+        if page.truncated {
+            next_cursor = Some(ShardCursor {
+                shard,
+                after: page.next_cursor,
+            });
+            break;
+        }
+    }
+    readings.sort_by_key(|item| (item.data.timestamp, item.data.id));
+    Ok(ShardedReadingPage {
+        readings,
+        truncated: next_cursor.is_some(),
+        next_cursor,
+    })
+}
+
+#[ic_cdk::query(composite = true)]
+async fn route_search_air_quality_data_by_location(
+    location: String,
+    cursor: Option<ShardCursor>,
+    fuzzy: Option<bool>,
+) -> Result<ShardedReadingPage, Error> {
+    fan_out(
+        "search_air_quality_data_by_location",
+        |after| (location.clone(), None::<ConcentrationUnit>, after, fuzzy),
+        cursor,
+    )
+    .await
+}
+
+#[ic_cdk::query(composite = true)]
+async fn route_get_air_quality_data_by_timestamp_range(
+    start_timestamp: u64,
+    end_timestamp: u64,
+    cursor: Option<ShardCursor>,
+) -> Result<ShardedReadingPage, Error> {
+    fan_out(
+        "get_air_quality_data_by_timestamp_range",
+        |after| {
+            (
+                start_timestamp,
+                end_timestamp,
+                None::<ConcentrationUnit>,
+                after,
+            )
+        },
+        cursor,
+    )
+    .await
+}
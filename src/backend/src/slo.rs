@@ -0,0 +1,268 @@
+use crate::{
+    get_memory, AirQualityData, Error, Memory, StringKey, SLO_COUNTER_MEMORY_ID,
+    SLO_LATENCY_MEMORY_ID,
+};
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const NANOS_PER_DAY: u64 = 86_400 * NANOS_PER_SECOND;
+const MAX_REPORT_DAYS: u32 = 366;
+// Endpoints whose availability is reported as ingestion availability
+const INGESTION_ENDPOINTS: &[&str] = &["add_air_quality_data", "upsert_reading"];
+const INGESTION_LAG: &str = "ingestion_lag";
+// Upper bounds of the latency histogram buckets in seconds; the last bucket is unbounded
+const LATENCY_BUCKETS_SECONDS: &[u64] = &[1, 5, 15, 60, 300, 900, 3_600, 14_400, 86_400];
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+pub(crate) struct ErrorCounts {
+    not_found: u64,
+    unauthorized: u64,
+    invalid_input: u64,
+    too_large: u64,
+    conflict: u64,
+    // None in days counted before duplicates were told apart
+    duplicate: Option<u64>,
+    // None in days counted before quotas existed
+    quota_exceeded: Option<u64>,
+}
+
+impl ErrorCounts {
+    fn total(&self) -> u64 {
+        self.not_found
+            + self.unauthorized
+            + self.invalid_input
+            + self.too_large
+            + self.conflict
+            + self.duplicate.unwrap_or(0)
+            + self.quota_exceeded.unwrap_or(0)
+    }
+
+    fn add(&mut self, other: &ErrorCounts) {
+        self.not_found += other.not_found;
+        self.unauthorized += other.unauthorized;
+        self.invalid_input += other.invalid_input;
+        self.too_large += other.too_large;
+        self.conflict += other.conflict;
+        if let Some(duplicate) = other.duplicate {
+            *self.duplicate.get_or_insert(0) += duplicate;
+        }
+        if let Some(quota_exceeded) = other.quota_exceeded {
+            *self.quota_exceeded.get_or_insert(0) += quota_exceeded;
+        }
+    }
+}
+
+// Outcomes of one endpoint on one UTC day
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct EndpointDay {
+    successes: u64,
+    errors: ErrorCounts,
+}
+
+impl_bounded_storable!(EndpointDay, 256);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct LatencyHistogram {
+    // One count per entry of LATENCY_BUCKETS_SECONDS, plus the unbounded bucket
+    counts: Vec<u64>,
+}
+
+impl_bounded_storable!(LatencyHistogram, 256);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct EndpointSlo {
+    endpoint: String,
+    calls: u64,
+    successes: u64,
+    errors: ErrorCounts,
+    // Successful calls over all calls; None without calls
+    availability: Option<f64>,
+}
+
+// Percentiles are the upper bound of the histogram bucket they fall in, in seconds. None when
+// there are no samples or the percentile is in the unbounded bucket.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct LatencyPercentiles {
+    samples: u64,
+    p50_seconds: Option<u64>,
+    p90_seconds: Option<u64>,
+    p99_seconds: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct SloReport {
+    // Start of the first day and end of the last day of the period
+    start_timestamp: u64,
+    end_timestamp: u64,
+    endpoints: Vec<EndpointSlo>,
+    ingestion: EndpointSlo,
+    // Time from observation to ingestion of new readings
+    ingestion_lag: LatencyPercentiles,
+}
+
+type DayKey = (StringKey, u64);
+
+thread_local! {
+    static SLO_COUNTERS: RefCell<StableBTreeMap<DayKey, EndpointDay, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(SLO_COUNTER_MEMORY_ID)));
+
+    static SLO_LATENCIES: RefCell<StableBTreeMap<DayKey, LatencyHistogram, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(SLO_LATENCY_MEMORY_ID)));
+}
+
+fn today() -> u64 {
+    time() / NANOS_PER_DAY
+}
+
+fn record_outcome<T>(endpoint: &str, result: &Result<T, Error>) {
+    let key = (StringKey(endpoint.to_string()), today());
+    SLO_COUNTERS.with(|s| {
+        let mut storage = s.borrow_mut();
+        let mut day = storage.get(&key).unwrap_or_default();
+        match result {
+            Ok(_) => day.successes += 1,
+            Err(Error::NotFound { .. }) => day.errors.not_found += 1,
+            Err(Error::Unauthorized { .. }) => day.errors.unauthorized += 1,
+            Err(Error::InvalidInput { .. }) => day.errors.invalid_input += 1,
+            Err(Error::TooLarge { .. }) => day.errors.too_large += 1,
+            Err(Error::Conflict { .. }) => day.errors.conflict += 1,
+            Err(Error::Duplicate { .. }) => *day.errors.duplicate.get_or_insert(0) += 1,
+            Err(Error::QuotaExceeded { .. }) => *day.errors.quota_exceeded.get_or_insert(0) += 1,
+        }
+        storage.insert(key, day);
+    });
+}
+
+fn record_latency(metric: &str, nanos: u64) {
+    let seconds = nanos / NANOS_PER_SECOND;
+    let bucket = LATENCY_BUCKETS_SECONDS
+        .iter()
+        .position(|bound| seconds < *bound)
+        .unwrap_or(LATENCY_BUCKETS_SECONDS.len());
+    let key = (StringKey(metric.to_string()), today());
+    SLO_LATENCIES.with(|s| {
+        let mut storage = s.borrow_mut();
+        let mut histogram = storage.get(&key).unwrap_or_default();
+        histogram
+            .counts
+            .resize(LATENCY_BUCKETS_SECONDS.len() + 1, 0);
+        histogram.counts[bucket] += 1;
+        storage.insert(key, histogram);
+    });
+}
+
+// Runs an endpoint and counts its outcome. Calls that trap are rolled back and not counted.
+pub(crate) fn tracked<T>(
+    endpoint: &str,
+    call: impl FnOnce() -> Result<T, Error>,
+) -> Result<T, Error> {
+    let result = call();
+    record_outcome(endpoint, &result);
+    result
+}
+
+// Like `tracked`, and samples the ingestion lag of readings stored by this call
+pub(crate) fn tracked_ingestion(
+    endpoint: &str,
+    call: impl FnOnce() -> Result<AirQualityData, Error>,
+) -> Result<AirQualityData, Error> {
+    let result = tracked(endpoint, call);
+    if let Ok(data) = &result {
+        // Retries and replacements return readings ingested earlier
+        if data.ingested_at == Some(time()) {
+            record_latency(INGESTION_LAG, time().saturating_sub(data.timestamp));
+        }
+    }
+    result
+}
+
+fn summarize(endpoint: String, days: &[EndpointDay]) -> EndpointSlo {
+    let successes = days.iter().map(|day| day.successes).sum();
+    let mut errors = ErrorCounts::default();
+    for day in days {
+        errors.add(&day.errors);
+    }
+    let calls = successes + errors.total();
+    EndpointSlo {
+        endpoint,
+        calls,
+        successes,
+        errors,
+        availability: (calls > 0).then(|| successes as f64 / calls as f64),
+    }
+}
+
+fn percentiles(counts: &[u64]) -> LatencyPercentiles {
+    let samples: u64 = counts.iter().sum();
+    let percentile = |p: f64| {
+        let rank = (samples as f64 * p).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        counts.iter().enumerate().find_map(|(bucket, count)| {
+            seen += count;
+            (seen >= rank).then(|| LATENCY_BUCKETS_SECONDS.get(bucket).copied())
+        })?
+    };
+    LatencyPercentiles {
+        samples,
+        p50_seconds: percentile(0.5),
+        p90_seconds: percentile(0.9),
+        p99_seconds: percentile(0.99),
+    }
+}
+
+// Service levels of the tracked endpoints over the last `days` UTC days, today included
+#[ic_cdk::query]
+fn get_slo_report(days: u32) -> Result<SloReport, Error> {
+    if days == 0 || days > MAX_REPORT_DAYS {
+        return Err(Error::InvalidInput {
+            msg: format!("days must be 1 to {}", MAX_REPORT_DAYS),
+            violations: None,
+        });
+    }
+    let last_day = today();
+    let first_day = last_day.saturating_sub(days as u64 - 1);
+    let in_period = |day: u64| (first_day..=last_day).contains(&day);
+
+    let mut by_endpoint: BTreeMap<String, Vec<EndpointDay>> = BTreeMap::new();
+    SLO_COUNTERS.with(|s| {
+        for ((StringKey(endpoint), day), counts) in s.borrow().iter() {
+            if in_period(day) {
+                by_endpoint.entry(endpoint).or_default().push(counts);
+            }
+        }
+    });
+    let ingestion_days: Vec<EndpointDay> = by_endpoint
+        .iter()
+        .filter(|(endpoint, _)| INGESTION_ENDPOINTS.contains(&endpoint.as_str()))
+        .flat_map(|(_, days)| days.iter().cloned())
+        .collect();
+
+    let mut lag_counts = vec![0; LATENCY_BUCKETS_SECONDS.len() + 1];
+    let start = (StringKey(INGESTION_LAG.to_string()), first_day);
+    let end = (StringKey(INGESTION_LAG.to_string()), last_day);
+    SLO_LATENCIES.with(|s| {
+        for (_, histogram) in s
+            .borrow()
+            .range((Bound::Included(start), Bound::Included(end)))
+        {
+            for (total, count) in lag_counts.iter_mut().zip(histogram.counts) {
+                *total += count;
+            }
+        }
+    });
+
+    Ok(SloReport {
+        start_timestamp: first_day * NANOS_PER_DAY,
+        end_timestamp: (last_day + 1) * NANOS_PER_DAY - 1,
+        endpoints: by_endpoint
+            .into_iter()
+            .map(|(endpoint, days)| summarize(endpoint, &days))
+            .collect(),
+        ingestion: summarize("ingestion".to_string(), &ingestion_days),
+        ingestion_lag: percentiles(&lag_counts),
+    })
+}
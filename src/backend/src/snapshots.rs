@@ -0,0 +1,174 @@
+use crate::aggregates::{level_in_micrograms, readings_at};
+use crate::aliases;
+use crate::standards::AqiCategory;
+use crate::{stations, AirQualityData, Pollutant};
+use std::collections::{BTreeMap, BTreeSet};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Days a snapshot looks back over, the day of the snapshot included
+const SNAPSHOT_DAYS: u64 = 30;
+
+// State of a location over the 30 UTC days up to and including one day
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct DailySnapshot {
+    // Start of the day of the snapshot
+    date: u64,
+    window_start: u64,
+    window_end: u64,
+    readings: u64,
+    // Mean µg/m³ per pollutant
+    pollutant_means: Vec<(Pollutant, f64)>,
+    // Days in each category by their mean AQI; days without readings are not counted
+    category_days: Vec<(AqiCategory, u64)>,
+    // Listed stations that reported in the window
+    stations: Vec<String>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct PollutantChange {
+    pollutant: Pollutant,
+    mean_a: Option<f64>,
+    mean_b: Option<f64>,
+    // None unless both snapshots report the pollutant
+    change: Option<f64>,
+    change_percent: Option<f64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CategoryDayChange {
+    category: AqiCategory,
+    days_a: u64,
+    days_b: u64,
+    change: i64,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct SnapshotDiff {
+    location: String,
+    a: DailySnapshot,
+    b: DailySnapshot,
+    pollutant_changes: Vec<PollutantChange>,
+    category_day_changes: Vec<CategoryDayChange>,
+    // Stations reporting in snapshot b but not in a, and the other way round
+    new_stations: Vec<String>,
+    removed_stations: Vec<String>,
+}
+
+fn snapshot(location: &str, date: u64) -> DailySnapshot {
+    let day = date / NANOS_PER_DAY;
+    let window_start = day.saturating_sub(SNAPSHOT_DAYS - 1) * NANOS_PER_DAY;
+    let window_end = (day + 1) * NANOS_PER_DAY - 1;
+    let readings: Vec<AirQualityData> = readings_at(location, window_start, window_end);
+
+    let mut levels: BTreeMap<Pollutant, (f64, u64)> = BTreeMap::new();
+    let mut day_indices: BTreeMap<u64, (f64, u64)> = BTreeMap::new();
+    let mut stations = BTreeSet::new();
+    for data in &readings {
+        for pollutant in data.pollutant_levels.keys() {
+            if let Some(level) = level_in_micrograms(data, pollutant) {
+                let entry = levels.entry(pollutant.clone()).or_default();
+                entry.0 += level;
+                entry.1 += 1;
+            }
+        }
+        let entry = day_indices
+            .entry(data.timestamp / NANOS_PER_DAY)
+            .or_default();
+        entry.0 += data.air_quality_index as f64;
+        entry.1 += 1;
+        if let Some(station_id) = &data.station_id {
+            if stations::is_station_visible(station_id) {
+                stations.insert(station_id.clone());
+            }
+        }
+    }
+
+    let mut category_days: BTreeMap<AqiCategory, u64> = BTreeMap::new();
+    for (sum, count) in day_indices.values() {
+        *category_days
+            .entry(AqiCategory::of(sum / *count as f64))
+            .or_default() += 1;
+    }
+
+    DailySnapshot {
+        date: day * NANOS_PER_DAY,
+        window_start,
+        window_end,
+        readings: readings.len() as u64,
+        pollutant_means: levels
+            .into_iter()
+            .map(|(pollutant, (sum, count))| (pollutant, sum / count as f64))
+            .collect(),
+        category_days: AqiCategory::ALL
+            .iter()
+            .map(|category| (*category, category_days.get(category).copied().unwrap_or(0)))
+            .collect(),
+        stations: stations.into_iter().collect(),
+    }
+}
+
+// What changed at a location between the snapshots of two days: pollutant means, days per AQI
+// category and reporting stations. Dates are any timestamp within the day.
+#[ic_cdk::query]
+fn diff_daily_snapshots(location: String, date_a: u64, date_b: u64) -> SnapshotDiff {
+    let location = aliases::location(location);
+    let a = snapshot(&location, date_a);
+    let b = snapshot(&location, date_b);
+
+    let means_a: BTreeMap<Pollutant, f64> = a.pollutant_means.iter().cloned().collect();
+    let means_b: BTreeMap<Pollutant, f64> = b.pollutant_means.iter().cloned().collect();
+    let pollutants: BTreeSet<&Pollutant> = means_a.keys().chain(means_b.keys()).collect();
+    let pollutant_changes = pollutants
+        .into_iter()
+        .map(|pollutant| {
+            let mean_a = means_a.get(pollutant).copied();
+            let mean_b = means_b.get(pollutant).copied();
+            let change = mean_a.zip(mean_b).map(|(a, b)| b - a);
+            PollutantChange {
+                pollutant: pollutant.clone(),
+                mean_a,
+                mean_b,
+                change,
+                change_percent: change
+                    .zip(mean_a)
+                    .filter(|(_, a)| *a != 0.0)
+                    .map(|(change, a)| change / a * 100.0),
+            }
+        })
+        .collect();
+
+    let category_day_changes = a
+        .category_days
+        .iter()
+        .zip(&b.category_days)
+        .map(|((category, days_a), (_, days_b))| CategoryDayChange {
+            category: *category,
+            days_a: *days_a,
+            days_b: *days_b,
+            change: *days_b as i64 - *days_a as i64,
+        })
+        .collect();
+
+    let new_stations = b
+        .stations
+        .iter()
+        .filter(|station| !a.stations.contains(station))
+        .cloned()
+        .collect();
+    let removed_stations = a
+        .stations
+        .iter()
+        .filter(|station| !b.stations.contains(station))
+        .cloned()
+        .collect();
+
+    SnapshotDiff {
+        location,
+        a,
+        b,
+        pollutant_changes,
+        category_day_changes,
+        new_stations,
+        removed_stations,
+    }
+}
@@ -0,0 +1,87 @@
+use crate::stations;
+
+pub(crate) const BACKFILL_CONNECTOR: &str = "backfill";
+pub(crate) const AIRNOW_CONNECTOR: &str = "airnow";
+pub(crate) const PURPLEAIR_CONNECTOR: &str = "purpleair";
+pub(crate) const SENSOR_COMMUNITY_CONNECTOR: &str = "sensor_community";
+
+// How a reading entered the canister, recorded by the path that stored it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub(crate) enum ReadingSource {
+    // Submitted through the reading endpoints by anyone but a station's sensor
+    ManualEntry,
+    // Submitted by the sensor of station `sensor_id`, calling as its ingest principal
+    SensorPush {
+        sensor_id: String,
+    },
+    // Fetched by the canister from another service. `external_id` names what was fetched
+    // there: a backfill job, an AirNow reporting area or site, a PurpleAir sensor index or a
+    // Sensor.Community sensor id.
+    Import {
+        connector: String,
+        external_id: String,
+    },
+}
+
+impl ReadingSource {
+    pub(crate) fn import(connector: &str, external_id: impl ToString) -> Self {
+        ReadingSource::Import {
+            connector: connector.to_string(),
+            external_id: external_id.to_string(),
+        }
+    }
+}
+
+// What a source filter matches; fields left as None match any value. Readings stored before
+// sources were recorded match none.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum SourceFilter {
+    ManualEntry,
+    SensorPush {
+        sensor_id: Option<String>,
+    },
+    Import {
+        connector: Option<String>,
+        external_id: Option<String>,
+    },
+}
+
+impl SourceFilter {
+    pub(crate) fn matches(&self, source: Option<&ReadingSource>) -> bool {
+        let field_matches =
+            |wanted: &Option<String>, value: &str| wanted.as_deref().is_none_or(|w| w == value);
+        match (self, source) {
+            (SourceFilter::ManualEntry, Some(ReadingSource::ManualEntry)) => true,
+            (
+                SourceFilter::SensorPush { sensor_id },
+                Some(ReadingSource::SensorPush { sensor_id: value }),
+            ) => field_matches(sensor_id, value),
+            (
+                SourceFilter::Import {
+                    connector,
+                    external_id,
+                },
+                Some(ReadingSource::Import {
+                    connector: connector_value,
+                    external_id: external_id_value,
+                }),
+            ) => {
+                field_matches(connector, connector_value)
+                    && field_matches(external_id, external_id_value)
+            }
+            _ => false,
+        }
+    }
+}
+
+// The source of a reading the caller submits: a sensor push if the caller is the station's
+// ingest principal, a manual entry otherwise
+pub(crate) fn of_caller(station_id: Option<&str>) -> ReadingSource {
+    let caller = ic_cdk::caller();
+    match station_id.and_then(stations::get_station) {
+        Some(station) if station.ingest_principal() == Some(caller) => ReadingSource::SensorPush {
+            sensor_id: station.station_id().to_string(),
+        },
+        _ => ReadingSource::ManualEntry,
+    }
+}
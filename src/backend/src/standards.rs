@@ -0,0 +1,247 @@
+use crate::units::ConcentrationUnit;
+use crate::Pollutant;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum AqiStandard {
+    // US EPA Air Quality Index
+    UsEpa,
+}
+
+// Limits concentrations are assessed against, rather than an index
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum GuidelineStandard {
+    // WHO 2021 air quality guidelines, short-term levels
+    Who2021,
+    // US National Ambient Air Quality Standards, short-term primary standards
+    Naaqs,
+}
+
+// A limit on the mean concentration over a window, in µg/m³. NAAQS limits set as mixing
+// ratios are converted at 25 °C and 1013.25 hPa.
+pub(crate) struct Limit {
+    pub(crate) pollutant: Pollutant,
+    pub(crate) averaging_hours: u32,
+    pub(crate) micrograms_per_cubic_meter: f64,
+}
+
+const fn limit(
+    pollutant: Pollutant,
+    averaging_hours: u32,
+    micrograms_per_cubic_meter: f64,
+) -> Limit {
+    Limit {
+        pollutant,
+        averaging_hours,
+        micrograms_per_cubic_meter,
+    }
+}
+
+const WHO_2021_LIMITS: &[Limit] = &[
+    limit(Pollutant::PM25, 24, 15.0),
+    limit(Pollutant::PM10, 24, 45.0),
+    limit(Pollutant::O3, 8, 100.0),
+    limit(Pollutant::NO2, 24, 25.0),
+    limit(Pollutant::SO2, 24, 40.0),
+    limit(Pollutant::CO, 24, 4_000.0),
+];
+
+const NAAQS_LIMITS: &[Limit] = &[
+    limit(Pollutant::PM25, 24, 35.0),
+    limit(Pollutant::PM10, 24, 150.0),
+    // 0.070 ppm
+    limit(Pollutant::O3, 8, 137.4),
+    // 100 ppb
+    limit(Pollutant::NO2, 1, 188.1),
+    // 75 ppb
+    limit(Pollutant::SO2, 1, 196.5),
+    // 9 ppm and 35 ppm
+    limit(Pollutant::CO, 8, 10_306.0),
+    limit(Pollutant::CO, 1, 40_080.0),
+];
+
+impl GuidelineStandard {
+    pub(crate) fn limits(&self) -> &'static [Limit] {
+        match self {
+            GuidelineStandard::Who2021 => WHO_2021_LIMITS,
+            GuidelineStandard::Naaqs => NAAQS_LIMITS,
+        }
+    }
+}
+
+// (lowest and highest concentration in µg/m³, lowest and highest index) of the US EPA bands of
+// the particulates, PM2.5 as revised in 2024
+const PM25_BREAKPOINTS: &[(f64, f64, u32, u32)] = &[
+    (0.0, 9.0, 0, 50),
+    (9.1, 35.4, 51, 100),
+    (35.5, 55.4, 101, 150),
+    (55.5, 125.4, 151, 200),
+    (125.5, 225.4, 201, 300),
+    (225.5, 325.4, 301, 500),
+];
+const PM10_BREAKPOINTS: &[(f64, f64, u32, u32)] = &[
+    (0.0, 54.0, 0, 50),
+    (55.0, 154.0, 51, 100),
+    (155.0, 254.0, 101, 150),
+    (255.0, 354.0, 151, 200),
+    (355.0, 424.0, 201, 300),
+    (425.0, 604.0, 301, 500),
+];
+
+// US EPA index of a PM2.5 or PM10 level in µg/m³, interpolated within its band. Levels are
+// truncated to the band precision first; levels beyond the top band are 500. None for other
+// pollutants.
+pub(crate) fn us_epa_particulate_index(pollutant: &Pollutant, level: f64) -> Option<u32> {
+    let (breakpoints, level) = match pollutant {
+        Pollutant::PM25 => (PM25_BREAKPOINTS, (level * 10.0).floor() / 10.0),
+        Pollutant::PM10 => (PM10_BREAKPOINTS, level.floor()),
+        _ => return None,
+    };
+    let level = level.max(0.0);
+    let index = breakpoints
+        .iter()
+        .find(|(_, high, _, _)| level <= *high)
+        .map_or(500.0, |(low, high, index_low, index_high)| {
+            (*index_high - *index_low) as f64 / (high - low) * (level - low) + *index_low as f64
+        });
+    Some(index.round() as u32)
+}
+
+// US EPA AQI categories
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub(crate) enum AqiCategory {
+    Good,
+    Moderate,
+    UnhealthyForSensitiveGroups,
+    Unhealthy,
+    VeryUnhealthy,
+    Hazardous,
+}
+
+// Index range of a category, both ends included; the last band has no upper end
+const US_EPA_BANDS: &[(AqiCategory, u32, Option<u32>)] = &[
+    (AqiCategory::Good, 0, Some(50)),
+    (AqiCategory::Moderate, 51, Some(100)),
+    (AqiCategory::UnhealthyForSensitiveGroups, 101, Some(150)),
+    (AqiCategory::Unhealthy, 151, Some(200)),
+    (AqiCategory::VeryUnhealthy, 201, Some(300)),
+    (AqiCategory::Hazardous, 301, None),
+];
+
+impl AqiCategory {
+    pub(crate) const ALL: [AqiCategory; 6] = [
+        AqiCategory::Good,
+        AqiCategory::Moderate,
+        AqiCategory::UnhealthyForSensitiveGroups,
+        AqiCategory::Unhealthy,
+        AqiCategory::VeryUnhealthy,
+        AqiCategory::Hazardous,
+    ];
+
+    pub(crate) fn of(air_quality_index: f64) -> AqiCategory {
+        let index = air_quality_index.round().max(0.0) as u32;
+        US_EPA_BANDS
+            .iter()
+            .find(|(_, _, max)| max.is_none_or(|max| index <= max))
+            .map_or(AqiCategory::Hazardous, |(category, _, _)| *category)
+    }
+
+    // Lowest and highest index of the category; Hazardous has no highest
+    pub(crate) fn bounds(self) -> (u32, Option<u32>) {
+        US_EPA_BANDS
+            .iter()
+            .find(|(category, _, _)| *category == self)
+            .map(|(_, min, max)| (*min, *max))
+            .expect("every category has a band")
+    }
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct AveragingWindow {
+    hours: u32,
+    // get_rolling_averages computes this window
+    rolling_average_available: bool,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct StandardPollutant {
+    pollutant: Pollutant,
+    // Unit the standard's breakpoints are set in
+    unit: ConcentrationUnit,
+    averaging_windows: Vec<AveragingWindow>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct CategoryBand {
+    category: AqiCategory,
+    min_index: u32,
+    // None for the open-ended top band
+    max_index: Option<u32>,
+}
+
+// What this canister implements of a standard. Pollutants not listed are not part of it, and
+// their levels do not count towards its index.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct StandardDetails {
+    standard: AqiStandard,
+    name: String,
+    pollutants: Vec<StandardPollutant>,
+    category_bands: Vec<CategoryBand>,
+}
+
+fn pollutant(
+    pollutant: Pollutant,
+    unit: ConcentrationUnit,
+    windows: &[(u32, bool)],
+) -> StandardPollutant {
+    StandardPollutant {
+        pollutant,
+        unit,
+        averaging_windows: windows
+            .iter()
+            .map(|(hours, rolling_average_available)| AveragingWindow {
+                hours: *hours,
+                rolling_average_available: *rolling_average_available,
+            })
+            .collect(),
+    }
+}
+
+#[ic_cdk::query]
+fn get_standard_details(standard: AqiStandard) -> StandardDetails {
+    match standard {
+        AqiStandard::UsEpa => StandardDetails {
+            standard,
+            name: "US EPA Air Quality Index".to_string(),
+            pollutants: vec![
+                pollutant(
+                    Pollutant::PM25,
+                    ConcentrationUnit::MicrogramsPerCubicMeter,
+                    &[(24, true)],
+                ),
+                pollutant(
+                    Pollutant::PM10,
+                    ConcentrationUnit::MicrogramsPerCubicMeter,
+                    &[(24, true)],
+                ),
+                pollutant(
+                    Pollutant::O3,
+                    ConcentrationUnit::Ppm,
+                    &[(8, true), (1, false)],
+                ),
+                pollutant(Pollutant::CO, ConcentrationUnit::Ppm, &[(8, false)]),
+                pollutant(Pollutant::SO2, ConcentrationUnit::Ppb, &[(1, false)]),
+                pollutant(Pollutant::NO2, ConcentrationUnit::Ppb, &[(1, false)]),
+            ],
+            category_bands: US_EPA_BANDS
+                .iter()
+                .map(|(category, min_index, max_index)| CategoryBand {
+                    category: *category,
+                    min_index: *min_index,
+                    max_index: *max_index,
+                })
+                .collect(),
+        },
+    }
+}
@@ -0,0 +1,487 @@
+use crate::aliases;
+use crate::merge::{MergePolicy, MAX_MERGE_WINDOW_SECONDS};
+use crate::{
+    accepts_writes, get_memory, organizations, Error, Memory, StringKey, STATION_STORAGE_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+const MAX_STATION_ID_LEN: usize = 64;
+const MAX_NAME_LEN: usize = 200;
+const MAX_ENCRYPTED_COORDINATES_LEN: usize = 512;
+const MIN_GRID_METERS: u32 = 100;
+const METERS_PER_DEGREE_LATITUDE: f64 = 111_320.0;
+const DEFAULT_REPORTING_INTERVAL_SECONDS: u32 = 300;
+const MIN_REPORTING_INTERVAL_SECONDS: u32 = 10;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const MAX_EMBARGO_SECONDS: u64 = 90 * 24 * 60 * 60;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum PrivacyTier {
+    // Exact coordinates are public
+    Public,
+    // Public outputs only ever show coordinates snapped to a grid of this size
+    Private { grid_meters: u32 },
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum StationVisibility {
+    // The station and its readings are returned by public queries
+    Listed,
+    // Readings only count towards aggregates; the station and its readings are shown to the
+    // owner and controllers alone
+    AggregateOnly,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Station {
+    station_id: String,
+    name: String,
+    location: String,
+    owner: Principal,
+    // Exact for public stations, the centre of the grid cell for private ones
+    latitude: f64,
+    longitude: f64,
+    privacy: PrivacyTier,
+    // Exact coordinates encrypted client-side by the owner; never decrypted by the canister
+    encrypted_coordinates: Option<Vec<u8>>,
+    registered_at: u64,
+    updated_at: u64,
+    // None for stations registered before visibility modes, which are listed
+    visibility: Option<StationVisibility>,
+    // Principal the sensor submits readings as
+    ingest_principal: Option<Principal>,
+    // How often the sensor reports; None for the default
+    reporting_interval_seconds: Option<u32>,
+    // Readings stay hidden from the public this long after they were observed, so the owner
+    // can validate them first
+    embargo_seconds: Option<u64>,
+    // How readings reported twice in quick succession are merged; None stores them all
+    merge_policy: Option<MergePolicy>,
+    // Organization the station and its readings belong to; None for a station of no tenant
+    organization_id: Option<u64>,
+}
+
+impl_bounded_storable!(Station, 2048);
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct StationPayload {
+    station_id: String,
+    name: String,
+    location: String,
+    latitude: f64,
+    longitude: f64,
+    privacy: PrivacyTier,
+    encrypted_coordinates: Option<Vec<u8>>,
+    // Defaults to Listed
+    visibility: Option<StationVisibility>,
+    ingest_principal: Option<Principal>,
+    reporting_interval_seconds: Option<u32>,
+    embargo_seconds: Option<u64>,
+    merge_policy: Option<MergePolicy>,
+    // The caller must be a member
+    organization_id: Option<u64>,
+}
+
+// What a sensor needs to start reporting, handed to it at installation
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct StationProvisioning {
+    canister_id: Principal,
+    station_id: String,
+    ingest_principal: Option<Principal>,
+    reporting_interval_seconds: u32,
+    // The fields above as one short URI, to be encoded into a QR code
+    uri: String,
+}
+
+// What everyone but the owner gets to see of a station
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct PublicStation {
+    station_id: String,
+    name: String,
+    location: String,
+    latitude: f64,
+    longitude: f64,
+    coordinates_fuzzed: bool,
+    registered_at: u64,
+}
+
+thread_local! {
+    static STATION_STORAGE: RefCell<StableBTreeMap<StringKey, Station, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(STATION_STORAGE_MEMORY_ID)));
+}
+
+impl PublicStation {
+    pub(crate) fn station_id(&self) -> &str {
+        &self.station_id
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    // (latitude, longitude)
+    pub(crate) fn coordinates(&self) -> (f64, f64) {
+        (self.latitude, self.longitude)
+    }
+}
+
+impl Station {
+    fn is_aggregate_only(&self) -> bool {
+        self.visibility == Some(StationVisibility::AggregateOnly)
+    }
+
+    // Owners and controllers see aggregate-only stations like any other, and stations of
+    // members-only organizations
+    fn is_visible_to(&self, caller: &Principal) -> bool {
+        self.owner == *caller
+            || ic_cdk::api::is_controller(caller)
+            || (!self.is_aggregate_only()
+                && organizations::is_visible_to(self.organization_id, caller))
+    }
+
+    pub(crate) fn location(&self) -> &str {
+        &self.location
+    }
+
+    pub(crate) fn owner(&self) -> Principal {
+        self.owner
+    }
+
+    pub(crate) fn station_id(&self) -> &str {
+        &self.station_id
+    }
+
+    pub(crate) fn ingest_principal(&self) -> Option<Principal> {
+        self.ingest_principal
+    }
+
+    pub(crate) fn organization_id(&self) -> Option<u64> {
+        self.organization_id
+    }
+
+    // The owner, controllers and the admins of the station's organization
+    pub(crate) fn is_managed_by(&self, caller: &Principal) -> bool {
+        self.owner == *caller
+            || ic_cdk::api::is_controller(caller)
+            || self
+                .organization_id
+                .is_some_and(|id| organizations::is_admin_of(id, caller))
+    }
+
+    fn to_public(&self) -> PublicStation {
+        PublicStation {
+            station_id: self.station_id.clone(),
+            name: self.name.clone(),
+            location: self.location.clone(),
+            latitude: self.latitude,
+            longitude: self.longitude,
+            coordinates_fuzzed: self.privacy != PrivacyTier::Public,
+            registered_at: self.registered_at,
+        }
+    }
+}
+
+// Snaps coordinates to the centre of their grid cell
+fn snap_to_grid(latitude: f64, longitude: f64, grid_meters: u32) -> (f64, f64) {
+    let lat_step = grid_meters as f64 / METERS_PER_DEGREE_LATITUDE;
+    let snapped_lat = ((latitude / lat_step).floor() + 0.5) * lat_step;
+    // Cells keep the same width in meters towards the poles
+    let lon_scale = snapped_lat.to_radians().cos().max(0.01);
+    let lon_step = lat_step / lon_scale;
+    let snapped_lon = ((longitude / lon_step).floor() + 0.5) * lon_step;
+    (
+        snapped_lat.clamp(-90.0, 90.0),
+        snapped_lon.clamp(-180.0, 180.0),
+    )
+}
+
+fn validate_payload(payload: &StationPayload) -> Result<(), Error> {
+    if payload.station_id.is_empty() || payload.station_id.len() > MAX_STATION_ID_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!("station_id must be 1 to {} bytes", MAX_STATION_ID_LEN),
+            violations: None,
+        });
+    }
+    if payload.name.len() > MAX_NAME_LEN || payload.location.len() > MAX_NAME_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!("name and location must be at most {} bytes", MAX_NAME_LEN),
+            violations: None,
+        });
+    }
+    if !(-90.0..=90.0).contains(&payload.latitude) || !(-180.0..=180.0).contains(&payload.longitude)
+    {
+        return Err(Error::InvalidInput {
+            msg: "coordinates are out of range".to_string(),
+            violations: None,
+        });
+    }
+    if let PrivacyTier::Private { grid_meters } = payload.privacy {
+        if grid_meters < MIN_GRID_METERS {
+            return Err(Error::InvalidInput {
+                msg: format!("grid_meters must be at least {}", MIN_GRID_METERS),
+                violations: None,
+            });
+        }
+    }
+    if payload
+        .encrypted_coordinates
+        .as_ref()
+        .is_some_and(|blob| blob.len() > MAX_ENCRYPTED_COORDINATES_LEN)
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "encrypted_coordinates must be at most {} bytes",
+                MAX_ENCRYPTED_COORDINATES_LEN
+            ),
+            violations: None,
+        });
+    }
+    if payload
+        .reporting_interval_seconds
+        .is_some_and(|interval| interval < MIN_REPORTING_INTERVAL_SECONDS)
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "reporting_interval_seconds must be at least {}",
+                MIN_REPORTING_INTERVAL_SECONDS
+            ),
+            violations: None,
+        });
+    }
+    if payload
+        .embargo_seconds
+        .is_some_and(|seconds| seconds > MAX_EMBARGO_SECONDS)
+    {
+        return Err(Error::InvalidInput {
+            msg: format!("embargo_seconds must be at most {}", MAX_EMBARGO_SECONDS),
+            violations: None,
+        });
+    }
+    if payload.merge_policy.is_some_and(|policy| {
+        policy.window_seconds == 0 || policy.window_seconds > MAX_MERGE_WINDOW_SECONDS
+    }) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "the merge window must be 1 to {} seconds",
+                MAX_MERGE_WINDOW_SECONDS
+            ),
+            violations: None,
+        });
+    }
+    Ok(())
+}
+
+fn build_station(payload: StationPayload, owner: Principal, registered_at: u64) -> Station {
+    let (latitude, longitude) = match payload.privacy {
+        PrivacyTier::Public => (payload.latitude, payload.longitude),
+        PrivacyTier::Private { grid_meters } => {
+            snap_to_grid(payload.latitude, payload.longitude, grid_meters)
+        }
+    };
+    Station {
+        station_id: payload.station_id,
+        name: payload.name,
+        location: payload.location,
+        owner,
+        latitude,
+        longitude,
+        privacy: payload.privacy,
+        encrypted_coordinates: payload.encrypted_coordinates,
+        registered_at,
+        updated_at: time(),
+        visibility: payload.visibility,
+        ingest_principal: payload.ingest_principal,
+        reporting_interval_seconds: payload.reporting_interval_seconds,
+        embargo_seconds: payload.embargo_seconds.filter(|seconds| *seconds > 0),
+        merge_policy: payload.merge_policy,
+        organization_id: payload.organization_id,
+    }
+}
+
+pub(crate) fn get_station(station_id: &str) -> Option<Station> {
+    if station_id.len() > MAX_STATION_ID_LEN {
+        return None;
+    }
+    STATION_STORAGE.with(|s| s.borrow().get(&StringKey(station_id.to_string())))
+}
+
+// Whether public queries may return a reading from this station to the caller
+pub(crate) fn is_station_visible(station_id: &str) -> bool {
+    is_station_visible_to(station_id, &ic_cdk::caller())
+}
+
+pub(crate) fn is_station_visible_to(station_id: &str, principal: &Principal) -> bool {
+    get_station(station_id).is_none_or(|station| station.is_visible_to(principal))
+}
+
+// The stations at a location the caller may see, as everyone but their owners sees them
+pub(crate) fn public_stations_at(location: &str) -> Vec<PublicStation> {
+    let caller = ic_cdk::caller();
+    STATION_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, station)| station)
+            .filter(|station| station.location == location && station.is_visible_to(&caller))
+            .map(|station| station.to_public())
+            .collect()
+    })
+}
+
+// Public coordinates of every station, aggregate-only ones included, for aggregates over areas
+pub(crate) fn coordinates() -> Vec<(String, (f64, f64))> {
+    STATION_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(StringKey(station_id), station)| {
+                (station_id, (station.latitude, station.longitude))
+            })
+            .collect()
+    })
+}
+
+pub(crate) fn merge_policy(station_id: &str) -> Option<MergePolicy> {
+    get_station(station_id).and_then(|station| station.merge_policy)
+}
+
+// Whether a reading observed at `timestamp` is still under the station's embargo for the caller
+pub(crate) fn is_embargoed(station_id: &str, timestamp: u64) -> bool {
+    is_embargoed_for(station_id, timestamp, &ic_cdk::caller())
+}
+
+pub(crate) fn is_embargoed_for(station_id: &str, timestamp: u64, principal: &Principal) -> bool {
+    get_station(station_id).is_some_and(|station| {
+        station.embargo_seconds.is_some_and(|seconds| {
+            time() < timestamp.saturating_add(seconds * NANOS_PER_SECOND)
+                && !station.is_managed_by(principal)
+        })
+    })
+}
+
+fn do_insert_station(station: &Station) {
+    STATION_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert(StringKey(station.station_id.clone()), station.clone())
+    });
+}
+
+// Registers a station owned by the caller
+#[ic_cdk::update(guard = "accepts_writes")]
+fn register_station(payload: StationPayload) -> Result<PublicStation, Error> {
+    validate_payload(&payload)?;
+    organizations::ensure_can_write(payload.organization_id)?;
+    if get_station(&payload.station_id).is_some() {
+        return Err(Error::InvalidInput {
+            msg: format!("station {} is already registered", payload.station_id),
+            violations: None,
+        });
+    }
+    let station = build_station(payload, ic_cdk::caller(), time());
+    do_insert_station(&station);
+    Ok(station.to_public())
+}
+
+#[ic_cdk::update(guard = "accepts_writes")]
+fn update_station(payload: StationPayload) -> Result<PublicStation, Error> {
+    validate_payload(&payload)?;
+    let existing = owned_station(&payload.station_id)?;
+    if payload.organization_id != existing.organization_id {
+        organizations::ensure_can_write(payload.organization_id)?;
+    }
+    let station = build_station(payload, existing.owner, existing.registered_at);
+    do_insert_station(&station);
+    Ok(station.to_public())
+}
+
+fn owned_station(station_id: &str) -> Result<Station, Error> {
+    let station = get_station(station_id).ok_or(Error::NotFound {
+        msg: format!("station {} not found", station_id),
+    })?;
+    if !station.is_managed_by(&ic_cdk::caller()) {
+        return Err(Error::Unauthorized {
+            msg: format!("caller does not manage station {}", station_id),
+        });
+    }
+    Ok(station)
+}
+
+// A station the caller may see, as everyone but its owner sees it
+pub(crate) fn public_station(station_id: &str) -> Option<PublicStation> {
+    get_station(station_id)
+        .filter(|station| station.is_visible_to(&ic_cdk::caller()))
+        .map(|station| station.to_public())
+}
+
+#[ic_cdk::query]
+fn get_station_info(station_id: String) -> Result<PublicStation, Error> {
+    let station_id = aliases::station_id(station_id);
+    public_station(&station_id).ok_or(Error::NotFound {
+        msg: format!("station {} not found", station_id),
+    })
+}
+
+continued_page!(PublicStationPage, PublicStation);
+
+#[ic_cdk::query]
+fn list_stations(continuation: Option<String>) -> Result<PublicStationPage, Error> {
+    let caller = ic_cdk::caller();
+    STATION_STORAGE.with(|s| {
+        PublicStationPage::new(
+            s.borrow()
+                .iter()
+                .filter(|(_, station)| station.is_visible_to(&caller))
+                .map(|(_, station)| station.to_public()),
+            continuation,
+        )
+    })
+}
+
+// Percent-encodes everything but unreserved URI characters
+pub(crate) fn uri_component(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+// Provisioning payload a field technician scans while installing the station's sensor (owner
+// and controllers only)
+#[ic_cdk::query]
+fn export_station_provisioning(station_id: String) -> Result<StationProvisioning, Error> {
+    let station_id = aliases::station_id(station_id);
+    let station = owned_station(&station_id)?;
+    let canister_id = ic_cdk::id();
+    let reporting_interval_seconds = station
+        .reporting_interval_seconds
+        .unwrap_or(DEFAULT_REPORTING_INTERVAL_SECONDS);
+    let mut uri = format!(
+        "aqprov:1?c={}&s={}&i={}",
+        canister_id,
+        uri_component(&station.station_id),
+        reporting_interval_seconds
+    );
+    if let Some(principal) = station.ingest_principal {
+        uri.push_str(&format!("&p={}", principal));
+    }
+    Ok(StationProvisioning {
+        canister_id,
+        station_id: station.station_id,
+        ingest_principal: station.ingest_principal,
+        reporting_interval_seconds,
+        uri,
+    })
+}
+
+// The full record, including the encrypted exact coordinates, for the owner only
+#[ic_cdk::query]
+fn get_my_station(station_id: String) -> Result<Station, Error> {
+    let station_id = aliases::station_id(station_id);
+    owned_station(&station_id)
+}
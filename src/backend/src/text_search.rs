@@ -0,0 +1,119 @@
+use crate::paging::{self, ReadingPage};
+use crate::{
+    clear_stable_map, get_memory, is_visible_to_caller, AirQualityData, Error, Memory, StringKey,
+    AIR_QUALITY_STORAGE, TEXT_INDEX_MEMORY_ID,
+};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::ops::Bound;
+
+const MIN_TOKEN_LEN: usize = 2;
+const MAX_TOKEN_LEN: usize = 32;
+// Words past this many in a record are not indexed
+const MAX_TOKENS_PER_RECORD: usize = 64;
+const MAX_QUERY_TOKENS: usize = 8;
+const MAX_QUERY_LEN: usize = 512;
+// Too common to tell records apart
+const STOPWORDS: &[&str] = &[
+    "an", "and", "are", "as", "at", "be", "by", "for", "if", "in", "is", "it", "of", "on", "or",
+    "the", "to", "with",
+];
+
+type TokenKey = (StringKey, u64);
+
+thread_local! {
+    // (token, record id) for every word of the location and health recommendations of a
+    // record. Entries of records removed from storage are skipped by lookups.
+    static TEXT_INDEX: RefCell<StableBTreeMap<TokenKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(TEXT_INDEX_MEMORY_ID)));
+}
+
+pub(crate) fn reset() {
+    TEXT_INDEX.with(|s| clear_stable_map(s, TEXT_INDEX_MEMORY_ID));
+}
+
+// Lowercased words of a text, split on anything but letters and digits
+fn tokenize(text: &str) -> BTreeSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .map(str::to_lowercase)
+        .filter(|token| {
+            (MIN_TOKEN_LEN..=MAX_TOKEN_LEN).contains(&token.len())
+                && !STOPWORDS.contains(&token.as_str())
+        })
+        .collect()
+}
+
+fn tokens_of(data: &AirQualityData) -> impl Iterator<Item = String> {
+    let mut tokens = tokenize(&data.location);
+    tokens.extend(tokenize(&data.health_recommendations));
+    tokens.into_iter().take(MAX_TOKENS_PER_RECORD)
+}
+
+// Keeps the index in step with a write; `previous` is the record it replaced
+pub(crate) fn on_write(previous: Option<&AirQualityData>, data: &AirQualityData) {
+    TEXT_INDEX.with(|s| {
+        let mut index = s.borrow_mut();
+        if let Some(previous) = previous {
+            for token in tokens_of(previous) {
+                index.remove(&(StringKey(token), previous.id));
+            }
+        }
+        for token in tokens_of(data) {
+            index.insert((StringKey(token), data.id), ());
+        }
+    });
+}
+
+// Adds a reading stored before the index existed
+pub(crate) fn backfill(data: &AirQualityData) {
+    on_write(None, data);
+}
+
+// Live readings, after `cursor` in id order, whose location or health recommendations
+// contain every word of the query
+#[ic_cdk::query]
+fn search_text(query: String, cursor: Option<u64>) -> Result<ReadingPage, Error> {
+    if query.len() > MAX_QUERY_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!("query must be at most {} bytes", MAX_QUERY_LEN),
+            violations: None,
+        });
+    }
+    let tokens = tokenize(&query);
+    if tokens.is_empty() || tokens.len() > MAX_QUERY_TOKENS {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "query must have 1 to {} words of {} to {} characters, not counting common words",
+                MAX_QUERY_TOKENS, MIN_TOKEN_LEN, MAX_TOKEN_LEN
+            ),
+            violations: None,
+        });
+    }
+    // The longest word is likely the rarest, so its entries drive the scan
+    let driver = tokens
+        .iter()
+        .max_by_key(|token| token.len())
+        .expect("the query has a token")
+        .clone();
+    let start = match cursor {
+        Some(id) => Bound::Excluded((StringKey(driver.clone()), id)),
+        None => Bound::Included((StringKey(driver.clone()), 0)),
+    };
+    let end = Bound::Included((StringKey(driver), u64::MAX));
+    Ok(TEXT_INDEX.with(|s| {
+        let index = s.borrow();
+        paging::reading_page(
+            index
+                .range((start, end))
+                .map(|((_, id), _)| id)
+                .filter(|id| {
+                    tokens
+                        .iter()
+                        .all(|token| index.contains_key(&(StringKey(token.clone()), *id)))
+                })
+                .filter_map(|id| AIR_QUALITY_STORAGE.with(|service| service.borrow().get(&id)))
+                .filter(|data| !data.is_deleted() && is_visible_to_caller(data)),
+        )
+    }))
+}
@@ -0,0 +1,67 @@
+use crate::{
+    clear_stable_map, get_memory, AirQualityData, Memory, AIR_QUALITY_STORAGE, TIME_INDEX_MEMORY_ID,
+};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::ops::Bound;
+
+// (observation time, id)
+pub(crate) type TimeKey = (u64, u64);
+
+thread_local! {
+    // (observation time, id) of every stored reading, tombstones included, so background work
+    // on old readings does not scan the whole store
+    static TIME_INDEX: RefCell<StableBTreeMap<TimeKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(TIME_INDEX_MEMORY_ID)));
+}
+
+pub(crate) fn reset() {
+    TIME_INDEX.with(|s| clear_stable_map(s, TIME_INDEX_MEMORY_ID));
+}
+
+pub(crate) fn key_of(data: &AirQualityData) -> TimeKey {
+    (data.timestamp, data.id)
+}
+
+// Keeps the index in step with a write; `previous` is the record it replaced
+pub(crate) fn on_write(previous: Option<&AirQualityData>, data: &AirQualityData) {
+    TIME_INDEX.with(|s| {
+        let mut index = s.borrow_mut();
+        if let Some(previous) = previous {
+            index.remove(&key_of(previous));
+        }
+        index.insert(key_of(data), ());
+    });
+}
+
+pub(crate) fn on_remove(data: &AirQualityData) {
+    TIME_INDEX.with(|s| s.borrow_mut().remove(&key_of(data)));
+}
+
+// Adds a reading stored before the index existed
+pub(crate) fn backfill(data: &AirQualityData) {
+    on_write(None, data);
+}
+
+// Stored readings observed before `before`, after the key `after`, oldest first and at most
+// `limit` of them
+pub(crate) fn readings_before(
+    before: u64,
+    after: Option<TimeKey>,
+    limit: usize,
+) -> Vec<AirQualityData> {
+    let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+    let keys: Vec<TimeKey> = TIME_INDEX.with(|s| {
+        s.borrow()
+            .range((start, Bound::Excluded((before, 0))))
+            .take(limit)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    AIR_QUALITY_STORAGE.with(|service| {
+        let storage = service.borrow();
+        keys.into_iter()
+            .filter_map(|(_, id)| storage.get(&id))
+            .collect()
+    })
+}
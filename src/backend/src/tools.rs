@@ -0,0 +1,223 @@
+use crate::aggregates::{self, AggregationBucket, AggregationStat};
+use crate::http::{json, respond, HttpGatewayResponse};
+use crate::{forecast, Error, Pollutant};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+// Tools for AI assistants, described in the MCP tool format. Each wraps a query and returns
+// its result as JSON. Timestamps are nanoseconds since the epoch.
+fn manifest() -> Value {
+    let location =
+        json!({ "type": "string", "description": "Location name as stored with the readings" });
+    let timestamp = |what: &str| json!({ "type": "integer", "minimum": 0, "description": format!("{} of the period, nanoseconds since the epoch", what) });
+    let pollutant = json!({ "type": "string", "description": "Pollutant name, e.g. \"pm2.5\", \"pm10\", \"o3\", \"no2\"" });
+    json!({
+        "tools": [
+            {
+                "name": "get_location_summary",
+                "description": "Count, minimum, maximum and mean of the AQI and of each pollutant (µg/m³) at a location over a period.",
+                "inputSchema": {
+                    "type": "object",
+                    "properties": {
+                        "location": location,
+                        "start_timestamp": timestamp("Start"),
+                        "end_timestamp": timestamp("End"),
+                    },
+                    "required": ["location", "start_timestamp", "end_timestamp"],
+                },
+            },
+            {
+                "name": "get_trend",
+                "description": "Whether a pollutant at a location is improving, stable or worsening over the last window, from a linear regression.",
+                "inputSchema": {
+                    "type": "object",
+                    "properties": {
+                        "location": location,
+                        "pollutant": pollutant,
+                        "window_seconds": { "type": "integer", "minimum": 3600, "maximum": 31_622_400 },
+                    },
+                    "required": ["location", "pollutant", "window_seconds"],
+                },
+            },
+            {
+                "name": "forecast_aqi",
+                "description": "Hourly AQI forecast for a location over the next hours.",
+                "inputSchema": {
+                    "type": "object",
+                    "properties": {
+                        "location": location,
+                        "horizon_hours": { "type": "integer", "minimum": 1, "maximum": 48 },
+                    },
+                    "required": ["location", "horizon_hours"],
+                },
+            },
+            {
+                "name": "get_pollutant_percentiles",
+                "description": "Percentiles of a pollutant's levels (µg/m³) at a location over a period, e.g. the PM10 98th percentile.",
+                "inputSchema": {
+                    "type": "object",
+                    "properties": {
+                        "location": location,
+                        "pollutant": pollutant,
+                        "start_timestamp": timestamp("Start"),
+                        "end_timestamp": timestamp("End"),
+                        "percentiles": {
+                            "type": "array",
+                            "items": { "type": "number", "exclusiveMinimum": 0, "maximum": 100 },
+                            "minItems": 1,
+                            "maxItems": 20,
+                        },
+                    },
+                    "required": ["location", "pollutant", "start_timestamp", "end_timestamp", "percentiles"],
+                },
+            },
+            {
+                "name": "aggregate_readings",
+                "description": "A location's AQI and pollutant levels bucketed by hour, day or month, with the mean, max or min per bucket.",
+                "inputSchema": {
+                    "type": "object",
+                    "properties": {
+                        "location": location,
+                        "start_timestamp": timestamp("Start"),
+                        "end_timestamp": timestamp("End"),
+                        "bucket": { "type": "string", "enum": ["Hour", "Day", "Month"] },
+                        "stat": { "type": "string", "enum": ["Mean", "Max", "Min"] },
+                    },
+                    "required": ["location", "start_timestamp", "end_timestamp", "bucket", "stat"],
+                },
+            },
+        ],
+    })
+}
+
+#[derive(Deserialize)]
+struct PeriodArgs {
+    location: String,
+    start_timestamp: u64,
+    end_timestamp: u64,
+}
+
+#[derive(Deserialize)]
+struct TrendArgs {
+    location: String,
+    pollutant: Pollutant,
+    window_seconds: u64,
+}
+
+#[derive(Deserialize)]
+struct ForecastArgs {
+    location: String,
+    horizon_hours: u32,
+}
+
+#[derive(Deserialize)]
+struct PercentileArgs {
+    location: String,
+    pollutant: Pollutant,
+    start_timestamp: u64,
+    end_timestamp: u64,
+    percentiles: Vec<f64>,
+}
+
+#[derive(Deserialize)]
+struct AggregateArgs {
+    location: String,
+    start_timestamp: u64,
+    end_timestamp: u64,
+    bucket: AggregationBucket,
+    stat: AggregationStat,
+}
+
+fn parse<T: DeserializeOwned>(arguments: &str) -> Result<T, Error> {
+    serde_json::from_str(arguments).map_err(|e| Error::InvalidInput {
+        msg: format!("invalid arguments: {}", e),
+        violations: None,
+    })
+}
+
+fn to_json(value: &impl serde::Serialize) -> Result<String, Error> {
+    Ok(serde_json::to_string(value).expect("tool results serialize to JSON"))
+}
+
+fn run(name: &str, arguments: &str) -> Result<String, Error> {
+    match name {
+        "get_location_summary" => {
+            let args: PeriodArgs = parse(arguments)?;
+            to_json(&aggregates::get_location_summary(
+                args.location,
+                args.start_timestamp,
+                args.end_timestamp,
+            ))
+        }
+        "get_trend" => {
+            let args: TrendArgs = parse(arguments)?;
+            to_json(&aggregates::get_trend(
+                args.location,
+                args.pollutant,
+                args.window_seconds,
+            )?)
+        }
+        "forecast_aqi" => {
+            let args: ForecastArgs = parse(arguments)?;
+            to_json(&forecast::forecast_aqi(args.location, args.horizon_hours)?)
+        }
+        "get_pollutant_percentiles" => {
+            let args: PercentileArgs = parse(arguments)?;
+            to_json(&aggregates::get_pollutant_percentiles(
+                args.location,
+                args.pollutant,
+                args.start_timestamp,
+                args.end_timestamp,
+                args.percentiles,
+            )?)
+        }
+        "aggregate_readings" => {
+            let args: AggregateArgs = parse(arguments)?;
+            to_json(&aggregates::aggregate_readings(
+                args.location,
+                args.start_timestamp,
+                args.end_timestamp,
+                args.bucket,
+                args.stat,
+            )?)
+        }
+        _ => Err(Error::NotFound {
+            msg: format!("tool {} not found", name),
+        }),
+    }
+}
+
+// GET /tools
+pub(crate) fn handle_manifest() -> HttpGatewayResponse {
+    json(&manifest())
+}
+
+// POST /tools/{name} with the arguments as the JSON body; errors come back as
+// {"error": <Error>}
+pub(crate) fn handle_call(name: &str, body: &[u8]) -> HttpGatewayResponse {
+    let arguments = String::from_utf8_lossy(body);
+    match run(name, &arguments) {
+        Ok(result) => respond(200, result.into_bytes()),
+        Err(error) => {
+            let status_code = match error {
+                Error::NotFound { .. } => 404,
+                _ => 400,
+            };
+            respond(
+                status_code,
+                json!({ "error": error }).to_string().into_bytes(),
+            )
+        }
+    }
+}
+
+#[ic_cdk::query]
+fn get_tool_manifest() -> String {
+    manifest().to_string()
+}
+
+// Runs a tool with JSON arguments and returns its JSON result
+#[ic_cdk::query]
+fn call_tool(name: String, arguments: String) -> Result<String, Error> {
+    run(&name, &arguments)
+}
@@ -0,0 +1,456 @@
+use crate::{
+    accepts_writes, ensure_admin, get_memory, AirQualityData, Error, Memory, Pollutant, StringKey,
+    WeatherData, POLLUTANT_UNIT_MEMORY_ID,
+};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+const MAX_POLLUTANT_NAME_LEN: usize = 64;
+// J/(mol K)
+const GAS_CONSTANT: f64 = 8.314_462_618;
+// Reference conditions used when a reading does not report its own
+const DEFAULT_TEMPERATURE_CELSIUS: f64 = 25.0;
+const DEFAULT_PRESSURE_HPA: f64 = 1013.25;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+pub(crate) enum ConcentrationUnit {
+    Ppm,
+    Ppb,
+    MicrogramsPerCubicMeter,
+    MilligramsPerCubicMeter,
+}
+
+pub(crate) type PollutantUnits = HashMap<Pollutant, ConcentrationUnit>;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum PollutantClass {
+    // Converted between mixing ratios and mass concentrations through its molar mass
+    Gas { molar_mass: f64 },
+    Particulate,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct PollutantUnit {
+    pollutant: Pollutant,
+    class: Option<PollutantClass>,
+    // Unit levels are stored in
+    unit: ConcentrationUnit,
+}
+
+impl_bounded_storable!(ConcentrationUnit, 16);
+
+// Built-in pollutants by `Pollutant::name`: class and default unit of record
+const KNOWN_POLLUTANTS: &[(&str, PollutantClass, ConcentrationUnit)] = &[
+    (
+        "co",
+        PollutantClass::Gas { molar_mass: 28.01 },
+        ConcentrationUnit::Ppm,
+    ),
+    (
+        "no",
+        PollutantClass::Gas { molar_mass: 30.01 },
+        ConcentrationUnit::Ppb,
+    ),
+    (
+        "no2",
+        PollutantClass::Gas { molar_mass: 46.01 },
+        ConcentrationUnit::Ppb,
+    ),
+    (
+        "so2",
+        PollutantClass::Gas { molar_mass: 64.07 },
+        ConcentrationUnit::Ppb,
+    ),
+    (
+        "o3",
+        PollutantClass::Gas { molar_mass: 48.00 },
+        ConcentrationUnit::Ppb,
+    ),
+    (
+        "pm1",
+        PollutantClass::Particulate,
+        ConcentrationUnit::MicrogramsPerCubicMeter,
+    ),
+    (
+        "pm2.5",
+        PollutantClass::Particulate,
+        ConcentrationUnit::MicrogramsPerCubicMeter,
+    ),
+    (
+        "pm10",
+        PollutantClass::Particulate,
+        ConcentrationUnit::MicrogramsPerCubicMeter,
+    ),
+];
+
+thread_local! {
+    // Units of record configured by controllers, overriding the built-in defaults
+    static POLLUTANT_UNITS: RefCell<StableBTreeMap<StringKey, ConcentrationUnit, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(POLLUTANT_UNIT_MEMORY_ID)));
+}
+
+fn known_pollutant(pollutant: &Pollutant) -> Option<(PollutantClass, ConcentrationUnit)> {
+    KNOWN_POLLUTANTS
+        .iter()
+        .find(|(known, _, _)| *known == pollutant.name())
+        .map(|(_, class, unit)| (*class, *unit))
+}
+
+fn configured_unit(pollutant: &Pollutant) -> Option<ConcentrationUnit> {
+    if pollutant.name().len() > MAX_POLLUTANT_NAME_LEN {
+        return None;
+    }
+    POLLUTANT_UNITS.with(|s| s.borrow().get(&StringKey(pollutant.name().to_string())))
+}
+
+fn unit_of_record(pollutant: &Pollutant) -> Option<ConcentrationUnit> {
+    configured_unit(pollutant).or_else(|| known_pollutant(pollutant).map(|(_, unit)| unit))
+}
+
+fn is_mixing_ratio(unit: ConcentrationUnit) -> bool {
+    matches!(unit, ConcentrationUnit::Ppm | ConcentrationUnit::Ppb)
+}
+
+// Converts to ppb for mixing ratios and µg/m³ for mass concentrations
+fn to_base(value: f64, unit: ConcentrationUnit) -> f64 {
+    match unit {
+        ConcentrationUnit::Ppm | ConcentrationUnit::MilligramsPerCubicMeter => value * 1000.0,
+        ConcentrationUnit::Ppb | ConcentrationUnit::MicrogramsPerCubicMeter => value,
+    }
+}
+
+fn from_base(value: f64, unit: ConcentrationUnit) -> f64 {
+    match unit {
+        ConcentrationUnit::Ppm | ConcentrationUnit::MilligramsPerCubicMeter => value / 1000.0,
+        ConcentrationUnit::Ppb | ConcentrationUnit::MicrogramsPerCubicMeter => value,
+    }
+}
+
+// Ideal gas law: µg/m³ = ppb × M × P / (R × T) × 10⁻³, with P in Pa and T in K. Conditions
+// that are missing or physically impossible fall back to the reference ones.
+fn micrograms_per_ppb(molar_mass: f64, weather: Option<&WeatherData>) -> f64 {
+    let temperature_celsius = weather
+        .map(|weather| weather.temperature)
+        .filter(|temperature| temperature.is_finite() && *temperature > -273.15)
+        .unwrap_or(DEFAULT_TEMPERATURE_CELSIUS);
+    let pressure_hpa = weather
+        .and_then(|weather| weather.pressure)
+        .filter(|pressure| pressure.is_finite() && *pressure > 0.0)
+        .unwrap_or(DEFAULT_PRESSURE_HPA);
+    molar_mass * pressure_hpa * 100.0 / (GAS_CONSTANT * (temperature_celsius + 273.15)) * 1e-3
+}
+
+fn convert(
+    pollutant: &Pollutant,
+    value: f64,
+    from: ConcentrationUnit,
+    to: ConcentrationUnit,
+    weather: Option<&WeatherData>,
+) -> Result<f64, Error> {
+    let base = to_base(value, from);
+    if is_mixing_ratio(from) == is_mixing_ratio(to) {
+        return Ok(from_base(base, to));
+    }
+    let Some((PollutantClass::Gas { molar_mass }, _)) = known_pollutant(pollutant) else {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "{} cannot be converted from {:?} to {:?} without a molar mass",
+                pollutant.name(),
+                from,
+                to
+            ),
+            violations: None,
+        });
+    };
+    let factor = micrograms_per_ppb(molar_mass, weather);
+    let converted = if is_mixing_ratio(from) {
+        base * factor
+    } else {
+        base / factor
+    };
+    Ok(from_base(converted, to))
+}
+
+// A stored level in µg/m³, if it can be converted
+pub(crate) fn to_micrograms(
+    pollutant: &Pollutant,
+    value: f64,
+    unit: ConcentrationUnit,
+    weather: &WeatherData,
+) -> Option<f64> {
+    convert(
+        pollutant,
+        value,
+        unit,
+        ConcentrationUnit::MicrogramsPerCubicMeter,
+        Some(weather),
+    )
+    .ok()
+}
+
+// Expresses a record's levels in `unit` where they can be converted. Levels without a recorded
+// unit, and levels that cannot be converted (particulates to mixing ratios), are unchanged.
+pub(crate) fn in_unit(mut data: AirQualityData, unit: Option<ConcentrationUnit>) -> AirQualityData {
+    let (Some(unit), Some(stored_units)) = (unit, data.pollutant_units.as_mut()) else {
+        return data;
+    };
+    let weather = Some(&data.weather_conditions);
+    for (pollutant, stored_unit) in stored_units.iter_mut() {
+        let convert = |level: f64| convert(pollutant, level, *stored_unit, unit, weather).ok();
+        let Some(level) = data.pollutant_levels.get_mut(pollutant) else {
+            continue;
+        };
+        let Some(converted) = convert(*level) else {
+            continue;
+        };
+        *level = converted;
+        // Raw levels are in the same unit as the corrected ones
+        if let Some(raw) = data
+            .raw_pollutant_levels
+            .as_mut()
+            .and_then(|raw| raw.get_mut(pollutant))
+        {
+            *raw = convert(*raw).unwrap_or(*raw);
+        }
+        *stored_unit = unit;
+    }
+    data
+}
+
+// The unit of each pollutant across a series of readings; None for levels without a recorded
+// unit. Fails if any pollutant's levels are in more than one unit, since consumers of a series
+// read a single unit per column.
+pub(crate) fn series_units(
+    readings: &[AirQualityData],
+) -> Result<Vec<(Pollutant, Option<ConcentrationUnit>)>, Error> {
+    let mut units: BTreeMap<Pollutant, Option<ConcentrationUnit>> = BTreeMap::new();
+    for data in readings {
+        for pollutant in data.pollutant_levels.keys() {
+            let unit = data
+                .pollutant_units
+                .as_ref()
+                .and_then(|units| units.get(pollutant))
+                .copied();
+            match units.get(pollutant) {
+                Some(seen) if *seen != unit => {
+                    return Err(Error::InvalidInput {
+                        msg: format!(
+                            "{} levels of the series are in more than one unit; pass a unit to convert them",
+                            pollutant.name()
+                        ),
+                        violations: None,
+                    })
+                }
+                Some(_) => {}
+                None => {
+                    units.insert(pollutant.clone(), unit);
+                }
+            }
+        }
+    }
+    Ok(units.into_iter().collect())
+}
+
+// Converts levels reported in `units` into each pollutant's unit of record, using the
+// temperature and pressure the reading is stored with for gas conversions, or the reference
+// ones where they are missing. Returns the levels with the unit each is stored in; pollutants
+// without a unit of record or a reported unit are kept as given. `Other` spellings of named
+// pollutants are stored under the named pollutant.
+pub(crate) fn normalize_levels(
+    levels: HashMap<Pollutant, f64>,
+    units: Option<&PollutantUnits>,
+    weather: Option<&WeatherData>,
+) -> Result<(HashMap<Pollutant, f64>, PollutantUnits), Error> {
+    let reported_units: PollutantUnits = units
+        .into_iter()
+        .flatten()
+        .map(|(pollutant, unit)| (pollutant.clone().normalized(), *unit))
+        .collect();
+    let mut stored_units = HashMap::new();
+    let mut normalized = HashMap::new();
+    for (pollutant, value) in levels {
+        let pollutant = pollutant.normalized();
+        let reported = reported_units.get(&pollutant).copied();
+        let value = match (reported, unit_of_record(&pollutant)) {
+            (Some(from), Some(to)) => {
+                stored_units.insert(pollutant.clone(), to);
+                convert(&pollutant, value, from, to, weather)?
+            }
+            (Some(unit), None) | (None, Some(unit)) => {
+                stored_units.insert(pollutant.clone(), unit);
+                value
+            }
+            (None, None) => value,
+        };
+        normalized.insert(pollutant, value);
+    }
+    Ok((normalized, stored_units))
+}
+
+// Sets the unit future levels of a pollutant are stored in. Existing records keep theirs.
+#[ic_cdk::update(guard = "accepts_writes")]
+fn set_pollutant_unit(
+    pollutant: Pollutant,
+    unit: ConcentrationUnit,
+) -> Result<PollutantUnit, Error> {
+    ensure_admin()?;
+    let pollutant = pollutant.normalized();
+    if pollutant.name().is_empty() || pollutant.name().len() > MAX_POLLUTANT_NAME_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "pollutant names must be 1 to {} bytes",
+                MAX_POLLUTANT_NAME_LEN
+            ),
+            violations: None,
+        });
+    }
+    let class = known_pollutant(&pollutant).map(|(class, _)| class);
+    if class == Some(PollutantClass::Particulate) && is_mixing_ratio(unit) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "{} is a particulate and needs a mass unit",
+                pollutant.name()
+            ),
+            violations: None,
+        });
+    }
+    POLLUTANT_UNITS.with(|s| {
+        s.borrow_mut()
+            .insert(StringKey(pollutant.name().to_string()), unit)
+    });
+    Ok(PollutantUnit {
+        pollutant,
+        class,
+        unit,
+    })
+}
+
+// Units of record of the built-in and configured pollutants
+#[ic_cdk::query]
+fn get_pollutant_units() -> Vec<PollutantUnit> {
+    let mut units: Vec<PollutantUnit> = KNOWN_POLLUTANTS
+        .iter()
+        .map(|(name, class, unit)| {
+            let pollutant = Pollutant::parse(name);
+            PollutantUnit {
+                unit: configured_unit(&pollutant).unwrap_or(*unit),
+                pollutant,
+                class: Some(*class),
+            }
+        })
+        .collect();
+    POLLUTANT_UNITS.with(|s| {
+        for (StringKey(name), unit) in s.borrow().iter() {
+            let pollutant = Pollutant::parse(&name);
+            if known_pollutant(&pollutant).is_none() {
+                units.push(PollutantUnit {
+                    pollutant,
+                    class: None,
+                    unit,
+                });
+            }
+        }
+    });
+    units
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert_ok(
+        pollutant: &Pollutant,
+        value: f64,
+        from: ConcentrationUnit,
+        to: ConcentrationUnit,
+        weather: Option<&WeatherData>,
+    ) -> f64 {
+        convert(pollutant, value, from, to, weather)
+            .unwrap_or_else(|_| panic!("cannot convert {}", pollutant.name()))
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9 * expected.abs().max(1.0),
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn no2_at_reference_conditions() {
+        // 1 ppb of NO2 is about 1.88 µg/m³ at 25 °C and 1013.25 hPa
+        let micrograms = convert_ok(
+            &Pollutant::NO2,
+            1.0,
+            ConcentrationUnit::Ppb,
+            ConcentrationUnit::MicrogramsPerCubicMeter,
+            None,
+        );
+        assert!((micrograms - 1.881).abs() < 0.001, "{}", micrograms);
+    }
+
+    #[test]
+    fn ppb_and_micrograms_round_trip_at_reference_conditions() {
+        use ConcentrationUnit::*;
+        for pollutant in [Pollutant::NO2, Pollutant::SO2, Pollutant::O3, Pollutant::CO] {
+            for (from, to) in [
+                (Ppb, MicrogramsPerCubicMeter),
+                (Ppm, MilligramsPerCubicMeter),
+            ] {
+                let there = convert_ok(&pollutant, 42.5, from, to, None);
+                let back = convert_ok(&pollutant, there, to, from, None);
+                assert_close(back, 42.5);
+            }
+        }
+    }
+
+    #[test]
+    fn missing_or_impossible_weather_uses_reference_conditions() {
+        let reference = micrograms_per_ppb(46.01, None);
+        let impossible = WeatherData {
+            temperature: f64::NAN,
+            pressure: Some(-1.0),
+            ..WeatherData::default()
+        };
+        assert_close(micrograms_per_ppb(46.01, Some(&impossible)), reference);
+        let reported = WeatherData {
+            temperature: 25.0,
+            pressure: None,
+            ..WeatherData::default()
+        };
+        assert_close(micrograms_per_ppb(46.01, Some(&reported)), reference);
+    }
+
+    #[test]
+    fn levels_written_without_weather_read_back_unchanged() {
+        let weather = WeatherData::default();
+        let levels = HashMap::from([(Pollutant::NO2, 40.0)]);
+        let reported = HashMap::from([(Pollutant::NO2, ConcentrationUnit::Ppb)]);
+        let (pollutant_levels, pollutant_units) =
+            normalize_levels(levels, Some(&reported), Some(&weather))
+                .unwrap_or_else(|_| panic!("cannot normalize"));
+        let data = AirQualityData {
+            pollutant_levels,
+            pollutant_units: Some(pollutant_units),
+            weather_conditions: weather,
+            ..AirQualityData::default()
+        };
+        let read = in_unit(data, Some(ConcentrationUnit::Ppb));
+        assert_close(read.pollutant_levels[&Pollutant::NO2], 40.0);
+    }
+
+    #[test]
+    fn particulates_are_not_converted_to_mixing_ratios() {
+        assert!(convert(
+            &Pollutant::PM25,
+            10.0,
+            ConcentrationUnit::MicrogramsPerCubicMeter,
+            ConcentrationUnit::Ppb,
+            None,
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,198 @@
+use crate::{
+    accepts_writes, ensure_admin, get_memory, AirQualityData, Error, Memory, Pollutant,
+    VALIDATION_RULES_MEMORY_ID,
+};
+use ic_stable_structures::Cell;
+use std::cell::RefCell;
+
+const MAX_RULES: usize = 64;
+const MAX_POLLUTANT_NAME_LEN: usize = 64;
+
+// The value of a reading a rule constrains. Pollutant levels are checked in their unit of
+// record, after conversion and calibration.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub(crate) enum RuleTarget {
+    AirQualityIndex,
+    Temperature,
+    Humidity,
+    WindSpeed,
+    Pressure,
+    Pollutant(Pollutant),
+}
+
+// Readings whose target lies outside [min, max] are rejected. Readings without the target pass.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ValidationRule {
+    target: RuleTarget,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RuleViolation {
+    target: RuleTarget,
+    value: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ValidationRules {
+    rules: Vec<ValidationRule>,
+}
+
+impl Default for ValidationRules {
+    fn default() -> Self {
+        ValidationRules {
+            rules: vec![
+                ValidationRule {
+                    target: RuleTarget::AirQualityIndex,
+                    min: Some(0.0),
+                    max: Some(500.0),
+                },
+                ValidationRule {
+                    target: RuleTarget::Humidity,
+                    min: Some(0.0),
+                    max: Some(100.0),
+                },
+            ],
+        }
+    }
+}
+
+impl_bounded_storable!(ValidationRules, 16 * 1024);
+
+thread_local! {
+    static VALIDATION_RULES: RefCell<Cell<ValidationRules, Memory>> = RefCell::new(
+        Cell::init(get_memory(VALIDATION_RULES_MEMORY_ID), ValidationRules::default())
+            .expect("Cannot create the validation rules cell")
+    );
+}
+
+fn rules() -> Vec<ValidationRule> {
+    VALIDATION_RULES.with(|r| r.borrow().get().rules.clone())
+}
+
+fn target_value(data: &AirQualityData, target: &RuleTarget) -> Option<f64> {
+    let weather = &data.weather_conditions;
+    match target {
+        RuleTarget::AirQualityIndex => Some(data.air_quality_index as f64),
+        RuleTarget::Temperature => Some(weather.temperature),
+        RuleTarget::Humidity => Some(weather.humidity),
+        RuleTarget::WindSpeed => Some(weather.wind_speed),
+        RuleTarget::Pressure => weather.pressure,
+        RuleTarget::Pollutant(pollutant) => data.pollutant_levels.get(pollutant).copied(),
+    }
+}
+
+fn describe(target: &RuleTarget) -> &str {
+    match target {
+        RuleTarget::AirQualityIndex => "air_quality_index",
+        RuleTarget::Temperature => "temperature",
+        RuleTarget::Humidity => "humidity",
+        RuleTarget::WindSpeed => "wind_speed",
+        RuleTarget::Pressure => "pressure",
+        RuleTarget::Pollutant(pollutant) => pollutant.name(),
+    }
+}
+
+// Checks a reading against every rule and reports all violations at once
+pub(crate) fn check(data: &AirQualityData) -> Result<(), Error> {
+    let violations: Vec<RuleViolation> = rules()
+        .into_iter()
+        .filter_map(|rule| {
+            let value = target_value(data, &rule.target)?;
+            let in_range =
+                rule.min.is_none_or(|min| value >= min) && rule.max.is_none_or(|max| value <= max);
+            (!in_range).then_some(RuleViolation {
+                target: rule.target,
+                value,
+                min: rule.min,
+                max: rule.max,
+            })
+        })
+        .collect();
+    if violations.is_empty() {
+        return Ok(());
+    }
+    let details: Vec<String> = violations
+        .iter()
+        .map(|violation| {
+            format!(
+                "{} {} is outside [{}, {}]",
+                describe(&violation.target),
+                violation.value,
+                violation
+                    .min
+                    .map_or("-inf".to_string(), |min| min.to_string()),
+                violation
+                    .max
+                    .map_or("inf".to_string(), |max| max.to_string()),
+            )
+        })
+        .collect();
+    Err(Error::InvalidInput {
+        msg: format!("reading violates validation rules: {}", details.join("; ")),
+        violations: Some(violations),
+    })
+}
+
+// Replaces the rule set (controllers only). Applies to writes from now on.
+#[ic_cdk::update(guard = "accepts_writes")]
+fn set_validation_rules(rules: Vec<ValidationRule>) -> Result<Vec<ValidationRule>, Error> {
+    ensure_admin()?;
+    if rules.len() > MAX_RULES {
+        return Err(Error::InvalidInput {
+            msg: format!("at most {} rules are allowed", MAX_RULES),
+            violations: None,
+        });
+    }
+    let rules: Vec<ValidationRule> = rules
+        .into_iter()
+        .map(|rule| match rule.target {
+            RuleTarget::Pollutant(pollutant) => ValidationRule {
+                target: RuleTarget::Pollutant(pollutant.normalized()),
+                ..rule
+            },
+            _ => rule,
+        })
+        .collect();
+    for rule in &rules {
+        if let RuleTarget::Pollutant(pollutant) = &rule.target {
+            if pollutant.name().is_empty() || pollutant.name().len() > MAX_POLLUTANT_NAME_LEN {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "pollutant names must be 1 to {} bytes",
+                        MAX_POLLUTANT_NAME_LEN
+                    ),
+                    violations: None,
+                });
+            }
+        }
+        let bounds_valid = rule.min.is_none_or(f64::is_finite)
+            && rule.max.is_none_or(f64::is_finite)
+            && rule.min.zip(rule.max).is_none_or(|(min, max)| min <= max);
+        if !bounds_valid {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "the bounds of the {} rule must be finite, with min at most max",
+                    describe(&rule.target)
+                ),
+                violations: None,
+            });
+        }
+    }
+    VALIDATION_RULES
+        .with(|r| {
+            r.borrow_mut().set(ValidationRules {
+                rules: rules.clone(),
+            })
+        })
+        .expect("cannot store the validation rules");
+    Ok(rules)
+}
+
+#[ic_cdk::query]
+fn get_validation_rules() -> Vec<ValidationRule> {
+    rules()
+}
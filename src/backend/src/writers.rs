@@ -0,0 +1,101 @@
+use crate::{
+    accepts_writes, ensure_admin, get_memory, organizations, principal_key, stations, Error,
+    Memory, PrincipalKey, ALLOWED_WRITER_MEMORY_ID, WRITER_ALLOWLIST_ENFORCED_MEMORY_ID,
+};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::{Cell, StableBTreeMap};
+use std::cell::RefCell;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct AllowedWriter {
+    principal: Principal,
+    added_by: Principal,
+    added_at: u64,
+}
+
+impl_bounded_storable!(AllowedWriter, 128);
+
+thread_local! {
+    static ALLOWED_WRITERS: RefCell<StableBTreeMap<PrincipalKey, AllowedWriter, Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(ALLOWED_WRITER_MEMORY_ID)));
+
+    // When the first writer was added, 0 before. Never cleared, so removing the last writer
+    // does not open writes to everyone again.
+    static ALLOWLIST_ENFORCED_SINCE: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(get_memory(WRITER_ALLOWLIST_ENFORCED_MEMORY_ID), 0)
+            .expect("Cannot create the writer allowlist cell")
+    );
+}
+
+fn is_enforced() -> bool {
+    ALLOWLIST_ENFORCED_SINCE.with(|e| *e.borrow().get() > 0)
+}
+
+// Readings are submitted by controllers and allowed writers only, once the allowlist is in use.
+// A reading of a station must also come from the station's ingest principal, someone who
+// manages the station or, for a station of an organization, a member of it.
+pub(crate) fn ensure_allowed_writer(station_id: Option<&str>) -> Result<(), Error> {
+    let caller = ic_cdk::caller();
+    if is_enforced()
+        && !ic_cdk::api::is_controller(&caller)
+        && !ALLOWED_WRITERS.with(|w| w.borrow().contains_key(&principal_key(&caller)))
+    {
+        return Err(Error::Unauthorized {
+            msg: "caller is not an allowed writer".to_string(),
+        });
+    }
+    let Some(station) = station_id.and_then(stations::get_station) else {
+        return Ok(());
+    };
+    if station.ingest_principal() == Some(caller) || station.is_managed_by(&caller) {
+        return Ok(());
+    }
+    if station.organization_id().is_some() {
+        return organizations::ensure_can_write(station.organization_id());
+    }
+    Err(Error::Unauthorized {
+        msg: format!(
+            "caller may not submit readings of station {}",
+            station.station_id()
+        ),
+    })
+}
+
+// Lets a gateway submit readings; from the first call on, nobody else but controllers may
+// (controllers only)
+#[ic_cdk::update(guard = "accepts_writes")]
+fn add_allowed_writer(principal: Principal) -> Result<AllowedWriter, Error> {
+    ensure_admin()?;
+    let writer = AllowedWriter {
+        principal,
+        added_by: ic_cdk::caller(),
+        added_at: time(),
+    };
+    ALLOWED_WRITERS.with(|w| {
+        w.borrow_mut()
+            .insert(principal_key(&principal), writer.clone())
+    });
+    if !is_enforced() {
+        ALLOWLIST_ENFORCED_SINCE
+            .with(|e| e.borrow_mut().set(writer.added_at))
+            .expect("cannot store the writer allowlist state");
+    }
+    Ok(writer)
+}
+
+#[ic_cdk::update(guard = "accepts_writes")]
+fn remove_allowed_writer(principal: Principal) -> Result<AllowedWriter, Error> {
+    ensure_admin()?;
+    ALLOWED_WRITERS
+        .with(|w| w.borrow_mut().remove(&principal_key(&principal)))
+        .ok_or(Error::NotFound {
+            msg: format!("{} is not an allowed writer", principal),
+        })
+}
+
+#[ic_cdk::query]
+fn list_allowed_writers() -> Result<Vec<AllowedWriter>, Error> {
+    ensure_admin()?;
+    Ok(ALLOWED_WRITERS.with(|w| w.borrow().iter().map(|(_, writer)| writer).collect()))
+}